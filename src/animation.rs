@@ -1,5 +1,7 @@
 use crate::math::{f32x4, Transform, Vec2, Vec3, Wec2, Wec3};
 
+use std::ops::Range;
+
 /// A generic object which contains a property of type T which is sequenced over time.
 pub trait Sequenced<T>: Send + Sync {
     fn sample_at(&self, t: f32) -> T;
@@ -58,7 +60,22 @@ impl<T, F: Fn(f32) -> T + Send + Sync> Sequenced<T> for F {
 impl<F: Fn(f32) -> Vec3 + Send + Sync> WSequenced<Wec3> for F {
     fn sample_at(&self, t: f32x4) -> Wec3 {
         let ts = t.as_ref();
-        [self(ts[0]), self(ts[0]), self(ts[0]), self(ts[0])].into()
+        [self(ts[0]), self(ts[1]), self(ts[2]), self(ts[3])].into()
+    }
+}
+
+/// When each frame of an animation is exposed. Frame `n` opens the shutter at
+/// `n / frame_rate` and keeps it open for `shutter_speed` seconds.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameTiming {
+    pub frame_rate: f32,
+    pub shutter_speed: f32,
+}
+
+impl FrameTiming {
+    pub fn time_range(&self, frame: usize) -> Range<f32> {
+        let start = frame as f32 / self.frame_rate;
+        start..start + self.shutter_speed
     }
 }
 
@@ -69,6 +86,19 @@ mod minterpolate_integration {
     use super::*;
     use minterpolate::{InterpolationFunction, InterpolationPrimitive};
 
+    /// What a keyframed `Sequence` does at times before its first or after its last key, such
+    /// as when a shutter interval reaches past the end of the animation.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Extrapolation {
+        /// Times are clamped to the keyed range before interpolating, which holds the value
+        /// of the nearest end key.
+        Clamp,
+        /// Continues along the line through the last two keys at each end.
+        Linear,
+        /// Repeats the keyed range over and over.
+        Cycle,
+    }
+
     /// A concrete struct which holds a sequence of interpolated values of type T. Basically,
     /// a keyframed animation.
     pub struct Sequence<T: InterpolationPrimitive + Clone + Send + Sync> {
@@ -82,6 +112,7 @@ mod minterpolate_integration {
         /// If the output should be normalized after being interpolated
         /// (useful when interpolating between rotations stored as Quaternions)
         normalize: bool,
+        extrapolation: Extrapolation,
     }
 
     impl<T: InterpolationPrimitive + Clone + Send + Sync> Sequence<T> {
@@ -97,13 +128,60 @@ mod minterpolate_integration {
                 outputs,
                 interpolation,
                 normalize,
+                extrapolation: Extrapolation::Clamp,
             }
         }
 
-        pub fn sample(&self, t: f32) -> T {
+        #[allow(dead_code)]
+        pub fn with_extrapolation(mut self, extrapolation: Extrapolation) -> Self {
+            self.extrapolation = extrapolation;
+            self
+        }
+
+        fn interpolate(&self, t: f32) -> T {
             self.interpolation
                 .interpolate(t, &self.inputs, &self.outputs, self.normalize)
         }
+
+        pub fn sample(&self, t: f32) -> T {
+            let (first, last) = match (self.inputs.first(), self.inputs.last()) {
+                (Some(first), Some(last)) if *first < *last => (*first, *last),
+                _ => return self.interpolate(t),
+            };
+            if t >= first && t <= last {
+                return self.interpolate(t);
+            }
+
+            match self.extrapolation {
+                Extrapolation::Clamp => self.interpolate(t.max(first).min(last)),
+                Extrapolation::Linear => {
+                    // slope of the segment between the end key and its neighbor
+                    let n = self.inputs.len();
+                    let (a, b) = if t < first {
+                        (first, self.inputs[1])
+                    } else {
+                        (self.inputs[n - 2], last)
+                    };
+                    let (value_a, value_b) = (self.interpolate(a), self.interpolate(b));
+                    let slope = value_b.sub(&value_a).mul(1.0 / (b - a));
+                    let (end, end_value) = if t < first {
+                        (a, value_a)
+                    } else {
+                        (b, value_b)
+                    };
+                    let value = end_value.add(&slope.mul(t - end));
+                    if self.normalize {
+                        value.normalize()
+                    } else {
+                        value
+                    }
+                }
+                Extrapolation::Cycle => {
+                    let period = last - first;
+                    self.interpolate(first + (t - first).rem_euclid(period))
+                }
+            }
+        }
     }
 
     impl<T: InterpolationPrimitive + Clone + Send + Sync> Sequenced<T> for Sequence<T> {
@@ -117,6 +195,42 @@ mod minterpolate_integration {
             Vec3::from(self.sample(t))
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn line(extrapolation: Extrapolation) -> Sequence<f32> {
+            Sequence::new(
+                vec![0.0, 1.0],
+                vec![0.0, 2.0],
+                InterpolationFunction::Linear,
+                false,
+            )
+            .with_extrapolation(extrapolation)
+        }
+
+        #[test]
+        fn extrapolation_past_the_last_key() {
+            let t = 1.25;
+            assert!((line(Extrapolation::Clamp).sample(t) - 2.0).abs() < 1e-5);
+            assert!((line(Extrapolation::Linear).sample(t) - 2.5).abs() < 1e-5);
+            assert!((line(Extrapolation::Cycle).sample(t) - 0.5).abs() < 1e-5);
+        }
+
+        #[test]
+        fn linear_extrapolation_stays_normalized() {
+            let sequence = Sequence::new(
+                vec![0.0, 1.0],
+                vec![[1.0, 0.0, 0.0], [0.0, 1.0, 0.0]],
+                InterpolationFunction::Linear,
+                true,
+            )
+            .with_extrapolation(Extrapolation::Linear);
+            let value = sequence.sample(2.0);
+            assert!((value.magnitude() - 1.0).abs() < 1e-5);
+        }
+    }
 }
 
 // /// A convenient struct to hold the animation of a single Transform
@@ -0,0 +1,383 @@
+use crate::camera::Camera;
+use crate::hitable::{intersection_error_bound, Hitable, WHit, WHitAttributes, WShadingPoint};
+use crate::material::MaterialHandle;
+use crate::math::{f32x4, gamma, Aabb, Mask, Vec2, Vec2u, Vec3, Wec2, Wec3};
+use crate::ray::{Ray, WRay};
+
+/// Cells along each side of the grid of triangles which give Newton's method its starting
+/// points.
+const GRID_RESOLUTION: usize = 8;
+const NEWTON_STEPS: usize = 10;
+/// How far outside of `[0, 1]` converged parameters may be and still count as on the patch.
+const UV_TOLERANCE: f32 = 1e-4;
+/// How close to the surface Newton's method has to get, relative to the size of the patch.
+const RELATIVE_TOLERANCE: f32 = 1e-5;
+
+/// Bicubic Bezier patch given by a 4x4 grid of control points, `control_points[v][u]`.
+///
+/// Rays are intersected one lane at a time: the patch is tessellated into a coarse grid of
+/// triangles, and every triangle a ray hits seeds Newton's method on the exact surface in
+/// `(u, v)`. Normals come from the partial derivatives of the surface, and `(u, v)` is
+/// recorded as the uv of the hit.
+pub struct BezierPatch {
+    control_points: [[Vec3; 4]; 4],
+    // surface points at the corners of the grid cells, u varying fastest
+    grid: Vec<Vec3>,
+    bounds: Aabb,
+    tolerance: f32,
+    material: MaterialHandle,
+}
+
+impl BezierPatch {
+    #[allow(dead_code)]
+    pub fn new(control_points: [[Vec3; 4]; 4], material: MaterialHandle) -> Self {
+        // the patch lies within the convex hull of its control points
+        let mut bounds = Aabb::new(control_points[0][0], control_points[0][0]);
+        for point in control_points.iter().flat_map(|row| row.iter()) {
+            bounds = bounds.union(&Aabb::new(*point, *point));
+        }
+
+        let mut patch = BezierPatch {
+            control_points,
+            grid: Vec::with_capacity((GRID_RESOLUTION + 1) * (GRID_RESOLUTION + 1)),
+            bounds,
+            tolerance: RELATIVE_TOLERANCE * bounds.size().mag(),
+            material,
+        };
+        for v in 0..=GRID_RESOLUTION {
+            for u in 0..=GRID_RESOLUTION {
+                let uv = Vec2::new(u as f32, v as f32) / GRID_RESOLUTION as f32;
+                let (point, _, _) = patch.evaluate(uv);
+                patch.grid.push(point);
+            }
+        }
+        patch
+    }
+
+    /// Point on the surface at `uv` and its partial derivatives along u and v.
+    fn evaluate(&self, uv: Vec2) -> (Vec3, Vec3, Vec3) {
+        let (bu, dbu) = bernstein(uv.x);
+        let (bv, dbv) = bernstein(uv.y);
+        let mut point = Vec3::zero();
+        let mut dpdu = Vec3::zero();
+        let mut dpdv = Vec3::zero();
+        for (row, (bv, dbv)) in self.control_points.iter().zip(bv.iter().zip(dbv.iter())) {
+            for (control_point, (bu, dbu)) in row.iter().zip(bu.iter().zip(dbu.iter())) {
+                point += *control_point * (bu * bv);
+                dpdu += *control_point * (dbu * bv);
+                dpdv += *control_point * (bu * dbv);
+            }
+        }
+        (point, dpdu, dpdv)
+    }
+
+    /// Refines `uv` with Newton's method towards where the ray crosses the surface, which
+    /// is where the surface lies on both of two planes through the ray.
+    fn newton(&self, ray: &Ray, planes: &[Vec3; 2], mut uv: Vec2) -> Option<Vec2> {
+        for _step in 0..NEWTON_STEPS {
+            let (point, dpdu, dpdv) = self.evaluate(uv);
+            let offset = point - ray.origin;
+            let residual = Vec2::new(planes[0].dot(offset), planes[1].dot(offset));
+            if residual.mag() < self.tolerance {
+                return Some(uv);
+            }
+
+            // solve J * delta = -residual for the 2x2 jacobian
+            let (a, b) = (planes[0].dot(dpdu), planes[0].dot(dpdv));
+            let (c, d) = (planes[1].dot(dpdu), planes[1].dot(dpdv));
+            let det = a * d - b * c;
+            if det.abs() < std::f32::MIN_POSITIVE {
+                return None;
+            }
+            let delta = Vec2::new(
+                d * residual.x - b * residual.y,
+                a * residual.y - c * residual.x,
+            ) / det;
+            uv -= delta;
+            if !uv.x.is_finite() || !uv.y.is_finite() {
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Closest intersection of a single ray with `t` in `(t_min, t_max]`, and its `(u, v)`.
+    fn hit_one(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(f32, Vec2)> {
+        // two planes which intersect along the ray
+        let dir = ray.dir;
+        let first = if dir.x.abs() > dir.y.abs() && dir.x.abs() > dir.z.abs() {
+            Vec3::new(dir.y, -dir.x, 0.0)
+        } else {
+            Vec3::new(0.0, dir.z, -dir.y)
+        }
+        .normalized();
+        let planes = [first, first.cross(dir).normalized()];
+
+        let mut closest: Option<(f32, Vec2)> = None;
+        let row = GRID_RESOLUTION + 1;
+        let cell_size = 1.0 / GRID_RESOLUTION as f32;
+        for v in 0..GRID_RESOLUTION {
+            for u in 0..GRID_RESOLUTION {
+                let corner = Vec2::new(u as f32, v as f32) * cell_size;
+                let p00 = self.grid[u + v * row];
+                let p10 = self.grid[u + 1 + v * row];
+                let p01 = self.grid[u + (v + 1) * row];
+                let p11 = self.grid[u + 1 + (v + 1) * row];
+
+                // the two triangles of the cell, with the uv offsets of their second and
+                // third corners from the first
+                let triangles = [
+                    (p00, p10, p11, Vec2::new(1.0, 0.0), Vec2::new(1.0, 1.0)),
+                    (p00, p11, p01, Vec2::new(1.0, 1.0), Vec2::new(0.0, 1.0)),
+                ];
+                for (v0, v1, v2, uv1, uv2) in triangles.iter() {
+                    let (b1, b2) = match barycentrics(ray, *v0, *v1, *v2) {
+                        Some(barycentrics) => barycentrics,
+                        None => continue,
+                    };
+                    let guess = corner + (*uv1 * b1 + *uv2 * b2) * cell_size;
+                    let uv = match self.newton(ray, &planes, guess) {
+                        Some(uv) => uv,
+                        None => continue,
+                    };
+                    let on_patch = |x: f32| x >= -UV_TOLERANCE && x <= 1.0 + UV_TOLERANCE;
+                    if !on_patch(uv.x) || !on_patch(uv.y) {
+                        continue;
+                    }
+
+                    let uv = Vec2::new(uv.x.max(0.0).min(1.0), uv.y.max(0.0).min(1.0));
+                    let (point, _, _) = self.evaluate(uv);
+                    let t = (point - ray.origin).dot(dir);
+                    let closer = closest.map_or(true, |(closest_t, _)| t < closest_t);
+                    if t > t_min && t <= t_max && closer {
+                        closest = Some((t, uv));
+                    }
+                }
+            }
+        }
+        closest
+    }
+}
+
+/// Cubic Bernstein polynomials at `t` and their derivatives.
+fn bernstein(t: f32) -> ([f32; 4], [f32; 4]) {
+    let s = 1.0 - t;
+    (
+        [s * s * s, 3.0 * t * s * s, 3.0 * t * t * s, t * t * t],
+        [
+            -3.0 * s * s,
+            3.0 * s * s - 6.0 * t * s,
+            6.0 * t * s - 3.0 * t * t,
+            3.0 * t * t,
+        ],
+    )
+}
+
+/// Barycentric coordinates of `v1` and `v2` where the line of the ray crosses the triangle,
+/// in front of the origin or not.
+fn barycentrics(ray: &Ray, v0: Vec3, v1: Vec3, v2: Vec3) -> Option<(f32, f32)> {
+    let e1 = v1 - v0;
+    let e2 = v2 - v0;
+    let p = ray.dir.cross(e2);
+    let det = e1.dot(p);
+    if det.abs() < std::f32::EPSILON * e1.mag() * e2.mag() {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+
+    let s = ray.origin - v0;
+    let b1 = s.dot(p) * inv_det;
+    if b1 < 0.0 || b1 > 1.0 {
+        return None;
+    }
+    let b2 = ray.dir.dot(s.cross(e1)) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return None;
+    }
+    Some((b1, b2))
+}
+
+impl Hitable for BezierPatch {
+    fn hit(&self, rays: &WRay, t_ranges: ::std::ops::Range<f32x4>) -> f32x4 {
+        self.hit_with_attributes(rays, t_ranges).0
+    }
+
+    fn hit_with_attributes(
+        &self,
+        rays: &WRay,
+        t_ranges: ::std::ops::Range<f32x4>,
+    ) -> (f32x4, WHitAttributes) {
+        let mut ts = [std::f32::MAX; 4];
+        let mut uvs = [Vec2::zero(); 4];
+
+        let in_bounds = self.bounds.hit_wide(rays, t_ranges.clone()) & rays.valid_mask();
+        if in_bounds.any() {
+            let scalar_rays: [Ray; 4] = (*rays).into();
+            for (i, ray) in scalar_rays.iter().enumerate() {
+                if !ray.valid {
+                    continue;
+                }
+                let t_min = t_ranges.start.as_ref()[i];
+                let t_max = t_ranges.end.as_ref()[i];
+                if let Some((t, uv)) = self.hit_one(ray, t_min, t_max) {
+                    ts[i] = t;
+                    uvs[i] = uv;
+                }
+            }
+        }
+
+        (
+            f32x4::from(ts),
+            WHitAttributes {
+                uv: Wec2::from(uvs),
+                prim_id: [0; 4],
+            },
+        )
+    }
+
+    fn occluded(&self, start: Wec3, end: Wec3, time: f32x4) -> f32x4 {
+        let starts: [Vec3; 4] = start.into();
+        let ends: [Vec3; 4] = end.into();
+        let times = time.as_ref();
+        let mut visible = [1.0; 4];
+
+        for (i, (start, end)) in starts.iter().zip(ends.iter()).enumerate() {
+            let dir = *end - *start;
+            let dist = dir.mag();
+            let ray = Ray::new(*start, dir / dist, times[i], Vec2u::zero(), 0.0, 0);
+            // Newton's method only gets within the tolerance of the surface
+            let t_min = (2.0 * self.tolerance)
+                .max(gamma(7) * start.x.abs().max(start.y.abs()).max(start.z.abs()));
+            if self.hit_one(&ray, t_min, dist).is_some() {
+                visible[i] = 0.0;
+            }
+        }
+
+        f32x4::from(visible)
+    }
+
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+
+    fn get_shading_info(
+        &self,
+        hit: WHit,
+        primary: bool,
+        camera: &dyn Camera,
+    ) -> (MaterialHandle, WShadingPoint) {
+        let point = hit.point();
+        let offset_by = (f32x4::from(2.0 * self.tolerance))
+            .max(intersection_error_bound(&hit, primary, camera));
+
+        let uvs: [Vec2; 4] = hit.attributes.uv.into();
+        let mut normals = [Vec3::unit_z(); 4];
+        let mut dpdus = [Vec3::unit_x(); 4];
+        let mut dpdvs = [Vec3::unit_y(); 4];
+        for (i, uv) in uvs.iter().enumerate() {
+            if !hit.ray.valid[i] {
+                continue;
+            }
+            let (_, dpdu, dpdv) = self.evaluate(*uv);
+            let normal = dpdu.cross(dpdv);
+            // collapsed edges (like the poles of a sphere-like patch) have no normal
+            if normal.mag_sq() > 0.0 {
+                normals[i] = normal.normalized();
+                dpdus[i] = dpdu;
+                dpdvs[i] = dpdv;
+            }
+        }
+
+        let shading_point = WShadingPoint::new(hit, point, offset_by, Wec3::from(normals))
+            .with_uv(hit.attributes.uv)
+            .with_uv_derivatives(Wec3::from(dpdus), Wec3::from(dpdvs));
+        (self.material, shading_point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::OrthographicCamera;
+
+    /// Flat patch covering the parallelogram `origin + u * du + v * dv`, parameterized
+    /// linearly.
+    fn parallelogram(origin: Vec3, du: Vec3, dv: Vec3) -> BezierPatch {
+        let mut control_points = [[Vec3::zero(); 4]; 4];
+        for (v, row) in control_points.iter_mut().enumerate() {
+            for (u, point) in row.iter_mut().enumerate() {
+                *point = origin + du * (u as f32 / 3.0) + dv * (v as f32 / 3.0);
+            }
+        }
+        BezierPatch::new(control_points, MaterialHandle(0))
+    }
+
+    fn camera() -> OrthographicCamera<Vec3, Vec3, Vec3> {
+        OrthographicCamera::new(
+            Vec2::new(4.0, 4.0),
+            4.0,
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::zero(),
+            Vec3::unit_y(),
+        )
+    }
+
+    #[test]
+    fn tangents_follow_u() {
+        // u and v aren't perpendicular, so the bitangent isn't along v
+        let (du, dv) = (Vec3::new(2.0, 1.0, 0.0), Vec3::new(0.5, 2.0, 0.0));
+        let patch = parallelogram(Vec3::new(-1.0, -1.0, 0.0), du, dv);
+        let ray = WRay::along(Vec3::new(-0.1, 0.5, 5.0), -Vec3::unit_z());
+        let (t, attributes) = patch.hit_with_attributes(&ray, f32x4::ZERO..f32x4::from(10.0));
+        assert!((t.as_ref()[0] - 5.0).abs() < 1e-4);
+
+        let hit = WHit { ray, t, attributes };
+        let (_, point) = patch.get_shading_info(hit, true, &camera());
+        let tangents: [Vec3; 4] = point.tangent.into();
+        let bitangents: [Vec3; 4] = point.bitangent.into();
+        assert!((tangents[0] - du.normalized()).mag() < 1e-4);
+        assert!(bitangents[0].dot(du).abs() < 1e-4 && bitangents[0].dot(dv) > 0.0);
+    }
+
+    #[test]
+    fn flat_patches_hit_like_a_quad() {
+        // a tilted quad over [-1, 1] in x and y, where u and v follow x and y
+        let (origin, du, dv) = (
+            Vec3::new(-1.0, -1.0, -0.5),
+            Vec3::new(2.0, 0.0, 0.5),
+            Vec3::new(0.0, 2.0, 0.5),
+        );
+        let patch = parallelogram(origin, du, dv);
+        let xys = [(0.0, 0.0), (0.6, -0.8), (-0.9, 0.95), (1.2, 0.0)];
+        let origins = xys
+            .iter()
+            .map(|(x, y)| Vec3::new(*x, *y, 5.0))
+            .collect::<Vec<_>>();
+        let ray = WRay::new(
+            Wec3::from([origins[0], origins[1], origins[2], origins[3]]),
+            Wec3::splat(-Vec3::unit_z()),
+            f32x4::ZERO,
+            [Vec2u::zero(); 4],
+            [true; 4],
+            [0.0; 4],
+            [0; 4],
+        );
+        let (t, attributes) = patch.hit_with_attributes(&ray, f32x4::ZERO..f32x4::from(10.0));
+        let uvs: [Vec2; 4] = attributes.uv.into();
+
+        for ((x, y), (t, uv)) in xys.iter().zip(t.as_ref().iter().zip(uvs.iter())) {
+            let (u, v) = ((x + 1.0) / 2.0, (y + 1.0) / 2.0);
+            if u > 1.0 {
+                assert_eq!(*t, std::f32::MAX);
+                continue;
+            }
+            let quad_t = 5.0 - (origin.z + du.z * u + dv.z * v);
+            assert!((t - quad_t).abs() < 1e-4, "{} != {}", t, quad_t);
+            assert!(
+                (uv.x - u).abs() < 1e-4 && (uv.y - v).abs() < 1e-4,
+                "{:?}",
+                uv
+            );
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use bumpalo::collections::Vec as BumpVec;
+
+use crate::hitable::Hitable;
+use crate::math::{f32x4, Aabb, Mask};
+use crate::ray::WRay;
+
+/// Most objects in a leaf, below which nodes aren't split any further.
+const MAX_LEAF_SIZE: usize = 4;
+/// Room for nodes waiting to be visited. Median splits keep the tree balanced, so this
+/// covers far more objects than fit in memory.
+const MAX_STACK: usize = 64;
+
+enum BvhNodeKind {
+    // the first child directly follows its parent
+    Interior { second_child: usize },
+    Leaf { start: usize, count: usize },
+}
+
+struct BvhNode {
+    bounds: Aabb,
+    kind: BvhNodeKind,
+}
+
+/// Bounding volume hierarchy over hitables, split at the median along the longest axis of
+/// the objects' centers. It adapts to objects clumped together, like the triangles of a
+/// detailed mesh, which a `UniformGrid` doesn't. Objects without finite bounds are always
+/// tested. Leaves refer to objects by their index in the slice the BVH was built from.
+///
+/// Nodes are laid out depth-first in one `Vec` which is reserved up front, so a build makes
+/// the same few allocations however many objects there are.
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    // object indices, in the order the leaves refer to them
+    indices: Vec<usize>,
+    unbounded: Vec<usize>,
+}
+
+impl Bvh {
+    /// Builds a BVH over `hitables` as they are from `t0` to `t1`.
+    pub fn new(hitables: &[Box<dyn Hitable>], t0: f32, t1: f32) -> Self {
+        let mut unbounded = Vec::new();
+        let mut bounded = Vec::new();
+        for (i, hitable) in hitables.iter().enumerate() {
+            match hitable.bounding_box(t0, t1).filter(Aabb::is_finite) {
+                Some(bounds) => bounded.push((i, bounds)),
+                None => unbounded.push(i),
+            }
+        }
+
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(2 * bounded.len()),
+            indices: Vec::with_capacity(bounded.len()),
+            unbounded,
+        };
+        if !bounded.is_empty() {
+            bvh.build(&mut bounded);
+        }
+        bvh
+    }
+
+    /// Appends the subtree over `objects`, and returns the index of its root.
+    fn build(&mut self, objects: &mut [(usize, Aabb)]) -> usize {
+        let bounds = objects[1..]
+            .iter()
+            .fold(objects[0].1, |acc, (_, bounds)| acc.union(bounds));
+        let node = self.nodes.len();
+
+        if objects.len() <= MAX_LEAF_SIZE {
+            let start = self.indices.len();
+            self.indices.extend(objects.iter().map(|(i, _)| *i));
+            self.nodes.push(BvhNode {
+                bounds,
+                kind: BvhNodeKind::Leaf {
+                    start,
+                    count: objects.len(),
+                },
+            });
+            return node;
+        }
+
+        let center = |bounds: &Aabb| (bounds.min + bounds.max) * 0.5;
+        let mut center_min = center(&objects[0].1);
+        let mut center_max = center_min;
+        for (_, bounds) in objects.iter() {
+            center_min = center_min.min_by_component(center(bounds));
+            center_max = center_max.max_by_component(center(bounds));
+        }
+        let extent = center_max - center_min;
+        let axis = if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        };
+        let key = |bounds: &Aabb| {
+            let c = center(bounds);
+            [c.x, c.y, c.z][axis]
+        };
+
+        let mid = objects.len() / 2;
+        objects.sort_unstable_by(|a, b| {
+            key(&a.1)
+                .partial_cmp(&key(&b.1))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        // placeholder until the second child's index is known
+        self.nodes.push(BvhNode {
+            bounds,
+            kind: BvhNodeKind::Interior { second_child: 0 },
+        });
+        let (first, second) = objects.split_at_mut(mid);
+        self.build(first);
+        let second_child = self.build(second);
+        self.nodes[node].kind = BvhNodeKind::Interior { second_child };
+        node
+    }
+
+    /// Replaces the contents of `candidates` with the sorted indices of the objects which
+    /// any valid lane of `ray` could hit within `t_range`. Subtrees whose bounds none of the
+    /// lanes pass through are skipped. Every object is in exactly one leaf, so there are no
+    /// duplicates.
+    pub fn candidates(
+        &self,
+        ray: &WRay,
+        t_range: std::ops::Range<f32x4>,
+        candidates: &mut BumpVec<usize>,
+    ) {
+        candidates.clear();
+        candidates.extend_from_slice(&self.unbounded);
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let valid = ray.valid_mask();
+        let mut stack = [0; MAX_STACK];
+        let mut stack_len = 1;
+        while stack_len > 0 {
+            stack_len -= 1;
+            let index = stack[stack_len];
+            let node = &self.nodes[index];
+            if (node.bounds.hit_wide(ray, t_range.clone()) & valid).none() {
+                continue;
+            }
+            match node.kind {
+                BvhNodeKind::Leaf { start, count } => {
+                    candidates.extend_from_slice(&self.indices[start..start + count])
+                }
+                BvhNodeKind::Interior { second_child } => {
+                    stack[stack_len] = second_child;
+                    stack[stack_len + 1] = index + 1;
+                    stack_len += 2;
+                }
+            }
+        }
+        candidates.sort_unstable();
+    }
+}
@@ -1,6 +1,16 @@
 use crate::animation::WSequenced;
-use crate::math::{f32x4, RandomSample2d, Vec2, Vec2u, Wec2, Wec3};
-use crate::ray::WRay;
+use crate::math::{
+    f32x4, Aabb, Distribution2D, Extent2u, RandomSample2d, Vec2, Vec2u, Vec3, Wec2, Wec3,
+};
+use crate::ray::{WRay, WRayDifferential};
+
+use std::ops::Range;
+
+/// Default near clipping distance, small enough to only avoid hitting the lens itself.
+pub const DEFAULT_NEAR: f32 = 0.0001;
+/// Default far clipping distance. Primitives report misses as `f32::MAX`, so this is
+/// effectively infinite.
+pub const DEFAULT_FAR: f32 = std::f32::MAX;
 
 pub trait Camera: Send + Sync {
     fn get_rays(
@@ -13,9 +23,163 @@ pub trait Camera: Send + Sync {
         samples: &[f32x4; 2],
     ) -> WRay;
 
+    /// Same as `get_rays`, but takes the pixel being sampled instead of its uv. Pixel (0, 0)
+    /// is the top-left corner of the image and `pixel_offsets` are the per-lane offsets of
+    /// the samples from the center of the pixel, in pixels. See `pixel_to_uv`.
+    #[allow(clippy::too_many_arguments)]
+    fn get_rays_for_pixel(
+        &self,
+        scramble: f32,
+        sample_nums: [usize; 4],
+        tile_coord: Vec2u,
+        pixel: Vec2u,
+        resolution: Extent2u,
+        pixel_offsets: Wec2,
+        time: f32x4,
+        samples: &[f32x4; 2],
+    ) -> WRay {
+        let uv = pixel_to_uv(pixel, resolution, pixel_offsets);
+        self.get_rays(scramble, sample_nums, tile_coord, uv, time, samples)
+    }
+
+    /// Generates a packet of four consecutive samples of the same pixel, numbered from
+    /// `sample_base`. Each lane has its own entry in `pixel_offsets`, and its own lens
+    /// position and time from the per-lane `samples` and `time`.
+    #[allow(clippy::too_many_arguments)]
+    fn get_sample_packet(
+        &self,
+        scramble: f32,
+        sample_base: usize,
+        tile_coord: Vec2u,
+        pixel: Vec2u,
+        resolution: Extent2u,
+        pixel_offsets: Wec2,
+        time: f32x4,
+        samples: &[f32x4; 2],
+    ) -> WRay {
+        let sample_nums = [
+            sample_base,
+            sample_base + 1,
+            sample_base + 2,
+            sample_base + 3,
+        ];
+        self.get_rays_for_pixel(
+            scramble,
+            sample_nums,
+            tile_coord,
+            pixel,
+            resolution,
+            pixel_offsets,
+            time,
+            samples,
+        )
+    }
+
+    /// Generates a packet of rays through the 2x2 block of pixels with `pixel` at its top
+    /// left, one per lane in the order (0, 0), (1, 0), (0, 1), (1, 1). Every lane uses the
+    /// same `pixel_offset` within its pixel, so the lanes are exactly one pixel apart, and
+    /// the differentials are the one pixel steps `get_rays` fills in. `tile_coord` is the
+    /// position of `pixel` in a tile of size `tile_extent`; lanes for pixels outside of the
+    /// tile are marked invalid.
+    #[allow(clippy::too_many_arguments)]
+    #[allow(dead_code)]
+    fn get_ray_quad(
+        &self,
+        scrambles: [f32; 4],
+        sample_num: usize,
+        tile_coord: Vec2u,
+        tile_extent: Extent2u,
+        pixel: Vec2u,
+        resolution: Extent2u,
+        pixel_offset: Vec2,
+        time: f32x4,
+        samples: &[f32x4; 2],
+    ) -> WRay {
+        let lane_offsets = [
+            Vec2u::new(0, 0),
+            Vec2u::new(1, 0),
+            Vec2u::new(0, 1),
+            Vec2u::new(1, 1),
+        ];
+        let mut offsets = [pixel_offset; 4];
+        let mut tile_coords = [tile_coord; 4];
+        let mut valid = [true; 4];
+        for (i, lane_offset) in lane_offsets.iter().enumerate() {
+            offsets[i] += Vec2::new(lane_offset.x as f32, lane_offset.y as f32);
+            tile_coords[i] = tile_coord + *lane_offset;
+            valid[i] = tile_coords[i].x < tile_extent.w && tile_coords[i].y < tile_extent.h;
+        }
+
+        let uv = pixel_to_uv(pixel, resolution, Wec2::from(offsets));
+        let mut rays = self.get_rays(scrambles[0], [sample_num; 4], tile_coord, uv, time, samples);
+        rays.tile_coord = tile_coords;
+        rays.valid = valid;
+        rays.scramble = scrambles;
+        rays
+    }
+
     /// gets the pixel radius size (half-width) at some t value (distance) from the camera
-    /// assumes that the distance is along a ray emitted from the camera.
-    fn half_pixel_size_at(&self, t: f32x4) -> f32x4;
+    /// assumes that the distance is along a ray emitted from the camera at `time`.
+    fn half_pixel_size_at(&self, t: f32x4, time: f32x4) -> f32x4;
+
+    /// The range of distances along primary rays within which geometry is visible. Anything
+    /// closer than the near plane or farther than the far plane is clipped.
+    fn clip_range(&self) -> Range<f32>;
+}
+
+/// Converts a position inside of a pixel to camera uv coordinates. Pixels are counted
+/// from the top-left of the image while uv starts at the bottom-left, so pixel (0, 0) maps
+/// to the top-left corner and the center of the image maps to uv (0.5, 0.5).
+pub fn pixel_to_uv(pixel: Vec2u, resolution: Extent2u, offsets: Wec2) -> Wec2 {
+    let pixel_center = Wec2::splat(Vec2::new(pixel.x as f32 + 0.5, pixel.y as f32 + 0.5));
+    let screen_coord = pixel_center + offsets;
+    Wec2::new(
+        screen_coord.x / f32x4::from(resolution.w as f32),
+        f32x4::ONE - screen_coord.y / f32x4::from(resolution.h as f32),
+    )
+}
+
+/// Finds a camera `(origin, at)` looking along `view_dir` which fits `bounds` entirely in
+/// view for a perspective camera with the given vertical fov (in degrees) and aspect ratio.
+/// `margin` scales the distance, e.g. 1.1 leaves roughly 10% of space around the bounds.
+#[allow(dead_code)]
+pub fn frame_bounds(
+    bounds: &Aabb,
+    view_dir: Vec3,
+    vfov: f32,
+    aspect: f32,
+    margin: f32,
+) -> (Vec3, Vec3) {
+    let at = bounds.center();
+    let radius = bounds.size().mag() * 0.5;
+
+    let half_vfov = vfov.to_radians() * 0.5;
+    let half_hfov = (half_vfov.tan() * aspect).atan();
+    let half_fov = half_vfov.min(half_hfov);
+
+    // distance at which the bounding sphere touches the edges of the narrower fov
+    let distance = radius / half_fov.sin() * margin;
+    (at - view_dir.normalized() * distance, at)
+}
+
+/// Which way the x axis of the image points relative to the scene. Scenes are y up, and by
+/// default right-handed: looking down -z, +x is to the right of the image. Scenes from
+/// left-handed (y up, looking down +z) tools render mirrored unless the camera is made
+/// `Left`, which flips its horizontal axis.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Handedness {
+    Right,
+    Left,
+}
+
+impl Handedness {
+    /// Factor applied to the horizontal basis vector of cameras.
+    fn sign(self) -> f32x4 {
+        match self {
+            Handedness::Right => f32x4::ONE,
+            Handedness::Left => -f32x4::ONE,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -38,48 +202,128 @@ impl CameraStore {
     }
 }
 
-#[derive(Clone, Copy)]
-pub struct ThinLensCamera<A, O, LA, U, F> {
-    half_size: Wec2,
-    // 2.0 * tan(hfov/2) / resolution.h / 2.0
-    half_pixel_size: f32x4,
+/// Perspective camera with a thin lens, which focuses at the distance of `focus`. The
+/// vertical field of view is in degrees and can be animated, like for a dolly zoom.
+#[derive(Clone)]
+pub struct ThinLensCamera<V, A, O, LA, U, F> {
+    vfov: V,
+    aspect: f32,
+    // size of a pixel in uv
+    pixel_uv_size: Wec2,
     aperture: A,
+    // over the square circumscribing the aperture, with row 0 at the top
+    aperture_mask: Option<Distribution2D>,
+    // anamorphic squeeze, 1 for a spherical lens
+    squeeze: f32,
     origin: O,
     at: LA,
     up: U,
     focus: F,
+    near: f32,
+    far: f32,
+    handedness: Handedness,
 }
 
-impl<A, O, LA, U, F> ThinLensCamera<A, O, LA, U, F> {
+impl<V, A, O, LA, U, F> ThinLensCamera<V, A, O, LA, U, F>
+where
+    V: WSequenced<f32x4>,
+{
     #[allow(dead_code)]
-    pub fn new(
-        resolution: Vec2,
-        vfov: f32,
-        aperture: A,
-        origin: O,
-        at: LA,
-        up: U,
-        focus: F,
-    ) -> Self {
-        let theta = vfov * std::f32::consts::PI / 180.0;
-        let half_height = (theta / 2.0).tan();
-        let aspect = resolution.x / resolution.y;
-        let half_width = aspect * half_height;
-        let half_pixel_size = f32x4::from(half_height / resolution.y);
+    pub fn new(resolution: Vec2, vfov: V, aperture: A, origin: O, at: LA, up: U, focus: F) -> Self {
         ThinLensCamera {
-            half_size: Wec2::splat(Vec2::new(half_width, half_height)),
-            half_pixel_size,
+            vfov,
+            aspect: resolution.x / resolution.y,
+            pixel_uv_size: Wec2::splat(Vec2::broadcast(1.0) / resolution),
             aperture,
+            aperture_mask: None,
+            squeeze: 1.0,
             origin,
             at,
             up,
             focus,
+            near: DEFAULT_NEAR,
+            far: DEFAULT_FAR,
+            handedness: Handedness::Right,
         }
     }
+
+    #[allow(dead_code)]
+    pub fn with_clipping(mut self, near: f32, far: f32) -> Self {
+        self.near = near;
+        self.far = far;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_handedness(mut self, handedness: Handedness) -> Self {
+        self.handedness = handedness;
+        self
+    }
+
+    /// Makes it an anamorphic lens, which sees `squeeze` times as wide a field of view at the
+    /// same resolution, for footage which is meant to be shown stretched. Its aperture is
+    /// squeezed horizontally by the same factor, so out of focus highlights become ovals
+    /// `squeeze` times taller than they are wide. A squeeze of 1 is an ordinary lens.
+    #[allow(dead_code)]
+    pub fn with_anamorphic_squeeze(mut self, squeeze: f32) -> Self {
+        self.squeeze = squeeze.max(std::f32::EPSILON);
+        self
+    }
+
+    /// Shapes the bokeh with a `width * height` grayscale mask (row by row, top row first)
+    /// covering the square around the aperture. Lens samples are drawn proportionally to
+    /// the mask's intensity, so a mask that's entirely black acts as an open square.
+    #[allow(dead_code)]
+    pub fn with_aperture_mask(mut self, mask: &[f32], width: usize, height: usize) -> Self {
+        self.aperture_mask = Some(Distribution2D::new(mask, width, height));
+        self
+    }
+
+    /// Same as `with_aperture_mask`, using the luminance of an image file as the mask.
+    #[allow(dead_code)]
+    pub fn with_aperture_image<P: AsRef<std::path::Path>>(self, path: P) -> Result<Self, String> {
+        let image = image::open(path.as_ref())
+            .map_err(|e| format!("Failed to open aperture image: {}", e))?
+            .to_luma();
+        let (width, height) = image.dimensions();
+        let mask = image
+            .pixels()
+            .map(|pixel| f32::from(pixel.0[0]) / 255.0)
+            .collect::<Vec<_>>();
+        Ok(self.with_aperture_mask(&mask, width as usize, height as usize))
+    }
+
+    /// Half the size of the image plane at distance 1, with the vfov at `time`.
+    fn half_size_at(&self, time: f32x4) -> Wec2 {
+        let half_height = half_height(self.vfov.sample_at(time));
+        Wec2::new(
+            half_height * f32x4::from(self.aspect * self.squeeze),
+            half_height,
+        )
+    }
+}
+
+/// Half the height of the image plane at distance 1 for a vertical field of view in degrees.
+fn half_height(vfov: f32x4) -> f32x4 {
+    let (sin, cos) = (vfov * f32x4::from(std::f32::consts::PI / 360.0)).sin_cos();
+    sin / cos
+}
+
+/// Lens position in the unit square around the aperture, importance sampled from the mask.
+fn sample_aperture_mask(mask: &Distribution2D, samples: &[f32x4; 2]) -> Wec2 {
+    let us = samples[0].as_ref();
+    let vs = samples[1].as_ref();
+    let mut points = [Vec2::zero(); 4];
+    for (point, (u, v)) in points.iter_mut().zip(us.iter().zip(vs.iter())) {
+        let (p, _pdf) = mask.sample_continuous([*u, *v]);
+        *point = Vec2::new(p.x * 2.0 - 1.0, 1.0 - p.y * 2.0);
+    }
+    Wec2::from(points)
 }
 
-impl<A, O, LA, U, F> Camera for ThinLensCamera<A, O, LA, U, F>
+impl<V, A, O, LA, U, F> Camera for ThinLensCamera<V, A, O, LA, U, F>
 where
+    V: WSequenced<f32x4>,
     A: WSequenced<f32x4>,
     O: WSequenced<Wec3>,
     LA: WSequenced<Wec3>,
@@ -101,47 +345,78 @@ where
         let focus = self.focus.sample_at(time);
         let focus_dist = (focus - origin).mag();
         let aperture = self.aperture.sample_at(time);
+        let half_size = self.half_size_at(time);
 
         let basis_w = (origin - at).normalized();
         let basis_u = up.cross(basis_w).normalized();
         let basis_v = basis_w.cross(basis_u);
+        let basis_u = basis_u * self.handedness.sign();
         let lower_left = origin
-            - basis_u * self.half_size.x * focus_dist
-            - basis_v * self.half_size.y * focus_dist
+            - basis_u * half_size.x * focus_dist
+            - basis_v * half_size.y * focus_dist
             - basis_w * focus_dist;
 
-        let horiz = basis_u * self.half_size.x * focus_dist * f32x4::from(2.0) * uv.x;
-        let verti = basis_v * self.half_size.y * focus_dist * f32x4::from(2.0) * uv.y;
+        let span_u = basis_u * half_size.x * focus_dist * f32x4::from(2.0);
+        let span_v = basis_v * half_size.y * focus_dist * f32x4::from(2.0);
+        let target = lower_left + span_u * uv.x + span_v * uv.y;
 
-        let rd = Wec2::rand_in_unit_disk(samples) * aperture;
-        let offset = basis_u * rd.x + basis_v * rd.y;
+        let rd = match &self.aperture_mask {
+            Some(mask) => sample_aperture_mask(mask, samples),
+            None => Wec2::rand_in_unit_disk(samples),
+        } * aperture;
+        let offset = basis_u * rd.x / f32x4::from(self.squeeze) + basis_v * rd.y;
 
         let origin = origin + offset;
+        // the auxiliary rays go through the same point on the lens, so they converge with
+        // the main ray at the focus distance
+        let dx_target = target + span_u * self.pixel_uv_size.x;
+        let dy_target = target + span_v * self.pixel_uv_size.y;
         WRay::new(
             origin,
-            (lower_left + horiz + verti - origin).normalized(),
+            (target - origin).normalized(),
             time,
             [tile_coord, tile_coord, tile_coord, tile_coord],
             [true, true, true, true],
             [scramble, scramble, scramble, scramble],
             sample_nums,
         )
+        .with_differential(WRayDifferential {
+            dx_origin: origin,
+            dx_dir: (dx_target - origin).normalized(),
+            dy_origin: origin,
+            dy_dir: (dy_target - origin).normalized(),
+        })
     }
 
-    fn half_pixel_size_at(&self, t: f32x4) -> f32x4 {
-        self.half_pixel_size * t
+    fn half_pixel_size_at(&self, t: f32x4, time: f32x4) -> f32x4 {
+        self.half_size_at(time).y * self.pixel_uv_size.y * t
+    }
+
+    fn clip_range(&self) -> Range<f32> {
+        self.near..self.far
     }
 }
 
+/// Parallel projection, for technical renders and elevations without any perspective. All
+/// rays point from `origin` towards `at` and start on the view rectangle through `origin`,
+/// `vertical_size` tall and as wide as the aspect of the resolution makes it. Like the other
+/// cameras it takes uvs from 0 to 1 with (0, 0) at the bottom-left of the image (see
+/// `pixel_to_uv`), which maps to the bottom-left corner of the rectangle with `up` at the
+/// top, so the image isn't flipped or stretched.
 #[derive(Clone, Copy)]
 pub struct OrthographicCamera<O, A, U> {
     half_size: Wec2,
     full_size: Wec2,
     half_pixel_size: f32x4,
+    pixel_uv_size: Wec2,
 
     origin: O,
     at: A,
     up: U,
+
+    near: f32,
+    far: f32,
+    handedness: Handedness,
 }
 
 impl<O, A, U> OrthographicCamera<O, A, U> {
@@ -154,11 +429,28 @@ impl<O, A, U> OrthographicCamera<O, A, U> {
             half_size: Wec2::splat(size / 2.0),
             full_size: Wec2::splat(size),
             half_pixel_size: f32x4::from(pixel_size / 2.0),
+            pixel_uv_size: Wec2::splat(Vec2::broadcast(1.0) / resolution),
             origin,
             at,
             up,
+            near: DEFAULT_NEAR,
+            far: DEFAULT_FAR,
+            handedness: Handedness::Right,
         }
     }
+
+    #[allow(dead_code)]
+    pub fn with_clipping(mut self, near: f32, far: f32) -> Self {
+        self.near = near;
+        self.far = far;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_handedness(mut self, handedness: Handedness) -> Self {
+        self.handedness = handedness;
+        self
+    }
 }
 
 impl<O, A, U> Camera for OrthographicCamera<O, A, U>
@@ -183,11 +475,14 @@ where
         let basis_w = (at - origin).normalized();
         let basis_u = basis_w.cross(up).normalized();
         let basis_v = basis_u.cross(basis_w);
+        let basis_u = basis_u * self.handedness.sign();
         let lower_left = origin - basis_u * self.half_size.x - basis_v * self.half_size.y;
 
         let offset = basis_u * uv.x * self.full_size.x + basis_v * uv.y * self.full_size.y;
 
         let origin = lower_left + offset;
+        let pixel_step_u = basis_u * self.full_size.x * self.pixel_uv_size.x;
+        let pixel_step_v = basis_v * self.full_size.y * self.pixel_uv_size.y;
 
         WRay::new(
             origin,
@@ -198,9 +493,537 @@ where
             [scramble, scramble, scramble, scramble],
             sample_nums,
         )
+        .with_differential(WRayDifferential {
+            dx_origin: origin + pixel_step_u,
+            dx_dir: basis_w,
+            dy_origin: origin + pixel_step_v,
+            dy_dir: basis_w,
+        })
     }
 
-    fn half_pixel_size_at(&self, _t: f32x4) -> f32x4 {
+    fn half_pixel_size_at(&self, _t: f32x4, _time: f32x4) -> f32x4 {
         self.half_pixel_size
     }
+
+    fn clip_range(&self) -> Range<f32> {
+        self.near..self.far
+    }
+}
+
+/// A camera given directly by a view-projection matrix, for matching the camera of another
+/// renderer exactly. The matrix is row-major and maps world space points (as column
+/// vectors) to clip space, with normalized device coordinates from -1 to 1 on every axis
+/// like in OpenGL. Rays start on the near plane and point towards the far plane, so both
+/// perspective and orthographic projections work.
+pub struct MatrixCamera {
+    inverse: [[f32; 4]; 4],
+    // half pixel size where rays start, and how much it grows per unit of distance
+    half_pixel_size: f32x4,
+    half_pixel_slope: f32x4,
+    // size of a pixel in normalized device coordinates
+    pixel_ndc_size: Vec2,
+    near: f32,
+    far: f32,
+}
+
+impl MatrixCamera {
+    #[allow(dead_code)]
+    pub fn new(view_projection: [[f32; 4]; 4], resolution: Vec2) -> Result<Self, String> {
+        let inverse = invert_matrix(view_projection)
+            .ok_or_else(|| "View-projection matrix is not invertible".to_string())?;
+        let mut camera = MatrixCamera {
+            inverse,
+            half_pixel_size: f32x4::ZERO,
+            half_pixel_slope: f32x4::ZERO,
+            pixel_ndc_size: Vec2::broadcast(2.0) / resolution,
+            near: DEFAULT_NEAR,
+            far: DEFAULT_FAR,
+        };
+
+        // footprint of one pixel at the center of the image, on both clip planes
+        let (near_0, far_0) = camera.unproject(Vec2::zero());
+        let (near_1, far_1) = camera.unproject(Vec2::new(0.0, 2.0 / resolution.y));
+        let near_size = (near_1 - near_0).mag();
+        let far_size = (far_1 - far_0).mag();
+        let depth = (far_0 - near_0).mag();
+        camera.half_pixel_size = f32x4::from(near_size * 0.5);
+        camera.half_pixel_slope = f32x4::from((far_size - near_size) * 0.5 / depth);
+        Ok(camera)
+    }
+
+    #[allow(dead_code)]
+    pub fn with_clipping(mut self, near: f32, far: f32) -> Self {
+        self.near = near;
+        self.far = far;
+        self
+    }
+
+    /// World space points on the near and far planes at normalized device coordinates `ndc`.
+    fn unproject(&self, ndc: Vec2) -> (Vec3, Vec3) {
+        let point = |z: f32| {
+            let m = &self.inverse;
+            let row = |r: usize| m[r][0] * ndc.x + m[r][1] * ndc.y + m[r][2] * z + m[r][3];
+            let w = row(3);
+            Vec3::new(row(0) / w, row(1) / w, row(2) / w)
+        };
+        (point(-1.0), point(1.0))
+    }
+}
+
+impl Camera for MatrixCamera {
+    fn get_rays(
+        &self,
+        scramble: f32,
+        sample_nums: [usize; 4],
+        tile_coord: Vec2u,
+        uv: Wec2,
+        time: f32x4,
+        _samples: &[f32x4; 2],
+    ) -> WRay {
+        let uvs: [Vec2; 4] = uv.into();
+        // main, dx and dy rays
+        let mut origins = [[Vec3::zero(); 4]; 3];
+        let mut dirs = [[Vec3::zero(); 4]; 3];
+        let steps = [
+            Vec2::zero(),
+            Vec2::new(self.pixel_ndc_size.x, 0.0),
+            Vec2::new(0.0, self.pixel_ndc_size.y),
+        ];
+        for (i, uv) in uvs.iter().enumerate() {
+            for (ray, step) in steps.iter().enumerate() {
+                let (near, far) = self.unproject(*uv * 2.0 - Vec2::broadcast(1.0) + *step);
+                origins[ray][i] = near;
+                dirs[ray][i] = (far - near).normalized();
+            }
+        }
+
+        WRay::new(
+            Wec3::from(origins[0]),
+            Wec3::from(dirs[0]),
+            time,
+            [tile_coord, tile_coord, tile_coord, tile_coord],
+            [true, true, true, true],
+            [scramble, scramble, scramble, scramble],
+            sample_nums,
+        )
+        .with_differential(WRayDifferential {
+            dx_origin: Wec3::from(origins[1]),
+            dx_dir: Wec3::from(dirs[1]),
+            dy_origin: Wec3::from(origins[2]),
+            dy_dir: Wec3::from(dirs[2]),
+        })
+    }
+
+    fn half_pixel_size_at(&self, t: f32x4, _time: f32x4) -> f32x4 {
+        self.half_pixel_size + self.half_pixel_slope * t
+    }
+
+    fn clip_range(&self) -> Range<f32> {
+        self.near..self.far
+    }
+}
+
+/// Inverse of a row-major 4x4 matrix by Gauss-Jordan elimination, if it's invertible.
+fn invert_matrix(matrix: [[f32; 4]; 4]) -> Option<[[f32; 4]; 4]> {
+    let mut m = matrix;
+    let mut inverse = [[0.0; 4]; 4];
+    for (i, row) in inverse.iter_mut().enumerate() {
+        row[i] = 1.0;
+    }
+
+    for col in 0..4 {
+        // pivot on the largest remaining entry of the column to keep things stable
+        let pivot = (col..4).fold(col, |best, row| {
+            if m[row][col].abs() > m[best][col].abs() {
+                row
+            } else {
+                best
+            }
+        });
+        if m[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        m.swap(col, pivot);
+        inverse.swap(col, pivot);
+
+        let scale = 1.0 / m[col][col];
+        for (x, y) in m[col].iter_mut().zip(inverse[col].iter_mut()) {
+            *x *= scale;
+            *y *= scale;
+        }
+
+        let (pivot_row, pivot_inverse) = (m[col], inverse[col]);
+        for (row, (m_row, inverse_row)) in m.iter_mut().zip(inverse.iter_mut()).enumerate() {
+            if row == col {
+                continue;
+            }
+            let factor = m_row[col];
+            for (x, p) in m_row.iter_mut().zip(pivot_row.iter()) {
+                *x -= factor * *p;
+            }
+            for (y, p) in inverse_row.iter_mut().zip(pivot_inverse.iter()) {
+                *y -= factor * *p;
+            }
+        }
+    }
+    Some(inverse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Orthographic camera from +z looking at the origin, one unit per pixel, so that pixel
+    /// centers are at half-integer x and y.
+    fn unit_pixel_camera(res: Extent2u) -> OrthographicCamera<Vec3, Vec3, Vec3> {
+        OrthographicCamera::new(
+            Vec2::new(res.w as f32, res.h as f32),
+            res.h as f32,
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::zero(),
+            Vec3::unit_y(),
+        )
+    }
+
+    #[test]
+    fn ray_quads_go_through_the_four_pixel_centers() {
+        let res = Extent2u::new(4, 4);
+        let camera = unit_pixel_camera(res);
+        let samples = [f32x4::ZERO; 2];
+        let rays = camera.get_ray_quad(
+            [0.0; 4],
+            0,
+            Vec2u::new(1, 1),
+            Extent2u::new(2, 2),
+            Vec2u::new(1, 1),
+            res,
+            Vec2::zero(),
+            f32x4::ZERO,
+            &samples,
+        );
+
+        let origins: [Vec3; 4] = rays.origin.into();
+        let centers = [(1.5, 1.5), (2.5, 1.5), (1.5, 2.5), (2.5, 2.5)];
+        for (origin, (x, y)) in origins.iter().zip(centers.iter()) {
+            // pixel rows go down the image while y goes up
+            assert!((origin.x - (x - 2.0)).abs() < 1e-5);
+            assert!((origin.y - (2.0 - y)).abs() < 1e-5);
+        }
+        // each lane's differential is the neighbouring lane's ray
+        let dx_origins: [Vec3; 4] = rays.differential.dx_origin.into();
+        assert!((dx_origins[0] - origins[1]).mag() < 1e-5);
+
+        // only the first lane is inside of the 2x2 tile starting one pixel before it
+        assert_eq!(rays.valid, [true, false, false, false]);
+        assert_eq!(rays.tile_coord[3], Vec2u::new(2, 2));
+    }
+
+    /// Vertical field of view keyed at 40 degrees at time 0 and 80 at time 1.
+    struct ZoomingFov;
+
+    impl WSequenced<f32x4> for ZoomingFov {
+        fn sample_at(&self, t: f32x4) -> f32x4 {
+            f32x4::from(40.0) + f32x4::from(40.0) * t
+        }
+    }
+
+    #[test]
+    fn animated_fov_is_sampled_per_ray() {
+        let res = Vec2::new(8.0, 8.0);
+        let origin = Vec3::new(0.0, 0.0, 5.0);
+        let camera = ThinLensCamera::new(
+            res,
+            ZoomingFov,
+            0f32,
+            origin,
+            Vec3::zero(),
+            Vec3::unit_y(),
+            Vec3::zero(),
+        );
+        let (early, late) = (f32x4::ZERO, f32x4::ONE);
+
+        let half_heights = |time| camera.half_size_at(time).y.as_ref()[0];
+        assert!((half_heights(early) - 20f32.to_radians().tan()).abs() < 1e-5);
+        assert!((half_heights(late) - 40f32.to_radians().tan()).abs() < 1e-5);
+        let pixel_sizes = |time| camera.half_pixel_size_at(f32x4::ONE, time).as_ref()[0];
+        let ratio = 40f32.to_radians().tan() / 20f32.to_radians().tan();
+        assert!((pixel_sizes(late) / pixel_sizes(early) - ratio).abs() < 1e-4);
+
+        // rays through the top and bottom edges spread further apart when zoomed out
+        let spread = |time| {
+            let uv = Wec2::new(f32x4::from(0.5), f32x4::from([0.0, 1.0, 0.0, 1.0]));
+            let rays = camera.get_rays(0.0, [0; 4], Vec2u::zero(), uv, time, &[f32x4::ZERO; 2]);
+            let dirs: [Vec3; 4] = rays.dir.into();
+            dirs[0].dot(dirs[1]).acos()
+        };
+        assert!((spread(early) - 40f32.to_radians()).abs() < 1e-4);
+        assert!((spread(late) - 80f32.to_radians()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn pixels_map_to_uvs_from_the_top_left() {
+        let uv = |pixel: Vec2u, res: Extent2u, offset: Vec2| {
+            let uv = pixel_to_uv(pixel, res, Wec2::splat(offset));
+            Vec2::new(uv.x.as_ref()[0], uv.y.as_ref()[0])
+        };
+        let res = Extent2u::new(5, 3);
+        let corner = Vec2::broadcast(-0.5);
+        assert_eq!(uv(Vec2u::zero(), res, corner), Vec2::new(0.0, 1.0));
+        assert_eq!(uv(Vec2u::new(4, 2), res, -corner), Vec2::new(1.0, 0.0));
+        assert!((uv(Vec2u::new(2, 1), res, Vec2::zero()) - Vec2::broadcast(0.5)).mag() < 1e-6);
+
+        // and the camera puts the first pixel in the top-left of the view
+        let camera = unit_pixel_camera(Extent2u::new(4, 4));
+        let samples = [f32x4::ZERO; 2];
+        let rays = camera.get_rays_for_pixel(
+            0.0,
+            [0; 4],
+            Vec2u::zero(),
+            Vec2u::zero(),
+            Extent2u::new(4, 4),
+            Wec2::zero(),
+            f32x4::ZERO,
+            &samples,
+        );
+        let origins: [Vec3; 4] = rays.origin.into();
+        assert!((origins[0].x + 1.5).abs() < 1e-5 && (origins[0].y - 1.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn framed_unit_cubes_fit_in_view_with_margin() {
+        let cube = Aabb::new(Vec3::zero(), Vec3::broadcast(1.0));
+        let (vfov, aspect) = (40.0f32, 1.5);
+        let view_dir = Vec3::new(-1.0, -0.5, -2.0).normalized();
+        let (origin, at) = frame_bounds(&cube, view_dir, vfov, aspect, 1.1);
+        assert!((at - cube.center()).mag() < 1e-6);
+
+        // wider than it is tall, so it's the vertical fov the corners have to fit in
+        let half_fov = vfov.to_radians() * 0.5;
+        let corners =
+            (0..8).map(|i| Vec3::new((i & 1) as f32, (i >> 1 & 1) as f32, (i >> 2) as f32));
+        for corner in corners {
+            let to_corner = (corner - origin).normalized();
+            let angle = to_corner.dot(view_dir).acos();
+            // the margin keeps them clear of the edges rather than just inside
+            assert!(
+                angle < half_fov * 0.95,
+                "corner {:?} at {} rad",
+                corner,
+                angle
+            );
+        }
+    }
+
+    #[test]
+    fn left_handed_cameras_mirror_the_image() {
+        let res = Extent2u::new(6, 4);
+        let rays = |camera: &dyn Camera, pixel: Vec2u| {
+            let rays = camera.get_rays_for_pixel(
+                0.0,
+                [0; 4],
+                Vec2u::zero(),
+                pixel,
+                res,
+                Wec2::zero(),
+                f32x4::ZERO,
+                &[f32x4::ZERO; 2],
+            );
+            let (origins, dirs): ([Vec3; 4], [Vec3; 4]) = (rays.origin.into(), rays.dir.into());
+            (origins[0], dirs[0])
+        };
+        let thin_lens = |handedness| {
+            let (origin, at) = (Vec3::new(1.0, 2.0, 5.0), Vec3::zero());
+            let camera = ThinLensCamera::new(
+                Vec2::new(6.0, 4.0),
+                40f32,
+                0f32,
+                origin,
+                at,
+                Vec3::unit_y(),
+                at,
+            );
+            camera.with_handedness(handedness)
+        };
+        let pairs: [(Box<dyn Camera>, Box<dyn Camera>); 2] = [
+            (
+                Box::new(unit_pixel_camera(res)),
+                Box::new(unit_pixel_camera(res).with_handedness(Handedness::Left)),
+            ),
+            (
+                Box::new(thin_lens(Handedness::Right)),
+                Box::new(thin_lens(Handedness::Left)),
+            ),
+        ];
+
+        for (right, left) in pairs.iter() {
+            for y in 0..res.h {
+                for x in 0..res.w {
+                    let mirrored = Vec2u::new(res.w - 1 - x, y);
+                    let (left_origin, left_dir) = rays(left.as_ref(), Vec2u::new(x, y));
+                    let (right_origin, right_dir) = rays(right.as_ref(), mirrored);
+                    assert!((left_origin - right_origin).mag() < 1e-4);
+                    assert!((left_dir - right_dir).mag() < 1e-5);
+                }
+            }
+        }
+    }
+
+    /// Row-major OpenGL style view-projection matrix of a perspective camera at `eye`
+    /// looking at `at` with y up.
+    fn perspective_matrix(eye: Vec3, at: Vec3, vfov: f32, aspect: f32) -> [[f32; 4]; 4] {
+        let w = (eye - at).normalized();
+        let u = Vec3::unit_y().cross(w).normalized();
+        let v = w.cross(u);
+        let view = [
+            [u.x, u.y, u.z, -u.dot(eye)],
+            [v.x, v.y, v.z, -v.dot(eye)],
+            [w.x, w.y, w.z, -w.dot(eye)],
+            [0.0, 0.0, 0.0, 1.0],
+        ];
+        let (near, far) = (0.5, 50.0);
+        let f = 1.0 / (vfov.to_radians() * 0.5).tan();
+        let projection = [
+            [f / aspect, 0.0, 0.0, 0.0],
+            [0.0, f, 0.0, 0.0],
+            [
+                0.0,
+                0.0,
+                (far + near) / (near - far),
+                2.0 * far * near / (near - far),
+            ],
+            [0.0, 0.0, -1.0, 0.0],
+        ];
+        let mut matrix = [[0.0; 4]; 4];
+        for (row, projection_row) in matrix.iter_mut().zip(projection.iter()) {
+            for (col, entry) in row.iter_mut().enumerate() {
+                *entry = (0..4).map(|k| projection_row[k] * view[k][col]).sum();
+            }
+        }
+        matrix
+    }
+
+    #[test]
+    fn matrix_cameras_match_the_perspective_camera() {
+        let res = Extent2u::new(6, 4);
+        let (eye, at, vfov) = (Vec3::new(1.0, 2.0, 5.0), Vec3::zero(), 40.0);
+        let aspect = res.w as f32 / res.h as f32;
+        let matrix = MatrixCamera::new(
+            perspective_matrix(eye, at, vfov, aspect),
+            Vec2::new(6.0, 4.0),
+        )
+        .unwrap();
+        let thin_lens =
+            ThinLensCamera::new(Vec2::new(6.0, 4.0), vfov, 0f32, eye, at, Vec3::unit_y(), at);
+
+        for y in 0..res.h {
+            for x in 0..res.w {
+                let rays = |camera: &dyn Camera| {
+                    let rays = camera.get_rays_for_pixel(
+                        0.0,
+                        [0; 4],
+                        Vec2u::zero(),
+                        Vec2u::new(x, y),
+                        res,
+                        Wec2::zero(),
+                        f32x4::ZERO,
+                        &[f32x4::ZERO; 2],
+                    );
+                    let (origins, dirs): ([Vec3; 4], [Vec3; 4]) =
+                        (rays.origin.into(), rays.dir.into());
+                    (origins[0], dirs[0])
+                };
+                let (matrix_origin, matrix_dir) = rays(&matrix);
+                let (lens_origin, lens_dir) = rays(&thin_lens);
+                assert!((matrix_dir - lens_dir).mag() < 1e-4, "pixel ({}, {})", x, y);
+                // the matrix camera's rays start on the near plane, along the same line
+                let to_start = matrix_origin - lens_origin;
+                assert!(
+                    to_start.cross(lens_dir).mag() < 1e-4,
+                    "pixel ({}, {})",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn sample_packets_share_a_pixel_with_distinct_offsets() {
+        let res = Extent2u::new(4, 4);
+        let camera = unit_pixel_camera(res);
+        let offsets = [
+            Vec2::new(-0.25, -0.25),
+            Vec2::new(0.25, -0.25),
+            Vec2::new(-0.25, 0.25),
+            Vec2::new(0.25, 0.25),
+        ];
+        let rays = camera.get_sample_packet(
+            0.0,
+            5,
+            Vec2u::new(1, 3),
+            Vec2u::new(2, 1),
+            res,
+            Wec2::from(offsets),
+            f32x4::ZERO,
+            &[f32x4::ZERO; 2],
+        );
+
+        assert_eq!(rays.sample, [5, 6, 7, 8]);
+        assert_eq!(rays.tile_coord, [Vec2u::new(1, 3); 4]);
+        let origins: [Vec3; 4] = rays.origin.into();
+        for (i, (origin, offset)) in origins.iter().zip(offsets.iter()).enumerate() {
+            // pixel (2, 1) spans x in [0, 1] and y in [0, 1] around its center (0.5, 0.5)
+            assert!((origin.x - (0.5 + offset.x)).abs() < 1e-5);
+            assert!((origin.y - (0.5 - offset.y)).abs() < 1e-5);
+            for other in origins[i + 1..].iter() {
+                assert!((*origin - *other).mag() > 0.1);
+            }
+        }
+    }
+
+    #[test]
+    fn anamorphic_bokeh_is_squeezed_horizontally() {
+        let res = Extent2u::new(8, 8);
+        // the right, left, top and bottom edges of the aperture
+        let samples = [
+            f32x4::from([1.0, 0.0, 0.5, 0.5]),
+            f32x4::from([0.5, 0.5, 1.0, 0.0]),
+        ];
+        let lens_extents = |squeeze: f32| {
+            let camera = ThinLensCamera::new(
+                Vec2::new(8.0, 8.0),
+                40f32,
+                0.5f32,
+                Vec3::new(0.0, 0.0, 5.0),
+                Vec3::zero(),
+                Vec3::unit_y(),
+                Vec3::zero(),
+            )
+            .with_anamorphic_squeeze(squeeze);
+            let rays = camera.get_rays_for_pixel(
+                0.0,
+                [0; 4],
+                Vec2u::zero(),
+                Vec2u::new(4, 4),
+                res,
+                Wec2::zero(),
+                f32x4::ZERO,
+                &samples,
+            );
+            let origins: [Vec3; 4] = rays.origin.into();
+            (
+                (origins[0] - origins[1]).mag(),
+                (origins[2] - origins[3]).mag(),
+            )
+        };
+
+        let (width, height) = lens_extents(1.0);
+        assert!(height > 0.1);
+        assert!((width - height).abs() < 1e-4);
+        for squeeze in [1.33, 2.0].iter() {
+            let (squeezed_width, squeezed_height) = lens_extents(*squeeze);
+            assert!((squeezed_height - height).abs() < 1e-4);
+            assert!((squeezed_width * squeeze - width).abs() < 1e-4);
+        }
+    }
 }
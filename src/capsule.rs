@@ -0,0 +1,182 @@
+use crate::camera::Camera;
+use crate::hitable::{intersection_error_bound, Hitable, WHit, WShadingPoint, SHADOW_T_MIN};
+use crate::material::MaterialHandle;
+use crate::math::{f32x4, gamma, Aabb, Vec3, Wec3};
+use crate::ray::WRay;
+
+/// All points within `radius` of the segment from `a` to `b`: a cylinder capped by two
+/// hemispheres. A zero-length segment makes it a sphere.
+pub struct Capsule {
+    a: Vec3,
+    b: Vec3,
+    radius: f32,
+    material: MaterialHandle,
+}
+
+impl Capsule {
+    #[allow(dead_code)]
+    pub fn new(a: Vec3, b: Vec3, radius: f32, material: MaterialHandle) -> Self {
+        Capsule {
+            a,
+            b,
+            radius,
+            material,
+        }
+    }
+
+    /// Closest intersection with `t` in `(t_start, t_end]`, or `f32::MAX` where there is none.
+    /// Both roots of the body and of each cap are considered, so rays starting inside the
+    /// capsule find where they leave it.
+    fn intersect(&self, origin: Wec3, dir: Wec3, t_start: f32x4, t_end: f32x4) -> f32x4 {
+        let a = Wec3::splat(self.a);
+        let ba = Wec3::splat(self.b - self.a);
+        let r2 = f32x4::from(self.radius * self.radius);
+        let oa = origin - a;
+
+        let baba = ba.mag_sq();
+        let bard = ba.dot(dir);
+        let baoa = ba.dot(oa);
+        let dd = dir.mag_sq();
+
+        let mut closest = f32x4::from(std::f32::MAX);
+        let mut consider = |t: f32x4, valid: f32x4| {
+            let valid = valid & t.cmp_gt(t_start) & t.cmp_le(t_end) & t.cmp_lt(closest);
+            closest = f32x4::merge(valid, t, closest);
+        };
+
+        // infinite cylinder around the segment, only valid between the two caps. Rays
+        // parallel to the axis (and zero-length capsules) have no quadratic term and can
+        // only hit the caps.
+        let qa = baba * dd - bard * bard;
+        let qb = baba * oa.dot(dir) - baoa * bard;
+        let qc = baba * oa.mag_sq() - baoa * baoa - r2 * baba;
+        let descrim = qb * qb - qa * qc;
+        let body_valid = descrim.cmp_gt(f32x4::ZERO) & qa.cmp_gt(f32x4::from(1e-12) * baba * dd);
+        let desc_sqrt = descrim.max(f32x4::ZERO).sqrt();
+        for t in [(-qb - desc_sqrt) / qa, (-qb + desc_sqrt) / qa].iter() {
+            let y = baoa + *t * bard;
+            consider(*t, body_valid & y.cmp_gt(f32x4::ZERO) & y.cmp_lt(baba));
+        }
+
+        // hemispherical caps, each only valid on its own side of the body
+        for (center, sign) in [(a, -f32x4::ONE), (a + ba, f32x4::ONE)].iter() {
+            let oc = origin - *center;
+            let b = oc.dot(dir);
+            let c = oc.mag_sq() - r2;
+            let descrim = b * b - dd * c;
+            let cap_valid = descrim.cmp_gt(f32x4::ZERO);
+            let desc_sqrt = descrim.max(f32x4::ZERO).sqrt();
+            for t in [(-b - desc_sqrt) / dd, (-b + desc_sqrt) / dd].iter() {
+                let side = (oc + dir * *t).dot(ba) * *sign;
+                consider(*t, cap_valid & !side.cmp_lt(f32x4::ZERO));
+            }
+        }
+
+        closest
+    }
+}
+
+impl Hitable for Capsule {
+    fn occluded(&self, start: Wec3, end: Wec3, _time: f32x4) -> f32x4 {
+        let dir = end - start;
+        let dist = dir.mag();
+        let dir = dir / dist;
+
+        // same as for spheres, roots this close to the start (or closer than `SHADOW_T_MIN`)
+        // can't be told apart from the surface the ray started on
+        let extent = (self.b - self.a).mag() + self.radius;
+        let oa = start - Wec3::splat(self.a);
+        let t_min = (f32x4::from(2.0 * gamma(7)) * oa.mag().max(f32x4::from(extent)))
+            .max(f32x4::from(SHADOW_T_MIN));
+
+        let t = self.intersect(start, dir, t_min, dist);
+        f32x4::merge(
+            t.cmp_lt(f32x4::from(std::f32::MAX)),
+            f32x4::ZERO,
+            f32x4::ONE,
+        )
+    }
+
+    fn hit(&self, ray: &WRay, t_range: ::std::ops::Range<f32x4>) -> f32x4 {
+        self.intersect(ray.origin, ray.dir, t_range.start, t_range.end)
+    }
+
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<Aabb> {
+        let half_size = Vec3::broadcast(self.radius);
+        Some(
+            Aabb::from_center_half_size(self.a, half_size)
+                .union(&Aabb::from_center_half_size(self.b, half_size)),
+        )
+    }
+
+    fn get_shading_info(
+        &self,
+        hit: WHit,
+        primary: bool,
+        camera: &dyn Camera,
+    ) -> (MaterialHandle, WShadingPoint) {
+        let point = hit.point();
+        let offset_by = intersection_error_bound(&hit, primary, camera);
+
+        // the normal points away from the closest point on the segment
+        let a = Wec3::splat(self.a);
+        let ba = Wec3::splat(self.b - self.a);
+        let baba = ba.mag_sq().max(f32x4::EPSILON);
+        let h = ((point - a).dot(ba) / baba)
+            .max(f32x4::ZERO)
+            .min(f32x4::ONE);
+        let normal = (point - (a + ba * h)).normalized();
+
+        (
+            self.material,
+            WShadingPoint::new(hit, point, offset_by, normal),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sphere::Sphere;
+
+    #[test]
+    fn zero_length_capsules_are_spheres() {
+        let center = Vec3::new(0.5, -0.25, 1.0);
+        let capsule = Capsule::new(center, center, 0.75, MaterialHandle(0));
+        let sphere = Sphere::new(center, 0.75, MaterialHandle(0));
+        let range = f32x4::ZERO..f32x4::from(std::f32::MAX);
+
+        let rays = [
+            (Vec3::new(0.5, -0.25, -3.0), Vec3::unit_z()),
+            (Vec3::new(-3.0, 0.0, 1.2), Vec3::unit_x()),
+            (Vec3::new(3.0, 3.0, 3.0), -Vec3::broadcast(1.0).normalized()),
+            // from inside, and one which misses
+            (center, Vec3::unit_y()),
+            (Vec3::new(0.5, 2.0, 1.0), Vec3::unit_x()),
+        ];
+        for (origin, dir) in rays.iter() {
+            let ray = WRay::along(*origin, *dir);
+            let capsule_t = capsule.hit(&ray, range.clone()).as_ref()[0];
+            let sphere_t = sphere.hit(&ray, range.clone()).as_ref()[0];
+            assert!(capsule_t == sphere_t || (capsule_t - sphere_t).abs() < 1e-4);
+
+            let (start, end) = (Wec3::splat(*origin), Wec3::splat(*origin + *dir * 10.0));
+            let capsule_occluded = capsule.occluded(start, end, f32x4::ZERO);
+            let sphere_occluded = sphere.occluded(start, end, f32x4::ZERO);
+            assert_eq!(capsule_occluded.as_ref()[0], sphere_occluded.as_ref()[0]);
+        }
+    }
+
+    #[test]
+    fn rays_along_the_axis_hit_the_caps() {
+        let capsule = Capsule::new(Vec3::zero(), Vec3::unit_y(), 0.5, MaterialHandle(0));
+        let range = f32x4::ZERO..f32x4::from(std::f32::MAX);
+
+        let down = WRay::along(Vec3::new(0.0, 3.0, 0.0), -Vec3::unit_y());
+        assert!((capsule.hit(&down, range.clone()).as_ref()[0] - 1.5).abs() < 1e-4);
+        let inside = WRay::along(Vec3::new(0.0, 0.5, 0.0), Vec3::unit_y());
+        assert!((capsule.hit(&inside, range.clone()).as_ref()[0] - 1.0).abs() < 1e-4);
+        let beside = WRay::along(Vec3::new(0.6, 3.0, 0.0), -Vec3::unit_y());
+        assert_eq!(capsule.hit(&beside, range).as_ref()[0], std::f32::MAX);
+    }
+}
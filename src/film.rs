@@ -4,22 +4,31 @@ use generic_array::{ArrayLength, GenericArray};
 
 use rand::prelude::*;
 
+use rayon::prelude::*;
+
+use crate::animation::FrameTiming;
 use crate::camera::CameraHandle;
 use crate::filter::{Filter, FilterImportanceSampler};
-use crate::hitable::HitStore;
+use crate::framebuffer::Framebuffer;
+use crate::hitable::{HitCountEstimate, HitStore, WShadingPoint};
 use crate::integrator::Integrator;
+use crate::material::MaterialHandle;
 use crate::math::{f32x4, Aabru, Extent2u, Vec2, Vec2u, Vec3, Wec2};
-use crate::ray::{Ray, WRay};
-use crate::sampler::Samples;
-use crate::spectrum::Srgb;
+use crate::post::{ATrous, Bloom, ColorGrade, CrossBilateral, DistanceFog};
+use crate::ray::{Ray, RayKind, WRay};
+use crate::sampler::{hash_seed, SampleDimensions, Samples};
+use crate::spectrum::{ColorSpace, Srgb};
 use crate::world::World;
 
+use std::borrow::Cow;
 use std::collections::hash_map::HashMap;
 use std::ops::Range;
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc, Mutex,
-};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Smallest piece in pixels that expensive tiles are split into.
+const MIN_CHUNK_PIXELS: usize = 16;
 
 macro_rules! declare_channels {
     {
@@ -38,6 +47,14 @@ macro_rules! declare_channels {
             $($name($storage),)+
         }
 
+        impl ChannelSample {
+            fn is_finite(&self) -> bool {
+                match self {
+                    $(ChannelSample::$name(sample) => FiniteSample::is_finite(sample),)+
+                }
+            }
+        }
+
         pub enum ChannelTileStorage {
             $($name(Vec<($storage)>),)+
         }
@@ -78,7 +95,7 @@ macro_rules! declare_channels {
                 }
             }
 
-            pub fn copy_from_tile(&mut self, other: &ChannelTileStorage, full_res: Extent2u, tile_bounds: Aabru, samples: usize) -> Result<(), ()> {
+            pub fn copy_from_tile(&mut self, other: &ChannelTileStorage, full_res: Extent2u, tile_bounds: Aabru, sample_counts: &[usize]) -> Result<(), ()> {
                 let extent = tile_bounds.size();
                 match (self, other) {
                     $( (ChannelStorage::$name(this_buf), ChannelTileStorage::$name(tile_buf)) => {
@@ -87,7 +104,7 @@ macro_rules! declare_channels {
                                 let tile_idx = x + y * extent.w;
                                 let this_idx = (tile_bounds.min.x + x) + (tile_bounds.min.y + y) * full_res.w;
                                 let tile_samp_sum = tile_buf[tile_idx];
-                                this_buf[this_idx] = tile_samp_sum / samples as f32;
+                                this_buf[this_idx] = tile_samp_sum / sample_counts[tile_idx] as f32;
                             }
                         }
                         Ok(())
@@ -99,6 +116,69 @@ macro_rules! declare_channels {
     }
 }
 
+trait FiniteSample {
+    fn is_finite(&self) -> bool;
+}
+
+impl FiniteSample for f32 {
+    fn is_finite(&self) -> bool {
+        f32::is_finite(*self)
+    }
+}
+
+impl FiniteSample for Vec3 {
+    fn is_finite(&self) -> bool {
+        self.x.is_finite() && self.y.is_finite() && self.z.is_finite()
+    }
+}
+
+impl FiniteSample for Srgb {
+    fn is_finite(&self) -> bool {
+        FiniteSample::is_finite(&self.0)
+    }
+}
+
+/// An integer id (of an object or material) which a pixel was covered by. Unlike other
+/// samples, ids aren't averaged: a pixel keeps the first id it receives, so ids never
+/// blend together at the edges of objects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Id(Option<usize>);
+
+impl Id {
+    pub const NONE: Id = Id(None);
+
+    pub fn new(id: usize) -> Self {
+        Id(Some(id))
+    }
+
+    /// The id as stored in saved images, where 0 means nothing was hit.
+    pub fn encoded(self) -> u32 {
+        self.0.map_or(0, |id| id as u32 + 1)
+    }
+}
+
+impl std::ops::AddAssign for Id {
+    fn add_assign(&mut self, other: Id) {
+        if self.0.is_none() {
+            *self = other;
+        }
+    }
+}
+
+impl std::ops::Div<f32> for Id {
+    type Output = Id;
+
+    fn div(self, _samples: f32) -> Id {
+        self
+    }
+}
+
+impl FiniteSample for Id {
+    fn is_finite(&self) -> bool {
+        true
+    }
+}
+
 declare_channels! {
     Color => {
         storage: Srgb,
@@ -115,6 +195,70 @@ declare_channels! {
     WorldNormal => {
         storage: Vec3,
         init: Vec3::zero(),
+    },
+    // Distance along the camera ray to the first hit
+    Depth => {
+        storage: f32,
+        init: 0f32,
+    },
+    DiffuseDirect => {
+        storage: Srgb,
+        init: Srgb::zero(),
+    },
+    DiffuseIndirect => {
+        storage: Srgb,
+        init: Srgb::zero(),
+    },
+    SpecularDirect => {
+        storage: Srgb,
+        init: Srgb::zero(),
+    },
+    SpecularIndirect => {
+        storage: Srgb,
+        init: Srgb::zero(),
+    },
+    // Color accumulated from only the even or odd sample indices, see `push_color_sample`
+    ColorEven => {
+        storage: Srgb,
+        init: Srgb::zero(),
+    },
+    ColorOdd => {
+        storage: Srgb,
+        init: Srgb::zero(),
+    },
+    ObjectId => {
+        storage: Id,
+        init: Id::NONE,
+    },
+    MaterialId => {
+        storage: Id,
+        init: Id::NONE,
+    }
+}
+
+impl ChannelKind {
+    /// Looks up the channel which accumulates the paths matching a light path expression.
+    /// `C` is the camera, `D` and `S` diffuse and specular scattering events, `L` a light
+    /// and `.+` one or more events of any kind. Whitespace is ignored.
+    ///
+    /// Supported expressions are `C.*L` (the full color), `CDL`, `CD.+L`, `CSL` and `CS.+L`.
+    /// The event type is the lobe sampled (or evaluated, for direct lighting) at the first
+    /// vertex of the path.
+    #[allow(dead_code)]
+    pub fn from_lpe(expression: &str) -> Option<ChannelKind> {
+        let expression = expression
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>();
+
+        match expression.as_str() {
+            "C.*L" => Some(ChannelKind::Color),
+            "CDL" => Some(ChannelKind::DiffuseDirect),
+            "CD.+L" => Some(ChannelKind::DiffuseIndirect),
+            "CSL" => Some(ChannelKind::SpecularDirect),
+            "CS.+L" => Some(ChannelKind::SpecularIndirect),
+            _ => None,
+        }
     }
 }
 
@@ -134,7 +278,9 @@ pub struct Tile<N: ArrayLength<ChannelTileStorage>> {
     channels: GenericArray<ChannelTileStorage, N>,
     raster_bounds: Aabru,
     raster_extent: Extent2u,
-    screen_to_ndc_size: Vec2,
+    /// Number of samples taken in each pixel of the tile, row by row
+    sample_counts: Vec<usize>,
+    non_finite_samples: usize,
 }
 
 impl<N: ArrayLength<ChannelTileStorage>> Tile<N> {
@@ -142,14 +288,12 @@ impl<N: ArrayLength<ChannelTileStorage>> Tile<N> {
         _index: usize,
         epoch: usize,
         channels: IC,
-        res: Extent2u,
         raster_bounds: Aabru,
+        sample_counts: Vec<usize>,
     ) -> Self
     where
         IC: std::iter::ExactSizeIterator<Item = ChannelKind>,
     {
-        let screen_to_ndc_size = Vec2::new(1.0 / res.w as f32, 1.0 / res.h as f32);
-
         Tile {
             _index,
             epoch,
@@ -159,11 +303,19 @@ impl<N: ArrayLength<ChannelTileStorage>> Tile<N> {
             .expect("Incorrect number of channels passed to tile creation"),
             raster_bounds,
             raster_extent: raster_bounds.size(),
-            screen_to_ndc_size,
+            sample_counts,
+            non_finite_samples: 0,
         }
     }
 
+    /// Adds a sample to the channels it belongs to. NaN and infinite samples are dropped
+    /// (i.e. count as zero) so they can't poison the whole pixel, and are counted instead.
     pub fn add_sample(&mut self, tile_coord: Vec2u, sample: ChannelSample) {
+        if !sample.is_finite() {
+            self.non_finite_samples += 1;
+            return;
+        }
+
         let idx = tile_coord.x + tile_coord.y * self.raster_extent.w;
         for channel in self.channels.iter_mut() {
             channel.add_sample(idx, &sample);
@@ -171,12 +323,57 @@ impl<N: ArrayLength<ChannelTileStorage>> Tile<N> {
     }
 }
 
+/// Shared flag for aborting a render from another thread. Cloning the token shares the flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[allow(dead_code)]
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Clears the flag so the token can be reused for the next render.
+    #[allow(dead_code)]
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Called with `(completed, total)` tiles of the frame being rendered.
+pub type ProgressCallback = dyn Fn(usize, usize) + Send + Sync;
+
 pub struct Film<N: ArrayLength<ChannelStorage>> {
     channel_indices: HashMap<ChannelKind, usize>,
-    channels: Mutex<GenericArray<ChannelStorage, N>>,
+    channels: GenericArray<ChannelStorage, N>,
     progressive_epoch: usize,
-    this_epoch_tiles_finished: AtomicUsize,
     res: Extent2u,
+    hit_count_estimate: HitCountEstimate,
+    non_finite_samples: usize,
+    output_color_space: ColorSpace,
+    bloom: Option<Bloom>,
+    denoiser: Option<CrossBilateral>,
+    atrous: Option<ATrous>,
+    fog: Option<DistanceFog>,
+    color_grade: Option<ColorGrade>,
+    cancel_token: Option<CancelToken>,
+    cancelled: bool,
+    progress_callback: Option<Box<ProgressCallback>>,
+    convergence: Option<f32>,
+    sample_map: Option<Vec<usize>>,
+    bounce_t_min: Option<f32>,
+    seed: u64,
+    // samples taken in each pixel during the last frame
+    pixel_sample_counts: Vec<usize>,
 }
 
 impl<'a, N: ArrayLength<ChannelStorage>> Film<N> {
@@ -189,18 +386,259 @@ impl<'a, N: ArrayLength<ChannelStorage>> Film<N> {
         }
         Ok(Film {
             channel_indices,
-            channels: Mutex::new(
-                GenericArray::from_exact_iter(
-                    channels.iter().map(|kind| ChannelStorage::new(*kind, res)),
-                )
-                .expect("Generic type length does not match the number of channels."),
-            ),
+            channels: GenericArray::from_exact_iter(
+                channels.iter().map(|kind| ChannelStorage::new(*kind, res)),
+            )
+            .expect("Generic type length does not match the number of channels."),
             progressive_epoch: 0,
-            this_epoch_tiles_finished: AtomicUsize::new(0),
             res,
+            hit_count_estimate: HitCountEstimate::default(),
+            non_finite_samples: 0,
+            output_color_space: ColorSpace::Srgb,
+            bloom: None,
+            denoiser: None,
+            atrous: None,
+            fog: None,
+            color_grade: None,
+            cancel_token: None,
+            cancelled: false,
+            progress_callback: None,
+            convergence: None,
+            sample_map: None,
+            bounce_t_min: None,
+            seed: 0,
+            pixel_sample_counts: vec![0; res.w * res.h],
         })
     }
 
+    /// Color space that color channels are converted to when saved.
+    #[allow(dead_code)]
+    pub fn with_output_color_space(mut self, color_space: ColorSpace) -> Self {
+        self.output_color_space = color_space;
+        self
+    }
+
+    /// Adds bloom to the color channel when it's saved. The channel itself is unaffected.
+    #[allow(dead_code)]
+    pub fn with_bloom(mut self, bloom: Bloom) -> Self {
+        self.bloom = Some(bloom);
+        self
+    }
+
+    /// Smooths the color channel when it's saved, guided by the `WorldNormal` and `Depth`
+    /// channels, which the film has to have for it to take effect. Applied before bloom.
+    #[allow(dead_code)]
+    pub fn with_denoiser(mut self, denoiser: CrossBilateral) -> Self {
+        self.denoiser = Some(denoiser);
+        self
+    }
+
+    /// Like `with_denoiser`, but with an `ATrous` filter which fades out as pixels get more
+    /// samples. Applied after the cross-bilateral denoiser if there's one as well.
+    #[allow(dead_code)]
+    pub fn with_atrous(mut self, atrous: ATrous) -> Self {
+        self.atrous = Some(atrous);
+        self
+    }
+
+    /// Fogs the color channel when it's saved, by the `Depth` channel which the film has to
+    /// have for it to take effect. Applied after denoising and before bloom.
+    #[allow(dead_code)]
+    pub fn with_fog(mut self, fog: DistanceFog) -> Self {
+        self.fog = Some(fog);
+        self
+    }
+
+    /// Grades the color channel when it's saved, after all the other post processing and
+    /// before it's converted to the output color space and gamma corrected.
+    #[allow(dead_code)]
+    pub fn with_color_grade(mut self, grade: ColorGrade) -> Self {
+        self.color_grade = Some(grade);
+        self
+    }
+
+    /// The color buffer with post processing applied, if there is any to apply.
+    fn post_processed<'b>(&self, color: &'b [Srgb]) -> Cow<'b, [Srgb]> {
+        let mut color = Cow::Borrowed(color);
+
+        let normal_idx = self.channel_indices.get(&ChannelKind::WorldNormal);
+        let depth_idx = self.channel_indices.get(&ChannelKind::Depth);
+        if let (Some(&normal_idx), Some(&depth_idx)) = (normal_idx, depth_idx) {
+            let normals = channel_storage_index!(self.channels, WorldNormal, normal_idx);
+            let depths = channel_storage_index!(self.channels, Depth, depth_idx);
+            if let Some(denoiser) = &self.denoiser {
+                denoiser.apply(color.to_mut(), normals, depths, self.res);
+            }
+            if let Some(atrous) = &self.atrous {
+                let counts = &self.pixel_sample_counts;
+                atrous.apply(color.to_mut(), normals, depths, counts, self.res);
+            }
+        }
+
+        if let (Some(fog), Some(&depth_idx)) = (&self.fog, depth_idx) {
+            let depths = channel_storage_index!(self.channels, Depth, depth_idx);
+            fog.apply(color.to_mut(), depths);
+        }
+
+        if let Some(bloom) = &self.bloom {
+            bloom.apply(color.to_mut(), self.res);
+        }
+
+        if let Some(grade) = &self.color_grade {
+            grade.apply(color.to_mut());
+        }
+        color
+    }
+
+    /// Overrides the number of samples per pixel passed to `render_frame_into` with a count
+    /// for each pixel, row by row from the top-left, so effort can be spent where it matters.
+    /// Like the uniform count, each one is a number of 4-wide sample packets. Pixels with a
+    /// count of zero still get one packet.
+    #[allow(dead_code)]
+    pub fn with_sample_map(mut self, counts: Vec<usize>) -> Result<Self, String> {
+        if counts.len() != self.res.w * self.res.h {
+            return Err(format!(
+                "Sample map needs {} pixels for a {}x{} film, got {}",
+                self.res.w * self.res.h,
+                self.res.w,
+                self.res.h,
+                counts.len()
+            ));
+        }
+        self.sample_map = Some(counts);
+        Ok(self)
+    }
+
+    /// Same as `with_sample_map`, reading the counts from the values of a grayscale image
+    /// the size of the film.
+    #[allow(dead_code)]
+    pub fn with_sample_map_image<P: AsRef<std::path::Path>>(self, path: P) -> Result<Self, String> {
+        let image = image::open(path.as_ref())
+            .map_err(|e| format!("Failed to open sample map image: {}", e))?
+            .to_luma();
+        let counts = image
+            .pixels()
+            .map(|pixel| usize::from(pixel.0[0]))
+            .collect::<Vec<_>>();
+        self.with_sample_map(counts)
+    }
+
+    /// Closest that rays after the first bounce can hit something, and the least they're
+    /// offset from the surface they start on, to keep them from hitting it again. By default
+    /// it scales with the size of the scene (see `HitableStore::scene_epsilon`). Camera rays
+    /// use the camera's near clipping distance instead, which can be much smaller.
+    #[allow(dead_code)]
+    pub fn with_bounce_t_min(mut self, t_min: f32) -> Self {
+        self.bounce_t_min = Some(t_min);
+        self
+    }
+
+    /// Seed for the per-pixel scrambling of the sample sequences. Each pixel's random state
+    /// is hashed from its coordinates, the frame and this seed, so renders with the same
+    /// seed are identical no matter how many threads there are or how tiles get scheduled.
+    #[allow(dead_code)]
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Lets renders be aborted through `token`. It's checked before each tile is started:
+    /// once it's cancelled no new tiles are rendered, but the ones already in flight finish
+    /// and everything rendered so far is still merged into the film.
+    #[allow(dead_code)]
+    pub fn with_cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel_token = Some(token);
+        self
+    }
+
+    /// Calls `callback` every time a tile is finished, with how many of the frame's tiles are
+    /// done so far and how many there are in total. Calls are made from the render threads
+    /// but never at the same time, and the completed count goes up by one with each.
+    ///
+    /// The total is the number of pieces actually rendered: `ceil(w / tile_w) * ceil(h /
+    /// tile_h)` tiles, less those outside the crop window, and more when a sample map makes
+    /// some of them expensive enough to be split.
+    #[allow(dead_code)]
+    pub fn with_progress_callback<C>(mut self, callback: C) -> Self
+    where
+        C: Fn(usize, usize) + Send + Sync + 'static,
+    {
+        self.progress_callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Whether the last frame was cancelled before all of its tiles were rendered. If so,
+    /// the skipped tiles keep whatever they held before the frame.
+    #[allow(dead_code)]
+    pub fn was_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// Mean relative variance of the color of the pixels rendered in the last frame, which
+    /// shrinks as the render converges. It's estimated from how far apart the `ColorEven`
+    /// and `ColorOdd` halves are, so it's `None` unless the film has both channels.
+    #[allow(dead_code)]
+    pub fn convergence(&self) -> Option<f32> {
+        self.convergence
+    }
+
+    fn estimate_convergence(&self, crop: Aabru) -> Option<f32> {
+        let even = self.channel_indices.get(&ChannelKind::ColorEven)?;
+        let odd = self.channel_indices.get(&ChannelKind::ColorOdd)?;
+        let (even, odd) = match (&self.channels[*even], &self.channels[*odd]) {
+            (ChannelStorage::ColorEven(even), ChannelStorage::ColorOdd(odd)) => (even, odd),
+            _ => return None,
+        };
+
+        // the squared difference of the two half estimates is, in expectation, four times
+        // the variance of their average
+        let mut total = 0.0;
+        let mut count = 0;
+        for y in crop.min.y..crop.max.y {
+            for x in crop.min.x..crop.max.x {
+                let idx = x + y * self.res.w;
+                let (even, odd) = (even[idx].luminance(), odd[idx].luminance());
+                let mean = (even + odd) * 0.5;
+                total += (even - odd) * (even - odd) * 0.25 / (mean * mean + 1e-4);
+                count += 1;
+            }
+        }
+        if count > 0 {
+            Some(total / count as f32)
+        } else {
+            None
+        }
+    }
+
+    /// The color channel of the last frame along with how many samples went into each pixel,
+    /// before any post processing.
+    #[allow(dead_code)]
+    pub fn framebuffer(&self) -> Result<Framebuffer, String> {
+        let idx = *self
+            .channel_indices
+            .get(&ChannelKind::Color)
+            .ok_or_else(|| String::from("Attempted to read Color channel but it didn't exist"))?;
+        let colors = channel_storage_index!(self.channels, Color, idx);
+        let mut framebuffer = Framebuffer::new(self.res.w, self.res.h);
+        for (i, (color, count)) in colors.iter().zip(&self.pixel_sample_counts).enumerate() {
+            framebuffer.set_pixel(i % self.res.w, i / self.res.w, color.0, *count)?;
+        }
+        Ok(framebuffer)
+    }
+
+    /// Number of NaN or infinite samples dropped while rendering the last frame.
+    pub fn non_finite_samples(&self) -> usize {
+        self.non_finite_samples
+    }
+
+    /// Tunes how per-object hit storage is pre-sized from the hit counts of previous frames.
+    /// See `HitCountEstimate`.
+    #[allow(dead_code)]
+    pub fn with_hit_store_sizing(mut self, smoothing: f32, headroom: f32) -> Self {
+        self.hit_count_estimate = HitCountEstimate::new(smoothing, headroom);
+        self
+    }
+
     pub fn save_to<P: AsRef<std::path::Path>, IS: Into<String>>(
         &self,
         write_channels: &[ChannelKind],
@@ -216,7 +654,7 @@ impl<'a, N: ArrayLength<ChannelStorage>> Film<N> {
 
         let base_name = base_name.into();
 
-        let channels = self.channels.lock().unwrap();
+        let channels = &self.channels;
 
         for kind in write_channels.iter() {
             match *kind {
@@ -228,14 +666,16 @@ impl<'a, N: ArrayLength<ChannelStorage>> Film<N> {
                     match (color_idx, alpha_idx, bg_idx, transparent_background) {
                         (Some(&color_idx), Some(&alpha_idx), _, true) => {
                             let color_buf = &channel_storage_index!(channels, Color, color_idx);
+                            let color_buf = self.post_processed(color_buf);
                             let alpha_buf = &channel_storage_index!(channels, Alpha, alpha_idx);
                             let mut img =
                                 image::RgbaImage::new(self.res.w as u32, self.res.h as u32);
                             for (x, y, pixel) in img.enumerate_pixels_mut() {
-                                let idx = x as usize + (self.res.h - 1 - y as usize) * self.res.w;
+                                let idx = x as usize + y as usize * self.res.w;
                                 let col = color_buf[idx];
                                 let a = alpha_buf[idx];
-                                let rgb = (col).saturated().gamma_corrected(2.2);
+                                let rgb = self.output_color_space.convert_srgb(col);
+                                let rgb = rgb.saturated().gamma_corrected(2.2);
                                 *pixel = image::Rgba([
                                     (rgb.x * 255.0).min(255.0).max(0.0) as u8,
                                     (rgb.y * 255.0).min(255.0).max(0.0) as u8,
@@ -251,14 +691,16 @@ impl<'a, N: ArrayLength<ChannelStorage>> Film<N> {
                         }
                         (Some(&color_idx), _, Some(&bg_idx), false) => {
                             let color_buf = channel_storage_index!(channels, Color, color_idx);
+                            let color_buf = self.post_processed(color_buf);
                             let bg_buf = channel_storage_index!(channels, Background, bg_idx);
                             let mut img =
                                 image::RgbImage::new(self.res.w as u32, self.res.h as u32);
                             for (x, y, pixel) in img.enumerate_pixels_mut() {
-                                let i = x as usize + (self.res.h - 1 - y as usize) * self.res.w;
+                                let i = x as usize + y as usize * self.res.w;
                                 let col = color_buf[i];
                                 let bg = bg_buf[i];
-                                let rgb = (col + bg).saturated().gamma_corrected(2.2);
+                                let rgb = self.output_color_space.convert_srgb(col + bg);
+                                let rgb = rgb.saturated().gamma_corrected(2.2);
                                 *pixel = image::Rgb([
                                     (rgb.x * 255.0).min(255.0).max(0.0) as u8,
                                     (rgb.y * 255.0).min(255.0).max(0.0) as u8,
@@ -273,11 +715,13 @@ impl<'a, N: ArrayLength<ChannelStorage>> Film<N> {
                         }
                         (Some(&color_idx), _, None, false) => {
                             let color_buf = channel_storage_index!(channels, Color, color_idx);
+                            let color_buf = self.post_processed(color_buf);
                             let mut img =
                                 image::RgbImage::new(self.res.w as u32, self.res.h as u32);
                             for (x, y, pixel) in img.enumerate_pixels_mut() {
-                                let idx = x as usize + (self.res.h - 1 - y as usize) * self.res.w;
-                                let rgb = color_buf[idx].gamma_corrected(2.2);
+                                let idx = x as usize + y as usize * self.res.w;
+                                let rgb = self.output_color_space.convert_srgb(color_buf[idx]);
+                                let rgb = rgb.gamma_corrected(2.2);
                                 *pixel = image::Rgb([
                                     (rgb.x * 255.0).min(255.0).max(0.0) as u8,
                                     (rgb.y * 255.0).min(255.0).max(0.0) as u8,
@@ -309,8 +753,9 @@ impl<'a, N: ArrayLength<ChannelStorage>> Film<N> {
                     let buf = channel_storage_index!(channels, Background, idx);
                     let mut img = image::RgbImage::new(self.res.w as u32, self.res.h as u32);
                     for (x, y, pixel) in img.enumerate_pixels_mut() {
-                        let idx = x as usize + (self.res.h - 1 - y as usize) * self.res.w;
-                        let rgb = buf[idx].saturated().gamma_corrected(2.2);
+                        let idx = x as usize + y as usize * self.res.w;
+                        let rgb = self.output_color_space.convert_srgb(buf[idx]);
+                        let rgb = rgb.saturated().gamma_corrected(2.2);
                         *pixel = image::Rgb([
                             (rgb.x * 255.0).min(255.0).max(0.0) as u8,
                             (rgb.y * 255.0).min(255.0).max(0.0) as u8,
@@ -335,7 +780,7 @@ impl<'a, N: ArrayLength<ChannelStorage>> Film<N> {
                     let buf = channel_storage_index!(channels, WorldNormal, idx);
                     let mut img = image::RgbImage::new(self.res.w as u32, self.res.h as u32);
                     for (x, y, pixel) in img.enumerate_pixels_mut() {
-                        let idx = x as usize + (self.res.h - 1 - y as usize) * self.res.w;
+                        let idx = x as usize + y as usize * self.res.w;
                         let vec = buf[idx];
                         let rgb = Srgb::from(vec * 0.5 + Vec3::new(0.5, 0.5, 0.5));
                         *pixel = image::Rgb([
@@ -350,6 +795,93 @@ impl<'a, N: ArrayLength<ChannelStorage>> Film<N> {
                     println!("Saving to {}...", filename.display());
                     img.save(filename).unwrap();
                 }
+                ChannelKind::DiffuseDirect
+                | ChannelKind::DiffuseIndirect
+                | ChannelKind::SpecularDirect
+                | ChannelKind::SpecularIndirect
+                | ChannelKind::ColorEven
+                | ChannelKind::ColorOdd => {
+                    let idx = *self.channel_indices.get(kind).ok_or_else(|| {
+                        format!("Attempted to write {:?} channel but it didn't exist", kind)
+                    })?;
+                    let (buf, suffix) = match &channels[idx] {
+                        ChannelStorage::DiffuseDirect(buf) => (buf, "diffuse_direct"),
+                        ChannelStorage::DiffuseIndirect(buf) => (buf, "diffuse_indirect"),
+                        ChannelStorage::SpecularDirect(buf) => (buf, "specular_direct"),
+                        ChannelStorage::SpecularIndirect(buf) => (buf, "specular_indirect"),
+                        ChannelStorage::ColorEven(buf) => (buf, "color_even"),
+                        ChannelStorage::ColorOdd(buf) => (buf, "color_odd"),
+                        _ => panic!(
+                            "Attempted to index into channel storage array with wrong channel type."
+                        ),
+                    };
+                    let mut img = image::RgbImage::new(self.res.w as u32, self.res.h as u32);
+                    for (x, y, pixel) in img.enumerate_pixels_mut() {
+                        let idx = x as usize + y as usize * self.res.w;
+                        let rgb = self.output_color_space.convert_srgb(buf[idx]);
+                        let rgb = rgb.saturated().gamma_corrected(2.2);
+                        *pixel = image::Rgb([
+                            (rgb.x * 255.0).min(255.0).max(0.0) as u8,
+                            (rgb.y * 255.0).min(255.0).max(0.0) as u8,
+                            (rgb.z * 255.0).min(255.0).max(0.0) as u8,
+                        ]);
+                    }
+                    let filename = output_folder.as_ref().join(format!(
+                        "{}_{}.png",
+                        base_name.clone(),
+                        suffix
+                    ));
+                    println!("Saving to {}...", filename.display());
+                    img.save(filename).unwrap();
+                }
+                ChannelKind::ObjectId | ChannelKind::MaterialId => {
+                    let idx = *self.channel_indices.get(kind).ok_or_else(|| {
+                        format!("Attempted to write {:?} channel but it didn't exist", kind)
+                    })?;
+                    let (buf, suffix) = match &channels[idx] {
+                        ChannelStorage::ObjectId(buf) => (buf, "object_id"),
+                        ChannelStorage::MaterialId(buf) => (buf, "material_id"),
+                        _ => panic!(
+                            "Attempted to index into channel storage array with wrong channel type."
+                        ),
+                    };
+                    // ids are stored as 24 bit integers, with the lowest byte in red
+                    let mut img = image::RgbImage::new(self.res.w as u32, self.res.h as u32);
+                    for (x, y, pixel) in img.enumerate_pixels_mut() {
+                        let idx = x as usize + y as usize * self.res.w;
+                        let id = buf[idx].encoded();
+                        *pixel = image::Rgb([id as u8, (id >> 8) as u8, (id >> 16) as u8]);
+                    }
+                    let filename = output_folder.as_ref().join(format!(
+                        "{}_{}.png",
+                        base_name.clone(),
+                        suffix
+                    ));
+                    println!("Saving to {}...", filename.display());
+                    img.save(filename).unwrap();
+                }
+                ChannelKind::Depth => {
+                    let idx = *self
+                        .channel_indices
+                        .get(&ChannelKind::Depth)
+                        .ok_or_else(|| {
+                            String::from("Attempted to write Depth channel but it didn't exist")
+                        })?;
+                    let buf = channel_storage_index!(channels, Depth, idx);
+                    // normalized so the farthest hit is white
+                    let max_depth = buf.iter().cloned().fold(0.0, f32::max).max(1e-6);
+                    let mut img = image::GrayImage::new(self.res.w as u32, self.res.h as u32);
+                    for (x, y, pixel) in img.enumerate_pixels_mut() {
+                        let idx = x as usize + y as usize * self.res.w;
+                        let d = buf[idx] / max_depth;
+                        *pixel = image::Luma([(d * 255.0).min(255.0).max(0.0) as u8]);
+                    }
+                    let filename = output_folder
+                        .as_ref()
+                        .join(format!("{}_depth.png", base_name.clone()));
+                    println!("Saving to {}...", filename.display());
+                    img.save(filename).unwrap();
+                }
                 ChannelKind::Alpha => {
                     let idx = *self
                         .channel_indices
@@ -360,7 +892,7 @@ impl<'a, N: ArrayLength<ChannelStorage>> Film<N> {
                     let buf = channel_storage_index!(channels, Alpha, idx);
                     let mut img = image::GrayImage::new(self.res.w as u32, self.res.h as u32);
                     for (x, y, pixel) in img.enumerate_pixels_mut() {
-                        let idx = x as usize + (self.res.h - 1 - y as usize) * self.res.w;
+                        let idx = x as usize + y as usize * self.res.w;
                         let a = buf[idx];
                         *pixel = image::Luma([(a * 255.0).min(255.0).max(0.0) as u8]);
                     }
@@ -388,54 +920,136 @@ impl<'a, N: ArrayLength<ChannelStorage> + ArrayLength<ChannelTileStorage>> Film<
         frame: usize,
         time_range: Range<f32>,
         samples: usize,
+        background: Srgb,
+        crop: Option<Aabru>,
     ) where
         F: Filter + Copy + Send,
         I: Integrator,
     {
         let camera = world.cameras.get(camera);
-        let mut tiles = Vec::new();
-
-        let rem = Vec2u::new((self.res.w) % tile_size.w, (self.res.h) % tile_size.h);
-        {
-            let mut idx = 0;
-            let channels = self.channels.lock().unwrap();
-            for tile_x in 0..((self.res.w + rem.x) / tile_size.w) {
-                for tile_y in 0..((self.res.h + rem.y) / tile_size.h) {
-                    let start = Vec2u::new(tile_x * tile_size.w, tile_y * tile_size.h);
-                    let end = Vec2u::new(
-                        (start.x + tile_size.w).min(self.res.w),
-                        (start.y + tile_size.h).min(self.res.h),
-                    );
-                    let tile_bounds = Aabru {
-                        min: start,
-                        max: end,
-                    };
-
-                    let tile = Tile::new(
-                        idx,
-                        self.progressive_epoch,
-                        channels.iter().map(|c| c.kind()),
-                        self.res,
-                        tile_bounds,
-                    );
-                    tiles.push(tile);
-
-                    idx += 1;
+        // Only pixels inside the crop window (in pixels from the top-left, max exclusive)
+        // get sampled; the rest of the film is left untouched.
+        let crop = crop.unwrap_or(Aabru {
+            min: Vec2u::zero(),
+            max: Vec2u::new(self.res.w, self.res.h),
+        });
+        let primary_t_range = camera.clip_range();
+        let bounce_t_min = self.bounce_t_min.unwrap_or_else(|| {
+            world
+                .hitables
+                .scene_epsilon(time_range.start, time_range.end)
+        });
+        self.non_finite_samples = 0;
+        // rounded up, so a partial tile covers the last rows and columns
+        let tile_count = Vec2u::new(
+            (self.res.w + tile_size.w - 1) / tile_size.w,
+            (self.res.h + tile_size.h - 1) / tile_size.h,
+        );
+        let mut tile_bounds = Vec::new();
+        for tile_x in 0..tile_count.x {
+            for tile_y in 0..tile_count.y {
+                let start = Vec2u::new(tile_x * tile_size.w, tile_y * tile_size.h);
+                let end = Vec2u::new(
+                    (start.x + tile_size.w).min(self.res.w).min(crop.max.x),
+                    (start.y + tile_size.h).min(self.res.h).min(crop.max.y),
+                );
+                let start = Vec2u::new(start.x.max(crop.min.x), start.y.max(crop.min.y));
+                if start.x >= end.x || start.y >= end.y {
+                    continue;
                 }
+                tile_bounds.push(Aabru {
+                    min: start,
+                    max: end,
+                });
             }
         }
 
-        let fis = FilterImportanceSampler::new(filter);
-
-        let sets_1d = 1 + integrator.requested_1d_sample_sets();
-        let sets_2d = 2 + integrator.requested_2d_sample_sets();
-
-        let sample_sets = Samples::new_rd(4 * samples, sets_1d, sets_2d, frame as u64);
-        // let sample_sets = Samples::new_random(4 * samples, sets_1d, sets_2d);
+        let pixel_samples = |x: usize, y: usize| {
+            self.sample_map
+                .as_ref()
+                .map_or(samples, |map| map[x + y * self.res.w].max(1))
+        };
+        let tile_cost = |bounds: Aabru| {
+            (bounds.min.y..bounds.max.y)
+                .flat_map(|y| (bounds.min.x..bounds.max.x).map(move |x| (x, y)))
+                .map(|(x, y)| pixel_samples(x, y))
+                .sum::<usize>()
+        };
+
+        // With a sample map some tiles take far longer than others, so those are split up
+        // until no piece is more work than a full tile at the average cost per pixel, and the
+        // pieces are handed out most expensive first so that a slow one doesn't start last
+        // and hold up the frame. Without one, tiles are left as they are.
+        let max_cost = if self.sample_map.is_some() {
+            let total_cost = tile_bounds
+                .iter()
+                .map(|bounds| tile_cost(*bounds))
+                .sum::<usize>();
+            let total_pixels = tile_bounds
+                .iter()
+                .map(|bounds| bounds.size().w * bounds.size().h)
+                .sum::<usize>();
+            (total_cost * tile_size.w * tile_size.h / total_pixels.max(1)).max(1)
+        } else {
+            std::usize::MAX
+        };
+        let mut chunks = Vec::new();
+        for bounds in tile_bounds {
+            split_tile(bounds, &tile_cost, max_cost, &mut chunks);
+        }
+        chunks.sort_by_key(|(_, cost)| std::cmp::Reverse(*cost));
+
+        let channels = &self.channels;
+        let tiles = chunks
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (bounds, _))| {
+                let mut sample_counts = Vec::with_capacity(bounds.size().w * bounds.size().h);
+                for y in bounds.min.y..bounds.max.y {
+                    for x in bounds.min.x..bounds.max.x {
+                        sample_counts.push(4 * pixel_samples(x, y));
+                    }
+                }
+                Tile::new(
+                    idx,
+                    self.progressive_epoch,
+                    channels.iter().map(|c| c.kind()),
+                    bounds,
+                    sample_counts,
+                )
+            })
+            .collect::<Vec<_>>();
 
-        let width = self.res.w;
+        let fis = FilterImportanceSampler::new(filter);
 
-        self.integrate_tiles(tiles, samples * 4, |tile| {
+        // `integrate` takes 3 1D and 6 2D sets per bounce
+        let bounces = (integrator.requested_1d_sample_sets() + 2) / 3;
+        let bounces = bounces.max((integrator.requested_2d_sample_sets() + 5) / 6);
+        let dimensions = SampleDimensions::new(3, 6, bounces);
+        let (sets_1d, sets_2d) = (dimensions.sets_1d(), dimensions.sets_2d());
+        let time_set = SampleDimensions::TIME;
+        let (pixel_set, lens_set) = (SampleDimensions::PIXEL_OFFSET, SampleDimensions::LENS);
+
+        let max_samples = tiles
+            .iter()
+            .flat_map(|tile| tile.sample_counts.iter())
+            .max()
+            .copied()
+            .unwrap_or(4 * samples);
+        let sample_sets = Samples::new_rd(max_samples, sets_1d, sets_2d, frame as u64);
+        // let sample_sets = Samples::new_random(max_samples, sets_1d, sets_2d, seed);
+
+        let res = self.res;
+        let seed = self.seed;
+
+        let output_ids = self.channel_indices.contains_key(&ChannelKind::ObjectId)
+            || self.channel_indices.contains_key(&ChannelKind::MaterialId);
+        let output_depth = self.channel_indices.contains_key(&ChannelKind::Depth);
+
+        let hit_capacities = self.hit_count_estimate.capacities(world.hitables.len());
+        let frame_peak_hits = Mutex::new(vec![0; world.hitables.len()]);
+
+        self.integrate_tiles(tiles, |tile| {
             // let mut rng = SmallRng::from_rng(thread_rng()).unwrap();
             // let offset = (tile.index as u64) << 32;
 
@@ -447,7 +1061,8 @@ impl<'a, N: ArrayLength<ChannelStorage> + ArrayLength<ChannelTileStorage>> Film<
             let sample_bump = Bump::new();
             let mut new_samples = BumpVec::new_in(&sample_bump);
             let hit_bump = Bump::new();
-            let mut hit_store = HitStore::from_hitable_store(&hit_bump, &world.hitables);
+            let mut hit_store =
+                HitStore::with_capacities(&hit_bump, &world.hitables, &hit_capacities);
             let mut bsdf_bump = Bump::new();
 
             let time_range_range = f32x4::from(time_range.end - time_range.start);
@@ -456,51 +1071,42 @@ impl<'a, N: ArrayLength<ChannelStorage> + ArrayLength<ChannelTileStorage>> Film<
                 for y in tile.raster_bounds.min.y..tile.raster_bounds.max.y {
                     let tile_coord = Vec2u::new(x, y) - tile.raster_bounds.min;
 
-                    let mut rng = SmallRng::seed_from_u64((x + y * width) as u64);
+                    let pixel_seed = hash_seed(&[x as u64, y as u64, frame as u64, seed]);
+                    let mut rng = SmallRng::seed_from_u64(pixel_seed);
                     let scramble = rng.gen();
+                    let pixel_samples =
+                        tile.sample_counts[tile_coord.x + tile_coord.y * tile.raster_extent.w] / 4;
 
-                    for samp in 0..samples {
+                    for samp in 0..pixel_samples {
                         let sample_nums = [4 * samp, 4 * samp + 1, 4 * samp + 2, 4 * samp + 3];
 
-                        let ndcs = Wec2::from([
-                            sample_uv(
-                                x,
-                                y,
-                                tile.screen_to_ndc_size,
+                        let pixel_offsets = Wec2::from([
+                            sample_pixel_offset(
                                 &fis,
                                 &[
-                                    sample_sets.sample_2d(0, sample_nums[0], scramble, 0),
-                                    sample_sets.sample_2d(1, sample_nums[0], scramble, 0),
+                                    sample_sets.sample_2d(0, sample_nums[0], scramble, pixel_set),
+                                    sample_sets.sample_2d(1, sample_nums[0], scramble, pixel_set),
                                 ],
                             ),
-                            sample_uv(
-                                x,
-                                y,
-                                tile.screen_to_ndc_size,
+                            sample_pixel_offset(
                                 &fis,
                                 &[
-                                    sample_sets.sample_2d(0, sample_nums[1], scramble, 0),
-                                    sample_sets.sample_2d(1, sample_nums[1], scramble, 0),
+                                    sample_sets.sample_2d(0, sample_nums[1], scramble, pixel_set),
+                                    sample_sets.sample_2d(1, sample_nums[1], scramble, pixel_set),
                                 ],
                             ),
-                            sample_uv(
-                                x,
-                                y,
-                                tile.screen_to_ndc_size,
+                            sample_pixel_offset(
                                 &fis,
                                 &[
-                                    sample_sets.sample_2d(0, sample_nums[2], scramble, 0),
-                                    sample_sets.sample_2d(1, sample_nums[2], scramble, 0),
+                                    sample_sets.sample_2d(0, sample_nums[2], scramble, pixel_set),
+                                    sample_sets.sample_2d(1, sample_nums[2], scramble, pixel_set),
                                 ],
                             ),
-                            sample_uv(
-                                x,
-                                y,
-                                tile.screen_to_ndc_size,
+                            sample_pixel_offset(
                                 &fis,
                                 &[
-                                    sample_sets.sample_2d(0, sample_nums[3], scramble, 0),
-                                    sample_sets.sample_2d(1, sample_nums[3], scramble, 0),
+                                    sample_sets.sample_2d(0, sample_nums[3], scramble, pixel_set),
+                                    sample_sets.sample_2d(1, sample_nums[3], scramble, pixel_set),
                                 ],
                             ),
                         ]);
@@ -508,17 +1114,19 @@ impl<'a, N: ArrayLength<ChannelStorage> + ArrayLength<ChannelTileStorage>> Film<
                         let times = f32x4::from(time_range.start)
                             + time_range_range
                             // * f32x4::from(rng.gen::<[f32; 4]>());
-                            * sample_sets.wide_sample_1d(sample_nums[0], scramble, 0);
+                            * sample_sets.wide_sample_1d(sample_nums[0], scramble, time_set);
 
-                        let rays = camera.get_rays(
+                        let rays = camera.get_sample_packet(
                             scramble,
-                            sample_nums,
+                            sample_nums[0],
                             tile_coord,
-                            ndcs,
+                            Vec2u::new(x, y),
+                            res,
+                            pixel_offsets,
                             times,
                             &[
-                                sample_sets.wide_sample_2d(0, sample_nums[0], scramble, 1),
-                                sample_sets.wide_sample_2d(1, sample_nums[0], scramble, 1),
+                                sample_sets.wide_sample_2d(0, sample_nums[0], scramble, lens_set),
+                                sample_sets.wide_sample_2d(1, sample_nums[0], scramble, lens_set),
                             ],
                         );
 
@@ -536,32 +1144,61 @@ impl<'a, N: ArrayLength<ChannelStorage> + ArrayLength<ChannelTileStorage>> Film<
 
                 hit_store.reset();
 
+                let t_range = if depth == 0 {
+                    f32x4::from(primary_t_range.start)..f32x4::from(primary_t_range.end)
+                } else {
+                    f32x4::from(bounce_t_min)..f32x4::from(500.0)
+                };
+                let ray_kind = if depth == 0 {
+                    RayKind::Camera
+                } else {
+                    RayKind::Reflection
+                };
+
                 for wray in spawned_wrays.drain(..) {
-                    world.hitables.add_hits(
-                        wray,
-                        f32x4::from(0.0001)..f32x4::from(500.0),
-                        &mut hit_store,
-                    );
+                    world
+                        .hitables
+                        .add_hits(wray, t_range.clone(), ray_kind, &mut hit_store);
+                }
+
+                for ray in hit_store.misses() {
+                    integrator.miss(ray, depth, background, &mut new_samples);
                 }
 
-                hit_store.process_hits(&world.hitables, &mut wintersections, depth == 0, camera);
+                hit_store.process_hits(
+                    &world.hitables,
+                    &mut wintersections,
+                    depth == 0,
+                    camera,
+                    f32x4::from(bounce_t_min),
+                );
+
+                for (obj_id, mat_id, wshading_point) in wintersections.drain(..) {
+                    if depth == 0 && output_ids {
+                        let obj_id = world.hitables.id(obj_id);
+                        push_id_samples(obj_id, mat_id, &wshading_point.ray, &mut new_samples);
+                    }
+                    if depth == 0 && output_depth {
+                        push_depth_samples(&wshading_point, &mut new_samples);
+                    }
 
-                for (mat_id, wshading_point) in wintersections.drain(..) {
+                    let sets_1d = dimensions.bounce_1d(depth);
+                    let sets_2d = dimensions.bounce_2d(depth);
                     let samples_1d = [
                         sample_sets.wide_sample_1d_array(
                             wshading_point.ray.sample,
                             wshading_point.ray.scramble,
-                            1 + depth * 3,
+                            sets_1d.start,
                         ),
                         sample_sets.wide_sample_1d_array(
                             wshading_point.ray.sample,
                             wshading_point.ray.scramble,
-                            2 + depth * 3,
+                            sets_1d.start + 1,
                         ),
                         sample_sets.wide_sample_1d_array(
                             wshading_point.ray.sample,
                             wshading_point.ray.scramble,
-                            3 + depth * 3,
+                            sets_1d.start + 2,
                         ),
                     ];
                     let samples_2d = [
@@ -569,73 +1206,73 @@ impl<'a, N: ArrayLength<ChannelStorage> + ArrayLength<ChannelTileStorage>> Film<
                             0,
                             wshading_point.ray.sample,
                             wshading_point.ray.scramble,
-                            2 + depth * 6,
+                            sets_2d.start,
                         ),
                         sample_sets.wide_sample_2d_array(
                             1,
                             wshading_point.ray.sample,
                             wshading_point.ray.scramble,
-                            2 + depth * 6,
+                            sets_2d.start,
                         ),
                         sample_sets.wide_sample_2d_array(
                             0,
                             wshading_point.ray.sample,
                             wshading_point.ray.scramble,
-                            3 + depth * 6,
+                            sets_2d.start + 1,
                         ),
                         sample_sets.wide_sample_2d_array(
                             1,
                             wshading_point.ray.sample,
                             wshading_point.ray.scramble,
-                            3 + depth * 6,
+                            sets_2d.start + 1,
                         ),
                         sample_sets.wide_sample_2d_array(
                             0,
                             wshading_point.ray.sample,
                             wshading_point.ray.scramble,
-                            4 + depth * 6,
+                            sets_2d.start + 2,
                         ),
                         sample_sets.wide_sample_2d_array(
                             1,
                             wshading_point.ray.sample,
                             wshading_point.ray.scramble,
-                            4 + depth * 6,
+                            sets_2d.start + 2,
                         ),
                         sample_sets.wide_sample_2d_array(
                             0,
                             wshading_point.ray.sample,
                             wshading_point.ray.scramble,
-                            5 + depth * 6,
+                            sets_2d.start + 3,
                         ),
                         sample_sets.wide_sample_2d_array(
                             1,
                             wshading_point.ray.sample,
                             wshading_point.ray.scramble,
-                            5 + depth * 6,
+                            sets_2d.start + 3,
                         ),
                         sample_sets.wide_sample_2d_array(
                             0,
                             wshading_point.ray.sample,
                             wshading_point.ray.scramble,
-                            6 + depth * 6,
+                            sets_2d.start + 4,
                         ),
                         sample_sets.wide_sample_2d_array(
                             1,
                             wshading_point.ray.sample,
                             wshading_point.ray.scramble,
-                            6 + depth * 6,
+                            sets_2d.start + 4,
                         ),
                         sample_sets.wide_sample_2d_array(
                             0,
                             wshading_point.ray.sample,
                             wshading_point.ray.scramble,
-                            7 + depth * 6,
+                            sets_2d.start + 5,
                         ),
                         sample_sets.wide_sample_2d_array(
                             1,
                             wshading_point.ray.sample,
                             wshading_point.ray.scramble,
-                            7 + depth * 6,
+                            sets_2d.start + 5,
                         ),
                     ];
                     integrator.integrate(
@@ -674,45 +1311,132 @@ impl<'a, N: ArrayLength<ChannelStorage> + ArrayLength<ChannelTileStorage>> Film<
                 }
                 spawned_rays.clear();
             }
+
+            let mut frame_peak_hits = frame_peak_hits.lock().unwrap();
+            for (frame_peak, peak) in frame_peak_hits.iter_mut().zip(hit_store.peak_hits()) {
+                *frame_peak = (*frame_peak).max(*peak);
+            }
         });
+
+        self.hit_count_estimate
+            .update(&frame_peak_hits.into_inner().unwrap());
+        self.convergence = self.estimate_convergence(crop);
     }
 
-    fn integrate_tiles<FN>(&mut self, tiles: Vec<Tile<N>>, samples: usize, integrate_tile: FN)
+    /// Renders and saves each frame of an animation in turn, with everything animated
+    /// sampled over the time range of the frame (see `FrameTiming`). Frames are saved to
+    /// `output_folder` with the frame number, zero padded, appended to `base_name`.
+    /// Stops early if the cancel token is set, after saving the partial frame.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render_animation<I, F, P, IS>(
+        &'a mut self,
+        world: &World,
+        camera: CameraHandle,
+        integrator: &I,
+        filter: &F,
+        tile_size: Extent2u,
+        frames: Range<usize>,
+        timing: FrameTiming,
+        samples: usize,
+        background: Srgb,
+        write_channels: &[ChannelKind],
+        output_folder: P,
+        base_name: IS,
+    ) -> Result<(), String>
     where
-        FN: FnOnce(&mut Tile<N>) + Send + Sync + Copy,
+        F: Filter + Copy + Send,
+        I: Integrator,
+        P: AsRef<std::path::Path>,
+        IS: Into<String>,
     {
-        let num_tiles = tiles.len();
+        let base_name = base_name.into();
+        for frame in frames {
+            let start = Instant::now();
+
+            self.render_frame_into(
+                world,
+                camera,
+                integrator,
+                filter,
+                tile_size,
+                frame,
+                timing.time_range(frame),
+                samples,
+                background,
+                None,
+            );
 
-        let pb = Arc::new(Mutex::new(pbr::ProgressBar::new(num_tiles as u64)));
+            println!(
+                "Frame {} done in {} seconds.",
+                frame,
+                start.elapsed().as_millis() as f32 / 1000.0
+            );
+            if self.non_finite_samples() > 0 {
+                println!(
+                    "Warning: dropped {} NaN or infinite samples.",
+                    self.non_finite_samples()
+                );
+            }
 
-        {
-            let this = &*self;
-            rayon::scope_fifo(|scope| {
-                for mut tile in tiles.into_iter() {
-                    let pb = pb.clone();
-                    scope.spawn_fifo(move |_| {
-                        integrate_tile(&mut tile);
+            self.save_to(
+                write_channels,
+                output_folder.as_ref(),
+                format!("{}_{:04}", base_name, frame),
+                false,
+            )?;
 
-                        this.tile_finished(tile, samples, pb)
-                    })
-                }
-            });
+            if self.was_cancelled() {
+                break;
+            }
         }
+        Ok(())
+    }
 
-        while !self.this_epoch_tiles_finished.load(Ordering::Relaxed) == num_tiles {
-            std::thread::sleep(std::time::Duration::from_millis(10));
+    /// Renders all the tiles in parallel, each into its own buffers, and then merges them
+    /// into the film once they're all done. Nothing is shared between tiles while rendering.
+    fn integrate_tiles<FN>(&mut self, tiles: Vec<Tile<N>>, integrate_tile: FN)
+    where
+        FN: Fn(&mut Tile<N>) + Send + Sync,
+    {
+        let tile_count = tiles.len();
+        // the bar and how many tiles are done, locked together so progress is reported in order
+        let progress = Mutex::new((pbr::ProgressBar::new(tile_count as u64), 0));
+        let progress_callback = self.progress_callback.as_ref();
+        let cancel_token = self.cancel_token.clone();
+
+        // handed to threads one at a time in order as they free up, rather than split up
+        // between them ahead of time
+        let finished_tiles = tiles
+            .into_iter()
+            .par_bridge()
+            .filter_map(|mut tile| {
+                if cancel_token
+                    .as_ref()
+                    .map_or(false, CancelToken::is_cancelled)
+                {
+                    return None;
+                }
+                integrate_tile(&mut tile);
+                let mut progress = progress.lock().unwrap();
+                progress.0.inc();
+                progress.1 += 1;
+                if let Some(callback) = progress_callback {
+                    callback(progress.1, tile_count);
+                }
+                Some(tile)
+            })
+            .collect::<Vec<_>>();
+
+        self.cancelled = finished_tiles.len() < tile_count;
+
+        for tile in finished_tiles {
+            self.tile_finished(tile);
         }
 
-        self.this_epoch_tiles_finished.store(0, Ordering::Relaxed);
         self.progressive_epoch += 1;
     }
 
-    fn tile_finished(
-        &self,
-        tile: Tile<N>,
-        samples: usize,
-        pb: Arc<Mutex<pbr::ProgressBar<std::io::Stdout>>>,
-    ) {
+    fn tile_finished(&mut self, tile: Tile<N>) {
         if self.progressive_epoch != tile.epoch {
             panic!(
                 "Epoch mismatch! Expected: {}, got: {}",
@@ -720,40 +1444,803 @@ impl<'a, N: ArrayLength<ChannelStorage> + ArrayLength<ChannelTileStorage>> Film<
             );
         }
 
-        let mut pb = pb.lock().unwrap();
-        pb.inc();
-
-        let mut channels = self.channels.lock().unwrap();
-
         let Tile {
             channels: tile_channels,
             raster_bounds: tile_bounds,
+            sample_counts,
+            non_finite_samples,
             ..
         } = tile;
 
-        for (tile_channel, channel) in tile_channels.iter().zip(channels.iter_mut()) {
-            // Safe because we guarantee that we won't start modifying this chunk again
-            // until the next epoch.
+        self.non_finite_samples += non_finite_samples;
+
+        let extent = tile_bounds.size();
+        for (y, row) in sample_counts.chunks(extent.w).enumerate() {
+            let start = tile_bounds.min.x + (tile_bounds.min.y + y) * self.res.w;
+            self.pixel_sample_counts[start..start + extent.w].copy_from_slice(row);
+        }
+
+        for (tile_channel, channel) in tile_channels.iter().zip(self.channels.iter_mut()) {
             channel
-                .copy_from_tile(tile_channel, self.res, tile_bounds, samples)
+                .copy_from_tile(tile_channel, self.res, tile_bounds, &sample_counts)
                 .unwrap();
         }
     }
 }
 
+/// Pushes the object and material ids of a primary hit for each of its valid lanes.
+fn push_id_samples(
+    obj_id: usize,
+    mat_id: MaterialHandle,
+    ray: &WRay,
+    output_samples: &mut BumpVec<(Vec2u, ChannelSample)>,
+) {
+    for (valid, tile_coord) in ray.valid.iter().zip(ray.tile_coord.iter()) {
+        if *valid {
+            output_samples.push((*tile_coord, ChannelSample::ObjectId(Id::new(obj_id))));
+            output_samples.push((*tile_coord, ChannelSample::MaterialId(Id::new(mat_id.0))));
+        }
+    }
+}
+
+/// Pushes the distance to a primary hit for each of its valid lanes.
+fn push_depth_samples(
+    shading_point: &WShadingPoint,
+    output_samples: &mut BumpVec<(Vec2u, ChannelSample)>,
+) {
+    let ray = &shading_point.ray;
+    let lanes = ray.valid.iter().zip(ray.tile_coord.iter());
+    for ((valid, tile_coord), t) in lanes.zip(shading_point.t.as_ref().iter()) {
+        if *valid {
+            output_samples.push((*tile_coord, ChannelSample::Depth(*t)));
+        }
+    }
+}
+
+/// Halves `bounds` along its longer side until each piece costs at most `max_cost`, or is no
+/// bigger than `MIN_CHUNK_PIXELS`, and pushes the pieces with their costs onto `chunks`.
+fn split_tile<C>(bounds: Aabru, cost: &C, max_cost: usize, chunks: &mut Vec<(Aabru, usize)>)
+where
+    C: Fn(Aabru) -> usize,
+{
+    let bounds_cost = cost(bounds);
+    let size = bounds.size();
+    if bounds_cost <= max_cost || size.w * size.h <= MIN_CHUNK_PIXELS {
+        chunks.push((bounds, bounds_cost));
+        return;
+    }
+
+    let (first, second) = if size.w >= size.h {
+        let mid = bounds.min.x + size.w / 2;
+        (
+            Aabru {
+                min: bounds.min,
+                max: Vec2u::new(mid, bounds.max.y),
+            },
+            Aabru {
+                min: Vec2u::new(mid, bounds.min.y),
+                max: bounds.max,
+            },
+        )
+    } else {
+        let mid = bounds.min.y + size.h / 2;
+        (
+            Aabru {
+                min: bounds.min,
+                max: Vec2u::new(bounds.max.x, mid),
+            },
+            Aabru {
+                min: Vec2u::new(bounds.min.x, mid),
+                max: bounds.max,
+            },
+        )
+    };
+    split_tile(first, cost, max_cost, chunks);
+    split_tile(second, cost, max_cost, chunks);
+}
+
+/// Offset of a sample from the center of its pixel, in pixels, importance sampled
+/// according to the filter.
 #[inline]
-fn sample_uv(
-    x: usize,
-    y: usize,
-    screen_to_ndc_size: Vec2,
-    fis: &FilterImportanceSampler,
-    samples: &[f32; 2],
-) -> Vec2 {
-    let uv_samp = Vec2::new(samples[0], samples[1]);
-    let fis_samp = Vec2::new(fis.sample(uv_samp.x), fis.sample(uv_samp.y));
-
-    let screen_coord = Vec2::new(x as f32 + 0.5, y as f32 + 0.5) + fis_samp;
-    // let screen_coord = Vec2::new(x as f32 + uv_samp.x, y as f32 + uv_samp.y);
-
-    screen_to_ndc_size * screen_coord
+fn sample_pixel_offset(fis: &FilterImportanceSampler, samples: &[f32; 2]) -> Vec2 {
+    Vec2::new(fis.sample(samples[0]), fis.sample(samples[1]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{CameraStore, OrthographicCamera};
+    use crate::filter::BlackmanHarrisFilter;
+    use crate::hitable::HitableStore;
+    use crate::integrator::{PathTracingIntegrator, RouletteHeuristic};
+    use crate::light::{Light, LightSampler, SphereLight};
+    use crate::material::{Dielectric, Lambertian, Material, MaterialHandle, MaterialStore, Sky};
+    use crate::spectrum::WSrgb;
+    use crate::sphere::Sphere;
+    use generic_array::typenum::{U1, U2, U3};
+
+    fn integrator() -> PathTracingIntegrator {
+        PathTracingIntegrator {
+            max_bounces: 3,
+            split_light_paths: false,
+            contact_shadows: false,
+            roulette: RouletteHeuristic::Throughput,
+            clamp: None,
+            regularization: None,
+            light_samples: 1,
+        }
+    }
+
+    /// A lit grey sphere inside a sky sphere, seen by a 24x16 orthographic camera.
+    fn sphere_world() -> (World, CameraHandle) {
+        let grey = Dielectric::new_remap(Srgb::new(0.5, 0.5, 0.5), 0.5);
+        sphere_world_with(grey, 3.0)
+    }
+
+    /// A lit unit sphere of `material` inside a sky sphere, seen by a 24x16 orthographic
+    /// camera with a view `vertical_size` high.
+    fn sphere_world_with<M: Material + 'static>(
+        material: M,
+        vertical_size: f32,
+    ) -> (World, CameraHandle) {
+        let mut materials = MaterialStore::new();
+        let surface = materials.add_material(material);
+        let sky = materials.add_material(Sky::new(Srgb::one(), Srgb::zero()));
+
+        let mut hitables = HitableStore::new();
+        hitables.push(Sphere::new(Vec3::zero(), 100.0, sky));
+        hitables.push(Sphere::new(Vec3::zero(), 1.0, surface));
+
+        let lights: Vec<Box<dyn Light>> = vec![Box::new(SphereLight::new(
+            Vec3::new(2.0, 2.0, 2.0),
+            0.5,
+            Srgb::new(10.0, 10.0, 10.0),
+        ))];
+        let light_sampler = LightSampler::new(&lights);
+
+        let mut cameras = CameraStore::new();
+        let camera = cameras.add_camera(Box::new(OrthographicCamera::new(
+            Vec2::new(24.0, 16.0),
+            vertical_size,
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::zero(),
+            Vec3::unit_y(),
+        )));
+        let world = World {
+            materials,
+            hitables,
+            lights,
+            light_sampler,
+            cameras,
+        };
+        (world, camera)
+    }
+
+    /// Color of the `crop` of a 24x16 render, or all of it, with `threads` threads.
+    fn render_with_threads(
+        world: &World,
+        camera: CameraHandle,
+        threads: usize,
+        crop: Option<Aabru>,
+    ) -> Vec<Vec3> {
+        let integrator = integrator();
+        let mut film = Film::<U1>::new(&[ChannelKind::Color], Extent2u::new(24, 16))
+            .unwrap()
+            .with_seed(7);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        pool.install(|| {
+            film.render_frame_into(
+                world,
+                camera,
+                &integrator,
+                &BlackmanHarrisFilter::new(1.5),
+                Extent2u::new(4, 4),
+                0,
+                0.0..1.0,
+                2,
+                Srgb::zero(),
+                crop,
+            )
+        });
+        film.framebuffer().unwrap().averaged()
+    }
+
+    #[test]
+    fn output_does_not_depend_on_the_thread_count() {
+        let (world, camera) = sphere_world();
+        let single = render_with_threads(&world, camera, 1, None);
+        assert!(single.iter().any(|color| color.mag_sq() > 0.0));
+        assert_eq!(single, render_with_threads(&world, camera, 8, None));
+    }
+
+    #[test]
+    fn progress_is_reported_once_per_tile() {
+        let (world, camera) = sphere_world();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let callback_reports = reports.clone();
+        let mut film = Film::<U1>::new(&[ChannelKind::Color], Extent2u::new(24, 16))
+            .unwrap()
+            .with_progress_callback(move |completed, total| {
+                callback_reports.lock().unwrap().push((completed, total))
+            });
+        // neither side is a multiple of the tile size
+        film.render_frame_into(
+            &world,
+            camera,
+            &integrator(),
+            &BlackmanHarrisFilter::new(1.5),
+            Extent2u::new(5, 5),
+            0,
+            0.0..1.0,
+            1,
+            Srgb::zero(),
+            None,
+        );
+
+        let tile_count = 5 * 4;
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), tile_count);
+        for (i, (completed, total)) in reports.iter().enumerate() {
+            assert_eq!(*completed, i + 1);
+            assert_eq!(*total, tile_count);
+        }
+    }
+
+    /// Scene with nothing in it, seen by a camera with the resolution `res`.
+    fn empty_world(res: Extent2u) -> (World, CameraHandle) {
+        let mut cameras = CameraStore::new();
+        let camera = cameras.add_camera(Box::new(OrthographicCamera::new(
+            Vec2::new(res.w as f32, res.h as f32),
+            3.0,
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::zero(),
+            Vec3::unit_y(),
+        )));
+        let world = World {
+            materials: MaterialStore::new(),
+            hitables: HitableStore::new(),
+            lights: Vec::new(),
+            light_sampler: LightSampler::new(&[]),
+            cameras,
+        };
+        (world, camera)
+    }
+
+    #[test]
+    fn empty_scenes_are_all_background() {
+        let (world, camera) = empty_world(Extent2u::new(8, 8));
+        let kinds = [ChannelKind::Color, ChannelKind::Background];
+        let mut film = Film::<U2>::new(&kinds, Extent2u::new(8, 8)).unwrap();
+        let background = Srgb::new(0.2, 0.4, 0.6);
+        film.render_frame_into(
+            &world,
+            camera,
+            &integrator(),
+            &BlackmanHarrisFilter::new(1.5),
+            Extent2u::new(4, 4),
+            0,
+            0.0..1.0,
+            2,
+            background,
+            None,
+        );
+
+        // the image is written out as color plus background
+        let colors = channel_storage_index!(film.channels, Color, film.channel_indices[&kinds[0]]);
+        let backgrounds =
+            channel_storage_index!(film.channels, Background, film.channel_indices[&kinds[1]]);
+        for (color, bg) in colors.iter().zip(backgrounds) {
+            let diff = *color + *bg - background;
+            assert!([diff.x, diff.y, diff.z].iter().all(|d| d.abs() < 1e-5));
+        }
+    }
+
+    #[test]
+    fn diffuse_light_paths_add_up_to_the_beauty() {
+        // close enough that the camera only sees the diffuse sphere
+        let white = WSrgb::splat(Srgb::new(0.8, 0.8, 0.8));
+        let (world, camera) = sphere_world_with(Lambertian::new(white), 1.0);
+
+        let kinds = [
+            ChannelKind::Color,
+            ChannelKind::DiffuseDirect,
+            ChannelKind::DiffuseIndirect,
+        ];
+        let mut film = Film::<U3>::new(&kinds, Extent2u::new(24, 16))
+            .unwrap()
+            .with_seed(3);
+        let integrator = PathTracingIntegrator {
+            split_light_paths: true,
+            ..integrator()
+        };
+        film.render_frame_into(
+            &world,
+            camera,
+            &integrator,
+            &BlackmanHarrisFilter::new(1.5),
+            Extent2u::new(8, 8),
+            0,
+            0.0..1.0,
+            4,
+            Srgb::zero(),
+            None,
+        );
+
+        let channel = |kind| film.channel_indices[&kind];
+        let colors = channel_storage_index!(film.channels, Color, channel(kinds[0]));
+        let direct = channel_storage_index!(film.channels, DiffuseDirect, channel(kinds[1]));
+        let indirect = channel_storage_index!(film.channels, DiffuseIndirect, channel(kinds[2]));
+        assert!(direct.iter().any(|c| c.x > 0.0) && indirect.iter().any(|c| c.x > 0.0));
+        for ((color, direct), indirect) in colors.iter().zip(direct).zip(indirect) {
+            let diff = *direct + *indirect - *color;
+            assert!([diff.x, diff.y, diff.z].iter().all(|d| d.abs() < 1e-4));
+        }
+    }
+
+    /// First-hit distances of a sphere 4 units in front of a camera and one 14 units away,
+    /// with the camera's far plane at `far`.
+    fn clipped_depths(far: f32) -> Vec<f32> {
+        let mut hitables = HitableStore::new();
+        hitables.push(Sphere::new(
+            Vec3::new(0.0, 2.0, 0.0),
+            1.0,
+            MaterialHandle(0),
+        ));
+        hitables.push(Sphere::new(
+            Vec3::new(0.0, -2.0, -10.0),
+            1.0,
+            MaterialHandle(0),
+        ));
+        let mut materials = MaterialStore::new();
+        materials.add_material(Sky::new(Srgb::one(), Srgb::one()));
+        let mut cameras = CameraStore::new();
+        let camera = OrthographicCamera::new(
+            Vec2::new(16.0, 16.0),
+            8.0,
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::zero(),
+            Vec3::unit_y(),
+        )
+        .with_clipping(0.01, far);
+        let camera = cameras.add_camera(Box::new(camera));
+        let world = World {
+            materials,
+            hitables,
+            lights: Vec::new(),
+            light_sampler: LightSampler::new(&[]),
+            cameras,
+        };
+
+        let mut film = Film::<U1>::new(&[ChannelKind::Depth], Extent2u::new(16, 16)).unwrap();
+        film.render_frame_into(
+            &world,
+            camera,
+            &integrator(),
+            &BlackmanHarrisFilter::new(1.5),
+            Extent2u::new(8, 8),
+            0,
+            0.0..1.0,
+            1,
+            Srgb::zero(),
+            None,
+        );
+        let depths = channel_storage_index!(film.channels, Depth, 0);
+        depths.clone()
+    }
+
+    #[test]
+    fn spheres_beyond_the_far_plane_are_clipped() {
+        let max_depth = |depths: Vec<f32>| depths.into_iter().fold(0.0, f32::max);
+        assert!(max_depth(clipped_depths(100.0)) > 13.0);
+
+        // pixels which miss are left at zero
+        let depths = clipped_depths(10.0);
+        assert!(depths.iter().any(|depth| *depth > 3.0));
+        assert!(max_depth(depths) < 5.0);
+    }
+
+    #[test]
+    fn crops_match_the_full_render() {
+        let (world, camera) = sphere_world();
+        let full = render_with_threads(&world, camera, 4, None);
+        // not aligned to the tiles
+        let crop = Aabru {
+            min: Vec2u::new(5, 3),
+            max: Vec2u::new(18, 11),
+        };
+        let cropped = render_with_threads(&world, camera, 4, Some(crop));
+
+        for (i, (full, cropped)) in full.iter().zip(&cropped).enumerate() {
+            let (x, y) = (i % 24, i / 24);
+            let inside =
+                (crop.min.x..crop.max.x).contains(&x) && (crop.min.y..crop.max.y).contains(&y);
+            if inside {
+                assert_eq!(cropped, full);
+            } else {
+                assert_eq!(*cropped, Vec3::zero());
+            }
+        }
+        assert!(cropped.iter().any(|color| color.mag_sq() > 0.0));
+    }
+
+    #[test]
+    fn non_finite_samples_are_dropped_and_counted() {
+        let mut film = Film::<U1>::new(&[ChannelKind::Color], Extent2u::new(2, 1)).unwrap();
+        let bounds = Aabru {
+            min: Vec2u::zero(),
+            max: Vec2u::new(2, 1),
+        };
+        let kinds = [ChannelKind::Color];
+        let mut tile = Tile::<U1>::new(0, 0, kinds.iter().copied(), bounds, vec![2, 2]);
+        let color = |value| ChannelSample::Color(Srgb::new(value, value, value));
+        tile.add_sample(Vec2u::new(0, 0), color(0.5));
+        tile.add_sample(Vec2u::new(0, 0), color(std::f32::NAN));
+        tile.add_sample(Vec2u::new(1, 0), color(std::f32::INFINITY));
+        tile.add_sample(Vec2u::new(1, 0), color(1.0));
+        film.tile_finished(tile);
+
+        assert_eq!(film.non_finite_samples(), 2);
+        // the dropped samples count as black
+        let colors = channel_storage_index!(film.channels, Color, 0);
+        assert_eq!(colors[0].x, 0.25);
+        assert_eq!(colors[1].x, 0.5);
+    }
+
+    #[test]
+    fn tiles_merged_from_many_threads_match_serial_accumulation() {
+        // neither dimension is a multiple of the tile size, so the edge tiles are partial
+        let (res, tile_size, samples) = (Extent2u::new(37, 23), 3, 5);
+        let sample_value = |pixel: Vec2u, k: usize| {
+            let hash = (pixel.x * 31 + pixel.y * 17 + k * 7) % 13;
+            Srgb::new(
+                hash as f32 * 0.1,
+                k as f32,
+                (pixel.x + pixel.y) as f32 * 0.01,
+            )
+        };
+
+        let kinds = [ChannelKind::Color];
+        let mut tiles = Vec::new();
+        for y in (0..res.h).step_by(tile_size) {
+            for x in (0..res.w).step_by(tile_size) {
+                let bounds = Aabru {
+                    min: Vec2u::new(x, y),
+                    max: Vec2u::new((x + tile_size).min(res.w), (y + tile_size).min(res.h)),
+                };
+                let pixels = bounds.size().w * bounds.size().h;
+                let index = tiles.len();
+                tiles.push(Tile::<U1>::new(
+                    index,
+                    0,
+                    kinds.iter().copied(),
+                    bounds,
+                    vec![samples; pixels],
+                ));
+            }
+        }
+
+        let mut film = Film::<U1>::new(&kinds, res).unwrap();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(32)
+            .build()
+            .unwrap();
+        pool.install(|| {
+            film.integrate_tiles(tiles, |tile| {
+                let (min, extent) = (tile.raster_bounds.min, tile.raster_bounds.size());
+                for k in 0..samples {
+                    for y in 0..extent.h {
+                        for x in 0..extent.w {
+                            let pixel = Vec2u::new(min.x + x, min.y + y);
+                            let sample = ChannelSample::Color(sample_value(pixel, k));
+                            tile.add_sample(Vec2u::new(x, y), sample);
+                        }
+                    }
+                }
+            })
+        });
+
+        let colors = channel_storage_index!(film.channels, Color, 0);
+        for y in 0..res.h {
+            for x in 0..res.w {
+                let pixel = Vec2u::new(x, y);
+                let mut sum = Srgb::zero();
+                for k in 0..samples {
+                    sum += sample_value(pixel, k);
+                }
+                assert_eq!(
+                    colors[x + y * res.w].0,
+                    (sum / samples as f32).0,
+                    "pixel {:?}",
+                    pixel
+                );
+            }
+        }
+    }
+
+    /// Film with the color and its even and odd halves of `samples` samples per pixel of a
+    /// diffuse sphere, which is noisy where it's lit.
+    fn half_sample_film(samples: usize) -> Film<U3> {
+        let white = WSrgb::splat(Srgb::new(0.8, 0.8, 0.8));
+        let (world, camera) = sphere_world_with(Lambertian::new(white), 3.0);
+        let kinds = [
+            ChannelKind::Color,
+            ChannelKind::ColorEven,
+            ChannelKind::ColorOdd,
+        ];
+        let mut film = Film::<U3>::new(&kinds, Extent2u::new(24, 16))
+            .unwrap()
+            .with_seed(5);
+        film.render_frame_into(
+            &world,
+            camera,
+            &integrator(),
+            &BlackmanHarrisFilter::new(1.5),
+            Extent2u::new(8, 8),
+            0,
+            0.0..1.0,
+            samples,
+            Srgb::zero(),
+            None,
+        );
+        film
+    }
+
+    #[test]
+    fn half_sample_colors_average_to_the_color() {
+        let film = half_sample_film(4);
+        let channel = |kind| film.channel_indices[&kind];
+        let colors = channel_storage_index!(film.channels, Color, channel(ChannelKind::Color));
+        let even =
+            channel_storage_index!(film.channels, ColorEven, channel(ChannelKind::ColorEven));
+        let odd = channel_storage_index!(film.channels, ColorOdd, channel(ChannelKind::ColorOdd));
+        // otherwise both halves could be the same as the color
+        assert!(even
+            .iter()
+            .zip(odd)
+            .any(|(even, odd)| (even.0 - odd.0).mag() > 1e-3));
+        for ((color, even), odd) in colors.iter().zip(even).zip(odd) {
+            let diff = (*even + *odd) * 0.5 - *color;
+            assert!([diff.x, diff.y, diff.z].iter().all(|d| d.abs() < 1e-4));
+        }
+    }
+
+    #[test]
+    fn cancelling_mid_render_skips_the_remaining_tiles() {
+        let (world, camera) = empty_world(Extent2u::new(24, 16));
+        let token = CancelToken::new();
+        let callback_token = token.clone();
+        let mut film = Film::<U1>::new(&[ChannelKind::Background], Extent2u::new(24, 16))
+            .unwrap()
+            .with_cancel_token(token.clone())
+            .with_progress_callback(move |completed, _| {
+                if completed == 2 {
+                    callback_token.cancel();
+                }
+            });
+        // one thread, so no other tile is in flight when the token is cancelled
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let background = Srgb::new(0.2, 0.4, 0.6);
+        let render = |film: &mut Film<U1>| {
+            pool.install(|| {
+                film.render_frame_into(
+                    &world,
+                    camera,
+                    &integrator(),
+                    &BlackmanHarrisFilter::new(1.5),
+                    Extent2u::new(8, 8),
+                    0,
+                    0.0..1.0,
+                    1,
+                    background,
+                    None,
+                )
+            });
+            let backgrounds = channel_storage_index!(film.channels, Background, 0);
+            backgrounds.iter().filter(|bg| bg.x > 0.0).count()
+        };
+
+        // two of the six 8x8 tiles are done before the token is cancelled
+        assert_eq!(render(&mut film), 2 * 64);
+        assert!(film.was_cancelled());
+
+        token.reset();
+        film = film.with_progress_callback(|_, _| ());
+        assert_eq!(render(&mut film), 24 * 16);
+        assert!(!film.was_cancelled());
+    }
+
+    #[test]
+    fn two_spheres_have_two_ids() {
+        let mut materials = MaterialStore::new();
+        let grey = WSrgb::splat(Srgb::new(0.5, 0.5, 0.5));
+        let left_material = materials.add_material(Lambertian::new(grey));
+        let right_material = materials.add_material(Lambertian::new(grey));
+        let mut hitables = HitableStore::new();
+        let left = hitables.push(Sphere::new(Vec3::new(-1.2, 0.0, 0.0), 1.0, left_material));
+        let right = hitables.push(Sphere::new(Vec3::new(1.2, 0.0, 0.0), 1.0, right_material));
+        let (world, camera) = empty_world(Extent2u::new(24, 16));
+        let world = World {
+            materials,
+            hitables,
+            ..world
+        };
+
+        let kinds = [ChannelKind::ObjectId, ChannelKind::MaterialId];
+        let mut film = Film::<U2>::new(&kinds, Extent2u::new(24, 16)).unwrap();
+        film.render_frame_into(
+            &world,
+            camera,
+            &integrator(),
+            &BlackmanHarrisFilter::new(1.5),
+            Extent2u::new(8, 8),
+            0,
+            0.0..1.0,
+            4,
+            Srgb::zero(),
+            None,
+        );
+
+        let ids = |buf: &[Id]| {
+            let mut ids = buf.iter().filter_map(|id| id.0).collect::<Vec<_>>();
+            ids.sort_unstable();
+            ids.dedup();
+            ids
+        };
+        let object_ids = channel_storage_index!(film.channels, ObjectId, 0);
+        let material_ids = channel_storage_index!(film.channels, MaterialId, 1);
+        assert_eq!(
+            ids(object_ids),
+            vec![world.hitables.id(left.0), world.hitables.id(right.0)]
+        );
+        assert_eq!(ids(material_ids), vec![left_material.0, right_material.0]);
+        // and the background between and around them has none
+        assert!(object_ids.contains(&Id::NONE));
+    }
+
+    #[test]
+    fn animations_save_each_frame_at_its_time() {
+        let mut materials = MaterialStore::new();
+        let black = materials.add_material(Lambertian::new(WSrgb::zero()));
+        let sky = materials.add_material(Sky::new(Srgb::one(), Srgb::one()));
+        let mut hitables = HitableStore::new();
+        hitables.push(Sphere::new(Vec3::zero(), 100.0, sky));
+        // from the left of the image at t = 0 to the right at t = 1
+        hitables.push(Sphere::new(
+            |t: f32| Vec3::new(2.0 * t - 1.0, 0.0, 0.0),
+            0.5,
+            black,
+        ));
+        let (world, camera) = empty_world(Extent2u::new(24, 16));
+        let world = World {
+            materials,
+            hitables,
+            ..world
+        };
+
+        let folder = std::env::temp_dir().join(format!("rayn_animation_{}", std::process::id()));
+        let mut film = Film::<U1>::new(&[ChannelKind::Color], Extent2u::new(24, 16)).unwrap();
+        let timing = FrameTiming {
+            frame_rate: 1.0,
+            shutter_speed: 0.01,
+        };
+        film.render_animation(
+            &world,
+            camera,
+            &integrator(),
+            &BlackmanHarrisFilter::new(1.5),
+            Extent2u::new(8, 8),
+            0..2,
+            timing,
+            2,
+            Srgb::zero(),
+            &[ChannelKind::Color],
+            &folder,
+            "moving",
+        )
+        .unwrap();
+
+        let brightness = |frame: usize, x: u32| {
+            let path = folder.join(format!("moving_{:04}_color.png", frame));
+            let image = image::open(path).unwrap().to_rgb();
+            image.get_pixel(x, 8)[0]
+        };
+        // the sphere is around x = -1 (pixel 6.7) and then x = 1 (pixel 17.3)
+        assert!(brightness(0, 7) < 50 && brightness(0, 17) > 200);
+        assert!(brightness(1, 7) > 200 && brightness(1, 17) < 50);
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn convergence_estimates_drop_with_more_samples() {
+        let estimates = [4, 16, 64]
+            .iter()
+            .map(|samples| half_sample_film(*samples).convergence().unwrap())
+            .collect::<Vec<_>>();
+        assert!(estimates[0] > 0.0);
+        for pair in estimates.windows(2) {
+            assert!(pair[1] < pair[0], "{:?}", estimates);
+        }
+    }
+
+    #[test]
+    fn sample_maps_give_pixels_their_own_sample_counts() {
+        // close enough that every sample of every pixel sees the diffuse sphere, so every
+        // camera ray adds an alpha of one
+        let white = WSrgb::splat(Srgb::new(0.8, 0.8, 0.8));
+        let (world, camera) = sphere_world_with(Lambertian::new(white), 0.8);
+        let res = Extent2u::new(24, 16);
+        let map = (0..res.w * res.h)
+            .map(|i| if i % res.w < 12 { i % 2 } else { 6 })
+            .collect::<Vec<_>>();
+        let mut film = Film::<U1>::new(&[ChannelKind::Alpha], res)
+            .unwrap()
+            .with_sample_map(map.clone())
+            .unwrap();
+        film.render_frame_into(
+            &world,
+            camera,
+            &integrator(),
+            &BlackmanHarrisFilter::new(1.5),
+            Extent2u::new(8, 8),
+            0,
+            0.0..1.0,
+            1,
+            Srgb::zero(),
+            None,
+        );
+
+        // a packet of four rays for each count, with zero still getting one packet
+        for (count, expected) in film.pixel_sample_counts.iter().zip(&map) {
+            assert_eq!(*count, 4 * (*expected).max(1));
+        }
+        // and that's how many rays were actually traced for each pixel
+        let alphas = channel_storage_index!(film.channels, Alpha, 0);
+        assert!(alphas.iter().all(|alpha| (alpha - 1.0).abs() < 1e-5));
+    }
+
+    #[test]
+    fn bounce_t_min_leaves_camera_rays_alone() {
+        let white = WSrgb::splat(Srgb::new(0.8, 0.8, 0.8));
+        let (mut world, _) = sphere_world_with(Lambertian::new(white), 0.2);
+        // just outside of the top of the unit sphere, much closer to it than the override
+        world.cameras = CameraStore::new();
+        let camera = world.cameras.add_camera(Box::new(OrthographicCamera::new(
+            Vec2::new(24.0, 16.0),
+            0.2,
+            Vec3::new(0.0, 0.0, 1.001),
+            Vec3::zero(),
+            Vec3::unit_y(),
+        )));
+
+        let res = Extent2u::new(24, 16);
+        for bounce_t_min in [None, Some(0.5)].iter() {
+            let mut film = Film::<U1>::new(&[ChannelKind::Depth], res).unwrap();
+            if let Some(t_min) = bounce_t_min {
+                film = film.with_bounce_t_min(*t_min);
+            }
+            film.render_frame_into(
+                &world,
+                camera,
+                &integrator(),
+                &BlackmanHarrisFilter::new(1.5),
+                Extent2u::new(8, 8),
+                0,
+                0.0..1.0,
+                1,
+                Srgb::zero(),
+                None,
+            );
+
+            // every pixel sees the near side of the sphere rather than the inside of its
+            // far side, about 2 away
+            let depths = channel_storage_index!(film.channels, Depth, 0);
+            assert!(depths.iter().all(|depth| *depth > 0.0 && *depth < 0.05));
+        }
+    }
 }
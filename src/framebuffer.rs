@@ -0,0 +1,166 @@
+use crate::math::Vec3;
+
+/// Linear radiance per pixel, kept as the sum of its samples along with how many there
+/// were, so that more samples can be added to it later. Pixels are indexed from the
+/// top-left.
+#[derive(Clone, Debug)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    sums: Vec<Vec3>,
+    counts: Vec<usize>,
+}
+
+#[allow(dead_code)]
+impl Framebuffer {
+    /// A framebuffer where no pixel has any samples yet.
+    pub fn new(width: usize, height: usize) -> Self {
+        Framebuffer {
+            width,
+            height,
+            sums: vec![Vec3::zero(); width * height],
+            counts: vec![0; width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    fn index(&self, x: usize, y: usize) -> Result<usize, String> {
+        if x < self.width && y < self.height {
+            Ok(x + y * self.width)
+        } else {
+            Err(format!(
+                "Pixel ({}, {}) is outside of the {}x{} framebuffer",
+                x, y, self.width, self.height
+            ))
+        }
+    }
+
+    /// Average of the samples of a pixel, or zero if it has none.
+    pub fn get_pixel(&self, x: usize, y: usize) -> Result<Vec3, String> {
+        let idx = self.index(x, y)?;
+        Ok(average(self.sums[idx], self.counts[idx]))
+    }
+
+    /// Replaces the samples of a pixel with `count` samples averaging to `radiance`.
+    pub fn set_pixel(
+        &mut self,
+        x: usize,
+        y: usize,
+        radiance: Vec3,
+        count: usize,
+    ) -> Result<(), String> {
+        let idx = self.index(x, y)?;
+        self.sums[idx] = radiance * count as f32;
+        self.counts[idx] = count;
+        Ok(())
+    }
+
+    pub fn add_sample(&mut self, x: usize, y: usize, radiance: Vec3) -> Result<(), String> {
+        let idx = self.index(x, y)?;
+        self.sums[idx] += radiance;
+        self.counts[idx] += 1;
+        Ok(())
+    }
+
+    pub fn sample_count(&self, x: usize, y: usize) -> Result<usize, String> {
+        Ok(self.counts[self.index(x, y)?])
+    }
+
+    /// Adds the samples of `other`, e.g. from another pass over the same image.
+    pub fn accumulate(&mut self, other: &Framebuffer) -> Result<(), String> {
+        if (other.width, other.height) != (self.width, self.height) {
+            return Err(format!(
+                "Can't accumulate a {}x{} framebuffer into a {}x{} one",
+                other.width, other.height, self.width, self.height
+            ));
+        }
+        for (sum, other) in self.sums.iter_mut().zip(&other.sums) {
+            *sum += *other;
+        }
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += *other;
+        }
+        Ok(())
+    }
+
+    /// `(x, y, average)` of every pixel, row by row.
+    pub fn pixels(&self) -> impl Iterator<Item = (usize, usize, Vec3)> + '_ {
+        let width = self.width;
+        self.sums
+            .iter()
+            .zip(self.counts.iter())
+            .enumerate()
+            .map(move |(idx, (sum, count))| (idx % width, idx / width, average(*sum, *count)))
+    }
+
+    /// The averages of all pixels, row by row.
+    pub fn averaged(&self) -> Vec<Vec3> {
+        self.pixels().map(|(_, _, radiance)| radiance).collect()
+    }
+}
+
+fn average(sum: Vec3, count: usize) -> Vec3 {
+    if count > 0 {
+        sum / count as f32
+    } else {
+        Vec3::zero()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_of_bounds_pixels_are_errors() {
+        let mut framebuffer = Framebuffer::new(3, 2);
+        assert!(framebuffer.get_pixel(2, 1).is_ok());
+        assert!(framebuffer.get_pixel(3, 0).is_err());
+        assert!(framebuffer.get_pixel(0, 2).is_err());
+        assert!(framebuffer.set_pixel(3, 1, Vec3::zero(), 1).is_err());
+        assert!(framebuffer.add_sample(0, 2, Vec3::zero()).is_err());
+        assert!(framebuffer.sample_count(5, 5).is_err());
+        assert!(framebuffer.accumulate(&Framebuffer::new(2, 3)).is_err());
+    }
+
+    #[test]
+    fn pixels_average_their_samples() {
+        let mut framebuffer = Framebuffer::new(2, 2);
+        framebuffer
+            .add_sample(1, 0, Vec3::new(1.0, 2.0, 3.0))
+            .unwrap();
+        framebuffer
+            .add_sample(1, 0, Vec3::new(3.0, 2.0, 1.0))
+            .unwrap();
+        framebuffer
+            .set_pixel(0, 1, Vec3::broadcast(0.5), 4)
+            .unwrap();
+
+        assert_eq!(framebuffer.get_pixel(1, 0).unwrap(), Vec3::broadcast(2.0));
+        assert_eq!(framebuffer.sample_count(1, 0).unwrap(), 2);
+        // pixels without samples are black rather than NaN
+        assert_eq!(framebuffer.get_pixel(0, 0).unwrap(), Vec3::zero());
+
+        // another pass weighs each pixel by how many samples it had
+        let mut other = Framebuffer::new(2, 2);
+        other.add_sample(0, 1, Vec3::broadcast(3.0)).unwrap();
+        framebuffer.accumulate(&other).unwrap();
+        assert_eq!(framebuffer.get_pixel(0, 1).unwrap(), Vec3::broadcast(1.0));
+        assert_eq!(framebuffer.sample_count(0, 1).unwrap(), 5);
+
+        let averaged = framebuffer.averaged();
+        let expected = [0.0, 2.0, 1.0, 0.0];
+        for (pixel, (x, y, radiance)) in framebuffer.pixels().enumerate() {
+            assert_eq!((x, y), (pixel % 2, pixel / 2));
+            assert_eq!(radiance, averaged[pixel]);
+            assert_eq!(radiance, Vec3::broadcast(expected[pixel]));
+        }
+    }
+}
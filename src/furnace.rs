@@ -0,0 +1,138 @@
+//! White furnace checks for materials: a surface lit by a uniform environment of radiance 1
+//! must never reflect more than 1, and a lossless one must reflect exactly 1.
+
+use bumpalo::Bump;
+
+use rand::prelude::*;
+
+use crate::hitable::{WHit, WHitAttributes, WShadingPoint};
+use crate::material::{Dielectric, Lambertian, Material, OrenNayar};
+use crate::math::{f32x4, Vec2u, Vec3, Wec3};
+use crate::ray::WRay;
+use crate::spectrum::{Srgb, WSrgb};
+
+/// Cosines of the viewing angles that materials are checked at, from head-on to grazing.
+const CHECKED_COS_THETAS: [f32; 5] = [1.0, 0.75, 0.5, 0.25, 0.1];
+
+/// Estimates the directional albedo of `material` by importance sampling its BSDF `sample_count`
+/// times, viewed from `cos_theta_o` off of the normal. This is the radiance the material
+/// reflects when lit by a uniform environment of radiance 1.
+#[allow(dead_code)]
+pub fn directional_albedo(material: &dyn Material, cos_theta_o: f32, sample_count: usize) -> Srgb {
+    let mut rng = SmallRng::seed_from_u64(0);
+
+    let normal = Wec3::splat(Vec3::unit_z());
+    let sin_theta_o = (1.0 - cos_theta_o * cos_theta_o).max(0.0).sqrt();
+    let wo = Wec3::splat(Vec3::new(sin_theta_o, 0.0, cos_theta_o));
+
+    let ray = WRay::new(
+        wo,
+        -wo,
+        f32x4::ZERO,
+        [Vec2u::zero(); 4],
+        [true; 4],
+        [0.0; 4],
+        [0; 4],
+    );
+    let hit = WHit {
+        ray,
+        t: f32x4::ONE,
+        attributes: WHitAttributes::none(),
+    };
+    let intersection = WShadingPoint::new(hit, Wec3::zero(), f32x4::ZERO, normal);
+
+    let mut bump = Bump::new();
+    let mut total = WSrgb::zero();
+    let wide_samples = (sample_count + 3) / 4;
+    for _ in 0..wide_samples {
+        bump.reset();
+        let bsdf = material.get_bsdf_at(&intersection, &bump);
+
+        let samples_1d = f32x4::from(rng.gen::<[f32; 4]>());
+        let samples_2d = [
+            f32x4::from(rng.gen::<[f32; 4]>()),
+            f32x4::from(rng.gen::<[f32; 4]>()),
+            f32x4::from(rng.gen::<[f32; 4]>()),
+            f32x4::from(rng.gen::<[f32; 4]>()),
+        ];
+
+        if let Some(se) = bsdf.scatter(wo, &intersection, samples_1d, &samples_2d) {
+            let ndl = se.wi.dot(normal).abs();
+            let valid = se.pdf.cmp_gt(f32x4::ZERO);
+            total += WSrgb::merge(valid, se.f * ndl / se.pdf, WSrgb::zero());
+        }
+    }
+
+    let lanes: [Srgb; 4] = total.into();
+    lanes.iter().copied().sum::<Srgb>() / (wide_samples * 4) as f32
+}
+
+/// Checks that `material` reflects at most 1 (plus `tolerance`) in the white furnace at a
+/// range of viewing angles. If `lossless`, it must also reflect at least 1 - `tolerance`.
+#[allow(dead_code)]
+pub fn check_energy_conservation(
+    name: &str,
+    material: &dyn Material,
+    lossless: bool,
+    sample_count: usize,
+    tolerance: f32,
+) -> Result<(), String> {
+    for &cos_theta_o in CHECKED_COS_THETAS.iter() {
+        let albedo = directional_albedo(material, cos_theta_o, sample_count);
+        let channels = [albedo.x, albedo.y, albedo.z];
+        if albedo.is_nan() || channels.iter().any(|c| *c > 1.0 + tolerance) {
+            return Err(format!(
+                "{} gains energy at cos theta {}: albedo {:?}",
+                name, cos_theta_o, albedo
+            ));
+        }
+        if lossless && channels.iter().any(|c| *c < 1.0 - tolerance) {
+            return Err(format!(
+                "{} loses energy at cos theta {}: albedo {:?}",
+                name, cos_theta_o, albedo
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Runs the white furnace over the built-in materials with a white albedo.
+#[allow(dead_code)]
+pub fn check_builtin_materials(sample_count: usize, tolerance: f32) -> Result<(), String> {
+    let white = WSrgb::splat(Srgb::one());
+
+    check_energy_conservation(
+        "Lambertian",
+        &Lambertian::new(white),
+        true,
+        sample_count,
+        tolerance,
+    )?;
+    check_energy_conservation(
+        "OrenNayar",
+        &OrenNayar::new(white, f32x4::from(0.5)),
+        false,
+        sample_count,
+        tolerance,
+    )?;
+    for &roughness in [0.0, 0.5, 1.0].iter() {
+        check_energy_conservation(
+            &format!("Dielectric (roughness {})", roughness),
+            &Dielectric::new_remap(Srgb::one(), roughness),
+            false,
+            sample_count,
+            tolerance,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_materials_conserve_energy() {
+        assert_eq!(check_builtin_materials(1 << 14, 0.05), Ok(()));
+    }
+}
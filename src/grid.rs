@@ -0,0 +1,227 @@
+use bumpalo::collections::Vec as BumpVec;
+
+use crate::hitable::Hitable;
+use crate::math::{f32x4, Aabb, Vec3};
+use crate::ray::WRay;
+
+/// Most cells along any one axis of a grid.
+const MAX_RESOLUTION: usize = 128;
+
+/// Acceleration structure bucketing objects into the voxels of a regular grid over their
+/// bounds. It's cheap to build and works well when objects are spread out evenly, like
+/// particles. Objects without finite bounds are always tested.
+pub struct UniformGrid {
+    bounds: Aabb,
+    resolution: [usize; 3],
+    cell_size: Vec3,
+    // indices of the hitables overlapping each cell, x varying fastest
+    cells: Vec<Vec<usize>>,
+    unbounded: Vec<usize>,
+}
+
+impl UniformGrid {
+    /// Builds a grid over `hitables` as they are from `t0` to `t1`, with about three cells
+    /// per object along the cube root of the object count on the longest axis.
+    pub fn new(hitables: &[Box<dyn Hitable>], t0: f32, t1: f32) -> Self {
+        Self::from_bounds(hitables.iter().map(|hitable| hitable.bounding_box(t0, t1)))
+    }
+
+    /// Builds a grid over objects with the given bounds, which are referred to by their
+    /// position in `object_bounds`.
+    pub fn from_bounds<I: IntoIterator<Item = Option<Aabb>>>(object_bounds: I) -> Self {
+        let mut unbounded = Vec::new();
+        let mut bounded = Vec::new();
+        for (i, bounds) in object_bounds.into_iter().enumerate() {
+            match bounds.filter(Aabb::is_finite) {
+                Some(bounds) => bounded.push((i, bounds)),
+                None => unbounded.push(i),
+            }
+        }
+
+        let bounds = bounded
+            .iter()
+            .map(|(_, bounds)| *bounds)
+            .fold(None, |acc: Option<Aabb>, bounds| match acc {
+                Some(acc) => Some(acc.union(&bounds)),
+                None => Some(bounds),
+            })
+            .unwrap_or_else(|| Aabb::new(Vec3::zero(), Vec3::zero()));
+
+        let size = bounds.size();
+        let max_extent = size.component_max().max(std::f32::MIN_POSITIVE);
+        let cells_on_longest = 3.0 * (bounded.len() as f32).cbrt();
+        let axis_resolution = |extent: f32| {
+            ((cells_on_longest * extent / max_extent).round() as usize)
+                .max(1)
+                .min(MAX_RESOLUTION)
+        };
+        let resolution = [
+            axis_resolution(size.x),
+            axis_resolution(size.y),
+            axis_resolution(size.z),
+        ];
+        let cell_size = Vec3::new(
+            size.x / resolution[0] as f32,
+            size.y / resolution[1] as f32,
+            size.z / resolution[2] as f32,
+        );
+
+        let mut grid = UniformGrid {
+            bounds,
+            resolution,
+            cell_size,
+            cells: vec![Vec::new(); resolution[0] * resolution[1] * resolution[2]],
+            unbounded,
+        };
+
+        for (i, object_bounds) in bounded {
+            let min = grid.cell_of(object_bounds.min);
+            let max = grid.cell_of(object_bounds.max);
+            for z in min[2]..=max[2] {
+                for y in min[1]..=max[1] {
+                    for x in min[0]..=max[0] {
+                        let idx = grid.cell_index([x, y, z]);
+                        grid.cells[idx].push(i);
+                    }
+                }
+            }
+        }
+
+        grid
+    }
+
+    /// Cell containing `point`, clamped to the grid.
+    fn cell_of(&self, point: Vec3) -> [usize; 3] {
+        let local = point - self.bounds.min;
+        let axis = |coord: f32, size: f32, res: usize| {
+            if size > 0.0 {
+                ((coord / size).max(0.0) as usize).min(res - 1)
+            } else {
+                0
+            }
+        };
+        [
+            axis(local.x, self.cell_size.x, self.resolution[0]),
+            axis(local.y, self.cell_size.y, self.resolution[1]),
+            axis(local.z, self.cell_size.z, self.resolution[2]),
+        ]
+    }
+
+    fn cell_index(&self, cell: [usize; 3]) -> usize {
+        cell[0] + self.resolution[0] * (cell[1] + self.resolution[1] * cell[2])
+    }
+
+    /// Replaces the contents of `candidates` with the sorted indices of the objects which
+    /// any valid lane of `ray` could hit within `t_range`, without duplicates.
+    pub fn candidates(
+        &self,
+        ray: &WRay,
+        t_range: std::ops::Range<f32x4>,
+        candidates: &mut BumpVec<usize>,
+    ) {
+        candidates.clear();
+        self.visit_candidates(ray, t_range, |index| candidates.push(index));
+        candidates.sort_unstable();
+        candidates.dedup();
+    }
+
+    /// Calls `visit` with the index of every object which any valid lane of `ray` could hit
+    /// within `t_range`, found by walking each lane through the cells it crosses. Objects
+    /// overlapping several of those cells are visited more than once, so this is for tests
+    /// which don't mind repeats, like finding the closest hit.
+    pub fn visit_candidates<F: FnMut(usize)>(
+        &self,
+        ray: &WRay,
+        t_range: std::ops::Range<f32x4>,
+        mut visit: F,
+    ) {
+        self.unbounded.iter().for_each(|index| visit(*index));
+        let origins: [Vec3; 4] = ray.origin.into();
+        let dirs: [Vec3; 4] = ray.dir.into();
+        let t_starts = t_range.start.as_ref();
+        let t_ends = t_range.end.as_ref();
+        let lanes = origins.iter().zip(&dirs).zip(t_starts.iter().zip(t_ends));
+        for (valid, ((origin, dir), (t_start, t_end))) in ray.valid.iter().zip(lanes) {
+            if *valid {
+                self.walk(*origin, *dir, *t_start, *t_end, |cell| {
+                    self.cells[cell].iter().for_each(|index| visit(*index))
+                });
+            }
+        }
+    }
+
+    /// 3D-DDA through the cells the ray crosses between `t_start` and `t_end`, in order.
+    fn walk<F: FnMut(usize)>(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        t_start: f32,
+        t_end: f32,
+        mut visit: F,
+    ) {
+        let o = [origin.x, origin.y, origin.z];
+        let d = [dir.x, dir.y, dir.z];
+        let lo = [self.bounds.min.x, self.bounds.min.y, self.bounds.min.z];
+        let hi = [self.bounds.max.x, self.bounds.max.y, self.bounds.max.z];
+
+        let mut t_enter = t_start;
+        let mut t_exit = t_end;
+        for (((o, d), lo), hi) in o.iter().zip(&d).zip(&lo).zip(&hi) {
+            if *d == 0.0 {
+                if o < lo || o > hi {
+                    return;
+                }
+                continue;
+            }
+            let (t0, t1) = ((lo - o) / d, (hi - o) / d);
+            t_enter = t_enter.max(t0.min(t1));
+            t_exit = t_exit.min(t0.max(t1));
+        }
+        if t_enter > t_exit {
+            return;
+        }
+
+        let mut cell = self.cell_of(origin + dir * t_enter);
+        let cell_size = [self.cell_size.x, self.cell_size.y, self.cell_size.z];
+        // (step, distance between cell boundaries, distance to the next boundary)
+        let axis = |a: usize| {
+            let boundary = |c: usize| lo[a] + c as f32 * cell_size[a];
+            if d[a] > 0.0 {
+                (
+                    1,
+                    cell_size[a] / d[a],
+                    (boundary(cell[a] + 1) - o[a]) / d[a],
+                )
+            } else if d[a] < 0.0 {
+                (-1, -cell_size[a] / d[a], (boundary(cell[a]) - o[a]) / d[a])
+            } else {
+                (0, std::f32::MAX, std::f32::MAX)
+            }
+        };
+        let axes = [axis(0), axis(1), axis(2)];
+        let step = [axes[0].0, axes[1].0, axes[2].0];
+        let delta = [axes[0].1, axes[1].1, axes[2].1];
+        let mut next = [axes[0].2, axes[1].2, axes[2].2];
+
+        loop {
+            visit(self.cell_index(cell));
+
+            let a = if next[0] < next[1] && next[0] < next[2] {
+                0
+            } else if next[1] < next[2] {
+                1
+            } else {
+                2
+            };
+            if next[a] > t_exit {
+                return;
+            }
+            let stepped = cell[a] as isize + step[a];
+            if stepped < 0 || stepped >= self.resolution[a] as isize {
+                return;
+            }
+            cell[a] = stepped as usize;
+            next[a] += delta[a];
+        }
+    }
+}
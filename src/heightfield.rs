@@ -0,0 +1,502 @@
+use crate::camera::Camera;
+use crate::hitable::{
+    intersection_error_bound, Hitable, WHit, WHitAttributes, WShadingPoint, SHADOW_T_MIN,
+};
+use crate::material::MaterialHandle;
+use crate::math::{f32x4, gamma, Aabb, Mask, Vec2, Vec2u, Vec3, Wec2, Wec3};
+use crate::ray::{Ray, WRay};
+
+/// Terrain made of a regular grid of height samples, lying in the xz plane with y up. Each
+/// cell between four samples is split into two triangles.
+pub struct Heightfield {
+    heights: Vec<f32>,
+    vertex_normals: Vec<Vec3>,
+    resolution: Vec2u,
+    origin: Vec3,
+    cell_size: Vec2,
+    bounds_min: Vec3,
+    bounds_max: Vec3,
+    cull_backfaces: bool,
+    material: MaterialHandle,
+}
+
+impl Heightfield {
+    /// `heights` holds `resolution.x * resolution.y` samples, row by row along x. The
+    /// sample at (0, 0) sits at `origin` (plus its height) and the grid spans `size` in
+    /// the x and z directions.
+    #[allow(dead_code)]
+    pub fn new(
+        heights: Vec<f32>,
+        resolution: Vec2u,
+        origin: Vec3,
+        size: Vec2,
+        material: MaterialHandle,
+    ) -> Result<Self, String> {
+        if resolution.x < 2 || resolution.y < 2 {
+            return Err(format!(
+                "Heightfield needs at least 2x2 samples, got {}x{}",
+                resolution.x, resolution.y
+            ));
+        }
+        if heights.len() != resolution.x * resolution.y {
+            return Err(format!(
+                "Heightfield of {}x{} samples given {} heights",
+                resolution.x,
+                resolution.y,
+                heights.len()
+            ));
+        }
+
+        let cell_size = Vec2::new(
+            size.x / (resolution.x - 1) as f32,
+            size.y / (resolution.y - 1) as f32,
+        );
+
+        let (min_height, max_height) = heights
+            .iter()
+            .fold((std::f32::MAX, std::f32::MIN), |(min, max), h| {
+                (min.min(*h), max.max(*h))
+            });
+
+        let mut heightfield = Self {
+            heights,
+            vertex_normals: Vec::new(),
+            resolution,
+            origin,
+            cell_size,
+            bounds_min: Vec3::new(origin.x, origin.y + min_height, origin.z),
+            bounds_max: Vec3::new(origin.x + size.x, origin.y + max_height, origin.z + size.y),
+            cull_backfaces: false,
+            material,
+        };
+        heightfield.vertex_normals = heightfield.compute_vertex_normals();
+        Ok(heightfield)
+    }
+
+    /// Makes camera and bounce rays go through the undersides of the triangles, which saves
+    /// shading them when they can't be seen anyway. Shadow rays still hit them, so the
+    /// terrain keeps casting shadows from below. Off by default.
+    #[allow(dead_code)]
+    pub fn with_backface_culling(mut self, cull: bool) -> Self {
+        self.cull_backfaces = cull;
+        self
+    }
+
+    fn height(&self, x: usize, z: usize) -> f32 {
+        self.heights[x + z * self.resolution.x]
+    }
+
+    fn vertex(&self, x: usize, z: usize) -> Vec3 {
+        Vec3::new(
+            self.origin.x + x as f32 * self.cell_size.x,
+            self.origin.y + self.height(x, z),
+            self.origin.z + z as f32 * self.cell_size.y,
+        )
+    }
+
+    /// Normals from central differences of the neighboring heights (one-sided at the edges).
+    fn compute_vertex_normals(&self) -> Vec<Vec3> {
+        let mut normals = Vec::with_capacity(self.heights.len());
+        for z in 0..self.resolution.y {
+            for x in 0..self.resolution.x {
+                let (x0, x1) = (x.saturating_sub(1), (x + 1).min(self.resolution.x - 1));
+                let (z0, z1) = (z.saturating_sub(1), (z + 1).min(self.resolution.y - 1));
+                let dhdx = (self.height(x1, z) - self.height(x0, z))
+                    / ((x1 - x0) as f32 * self.cell_size.x);
+                let dhdz = (self.height(x, z1) - self.height(x, z0))
+                    / ((z1 - z0) as f32 * self.cell_size.y);
+                normals.push(Vec3::new(-dhdx, 1.0, -dhdz).normalized());
+            }
+        }
+        normals
+    }
+
+    /// Intersects a single ray, returning the distance, the index of the cell hit and the
+    /// position of the hit inside that cell in 0..1. With `cull`, triangles facing away from
+    /// the ray are skipped.
+    fn hit_one(&self, ray: &Ray, t_min: f32, t_max: f32, cull: bool) -> Option<(f32, usize, Vec2)> {
+        let (t_enter, t_exit) = slab_test(ray, self.bounds_min, self.bounds_max)?;
+        let t_start = t_enter.max(t_min);
+        let t_end = t_exit.min(t_max);
+        if t_start > t_end {
+            return None;
+        }
+
+        let cells = Vec2u::new(self.resolution.x - 1, self.resolution.y - 1);
+        let start = ray.origin + ray.dir * t_start;
+        let grid_x = (start.x - self.origin.x) / self.cell_size.x;
+        let grid_z = (start.z - self.origin.z) / self.cell_size.y;
+        let mut cell_x = (grid_x.floor().max(0.0) as usize).min(cells.x - 1);
+        let mut cell_z = (grid_z.floor().max(0.0) as usize).min(cells.y - 1);
+
+        // Amanatides & Woo traversal of the cells in the xz plane
+        let (step_x, delta_x, mut next_x) = dda_axis(ray.dir.x, grid_x, cell_x, self.cell_size.x);
+        let (step_z, delta_z, mut next_z) = dda_axis(ray.dir.z, grid_z, cell_z, self.cell_size.y);
+        next_x += t_start;
+        next_z += t_start;
+
+        loop {
+            if let Some((t, uv)) = self.hit_cell(ray, cell_x, cell_z, t_min, t_max, cull) {
+                return Some((t, cell_x + cell_z * cells.x, uv));
+            }
+
+            if next_x < next_z {
+                if next_x > t_end {
+                    return None;
+                }
+                match step_cell(cell_x, step_x, cells.x) {
+                    Some(x) => cell_x = x,
+                    None => return None,
+                }
+                next_x += delta_x;
+            } else {
+                if next_z > t_end {
+                    return None;
+                }
+                match step_cell(cell_z, step_z, cells.y) {
+                    Some(z) => cell_z = z,
+                    None => return None,
+                }
+                next_z += delta_z;
+            }
+        }
+    }
+
+    fn hit_cell(
+        &self,
+        ray: &Ray,
+        x: usize,
+        z: usize,
+        t_min: f32,
+        t_max: f32,
+        cull: bool,
+    ) -> Option<(f32, Vec2)> {
+        let v00 = self.vertex(x, z);
+        let v10 = self.vertex(x + 1, z);
+        let v01 = self.vertex(x, z + 1);
+        let v11 = self.vertex(x + 1, z + 1);
+
+        let t_a = intersect_triangle(ray, v00, v10, v11, t_min, t_max, cull);
+        let t_max = t_max.min(t_a.unwrap_or(t_max));
+        let t_b = intersect_triangle(ray, v00, v11, v01, t_min, t_max, cull);
+
+        let t = match (t_a, t_b) {
+            (_, Some(t)) | (Some(t), None) => t,
+            (None, None) => return None,
+        };
+
+        let point = ray.origin + ray.dir * t;
+        let uv = Vec2::new(
+            ((point.x - v00.x) / self.cell_size.x).max(0.0).min(1.0),
+            ((point.z - v00.z) / self.cell_size.y).max(0.0).min(1.0),
+        );
+        Some((t, uv))
+    }
+
+    /// Normal of the triangle of cell `cell` which `uv` falls in.
+    fn face_normal_at(&self, cell: usize, uv: Vec2) -> Vec3 {
+        let cells_x = self.resolution.x - 1;
+        let (x, z) = (cell % cells_x, cell / cells_x);
+        let v00 = self.vertex(x, z);
+        let v11 = self.vertex(x + 1, z + 1);
+        // same split as in `hit_cell`
+        let normal = if uv.x >= uv.y {
+            (v11 - v00).cross(self.vertex(x + 1, z) - v00)
+        } else {
+            (self.vertex(x, z + 1) - v00).cross(v11 - v00)
+        };
+        normal.normalized()
+    }
+
+    /// Bilinearly interpolated vertex normal at `uv` inside cell `cell`.
+    fn normal_at(&self, cell: usize, uv: Vec2) -> Vec3 {
+        let cells_x = self.resolution.x - 1;
+        let (x, z) = (cell % cells_x, cell / cells_x);
+        let idx = |x: usize, z: usize| x + z * self.resolution.x;
+        let n00 = self.vertex_normals[idx(x, z)];
+        let n10 = self.vertex_normals[idx(x + 1, z)];
+        let n01 = self.vertex_normals[idx(x, z + 1)];
+        let n11 = self.vertex_normals[idx(x + 1, z + 1)];
+        let n0 = n00 * (1.0 - uv.x) + n10 * uv.x;
+        let n1 = n01 * (1.0 - uv.x) + n11 * uv.x;
+        (n0 * (1.0 - uv.y) + n1 * uv.y).normalized()
+    }
+}
+
+impl Hitable for Heightfield {
+    fn hit(&self, rays: &WRay, t_ranges: ::std::ops::Range<f32x4>) -> f32x4 {
+        self.hit_with_attributes(rays, t_ranges).0
+    }
+
+    fn hit_with_attributes(
+        &self,
+        rays: &WRay,
+        t_ranges: ::std::ops::Range<f32x4>,
+    ) -> (f32x4, WHitAttributes) {
+        let mut ts = [std::f32::MAX; 4];
+        let mut uvs = [Vec2::zero(); 4];
+        let mut prim_ids = [0; 4];
+
+        // the whole packet can usually be rejected before splitting it into scalar rays
+        let bounds = Aabb::new(self.bounds_min, self.bounds_max);
+        let in_bounds = bounds.hit_wide(rays, t_ranges.clone()) & rays.valid_mask();
+        if in_bounds.none() {
+            return (
+                f32x4::from(ts),
+                WHitAttributes {
+                    uv: Wec2::from(uvs),
+                    prim_id: prim_ids,
+                },
+            );
+        }
+
+        let scalar_rays: [Ray; 4] = (*rays).into();
+
+        for (i, ray) in scalar_rays.iter().enumerate() {
+            if !ray.valid {
+                continue;
+            }
+            let t_min = t_ranges.start.as_ref()[i];
+            let t_max = t_ranges.end.as_ref()[i];
+            if let Some((t, cell, uv)) = self.hit_one(ray, t_min, t_max, self.cull_backfaces) {
+                ts[i] = t;
+                uvs[i] = uv;
+                prim_ids[i] = cell;
+            }
+        }
+
+        (
+            f32x4::from(ts),
+            WHitAttributes {
+                uv: Wec2::from(uvs),
+                prim_id: prim_ids,
+            },
+        )
+    }
+
+    fn occluded(&self, start: Wec3, end: Wec3, time: f32x4) -> f32x4 {
+        let starts: [Vec3; 4] = start.into();
+        let ends: [Vec3; 4] = end.into();
+        let times = time.as_ref();
+        let mut visible = [1.0; 4];
+
+        for (i, (start, end)) in starts.iter().zip(ends.iter()).enumerate() {
+            let dir = *end - *start;
+            let dist = dir.mag();
+            let ray = Ray::new(*start, dir / dist, times[i], Vec2u::zero(), 0.0, 0);
+            // same as for spheres, hits this close to the start are the surface it's on
+            let t_min = (2.0 * gamma(7) * start.x.abs().max(start.y.abs()).max(start.z.abs()))
+                .max(SHADOW_T_MIN);
+            if self.hit_one(&ray, t_min, dist, false).is_some() {
+                visible[i] = 0.0;
+            }
+        }
+
+        f32x4::from(visible)
+    }
+
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<Aabb> {
+        Some(Aabb::new(self.bounds_min, self.bounds_max))
+    }
+
+    fn get_shading_info(
+        &self,
+        hit: WHit,
+        primary: bool,
+        camera: &dyn Camera,
+    ) -> (MaterialHandle, WShadingPoint) {
+        let point = hit.point();
+        let offset_by = intersection_error_bound(&hit, primary, camera);
+
+        let uvs: [Vec2; 4] = hit.attributes.uv.into();
+        let mut normals = [Vec3::unit_y(); 4];
+        let mut face_normals = [Vec3::unit_y(); 4];
+        for (i, (normal, face_normal)) in normals.iter_mut().zip(&mut face_normals).enumerate() {
+            if hit.ray.valid[i] {
+                *normal = self.normal_at(hit.attributes.prim_id[i], uvs[i]);
+                *face_normal = self.face_normal_at(hit.attributes.prim_id[i], uvs[i]);
+            }
+        }
+
+        let shading_point = WShadingPoint::new(hit, point, offset_by, Wec3::from(face_normals))
+            .with_shading_normal(Wec3::from(normals));
+        (self.material, shading_point)
+    }
+}
+
+/// Distances along the ray at which it enters and exits the box, if it intersects it.
+fn slab_test(ray: &Ray, min: Vec3, max: Vec3) -> Option<(f32, f32)> {
+    let mut t_enter = std::f32::MIN;
+    let mut t_exit = std::f32::MAX;
+    for axis in 0..3 {
+        let (o, d, inv_d, lo, hi) = match axis {
+            0 => (ray.origin.x, ray.dir.x, ray.inv_dir.x, min.x, max.x),
+            1 => (ray.origin.y, ray.dir.y, ray.inv_dir.y, min.y, max.y),
+            _ => (ray.origin.z, ray.dir.z, ray.inv_dir.z, min.z, max.z),
+        };
+        if d == 0.0 {
+            if o < lo || o > hi {
+                return None;
+            }
+            continue;
+        }
+        let (near, far) = if ray.dir_is_neg[axis] {
+            (hi, lo)
+        } else {
+            (lo, hi)
+        };
+        let (t0, t1) = ((near - o) * inv_d, (far - o) * inv_d);
+        t_enter = t_enter.max(t0);
+        t_exit = t_exit.min(t1);
+    }
+    if t_enter <= t_exit {
+        Some((t_enter, t_exit))
+    } else {
+        None
+    }
+}
+
+/// (step direction, distance between cell boundaries along the ray, distance from the
+/// start point to the first boundary) for one grid axis.
+fn dda_axis(dir: f32, grid_coord: f32, cell: usize, cell_size: f32) -> (isize, f32, f32) {
+    if dir > 0.0 {
+        let delta = cell_size / dir;
+        (1, delta, ((cell + 1) as f32 - grid_coord) * delta)
+    } else if dir < 0.0 {
+        let delta = -cell_size / dir;
+        (-1, delta, (grid_coord - cell as f32) * delta)
+    } else {
+        (0, std::f32::MAX, std::f32::MAX)
+    }
+}
+
+fn step_cell(cell: usize, step: isize, cells: usize) -> Option<usize> {
+    let next = cell as isize + step;
+    if next < 0 || next >= cells as isize {
+        None
+    } else {
+        Some(next as usize)
+    }
+}
+
+/// Möller-Trumbore, hitting both sides of the triangle unless `cull` is set. Its front is
+/// the side that `(v2 - v0) x (v1 - v0)` points to.
+fn intersect_triangle(
+    ray: &Ray,
+    v0: Vec3,
+    v1: Vec3,
+    v2: Vec3,
+    t_min: f32,
+    t_max: f32,
+    cull: bool,
+) -> Option<f32> {
+    let e1 = v1 - v0;
+    let e2 = v2 - v0;
+    let p = ray.dir.cross(e2);
+    // the ray direction dotted with the front facing normal
+    let det = e1.dot(p);
+    if det.abs() < std::f32::EPSILON * e1.mag() * e2.mag() || (cull && det > 0.0) {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+
+    let s = ray.origin - v0;
+    let u = s.dot(p) * inv_det;
+    if u < 0.0 || u > 1.0 {
+        return None;
+    }
+    let q = s.cross(e1);
+    let v = ray.dir.dot(q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = e2.dot(q) * inv_det;
+    if t > t_min && t < t_max {
+        Some(t)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4x4 heightfield at height 0.5 over the square from -1 to 1 in x and z.
+    fn flat() -> Heightfield {
+        Heightfield::new(
+            vec![0.5; 16],
+            Vec2u::new(4, 4),
+            Vec3::new(-1.0, 0.0, -1.0),
+            Vec2::new(2.0, 2.0),
+            MaterialHandle(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn flat_heightfields_match_a_plane() {
+        let heightfield = flat();
+        let range = f32x4::ZERO..f32x4::from(std::f32::MAX);
+        let rays = [
+            (Vec3::new(0.1, 2.0, 0.2), Vec3::new(0.1, -1.0, -0.3)),
+            (Vec3::new(-0.9, 1.0, 0.9), -Vec3::unit_y()),
+            (Vec3::new(-3.0, 1.5, 0.0), Vec3::new(1.0, -0.4, 0.1)),
+            // from below
+            (Vec3::new(0.3, -1.0, -0.4), Vec3::new(-0.2, 1.0, 0.3)),
+        ];
+        for (origin, dir) in rays.iter() {
+            let dir = dir.normalized();
+            let plane_t = (0.5 - origin.y) / dir.y;
+            let t = heightfield.hit(&WRay::along(*origin, dir), range.clone());
+            assert!((t.as_ref()[0] - plane_t).abs() < 1e-4);
+
+            let (start, end) = (Wec3::splat(*origin), Wec3::splat(*origin + dir * 10.0));
+            assert_eq!(
+                heightfield.occluded(start, end, f32x4::ZERO).as_ref()[0],
+                0.0
+            );
+        }
+
+        // past the edge of the grid, and parallel above it
+        let beside = WRay::along(Vec3::new(1.5, 1.0, 0.0), -Vec3::unit_y());
+        assert_eq!(
+            heightfield.hit(&beside, range.clone()).as_ref()[0],
+            std::f32::MAX
+        );
+        let above = WRay::along(Vec3::new(-2.0, 0.75, 0.0), Vec3::unit_x());
+        assert_eq!(heightfield.hit(&above, range).as_ref()[0], std::f32::MAX);
+    }
+
+    #[test]
+    fn carried_attributes_match_the_hit_point() {
+        let heights = (0..16).map(|i| (i as f32 * 0.7).sin() * 0.3).collect();
+        let origin = Vec3::new(-1.0, 0.0, -1.0);
+        let heightfield = Heightfield::new(
+            heights,
+            Vec2u::new(4, 4),
+            origin,
+            Vec2::new(2.0, 2.0),
+            MaterialHandle(0),
+        )
+        .unwrap();
+        let cell_size = 2.0 / 3.0;
+        let range = f32x4::ZERO..f32x4::from(std::f32::MAX);
+
+        // straight down, so the hits are right below the origins, none of them on cell edges
+        for i in 0..15 {
+            let above = Vec3::new(-0.95 + 0.13 * i as f32, 2.0, -0.9 + 0.29 * (i % 6) as f32);
+            let ray = WRay::along(above, -Vec3::unit_y());
+            let (t, attributes) = heightfield.hit_with_attributes(&ray, range.clone());
+            let point = above - Vec3::unit_y() * t.as_ref()[0];
+
+            let grid = Vec2::new(point.x - origin.x, point.z - origin.z) / cell_size;
+            let cell = grid.x.floor() as usize + 3 * grid.z.floor() as usize;
+            let uv = Vec2::new(grid.x.fract(), grid.y.fract());
+            let carried: [Vec2; 4] = attributes.uv.into();
+            assert_eq!(attributes.prim_id[0], cell);
+            assert!((carried[0] - uv).mag() < 1e-4);
+        }
+    }
+}
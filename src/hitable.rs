@@ -1,18 +1,63 @@
+use crate::bvh::Bvh;
 use crate::camera::Camera;
+use crate::grid::UniformGrid;
 use crate::material::MaterialHandle;
-use crate::math::{f32x4, OrthonormalBasis, Wat3, Wec3};
-use crate::ray::{Ray, WRay};
+use crate::math::{
+    f32x4, gamma, Aabb, Mask, OrthonormalBasis, Vec2, Vec2u, Vec3, Wat3, Wec2, Wec3, LANES,
+};
+use crate::ray::{Ray, RayKind, WRay, WRayDifferential};
+use crate::spectrum::{Srgb, WSrgb};
 
 use bumpalo::collections::Vec as BumpVec;
 use bumpalo::Bump;
 
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+/// Scene epsilon as a fraction of the diagonal of the scene bounds.
+const SCENE_EPSILON_SCALE: f32 = 1e-7;
+/// Scene epsilon for scenes without any bounded objects to measure, and the least it is
+/// for any scene.
+pub const DEFAULT_SCENE_EPSILON: f32 = 0.0001;
+/// Least distance at which analytic primitives count shadow rays as blocked by themselves.
+/// Shadow rays start offset from the surface they left, but a root that close to their
+/// start may still just be that surface again.
+pub const SHADOW_T_MIN: f32 = 0.001;
+/// Most segments the occlusion cache remembers. Once it's full, segments which aren't in it
+/// yet are still traced but no longer stored.
+const MAX_CACHED_SEGMENTS: usize = 1 << 20;
+
 pub trait Hitable: Send + Sync {
     fn hit(&self, rays: &WRay, t_ranges: ::std::ops::Range<f32x4>) -> f32x4;
+    /// Same as `hit`, but also returns any per-lane data the primitive computed while
+    /// intersecting (e.g. barycentrics and triangle index) which would otherwise have to be
+    /// recomputed in `get_shading_info`. The attributes are handed back through `WHit`.
+    fn hit_with_attributes(
+        &self,
+        rays: &WRay,
+        t_ranges: ::std::ops::Range<f32x4>,
+    ) -> (f32x4, WHitAttributes) {
+        (self.hit(rays, t_ranges), WHitAttributes::none())
+    }
     // return 0 if occluded, 1 if not
     fn occluded(&self, start: Wec3, end: Wec3, time: f32x4) -> f32x4;
-    /// err is a function that takes a point and returns the error bound
-    /// at that point based on a screen-space projection (i.e. computes pixel size
-    /// at that point).
+    /// Fraction of the light of each color which makes it through the object along the
+    /// segment from `start` to `end`. By default objects are opaque, so it's `occluded`.
+    fn transmittance(&self, start: Wec3, end: Wec3, time: f32x4) -> WSrgb {
+        WSrgb(Wec3::broadcast(self.occluded(start, end, time)))
+    }
+    /// Box containing the object at all times from `t0` to `t1`, or `None` if it is
+    /// unbounded (or its bounds aren't known).
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<Aabb> {
+        None
+    }
+    /// Radiance the object gives off if it's a light source, which is how lights are told
+    /// apart from other hitables (see `HitableStore::lights`).
+    fn emission(&self) -> Option<Wec3> {
+        None
+    }
+    /// `primary` and `camera` are there to compute the error bound of the hit point (see
+    /// `intersection_error_bound`), which spawned rays should be offset by.
     fn get_shading_info(
         &self,
         hits: WHit,
@@ -21,119 +66,656 @@ pub trait Hitable: Send + Sync {
     ) -> (MaterialHandle, WShadingPoint);
 }
 
+/// Conservative bound on how far the computed hit point may be from the true surface.
+/// Covers the floating point error of `ray.point_at(t)` (with room for the error in `t`),
+/// and for primary hits also the pixel footprint at the hit, since that's the scale that
+/// primitives resolve their surface at for camera rays. The footprint comes from the ray
+/// differentials, or from `camera` for rays which don't have any.
+pub fn intersection_error_bound(hit: &WHit, primary: bool, camera: &dyn Camera) -> f32x4 {
+    let float_err = float_error_bound(&hit.ray, hit.t);
+
+    if primary {
+        let footprint = hit.ray.half_footprint_at(hit.t);
+        let footprint = f32x4::merge(
+            footprint.cmp_gt(f32x4::ZERO),
+            footprint,
+            camera.half_pixel_size_at(hit.t, hit.ray.time),
+        );
+        float_err.max(footprint)
+    } else {
+        float_err
+    }
+}
+
+/// Just the floating point part of `intersection_error_bound`: the smallest offset which is
+/// guaranteed to move `ray.point_at(t)` off of an exactly computed surface.
+pub fn float_error_bound(ray: &WRay, t: f32x4) -> f32x4 {
+    let origin = ray.origin.map(|x| x.abs());
+    let along = (ray.dir * t).map(|x| x.abs());
+    (origin + along).component_max() * f32x4::from(gamma(7))
+}
+
 #[derive(Clone, Copy)]
 pub struct WShadingPoint {
     pub ray: WRay,
     pub t: f32x4,
     pub point: Wec3,
     pub offset_by: f32x4,
+    /// Shading normal, which the BSDF is evaluated around
     pub normal: Wec3,
+    /// Normal of the actual surface, used to offset rays leaving it. Differs from `normal`
+    /// where the shading normal is interpolated or perturbed.
+    pub geom_normal: Wec3,
     pub basis: Wat3,
+    /// Unit vector perpendicular to `normal` along the direction of increasing u
+    pub tangent: Wec3,
+    /// Unit vector perpendicular to `normal` and `tangent`, on the side of increasing v
+    pub bitangent: Wec3,
+    /// Surface parameterization at the point, zero for primitives without one
+    pub uv: Wec2,
+    /// How far the point moves on the tangent plane per pixel along x and y of the image,
+    /// from where the ray differentials cross it. Zero for rays without differentials.
+    pub dpdx: Wec3,
+    pub dpdy: Wec3,
+    /// How much the shading normal turns per pixel along x and y, zero unless the primitive
+    /// sets them with `with_normal_differentials`, i.e. as if the surface were flat
+    pub dndx: Wec3,
+    pub dndy: Wec3,
+    /// Mask of the lanes which hold a live path. The others are padding or belong to
+    /// terminated paths, and anything computed for them is thrown away.
+    pub active: f32x4,
 }
 
 impl WShadingPoint {
+    /// Creates a shading point whose tangent frame is taken from the orthonormal basis
+    /// of the normal. Use `with_uv_derivatives` if the primitive has a parameterization.
     pub fn new(hit: WHit, point: Wec3, offset_by: f32x4, normal: Wec3) -> Self {
+        let basis = normal.get_orthonormal_basis();
+        let active = hit.is_valid();
+
+        // where the auxiliary rays cross the tangent plane, which they may be (close to)
+        // parallel to at grazing angles
+        let plane_offset = normal.dot(point);
+        let transfer = |origin: Wec3, dir: Wec3| {
+            let cos = normal.dot(dir);
+            let t = (plane_offset - normal.dot(origin)) / cos;
+            let offset = origin + dir * t - point;
+            let valid = cos.abs().cmp_gt(f32x4::from(1e-6))
+                & offset.mag_sq().cmp_lt(f32x4::from(std::f32::MAX));
+            Wec3::merge(valid, offset, Wec3::zero())
+        };
+        let differential = &hit.ray.differential;
+        let dpdx = transfer(differential.dx_origin, differential.dx_dir);
+        let dpdy = transfer(differential.dy_origin, differential.dy_dir);
+
         WShadingPoint {
             ray: hit.ray,
             t: hit.t,
             point,
             offset_by,
             normal,
-            basis: normal.get_orthonormal_basis(),
+            geom_normal: normal,
+            basis,
+            tangent: basis.cols[0],
+            bitangent: basis.cols[1],
+            uv: Wec2::zero(),
+            dpdx,
+            dpdy,
+            dndx: Wec3::zero(),
+            dndy: Wec3::zero(),
+            active,
+        }
+    }
+
+    /// Sets how much the shading normal changes per pixel, which is what makes rays
+    /// reflected off of curved surfaces spread apart (see `create_rays`).
+    pub fn with_normal_differentials(mut self, dndx: Wec3, dndy: Wec3) -> Self {
+        self.dndx = dndx;
+        self.dndy = dndy;
+        self
+    }
+
+    /// Radius of the footprint of a pixel on the surface around the point, for filtering
+    /// textures over. Zero for rays without differentials.
+    #[allow(dead_code)]
+    pub fn footprint(&self) -> f32x4 {
+        self.dpdx.mag().max(self.dpdy.mag()) * f32x4::from(0.5)
+    }
+
+    /// Shades around `normal` instead of the geometric normal the point was created with.
+    /// The tangent frame is rebuilt around it, so call this before `with_uv_derivatives`.
+    pub fn with_shading_normal(mut self, normal: Wec3) -> Self {
+        let basis = normal.get_orthonormal_basis();
+        self.normal = normal;
+        self.basis = basis;
+        self.tangent = basis.cols[0];
+        self.bitangent = basis.cols[1];
+        self
+    }
+
+    pub fn with_uv(mut self, uv: Wec2) -> Self {
+        self.uv = uv;
+        self
+    }
+
+    /// Aligns the tangent frame with the surface partial derivatives dp/du and dp/dv. Lanes
+    /// where dp/du is degenerate or parallel to the normal keep the previous tangent frame.
+    pub fn with_uv_derivatives(mut self, dpdu: Wec3, dpdv: Wec3) -> Self {
+        let tangent = dpdu - self.normal * self.normal.dot(dpdu);
+        let tangent_mag_sq = tangent.mag_sq();
+        let valid = tangent_mag_sq.cmp_gt(f32x4::from(1e-12));
+        let tangent = tangent / tangent_mag_sq.max(f32x4::from(1e-12)).sqrt();
+
+        let bitangent = self.normal.cross(tangent);
+        let flip = bitangent.dot(dpdv).cmp_lt(f32x4::ZERO);
+        let bitangent = Wec3::merge(flip, -bitangent, bitangent);
+
+        self.tangent = Wec3::merge(valid, tangent, self.tangent);
+        self.bitangent = Wec3::merge(valid, bitangent, self.bitangent);
+        self
+    }
+
+    /// Flips the normal (and with it the tangent frame) of the lanes where it points away
+    /// from `w`, so that `w` is always in the hemisphere around the normal.
+    pub fn faced_towards(mut self, w: Wec3) -> Self {
+        let flip = w.dot(self.normal).cmp_lt(f32x4::ZERO);
+        if flip.none() {
+            return self;
         }
+
+        self.normal = Wec3::merge(flip, -self.normal, self.normal);
+        self.geom_normal = Wec3::merge(flip, -self.geom_normal, self.geom_normal);
+        self.dndx = Wec3::merge(flip, -self.dndx, self.dndx);
+        self.dndy = Wec3::merge(flip, -self.dndy, self.dndy);
+        self.basis = self.normal.get_orthonormal_basis();
+        self.bitangent = Wec3::merge(flip, -self.bitangent, self.bitangent);
+        self
+    }
+
+    /// The same point with the shading and geometric normals (and with them the tangent
+    /// frame) reversed in every lane.
+    pub fn flipped(mut self) -> Self {
+        self.normal = -self.normal;
+        self.geom_normal = -self.geom_normal;
+        self.dndx = -self.dndx;
+        self.dndy = -self.dndy;
+        self.basis = self.normal.get_orthonormal_basis();
+        self.bitangent = -self.bitangent;
+        self
     }
 
+    /// Continuations of the paths in the direction `dir`. Lanes which aren't active are
+    /// marked invalid, so they never get spawned.
+    ///
+    /// The auxiliary rays start a pixel footprint over on the surface. Where `dir` leaves on
+    /// the side the ray came from, they turn along with the mirror direction between their
+    /// hit points, so the footprint grows off of curved surfaces. Transmitted lanes keep the
+    /// spread of the incoming rays, as if the surface didn't bend them.
     pub fn create_rays(&self, dir: Wec3) -> WRay {
         let mut ray = self.ray;
-        ray.origin = self.point + self.normal * self.normal.dot(dir).signum() * self.offset_by;
-        ray.dir = dir;
+        let side = self.geom_normal.dot(dir).signum();
+        ray.origin = self.point + self.geom_normal * side * self.offset_by;
+        ray.set_dir(dir);
+
+        let wo = -self.ray.dir;
+        let reflected = (wo.dot(self.geom_normal) * side).cmp_ge(f32x4::ZERO);
+        let cos_o = wo.dot(self.normal);
+        let spread = |aux_dir: Wec3, dndx: Wec3| {
+            let dwo = self.ray.dir - aux_dir;
+            let dcos = dwo.dot(self.normal) + wo.dot(dndx);
+            let mirror = dir - dwo + (dndx * cos_o + self.normal * dcos) * f32x4::from(2.0);
+            Wec3::merge(reflected, mirror, dir - dwo)
+        };
+        let differential = &self.ray.differential;
+        ray.differential = WRayDifferential {
+            dx_origin: ray.origin + self.dpdx,
+            dx_dir: spread(differential.dx_dir, self.dndx),
+            dy_origin: ray.origin + self.dpdy,
+            dy_dir: spread(differential.dy_dir, self.dndy),
+        };
+        let active = self.active.move_mask();
+        for (i, valid) in ray.valid.iter_mut().enumerate() {
+            *valid = *valid && active & (1 << i) != 0;
+        }
         ray
     }
 }
 
+/// Turns `H` inside out by reversing the normals it reports, e.g. for meshes with the wrong
+/// winding. Hit distances are unchanged.
+#[allow(dead_code)]
+pub struct FlipNormals<H>(pub H);
+
+impl<H: Hitable> Hitable for FlipNormals<H> {
+    fn hit(&self, rays: &WRay, t_ranges: ::std::ops::Range<f32x4>) -> f32x4 {
+        self.0.hit(rays, t_ranges)
+    }
+
+    fn hit_with_attributes(
+        &self,
+        rays: &WRay,
+        t_ranges: ::std::ops::Range<f32x4>,
+    ) -> (f32x4, WHitAttributes) {
+        self.0.hit_with_attributes(rays, t_ranges)
+    }
+
+    fn occluded(&self, start: Wec3, end: Wec3, time: f32x4) -> f32x4 {
+        self.0.occluded(start, end, time)
+    }
+
+    fn transmittance(&self, start: Wec3, end: Wec3, time: f32x4) -> WSrgb {
+        self.0.transmittance(start, end, time)
+    }
+
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<Aabb> {
+        self.0.bounding_box(t0, t1)
+    }
+
+    fn emission(&self) -> Option<Wec3> {
+        self.0.emission()
+    }
+
+    fn get_shading_info(
+        &self,
+        hits: WHit,
+        primary: bool,
+        camera: &dyn Camera,
+    ) -> (MaterialHandle, WShadingPoint) {
+        let (material, shading_point) = self.0.get_shading_info(hits, primary, camera);
+        (material, shading_point.flipped())
+    }
+}
+
+/// Lets shadow rays through `hitable` tinted by `tint` instead of being blocked, for thin
+/// colored glass or fabric. Camera and bounce rays see `hitable` as usual.
+#[allow(dead_code)]
+pub struct ThinGlass<H> {
+    pub hitable: H,
+    pub tint: Srgb,
+}
+
+impl<H: Hitable> Hitable for ThinGlass<H> {
+    fn hit(&self, rays: &WRay, t_ranges: ::std::ops::Range<f32x4>) -> f32x4 {
+        self.hitable.hit(rays, t_ranges)
+    }
+
+    fn hit_with_attributes(
+        &self,
+        rays: &WRay,
+        t_ranges: ::std::ops::Range<f32x4>,
+    ) -> (f32x4, WHitAttributes) {
+        self.hitable.hit_with_attributes(rays, t_ranges)
+    }
+
+    fn occluded(&self, start: Wec3, end: Wec3, time: f32x4) -> f32x4 {
+        self.hitable.occluded(start, end, time)
+    }
+
+    fn transmittance(&self, start: Wec3, end: Wec3, time: f32x4) -> WSrgb {
+        let blocked = self.hitable.occluded(start, end, time).cmp_eq(f32x4::ZERO);
+        WSrgb::merge(blocked, WSrgb::splat(self.tint), WSrgb::one())
+    }
+
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<Aabb> {
+        self.hitable.bounding_box(t0, t1)
+    }
+
+    fn emission(&self) -> Option<Wec3> {
+        self.hitable.emission()
+    }
+
+    fn get_shading_info(
+        &self,
+        hits: WHit,
+        primary: bool,
+        camera: &dyn Camera,
+    ) -> (MaterialHandle, WShadingPoint) {
+        self.hitable.get_shading_info(hits, primary, camera)
+    }
+}
+
+/// Marks `hitable` as a light source giving off `emission`, so it's found by
+/// `HitableStore::lights`. What it looks like is still up to its material, which would
+/// usually be `Emissive` with the same emission.
+#[allow(dead_code)]
+pub struct Emitter<H> {
+    pub hitable: H,
+    pub emission: Vec3,
+}
+
+impl<H: Hitable> Hitable for Emitter<H> {
+    fn hit(&self, rays: &WRay, t_ranges: ::std::ops::Range<f32x4>) -> f32x4 {
+        self.hitable.hit(rays, t_ranges)
+    }
+
+    fn hit_with_attributes(
+        &self,
+        rays: &WRay,
+        t_ranges: ::std::ops::Range<f32x4>,
+    ) -> (f32x4, WHitAttributes) {
+        self.hitable.hit_with_attributes(rays, t_ranges)
+    }
+
+    fn occluded(&self, start: Wec3, end: Wec3, time: f32x4) -> f32x4 {
+        self.hitable.occluded(start, end, time)
+    }
+
+    fn transmittance(&self, start: Wec3, end: Wec3, time: f32x4) -> WSrgb {
+        self.hitable.transmittance(start, end, time)
+    }
+
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<Aabb> {
+        self.hitable.bounding_box(t0, t1)
+    }
+
+    fn emission(&self) -> Option<Wec3> {
+        Some(Wec3::splat(self.emission))
+    }
+
+    fn get_shading_info(
+        &self,
+        hits: WHit,
+        primary: bool,
+        camera: &dyn Camera,
+    ) -> (MaterialHandle, WShadingPoint) {
+        self.hitable.get_shading_info(hits, primary, camera)
+    }
+}
+
+/// Ray from `start` towards `end`, and the distance between them.
+fn segment_ray(start: Wec3, end: Wec3, time: f32x4) -> (WRay, f32x4) {
+    let dir = end - start;
+    let dist = dir.mag();
+    let ray = WRay::new(
+        start,
+        dir / dist,
+        time,
+        [Vec2u::zero(); 4],
+        [true; 4],
+        [0.0; 4],
+        [0; 4],
+    );
+    (ray, dist)
+}
+
+/// Primitive-specific data recorded during intersection. What `uv` and `prim_id` mean is
+/// up to the primitive which recorded them.
+#[derive(Clone, Copy, Debug)]
+pub struct HitAttributes {
+    pub uv: Vec2,
+    pub prim_id: usize,
+}
+
+impl HitAttributes {
+    pub fn none() -> Self {
+        Self {
+            uv: Vec2::zero(),
+            prim_id: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WHitAttributes {
+    pub uv: Wec2,
+    pub prim_id: [usize; 4],
+}
+
+impl WHitAttributes {
+    pub fn none() -> Self {
+        Self {
+            uv: Wec2::zero(),
+            prim_id: [0; 4],
+        }
+    }
+}
+
+impl From<[HitAttributes; 4]> for WHitAttributes {
+    fn from(attrs: [HitAttributes; 4]) -> Self {
+        Self {
+            uv: Wec2::from([attrs[0].uv, attrs[1].uv, attrs[2].uv, attrs[3].uv]),
+            prim_id: [
+                attrs[0].prim_id,
+                attrs[1].prim_id,
+                attrs[2].prim_id,
+                attrs[3].prim_id,
+            ],
+        }
+    }
+}
+
+impl Into<[HitAttributes; 4]> for WHitAttributes {
+    fn into(self) -> [HitAttributes; 4] {
+        let uvs: [Vec2; 4] = self.uv.into();
+        [
+            HitAttributes {
+                uv: uvs[0],
+                prim_id: self.prim_id[0],
+            },
+            HitAttributes {
+                uv: uvs[1],
+                prim_id: self.prim_id[1],
+            },
+            HitAttributes {
+                uv: uvs[2],
+                prim_id: self.prim_id[2],
+            },
+            HitAttributes {
+                uv: uvs[3],
+                prim_id: self.prim_id[3],
+            },
+        ]
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct Hit {
     pub ray: Ray,
     pub t: f32,
+    pub attributes: HitAttributes,
+}
+
+impl Hit {
+    /// Padding for filling up a packet.
+    pub fn new_invalid() -> Self {
+        Hit {
+            ray: Ray::new_invalid(),
+            t: 0.0,
+            attributes: HitAttributes::none(),
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
 pub struct WHit {
     pub ray: WRay,
     pub t: f32x4,
+    pub attributes: WHitAttributes,
 }
 
 impl WHit {
+    /// A packet with no valid lanes, built directly rather than from scalar padding.
+    #[allow(dead_code)]
+    pub fn new_invalid() -> Self {
+        WHit {
+            ray: WRay::new_invalid(),
+            t: f32x4::ZERO,
+            attributes: WHitAttributes::none(),
+        }
+    }
+
+    /// The intersection point of each lane. Lanes holding an invalid (padding) ray are NaN
+    /// rather than the ray origin, so they can't be mistaken for a real intersection.
     #[inline]
     pub fn point(&self) -> Wec3 {
-        self.ray.point_at(self.t)
+        Wec3::merge(
+            self.ray.valid_mask(),
+            self.ray.point_at(self.t),
+            Wec3::broadcast(f32x4::from(std::f32::NAN)),
+        )
+    }
+
+    /// Mask of the lanes which hold a real intersection.
+    pub fn is_valid(&self) -> f32x4 {
+        self.ray.valid_mask() & !self.t.cmp_nan(self.t)
     }
 }
 
-impl From<[Hit; 4]> for WHit {
-    fn from(hits: [Hit; 4]) -> Self {
+impl From<[Hit; LANES]> for WHit {
+    fn from(hits: [Hit; LANES]) -> Self {
         let ray = WRay::from([hits[0].ray, hits[1].ray, hits[2].ray, hits[3].ray]);
         let t = f32x4::from([hits[0].t, hits[1].t, hits[2].t, hits[3].t]);
-        Self { ray, t }
+        let attributes = WHitAttributes::from([
+            hits[0].attributes,
+            hits[1].attributes,
+            hits[2].attributes,
+            hits[3].attributes,
+        ]);
+        Self { ray, t, attributes }
+    }
+}
+
+/// Keeps an exponential moving average of the peak number of hits each object received
+/// in a single bounce of a single tile, so that the `HitStore`s of the next frame can be
+/// pre-sized instead of growing their bump allocations hit by hit.
+#[derive(Clone, Debug)]
+pub struct HitCountEstimate {
+    /// Weight given to the newest frame's peaks, in 0..=1. 1 only remembers the last frame.
+    pub smoothing: f32,
+    /// Multiplier applied to the estimate when reserving, to leave room for growth.
+    pub headroom: f32,
+    counts: Vec<f32>,
+}
+
+impl HitCountEstimate {
+    pub fn new(smoothing: f32, headroom: f32) -> Self {
+        Self {
+            smoothing: smoothing.max(0.0).min(1.0),
+            headroom: headroom.max(0.0),
+            counts: Vec::new(),
+        }
+    }
+
+    /// Number of hits to reserve up front for each of `num_objects` objects.
+    pub fn capacities(&self, num_objects: usize) -> Vec<usize> {
+        (0..num_objects)
+            .map(|i| {
+                self.counts
+                    .get(i)
+                    .map(|count| (count * self.headroom).ceil() as usize)
+                    .unwrap_or(0)
+            })
+            .collect()
+    }
+
+    pub fn update(&mut self, peaks: &[usize]) {
+        if self.counts.len() != peaks.len() {
+            // The scene changed shape, so the old estimates mean nothing anymore.
+            self.counts = peaks.iter().map(|peak| *peak as f32).collect();
+            return;
+        }
+
+        for (count, peak) in self.counts.iter_mut().zip(peaks.iter()) {
+            *count += (*peak as f32 - *count) * self.smoothing;
+        }
+    }
+}
+
+impl Default for HitCountEstimate {
+    fn default() -> Self {
+        Self::new(0.5, 1.25)
     }
 }
 
 pub struct HitStore<'bump> {
     hits: BumpVec<'bump, BumpVec<'bump, Hit>>,
+    misses: BumpVec<'bump, Ray>,
+    peak_hits: BumpVec<'bump, usize>,
+    /// Scratch space for the hitables each packet could hit, reused across `add_hits`
+    candidates: BumpVec<'bump, usize>,
 }
 
 impl<'bump> HitStore<'bump> {
+    #[allow(dead_code)]
     pub fn from_hitable_store(bump: &'bump Bump, hitable_store: &HitableStore) -> Self {
+        Self::with_capacities(bump, hitable_store, &[])
+    }
+
+    /// Creates a store reserving `capacities[i]` hits for object `i` up front. Objects
+    /// without an entry start empty.
+    pub fn with_capacities(
+        bump: &'bump Bump,
+        hitable_store: &HitableStore,
+        capacities: &[usize],
+    ) -> Self {
         let mut hits = BumpVec::with_capacity_in(hitable_store.len(), bump);
-        for _ in 0..hitable_store.len() {
-            hits.push(BumpVec::new_in(bump))
+        let mut peak_hits = BumpVec::with_capacity_in(hitable_store.len(), bump);
+        for i in 0..hitable_store.len() {
+            let capacity = capacities.get(i).copied().unwrap_or(0);
+            hits.push(BumpVec::with_capacity_in(capacity, bump));
+            peak_hits.push(0);
+        }
+        Self {
+            hits,
+            misses: BumpVec::new_in(bump),
+            peak_hits,
+            candidates: BumpVec::new_in(bump),
         }
-        Self { hits }
+    }
+
+    /// The most hits each object has received between two `reset`s over the lifetime
+    /// of this store.
+    pub fn peak_hits(&self) -> &[usize] {
+        &self.peak_hits
     }
 
     pub unsafe fn add_hit(&mut self, obj_id: usize, hit: Hit) {
         self.hits.get_unchecked_mut(obj_id).push(hit);
     }
 
+    pub fn add_miss(&mut self, ray: Ray) {
+        self.misses.push(ray);
+    }
+
+    /// Valid rays which didn't hit anything since the last `reset`.
+    pub fn misses(&self) -> &[Ray] {
+        &self.misses
+    }
+
+    /// Turns the hits gathered so far into shading points, along with the index of the
+    /// hitable they belong to and its material. Offsets are raised to at least `min_offset`.
     pub fn process_hits(
         &mut self,
         hitables: &HitableStore,
-        wintersections: &mut BumpVec<'_, (MaterialHandle, WShadingPoint)>,
+        wintersections: &mut BumpVec<'_, (usize, MaterialHandle, WShadingPoint)>,
         primary: bool,
         camera: &dyn Camera,
+        min_offset: f32x4,
     ) {
         let total_hits = self
             .hits
             .iter_mut()
-            .map(|hits| {
-                while hits.len() % 4 != 0 {
-                    hits.push(Hit {
-                        ray: Ray::new_invalid(),
-                        t: 0.0,
-                    })
+            .zip(self.peak_hits.iter_mut())
+            .map(|(hits, peak)| {
+                *peak = (*peak).max(hits.len());
+                while hits.len() % LANES != 0 {
+                    hits.push(Hit::new_invalid())
                 }
                 hits.len()
             })
             .sum::<usize>();
 
-        wintersections.reserve(total_hits / 4);
+        wintersections.reserve(total_hits / LANES);
 
         for (obj_id, hits) in self.hits.iter_mut().enumerate() {
-            for hits in hits[0..].chunks_exact(4) {
-                // Safe because we just assured that every window will have exactly
-                // 4 members.
-                let hits = WHit::from(unsafe {
-                    [
-                        *hits.get_unchecked(0),
-                        *hits.get_unchecked(1),
-                        *hits.get_unchecked(2),
-                        *hits.get_unchecked(3),
-                    ]
-                });
-                wintersections.push(
-                    unsafe { hitables.get_unchecked(obj_id) }
-                        .get_shading_info(hits, primary, camera),
-                );
+            // every chunk is whole since the hits were just padded
+            for hits in hits[0..].chunks_exact(LANES) {
+                let hits = WHit::from(*arrayref::array_ref![hits, 0, LANES]);
+                let (mat_id, mut shading_point) = unsafe { hitables.get_unchecked(obj_id) }
+                    .get_shading_info(hits, primary, camera);
+                shading_point.offset_by = shading_point.offset_by.max(min_offset);
+                let mat_id = hitables.material_override.unwrap_or(mat_id);
+                wintersections.push((obj_id, mat_id, shading_point));
             }
         }
     }
@@ -142,18 +724,337 @@ impl<'bump> HitStore<'bump> {
         for shading_points in self.hits.iter_mut() {
             shading_points.clear();
         }
+        self.misses.clear();
     }
 }
 
-pub struct HitableStore(Vec<Box<dyn Hitable>>);
+/// Which kinds of rays can see a hitable, e.g. a matte object which is hidden from the
+/// camera but still casts shadows and shows up in reflections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Visibility {
+    pub camera: bool,
+    pub shadow: bool,
+    pub reflection: bool,
+}
+
+impl Visibility {
+    /// Seen by every kind of ray.
+    pub const ALL: Visibility = Visibility {
+        camera: true,
+        shadow: true,
+        reflection: true,
+    };
+
+    /// Seen by nothing.
+    pub const NONE: Visibility = Visibility {
+        camera: false,
+        shadow: false,
+        reflection: false,
+    };
+
+    pub fn sees(self, kind: RayKind) -> bool {
+        match kind {
+            RayKind::Camera => self.camera,
+            RayKind::Shadow => self.shadow,
+            RayKind::Reflection => self.reflection,
+        }
+    }
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Visibility::ALL
+    }
+}
+
+/// Stands in for removed hitables, keeping their slot so later handles don't shift. It's
+/// never seen by any ray (see `HitableStore::remove`), and wouldn't be hit if it were.
+struct Tombstone;
+
+impl Hitable for Tombstone {
+    fn hit(&self, _rays: &WRay, _t_ranges: ::std::ops::Range<f32x4>) -> f32x4 {
+        f32x4::from(std::f32::MAX)
+    }
+
+    fn occluded(&self, _start: Wec3, _end: Wec3, _time: f32x4) -> f32x4 {
+        f32x4::ONE
+    }
+
+    fn get_shading_info(
+        &self,
+        _hit: WHit,
+        _primary: bool,
+        _camera: &dyn Camera,
+    ) -> (MaterialHandle, WShadingPoint) {
+        unreachable!("Removed hitables can't be hit")
+    }
+}
+
+/// End points of a shadow segment snapped to the cells of an `OcclusionCache`.
+type SegmentKey = ([i32; 3], [i32; 3]);
+
+/// Transmittance through the static hitables along shadow segments, remembered across
+/// frames. Segments are keyed on which cells their end points fall into, so all segments
+/// between the same two cells share the result of whichever was traced first.
+struct OcclusionCache {
+    cell_size: f32,
+    segments: RwLock<HashMap<SegmentKey, Srgb>>,
+}
+
+impl OcclusionCache {
+    fn keys(&self, start: Wec3, end: Wec3) -> [Option<SegmentKey>; 4] {
+        let starts: [Vec3; 4] = start.into();
+        let ends: [Vec3; 4] = end.into();
+        let cell = |point: Vec3| {
+            let cell = point / self.cell_size;
+            // also false for NaN, which invalid lanes can hold
+            let in_range = |x: f32| x.abs() < std::i32::MAX as f32;
+            if in_range(cell.x) && in_range(cell.y) && in_range(cell.z) {
+                Some([
+                    cell.x.floor() as i32,
+                    cell.y.floor() as i32,
+                    cell.z.floor() as i32,
+                ])
+            } else {
+                None
+            }
+        };
+        let mut keys = [None; 4];
+        for (key, (start, end)) in keys.iter_mut().zip(starts.iter().zip(ends.iter())) {
+            *key = cell(*start).and_then(|start| cell(*end).map(|end| (start, end)));
+        }
+        keys
+    }
+
+    fn clear(&mut self) {
+        self.segments.get_mut().unwrap().clear();
+    }
+}
+
+/// Position of a hitable in a `HitableStore`, which stays the same since hitables are only
+/// ever added to the end, and removed ones leave their slot behind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HitableHandle(pub usize);
+
+pub struct HitableStore {
+    hitables: Vec<Box<dyn Hitable>>,
+    // stable id of each hitable, parallel to `hitables`
+    ids: Vec<usize>,
+    // every id in `ids`, for finding free ones quickly
+    taken_ids: HashSet<usize>,
+    // parallel to `hitables` as well
+    visibility: Vec<Visibility>,
+    // whether each hitable is the same at all times, so shadow rays through it can be cached
+    is_static: Vec<bool>,
+    // whether each slot was emptied by `remove`
+    removed: Vec<bool>,
+    grid: Option<UniformGrid>,
+    bvh: Option<Bvh>,
+    occlusion_cache: Option<OcclusionCache>,
+    material_override: Option<MaterialHandle>,
+}
 
 impl HitableStore {
     pub fn new() -> Self {
-        HitableStore(Vec::new())
+        HitableStore {
+            hitables: Vec::new(),
+            ids: Vec::new(),
+            taken_ids: HashSet::new(),
+            visibility: Vec::new(),
+            is_static: Vec::new(),
+            removed: Vec::new(),
+            grid: None,
+            bvh: None,
+            occlusion_cache: None,
+            material_override: None,
+        }
+    }
+
+    /// Adds a hitable whose id is its position in the store, so it changes if hitables are
+    /// added before it. If `push_with_id` already took that id, it gets the next free one
+    /// after it instead. Use `push_with_id` for ids that stay the same across scene edits.
+    pub fn push<H: Hitable + 'static>(&mut self, hitable: H) -> HitableHandle {
+        let mut id = self.hitables.len();
+        while self.taken_ids.contains(&id) {
+            id += 1;
+        }
+        self.taken_ids.insert(id);
+        self.ids.push(id);
+        self.visibility.push(Visibility::ALL);
+        self.is_static.push(false);
+        self.removed.push(false);
+        self.hitables.push(Box::new(hitable));
+        self.geometry_changed();
+        HitableHandle(self.hitables.len() - 1)
+    }
+
+    /// Adds a hitable with an explicit id, which is what gets written to the object id
+    /// channel regardless of where in the store it ends up.
+    #[allow(dead_code)]
+    pub fn push_with_id<H: Hitable + 'static>(
+        &mut self,
+        hitable: H,
+        id: usize,
+    ) -> Result<HitableHandle, String> {
+        if !self.taken_ids.insert(id) {
+            return Err(format!("Attempted to add multiple hitables with id {}", id));
+        }
+        self.ids.push(id);
+        self.visibility.push(Visibility::ALL);
+        self.is_static.push(false);
+        self.removed.push(false);
+        self.hitables.push(Box::new(hitable));
+        self.geometry_changed();
+        Ok(HitableHandle(self.hitables.len() - 1))
+    }
+
+    #[allow(dead_code)]
+    pub fn get(&self, handle: HitableHandle) -> &dyn Hitable {
+        self.hitables[handle.0].as_ref()
+    }
+
+    /// Swaps out the hitable at `handle`, which keeps its id, visibility and whether it's
+    /// static, and returns the old one. Slots of removed hitables are made visible to
+    /// everything again.
+    #[allow(dead_code)]
+    pub fn replace<H: Hitable + 'static>(
+        &mut self,
+        handle: HitableHandle,
+        hitable: H,
+    ) -> Box<dyn Hitable> {
+        if self.removed[handle.0] {
+            self.removed[handle.0] = false;
+            self.visibility[handle.0] = Visibility::ALL;
+        }
+        self.geometry_changed();
+        std::mem::replace(&mut self.hitables[handle.0], Box::new(hitable))
+    }
+
+    /// Sets which kinds of rays can see the hitable at `handle`. Hitables are visible to
+    /// everything when they're added.
+    #[allow(dead_code)]
+    pub fn set_visibility(&mut self, handle: HitableHandle, visibility: Visibility) {
+        self.visibility[handle.0] = visibility;
+        if let Some(cache) = &mut self.occlusion_cache {
+            cache.clear();
+        }
+    }
+
+    /// Marks the hitable at `handle` as not moving or changing over time, which lets the
+    /// occlusion cache remember shadow rays through it. Hitables aren't static when they're
+    /// added.
+    #[allow(dead_code)]
+    pub fn set_static(&mut self, handle: HitableHandle, is_static: bool) {
+        self.is_static[handle.0] = is_static;
+        if let Some(cache) = &mut self.occlusion_cache {
+            cache.clear();
+        }
+    }
+
+    /// Remembers the transmittance through the static hitables (see `set_static`) along
+    /// shadow rays, so later frames of an animation can look it up instead of tracing it
+    /// again. Segments whose end points fall into the same cells of a grid of `cell_size`
+    /// share a result, so it should be well below the size of any shadow detail. Shadows
+    /// from the other hitables are always traced. The cache is emptied whenever hitables
+    /// are added, replaced or removed, and `None` turns it off. It stops growing at
+    /// `MAX_CACHED_SEGMENTS` segments, after which segments it doesn't have are traced
+    /// every time.
+    #[allow(dead_code)]
+    pub fn set_occlusion_cache(&mut self, cell_size: Option<f32>) {
+        self.occlusion_cache = cell_size.map(|cell_size| OcclusionCache {
+            cell_size,
+            segments: RwLock::new(HashMap::new()),
+        });
     }
 
-    pub fn push<H: Hitable + 'static>(&mut self, hitable: H) {
-        self.0.push(Box::new(hitable))
+    /// Drops everything built from the hitables as they were.
+    fn geometry_changed(&mut self) {
+        self.grid = None;
+        self.bvh = None;
+        if let Some(cache) = &mut self.occlusion_cache {
+            cache.clear();
+        }
+    }
+
+    /// Whether rays of `kind` can see the hitable at `index`.
+    fn visible(&self, index: usize, kind: RayKind) -> bool {
+        self.visibility[index].sees(kind)
+    }
+
+    /// Takes the hitable at `handle` out of the scene and returns it. Its slot is left
+    /// behind empty, so the handles and ids of the other hitables stay the same.
+    #[allow(dead_code)]
+    pub fn remove(&mut self, handle: HitableHandle) -> Box<dyn Hitable> {
+        let hitable = self.replace(handle, Tombstone);
+        // so that rays skip the slot entirely
+        self.visibility[handle.0] = Visibility::NONE;
+        self.removed[handle.0] = true;
+        hitable
+    }
+
+    /// Removes every hitable, the same as `remove` on each of them.
+    #[allow(dead_code)]
+    pub fn clear(&mut self) {
+        for index in 0..self.hitables.len() {
+            self.remove(HitableHandle(index));
+        }
+    }
+
+    /// Buckets the hitables, as they are from `t0` to `t1`, into a `UniformGrid` so rays only
+    /// test the ones near them. Adding another hitable drops the grid, and it has to be
+    /// rebuilt for renders outside of that time range. Replaces any BVH.
+    #[allow(dead_code)]
+    pub fn build_grid(&mut self, t0: f32, t1: f32) {
+        self.bvh = None;
+        self.grid = Some(UniformGrid::new(&self.hitables, t0, t1));
+    }
+
+    /// Same as `build_grid`, but with a `Bvh`, which holds up better when the hitables are
+    /// clumped together instead of spread out evenly. Replaces any grid.
+    #[allow(dead_code)]
+    pub fn build_bvh(&mut self, t0: f32, t1: f32) {
+        self.grid = None;
+        self.bvh = Some(Bvh::new(&self.hitables, t0, t1));
+    }
+
+    /// Fills `candidates` with the indices of the hitables which the rays could hit within
+    /// `t_range`. Returns false if there's no acceleration structure and all of them have
+    /// to be tested.
+    fn candidates(
+        &self,
+        ray: &WRay,
+        t_range: ::std::ops::Range<f32x4>,
+        candidates: &mut BumpVec<usize>,
+    ) -> bool {
+        match (&self.bvh, &self.grid) {
+            (Some(bvh), _) => bvh.candidates(ray, t_range, candidates),
+            (None, Some(grid)) => grid.candidates(ray, t_range, candidates),
+            (None, None) => return false,
+        }
+        true
+    }
+
+    /// Shades every hit with `material` instead of the hitable's own, e.g. to look at the
+    /// scene in plain clay. That includes things like sky spheres. `None` turns it off.
+    #[allow(dead_code)]
+    pub fn set_material_override(&mut self, material: Option<MaterialHandle>) {
+        self.material_override = material;
+    }
+
+    /// Handles of the hitables which are light sources, i.e. those with an `emission`.
+    /// Removed hitables and ones invisible to everything are left out.
+    #[allow(dead_code)]
+    pub fn lights(&self) -> Vec<HitableHandle> {
+        (0..self.hitables.len())
+            .filter(|index| self.visibility[*index] != Visibility::NONE)
+            .filter(|index| self.hitables[*index].emission().is_some())
+            .map(HitableHandle)
+            .collect()
+    }
+
+    /// Stable id of the hitable at `index`.
+    pub fn id(&self, index: usize) -> usize {
+        self.ids[index]
     }
 }
 
@@ -161,55 +1062,921 @@ impl ::std::ops::Deref for HitableStore {
     type Target = Vec<Box<dyn Hitable>>;
 
     fn deref(&self) -> &Vec<Box<dyn Hitable>> {
-        &self.0
+        &self.hitables
     }
 }
 
 impl HitableStore {
-    pub fn test_occluded(&self, start: Wec3, end: Wec3, time: f32x4) -> f32x4 {
-        self.iter().fold(f32x4::ONE, |acc, hitable| {
-            acc * hitable.occluded(start, end, time)
+    /// Bounds of all the bounded objects in the scene from `t0` to `t1`. Unbounded objects
+    /// (such as infinite planes) are left out, and `None` is returned if nothing is bounded.
+    pub fn scene_bounds(&self, t0: f32, t1: f32) -> Option<Aabb> {
+        self.iter()
+            .filter_map(|hitable| hitable.bounding_box(t0, t1))
+            .filter(Aabb::is_finite)
+            .fold(None, |acc: Option<Aabb>, bounds| match acc {
+                Some(acc) => Some(acc.union(&bounds)),
+                None => Some(bounds),
+            })
+    }
+
+    /// Smallest distance that secondary rays start at and are offset from surfaces by,
+    /// proportional to the diagonal of the scene bounds so that it holds up in huge scenes.
+    /// It's never less than `DEFAULT_SCENE_EPSILON`, since a fraction of the diagonal of an
+    /// ordinary scene is down at the rounding error of the hit points.
+    pub fn scene_epsilon(&self, t0: f32, t1: f32) -> f32 {
+        self.scene_bounds(t0, t1)
+            .map(|bounds| (bounds.size().mag() * SCENE_EPSILON_SCALE).max(DEFAULT_SCENE_EPSILON))
+            .unwrap_or(DEFAULT_SCENE_EPSILON)
+    }
+
+    /// Transmittance along the segment from `start` to `end`: the product of what each
+    /// object in the way lets through, so black where anything opaque blocks it.
+    /// `candidates` is scratch space, see `HitStore`.
+    pub fn test_occluded(
+        &self,
+        start: Wec3,
+        end: Wec3,
+        time: f32x4,
+        candidates: &mut BumpVec<usize>,
+    ) -> WSrgb {
+        let cache = match &self.occlusion_cache {
+            Some(cache) => cache,
+            None => return self.transmittance(start, end, time, candidates, |_| true),
+        };
+
+        let keys = cache.keys(start, end);
+        let mut cached = [None; 4];
+        {
+            let segments = cache.segments.read().unwrap();
+            for (cached, key) in cached.iter_mut().zip(keys.iter()) {
+                *cached = key.and_then(|key| segments.get(&key).copied());
+            }
+        }
+
+        let static_transmittance = if cached.iter().all(Option::is_some) {
+            WSrgb::from([
+                cached[0].unwrap(),
+                cached[1].unwrap(),
+                cached[2].unwrap(),
+                cached[3].unwrap(),
+            ])
+        } else {
+            let traced = self.transmittance(start, end, time, candidates, |id| self.is_static[id]);
+            let traced_lanes: [Srgb; 4] = traced.into();
+            let mut segments = cache.segments.write().unwrap();
+            for (key, transmittance) in keys.iter().zip(traced_lanes.iter()) {
+                if let Some(key) = key {
+                    if segments.len() < MAX_CACHED_SEGMENTS {
+                        segments.entry(*key).or_insert(*transmittance);
+                    }
+                }
+            }
+            // lanes which were already cached keep the result other frames got
+            let cached_lanes = [
+                cached[0].unwrap_or(traced_lanes[0]),
+                cached[1].unwrap_or(traced_lanes[1]),
+                cached[2].unwrap_or(traced_lanes[2]),
+                cached[3].unwrap_or(traced_lanes[3]),
+            ];
+            WSrgb::from(cached_lanes)
+        };
+        if static_transmittance.max_channel().cmp_eq(f32x4::ZERO).all() {
+            return static_transmittance;
+        }
+
+        static_transmittance
+            * self.transmittance(start, end, time, candidates, |id| !self.is_static[id])
+    }
+
+    /// `test_occluded` for any number of separate `(start, end, time)` segments, with the
+    /// transmittances written to `results` in the same order. Segments are tested four at a
+    /// time no matter which paths or lights they came from, so shadow rays of terminated
+    /// lanes don't take up room.
+    pub fn test_occluded_batch(
+        &self,
+        segments: &[(Vec3, Vec3, f32)],
+        results: &mut [Srgb],
+        candidates: &mut BumpVec<usize>,
+    ) {
+        let packets = segments.chunks(LANES).zip(results.chunks_mut(LANES));
+        for (packet, results) in packets {
+            // the last packet is padded with its first segment, and those results are dropped
+            let lane = |i: usize| packet.get(i).unwrap_or(&packet[0]);
+            let start = Wec3::from([lane(0).0, lane(1).0, lane(2).0, lane(3).0]);
+            let end = Wec3::from([lane(0).1, lane(1).1, lane(2).1, lane(3).1]);
+            let time = f32x4::from([lane(0).2, lane(1).2, lane(2).2, lane(3).2]);
+            let transmittances: [Srgb; 4] = self.test_occluded(start, end, time, candidates).into();
+            results.copy_from_slice(&transmittances[..results.len()]);
+        }
+    }
+
+    /// Same as `test_occluded`, only through the hitables for which `include` is true.
+    fn transmittance<F: Fn(usize) -> bool>(
+        &self,
+        start: Wec3,
+        end: Wec3,
+        time: f32x4,
+        candidates: &mut BumpVec<usize>,
+        include: F,
+    ) -> WSrgb {
+        let (ray, dist) = segment_ray(start, end, time);
+        let accelerated = self.candidates(&ray, f32x4::ZERO..dist, candidates);
+        // every hitable when there's no acceleration structure
+        let ids = (0..if accelerated {
+            candidates.len()
+        } else {
+            self.len()
         })
+            .map(|i| if accelerated { candidates[i] } else { i })
+            .filter(|id| self.visible(*id, RayKind::Shadow) && include(*id));
+
+        let mut acc = WSrgb::one();
+        for hitable_id in ids {
+            acc = acc * self[hitable_id].transmittance(start, end, time);
+            // lanes can be blocked by different hitables, so only stop once all of them are
+            if acc.max_channel().cmp_eq(f32x4::ZERO).all() {
+                break;
+            }
+        }
+        acc
     }
 
+    /// Distance from `start` to the first thing blocking the segment to `end`, or infinity
+    /// in the lanes where nothing does. Unlike `test_occluded` there's no transmission:
+    /// anything in the way counts as a blocker.
+    #[allow(dead_code)]
+    pub fn occlusion_distance(
+        &self,
+        start: Wec3,
+        end: Wec3,
+        time: f32x4,
+        candidates: &mut BumpVec<usize>,
+    ) -> f32x4 {
+        let (ray, dist) = segment_ray(start, end, time);
+        let t_min = float_error_bound(&ray, f32x4::ZERO);
+        let closest_hit = |closest: f32x4, hitable_id: usize| {
+            self[hitable_id].hit(&ray, t_min..closest).min(closest)
+        };
+        let closest = if self.candidates(&ray, t_min..dist, candidates) {
+            candidates
+                .iter()
+                .copied()
+                .filter(|id| self.visible(*id, RayKind::Shadow))
+                .fold(dist, closest_hit)
+        } else {
+            (0..self.len())
+                .filter(|id| self.visible(*id, RayKind::Shadow))
+                .fold(dist, closest_hit)
+        };
+        f32x4::merge(
+            closest.cmp_lt(dist),
+            closest,
+            f32x4::from(std::f32::INFINITY),
+        )
+    }
+
+    /// Finds the closest hit of each ray with the hitables visible to rays of `kind`.
     pub fn add_hits(
         &self,
         ray: WRay,
         t_ranges: ::std::ops::Range<f32x4>,
+        kind: RayKind,
         hit_store: &mut HitStore,
     ) {
-        let (ids, dists) = self.iter().enumerate().fold(
-            ([std::usize::MAX; 4], t_ranges.end),
-            |acc, (hitable_id, hitable)| {
-                let (mut closest_ids, mut closest) = acc;
-
-                let t = hitable.hit(&ray, t_ranges.start..closest);
-
-                for ((t, closest), closest_id) in t
-                    .as_ref()
-                    .iter()
-                    .zip(closest.as_mut().iter_mut())
-                    .zip(closest_ids.iter_mut())
-                {
-                    if *t < *closest {
-                        *closest = *t;
-                        *closest_id = hitable_id;
-                    }
+        let none = (
+            [std::usize::MAX; 4],
+            t_ranges.end,
+            [HitAttributes::none(); 4],
+        );
+        let closest_hit = |acc: ([usize; 4], f32x4, [HitAttributes; 4]), hitable_id: usize| {
+            let (mut closest_ids, mut closest, mut closest_attributes) = acc;
+
+            let (t, attributes) =
+                self[hitable_id].hit_with_attributes(&ray, t_ranges.start..closest);
+            let attributes: [HitAttributes; 4] = attributes.into();
+
+            for (((t, closest), closest_id), (attributes, closest_attributes)) in t
+                .as_ref()
+                .iter()
+                .zip(closest.as_mut().iter_mut())
+                .zip(closest_ids.iter_mut())
+                .zip(attributes.iter().zip(closest_attributes.iter_mut()))
+            {
+                if *t < *closest {
+                    *closest = *t;
+                    *closest_id = hitable_id;
+                    *closest_attributes = *attributes;
                 }
+            }
 
-                (closest_ids, closest)
-            },
-        );
+            (closest_ids, closest, closest_attributes)
+        };
+        let candidates = &mut hit_store.candidates;
+        let (ids, dists, attributes) = if self.candidates(&ray, t_ranges.clone(), candidates) {
+            candidates
+                .iter()
+                .copied()
+                .filter(|id| self.visible(*id, kind))
+                .fold(none, closest_hit)
+        } else {
+            (0..self.len())
+                .filter(|id| self.visible(*id, kind))
+                .fold(none, closest_hit)
+        };
 
         let rays: [Ray; 4] = ray.into();
         let dists = dists.as_ref();
 
-        for ((id, ray), t) in ids.iter().zip(rays.iter()).zip(dists.iter()) {
-            if *id < std::usize::MAX && ray.valid {
+        for (((id, ray), t), attributes) in ids
+            .iter()
+            .zip(rays.iter())
+            .zip(dists.iter())
+            .zip(attributes.iter())
+        {
+            if !ray.valid {
+                continue;
+            }
+
+            if *id == std::usize::MAX {
+                hit_store.add_miss(*ray);
+            } else {
                 unsafe {
-                    hit_store.add_hit(*id, Hit { ray: *ray, t: *t });
+                    hit_store.add_hit(
+                        *id,
+                        Hit {
+                            ray: *ray,
+                            t: *t,
+                            attributes: *attributes,
+                        },
+                    );
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sphere::Sphere;
+
+    #[test]
+    fn push_skips_ids_taken_by_push_with_id() {
+        let mut store = HitableStore::new();
+        store.push_with_id(Tombstone, 1).unwrap();
+        let first = store.push(Tombstone);
+        let second = store.push(Tombstone);
+
+        let mut ids = vec![store.id(0), store.id(first.0), store.id(second.0)];
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 3);
+        assert!(store.push_with_id(Tombstone, store.id(second.0)).is_err());
+    }
+
+    #[test]
+    fn replace_only_unhides_removed_slots() {
+        let mut store = HitableStore::new();
+        let hidden = store.push(Tombstone);
+        let removed = store.push(Tombstone);
+        store.set_visibility(hidden, Visibility::NONE);
+        store.remove(removed);
+
+        store.replace(hidden, Tombstone);
+        store.replace(removed, Tombstone);
+        assert!(store.visibility[hidden.0] == Visibility::NONE);
+        assert!(store.visibility[removed.0] == Visibility::ALL);
+    }
+
+    /// A field of opaque and tinted spheres, for rays to pass through.
+    fn sphere_field() -> HitableStore {
+        let mut store = HitableStore::new();
+        for i in 0..25 {
+            let center = Vec3::new((i % 5) as f32 - 2.0, (i / 5) as f32 - 2.0, 0.0) * 1.5;
+            let sphere = Sphere::new(center, 0.5, MaterialHandle(0));
+            if i % 3 == 0 {
+                let tint = Srgb::new(0.5, 0.8, 0.9);
+                store.push(ThinGlass {
+                    hitable: sphere,
+                    tint,
+                });
+            } else {
+                store.push(sphere);
+            }
+        }
+        store
+    }
+
+    /// The `i`th of a spread of segments crossing `sphere_field`.
+    fn segment(i: usize) -> (Vec3, Vec3) {
+        let angle = i as f32 * 0.37;
+        let start = Vec3::new(angle.cos(), angle.sin(), (i as f32).sin() * 0.4) * 6.0;
+        let offset = Vec3::new((3.0 * angle).cos(), (5.0 * angle).sin(), 0.0) * 2.0;
+        (start, offset - start)
+    }
+
+    fn assert_close(a: Srgb, b: Srgb) {
+        let diff = a - b;
+        assert!([diff.x, diff.y, diff.z].iter().all(|d| d.abs() < 1e-6));
+    }
+
+    #[test]
+    fn batched_shadow_rays_match_test_occluded() {
+        let store = sphere_field();
+        // not a multiple of the packet size, so the last packet is padded
+        let segments = (0..23)
+            .map(|i| {
+                let (start, end) = segment(i);
+                (start, end, 0.0)
+            })
+            .collect::<Vec<_>>();
+        let mut results = vec![Srgb::zero(); segments.len()];
+        let bump = Bump::new();
+        let mut candidates = BumpVec::new_in(&bump);
+        store.test_occluded_batch(&segments, &mut results, &mut candidates);
+        // otherwise the zip below would quietly skip segments
+        assert_eq!(segments.len(), results.len());
+
+        for ((start, end, _), result) in segments.iter().zip(results) {
+            let (start, end) = (Wec3::splat(*start), Wec3::splat(*end));
+            let single = store.test_occluded(start, end, f32x4::ZERO, &mut candidates);
+            let single: [Srgb; 4] = single.into();
+            assert_close(result, single[0]);
+        }
+    }
+
+    /// Packet of the segments `i..i + 4`.
+    fn segment_packet(i: usize) -> (Wec3, Wec3) {
+        let segments = [segment(i), segment(i + 1), segment(i + 2), segment(i + 3)];
+        (
+            Wec3::from([segments[0].0, segments[1].0, segments[2].0, segments[3].0]),
+            Wec3::from([segments[0].1, segments[1].1, segments[2].1, segments[3].1]),
+        )
+    }
+
+    /// Checks that `accelerated` finds the same blockers as testing every hitable.
+    fn assert_matches_a_linear_scan(accelerated: &HitableStore) {
+        let linear = sphere_field();
+        let bump = Bump::new();
+        let mut candidates = BumpVec::new_in(&bump);
+        for i in (0..64).step_by(4) {
+            let (start, end) = segment_packet(i);
+            let mut distances = |store: &HitableStore| {
+                let distances = store.occlusion_distance(start, end, f32x4::ZERO, &mut candidates);
+                let distances: &[f32; 4] = distances.as_ref();
+                *distances
+            };
+            assert_eq!(distances(accelerated), distances(&linear));
+
+            let mut transmittances = |store: &HitableStore| {
+                let transmittances = store.test_occluded(start, end, f32x4::ZERO, &mut candidates);
+                let transmittances: [Srgb; 4] = transmittances.into();
+                transmittances
+            };
+            for (a, b) in transmittances(accelerated)
+                .iter()
+                .zip(&transmittances(&linear))
+            {
+                assert_close(*a, *b);
+            }
+        }
+    }
+
+    #[test]
+    fn bvh_matches_a_linear_scan() {
+        let mut bvh = sphere_field();
+        bvh.build_bvh(0.0, 1.0);
+        assert_matches_a_linear_scan(&bvh);
+    }
+
+    #[test]
+    fn grid_matches_a_linear_scan() {
+        let mut grid = sphere_field();
+        grid.build_grid(0.0, 1.0);
+        assert_matches_a_linear_scan(&grid);
+    }
+
+    #[test]
+    fn early_out_matches_the_full_fold() {
+        let store = sphere_field();
+        let bump = Bump::new();
+        let mut candidates = BumpVec::new_in(&bump);
+        for i in (0..64).step_by(4) {
+            let (start, end) = segment_packet(i);
+            let full = store.iter().fold(WSrgb::one(), |acc, hitable| {
+                acc * hitable.transmittance(start, end, f32x4::ZERO)
+            });
+            let full: [Srgb; 4] = full.into();
+            let early_out = store.test_occluded(start, end, f32x4::ZERO, &mut candidates);
+            let early_out: [Srgb; 4] = early_out.into();
+            for (a, b) in early_out.iter().zip(&full) {
+                assert_close(*a, *b);
+            }
+        }
+    }
+
+    #[test]
+    fn bounces_are_repacked_by_what_they_hit() {
+        let mut store = HitableStore::new();
+        store.push(Sphere::new(
+            Vec3::new(-2.0, 0.0, 0.0),
+            1.0,
+            MaterialHandle(0),
+        ));
+        store.push(Sphere::new(
+            Vec3::new(2.0, 0.0, 0.0),
+            1.0,
+            MaterialHandle(1),
+        ));
+        let camera = crate::camera::OrthographicCamera::new(
+            Vec2::new(4.0, 4.0),
+            4.0,
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::zero(),
+            Vec3::unit_y(),
+        );
+
+        // the lanes of every packet alternate between the two spheres
+        let (left, right) = (Vec3::new(-2.0, 0.0, 5.0), Vec3::new(2.0, 0.0, 5.0));
+        let bump = Bump::new();
+        let mut hit_store = HitStore::from_hitable_store(&bump, &store);
+        for _ in 0..2 {
+            let ray = WRay::new(
+                Wec3::from([left, right, left, right]),
+                Wec3::splat(-Vec3::unit_z()),
+                f32x4::ZERO,
+                [Vec2u::zero(); 4],
+                [true; 4],
+                [0.0; 4],
+                [0; 4],
+            );
+            let t_range = f32x4::ZERO..f32x4::from(10.0);
+            store.add_hits(ray, t_range, RayKind::Reflection, &mut hit_store);
+        }
+        let mut wintersections = BumpVec::new_in(&bump);
+        hit_store.process_hits(&store, &mut wintersections, false, &camera, f32x4::ZERO);
+
+        // so shading sees one full packet per material instead of two half-empty ones each
+        let packets = wintersections
+            .iter()
+            .map(|(obj_id, mat_id, point)| (*obj_id, mat_id.0, point.ray.valid))
+            .collect::<Vec<_>>();
+        assert_eq!(packets, vec![(0, 0, [true; 4]), (1, 1, [true; 4])]);
+    }
+
+    #[test]
+    fn invalid_lanes_have_no_point() {
+        let ray = Ray::new(
+            Vec3::new(0.0, 0.0, 5.0),
+            -Vec3::unit_z(),
+            0.0,
+            Vec2u::zero(),
+            0.0,
+            0,
+        );
+        let hit = Hit {
+            ray,
+            t: 4.0,
+            attributes: HitAttributes::none(),
+        };
+        // unlike `Hit::new_invalid`, this padding has a plausible looking origin
+        let mut padding = Hit { t: 0.0, ..hit };
+        padding.ray.valid = false;
+        let hits = WHit::from([hit, padding, hit, Hit::new_invalid()]);
+
+        let points: [Vec3; 4] = hits.point().into();
+        assert_eq!(points[0], Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(points[2], Vec3::new(0.0, 0.0, 1.0));
+        assert!(points[1].x.is_nan() && points[3].x.is_nan());
+
+        let expected = [true, false, true, false];
+        let valid = hits.is_valid().move_mask();
+        let point = WShadingPoint::new(hits, hits.point(), f32x4::ZERO, Wec3::unit_z());
+        let active = point.active.move_mask();
+        for (lane, expected) in expected.iter().enumerate() {
+            assert_eq!(valid & (1 << lane) != 0, *expected);
+            assert_eq!(active & (1 << lane) != 0, *expected);
+        }
+    }
+
+    #[test]
+    fn error_bounds_grow_with_distance() {
+        let camera = crate::camera::ThinLensCamera::new(
+            Vec2::new(64.0, 64.0),
+            40f32,
+            0f32,
+            Vec3::zero(),
+            -Vec3::unit_z(),
+            Vec3::unit_y(),
+            Vec3::zero(),
+        );
+        // no differentials, so primary hits fall back to the camera's pixel size
+        let bound = |t: f32, primary: bool| {
+            let hit = WHit {
+                ray: WRay::along(Vec3::zero(), -Vec3::unit_z()),
+                t: f32x4::from(t),
+                attributes: WHitAttributes::none(),
+            };
+            intersection_error_bound(&hit, primary, &camera).as_ref()[0]
+        };
+
+        let pixel_size = 2.0 * 20f32.to_radians().tan() / 64.0;
+        for t in [1.0, 10.0, 100.0].iter() {
+            // the footprint is half a pixel across at that distance
+            assert!((bound(*t, true) / (0.5 * pixel_size * t) - 1.0).abs() < 1e-3);
+            assert!(bound(*t, false) < bound(*t, true));
+        }
+        assert!(bound(100.0, false) > bound(1.0, false));
+    }
+
+    #[test]
+    fn unbounded_hitables_are_left_out_of_the_scene_bounds() {
+        // like an infinite plane, a tombstone has no bounding box
+        let mut store = HitableStore::new();
+        store.push(Tombstone);
+        assert!(store.scene_bounds(0.0, 1.0).is_none());
+        assert_eq!(store.scene_epsilon(0.0, 1.0), DEFAULT_SCENE_EPSILON);
+
+        store.push(Sphere::new(Vec3::unit_x(), 0.5, MaterialHandle(0)));
+        let bounds = store.scene_bounds(0.0, 1.0).unwrap();
+        assert!((bounds.min - Vec3::new(0.5, -0.5, -0.5)).mag() < 1e-5);
+        assert!((bounds.max - Vec3::new(1.5, 0.5, 0.5)).mag() < 1e-5);
+    }
+
+    #[test]
+    fn scene_epsilons_scale_with_the_scene() {
+        // two spheres spanning 10 units, scaled up by `scale`
+        let scene = |scale: f32| {
+            let mut store = HitableStore::new();
+            for x in [-4.5, 4.5].iter() {
+                let center = Vec3::new(*x, 0.0, 0.0) * scale;
+                store.push(Sphere::new(center, 0.5 * scale, MaterialHandle(0)));
+            }
+            let diagonal = store.scene_bounds(0.0, 1.0).unwrap().size().mag();
+            (store.scene_epsilon(0.0, 1.0), diagonal)
+        };
+
+        // ordinary scenes get the floor rather than a fraction of their size
+        assert_eq!(scene(1.0).0, DEFAULT_SCENE_EPSILON);
+        for scale in [1e3, 1e5].iter() {
+            let (epsilon, diagonal) = scene(*scale);
+            assert!(epsilon >= DEFAULT_SCENE_EPSILON);
+            assert!((epsilon - diagonal * SCENE_EPSILON_SCALE).abs() <= epsilon * 1e-5);
+        }
+        assert!((scene(1e5).0 / scene(1e3).0 - 100.0).abs() < 1e-2);
+    }
+
+    #[test]
+    fn occlusion_distances_reach_the_first_blocker() {
+        let mut store = HitableStore::new();
+        store.push(Sphere::new(Vec3::zero(), 1.0, MaterialHandle(0)));
+        // a ThinGlass blocks too, even though shadow rays would pass through it tinted
+        store.push(ThinGlass {
+            hitable: Sphere::new(Vec3::new(0.0, 3.0, 0.0), 1.0, MaterialHandle(0)),
+            tint: Srgb::new(0.5, 0.5, 0.5),
+        });
+
+        let starts = Wec3::from([
+            Vec3::new(-5.0, 0.0, 0.0),
+            Vec3::new(-5.0, 3.0, 0.0),
+            // passing between the two, and stopping short of the sphere
+            Vec3::new(-5.0, 1.5, 0.0),
+            Vec3::new(-5.0, 0.0, 0.0),
+        ]);
+        let ends = Wec3::from([
+            Vec3::new(5.0, 0.0, 0.0),
+            Vec3::new(5.0, 3.0, 0.0),
+            Vec3::new(5.0, 1.5, 0.0),
+            Vec3::new(-2.0, 0.0, 0.0),
+        ]);
+        let bump = Bump::new();
+        let mut candidates = BumpVec::new_in(&bump);
+        let distances = store.occlusion_distance(starts, ends, f32x4::ZERO, &mut candidates);
+        let distances = distances.as_ref();
+        assert!((distances[0] - 4.0).abs() < 1e-4);
+        assert!((distances[1] - 4.0).abs() < 1e-4);
+        assert_eq!(distances[2], std::f32::INFINITY);
+        assert_eq!(distances[3], std::f32::INFINITY);
+    }
+
+    #[test]
+    fn rays_leave_by_the_geometric_normal_but_shade_by_the_shading_normal() {
+        let hit = WHit::from(
+            [Hit {
+                ray: Ray::new(Vec3::unit_z(), -Vec3::unit_z(), 0.0, Vec2u::zero(), 0.0, 0),
+                t: 1.0,
+                attributes: HitAttributes::none(),
+            }; 4],
+        );
+        let shading_normal = Vec3::new(0.6, 0.0, 0.8);
+        let offset = 0.01;
+        let point = WShadingPoint::new(hit, Wec3::zero(), f32x4::from(offset), Wec3::unit_z())
+            .with_shading_normal(Wec3::splat(shading_normal));
+
+        let normals: [Vec3; 4] = point.normal.into();
+        let basis_normals: [Vec3; 4] = point.basis.cols[2].into();
+        assert_eq!(normals[0], shading_normal);
+        assert_eq!(basis_normals[0], shading_normal);
+
+        // above the surface but below the shading normal's hemisphere, so offsetting along
+        // the shading normal would push the ray through the surface
+        let dir = Vec3::new(-0.8, 0.0, 0.2).normalized();
+        assert!(dir.dot(shading_normal) < 0.0);
+        let origins: [Vec3; 4] = point.create_rays(Wec3::splat(dir)).origin.into();
+        assert!((origins[0] - Vec3::new(0.0, 0.0, offset)).mag() < 1e-6);
+
+        // and into the surface the other way
+        let origins: [Vec3; 4] = point.create_rays(Wec3::splat(-dir)).origin.into();
+        assert!((origins[0] - Vec3::new(0.0, 0.0, -offset)).mag() < 1e-6);
+    }
+
+    #[test]
+    fn flipped_spheres_have_inward_normals() {
+        let camera = crate::camera::OrthographicCamera::new(
+            Vec2::new(8.0, 8.0),
+            4.0,
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::zero(),
+            Vec3::unit_y(),
+        );
+        let sphere = || Sphere::new(Vec3::zero(), 1.0, MaterialHandle(0));
+        let ray = WRay::along(Vec3::new(0.3, -0.2, 5.0), -Vec3::unit_z());
+        let range = f32x4::ZERO..f32x4::from(std::f32::MAX);
+        let shading_point = |hitable: &dyn Hitable| {
+            let (t, attributes) = hitable.hit_with_attributes(&ray, range.clone());
+            let hit = WHit { ray, t, attributes };
+            let (_, point) = hitable.get_shading_info(hit, true, &camera);
+            let (points, normals, geom_normals): ([Vec3; 4], [Vec3; 4], [Vec3; 4]) = (
+                point.point.into(),
+                point.normal.into(),
+                point.geom_normal.into(),
+            );
+            (points[0], normals[0], geom_normals[0])
+        };
+
+        let (point, normal, _) = shading_point(&sphere());
+        let (flipped_point, flipped_normal, flipped_geom_normal) =
+            shading_point(&FlipNormals(sphere()));
+        assert!((flipped_point - point).mag() < 1e-6);
+        // the center of a unit sphere is one normal's length behind the surface
+        assert!((flipped_normal - -point).mag() < 1e-5);
+        assert!((flipped_normal + normal).mag() < 1e-6);
+        assert!((flipped_geom_normal - flipped_normal).mag() < 1e-6);
+    }
+
+    #[test]
+    fn invalid_packets_have_no_valid_lanes() {
+        let ray = WRay::new_invalid();
+        assert_eq!(ray.valid, [false; 4]);
+        assert_eq!(ray.valid_mask().move_mask(), 0);
+
+        // built directly, it's as invalid as a packet of scalar padding
+        let padded = WHit::from([Hit::new_invalid(); 4]);
+        for hits in [WHit::new_invalid(), padded].iter() {
+            assert_eq!(hits.ray.valid, [false; 4]);
+            assert_eq!(hits.is_valid().move_mask(), 0);
+            let points: [Vec3; 4] = hits.point().into();
+            assert!(points.iter().all(|point| point.x.is_nan()));
+        }
+    }
+
+    #[test]
+    fn shadow_rays_through_red_glass_come_out_red() {
+        let red = Srgb::new(0.9, 0.1, 0.1);
+        let mut store = HitableStore::new();
+        store.push(ThinGlass {
+            hitable: Sphere::new(Vec3::zero(), 1.0, MaterialHandle(0)),
+            tint: red,
+        });
+        store.push(Sphere::new(
+            Vec3::new(0.0, 3.0, 0.0),
+            1.0,
+            MaterialHandle(0),
+        ));
+
+        let starts = Wec3::from([
+            Vec3::new(-5.0, 0.0, 0.0),
+            Vec3::new(-5.0, 3.0, 0.0),
+            Vec3::new(-5.0, -3.0, 0.0),
+            // through the glass and then the opaque sphere
+            Vec3::new(0.0, -5.0, 0.0),
+        ]);
+        let ends = Wec3::from([
+            Vec3::new(5.0, 0.0, 0.0),
+            Vec3::new(5.0, 3.0, 0.0),
+            Vec3::new(5.0, -3.0, 0.0),
+            Vec3::new(0.0, 5.0, 0.0),
+        ]);
+        let bump = Bump::new();
+        let mut candidates = BumpVec::new_in(&bump);
+        let transmittances = store.test_occluded(starts, ends, f32x4::ZERO, &mut candidates);
+        let transmittances: [Srgb; 4] = transmittances.into();
+
+        // tinted once for the whole object, even though the segment enters and leaves it
+        assert!((transmittances[0].0 - red.0).mag() < 1e-6);
+        assert_eq!(transmittances[1].0, Vec3::zero());
+        assert_eq!(transmittances[2].0, Vec3::broadcast(1.0));
+        assert_eq!(transmittances[3].0, Vec3::zero());
+    }
+
+    #[test]
+    fn handles_are_positions_in_the_store() {
+        let centers = [Vec3::zero(), Vec3::unit_x() * 4.0, Vec3::unit_y() * -4.0];
+        let sphere = |i: usize| Sphere::new(centers[i], 1.0, MaterialHandle(0));
+        let mut store = HitableStore::new();
+        let handles = [
+            store.push(sphere(0)),
+            store.push_with_id(sphere(1), 7).unwrap(),
+            store.push(sphere(2)),
+        ];
+        assert_eq!(
+            handles,
+            [HitableHandle(0), HitableHandle(1), HitableHandle(2)]
+        );
+
+        for (handle, center) in handles.iter().zip(centers.iter()) {
+            let bounds = store.get(*handle).bounding_box(0.0, 1.0).unwrap();
+            assert!(((bounds.min + bounds.max) * 0.5 - *center).mag() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn hits_are_padded_to_whole_packets() {
+        let mut store = HitableStore::new();
+        store.push(Sphere::new(
+            Vec3::new(-2.0, 0.0, 0.0),
+            1.0,
+            MaterialHandle(0),
+        ));
+        store.push(Sphere::new(
+            Vec3::new(2.0, 0.0, 0.0),
+            1.0,
+            MaterialHandle(1),
+        ));
+        let camera = crate::camera::OrthographicCamera::new(
+            Vec2::new(4.0, 4.0),
+            4.0,
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::zero(),
+            Vec3::unit_y(),
+        );
+
+        // five hits on the left sphere, two on the right one and a miss
+        let (left, right) = (Vec3::new(-2.0, 0.0, 5.0), Vec3::new(2.0, 0.0, 5.0));
+        let miss = Vec3::new(10.0, 0.0, 5.0);
+        let bump = Bump::new();
+        let mut hit_store = HitStore::from_hitable_store(&bump, &store);
+        for origins in [[left; LANES], [left, right, right, miss]].iter() {
+            let ray = WRay::new(
+                Wec3::from(*origins),
+                Wec3::splat(-Vec3::unit_z()),
+                f32x4::ZERO,
+                [Vec2u::zero(); 4],
+                [true; 4],
+                [0.0; 4],
+                [0; 4],
+            );
+            let t_range = f32x4::ZERO..f32x4::from(10.0);
+            store.add_hits(ray, t_range, RayKind::Reflection, &mut hit_store);
+        }
+        let mut wintersections = BumpVec::new_in(&bump);
+        hit_store.process_hits(&store, &mut wintersections, false, &camera, f32x4::ZERO);
+
+        let packets = wintersections
+            .iter()
+            .map(|(obj_id, _, point)| (*obj_id, point.ray.valid))
+            .collect::<Vec<_>>();
+        let expected = vec![
+            (0, [true; 4]),
+            (0, [true, false, false, false]),
+            (1, [true, true, false, false]),
+        ];
+        assert_eq!(packets, expected);
+    }
+
+    #[test]
+    fn material_overrides_reach_every_shading_point() {
+        let mut store = HitableStore::new();
+        store.push(Sphere::new(
+            Vec3::new(-2.0, 0.0, 0.0),
+            1.0,
+            MaterialHandle(0),
+        ));
+        store.push(Sphere::new(
+            Vec3::new(2.0, 0.0, 0.0),
+            1.0,
+            MaterialHandle(1),
+        ));
+        store.set_material_override(Some(MaterialHandle(2)));
+        let camera = crate::camera::OrthographicCamera::new(
+            Vec2::new(4.0, 4.0),
+            4.0,
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::zero(),
+            Vec3::unit_y(),
+        );
+
+        let (left, right) = (Vec3::new(-2.0, 0.0, 5.0), Vec3::new(2.0, 0.0, 5.0));
+        let bump = Bump::new();
+        let mut hit_store = HitStore::from_hitable_store(&bump, &store);
+        let ray = WRay::new(
+            Wec3::from([left, right, left, right]),
+            Wec3::splat(-Vec3::unit_z()),
+            f32x4::ZERO,
+            [Vec2u::zero(); 4],
+            [true; 4],
+            [0.0; 4],
+            [0; 4],
+        );
+        let t_range = f32x4::ZERO..f32x4::from(10.0);
+        store.add_hits(ray, t_range.clone(), RayKind::Camera, &mut hit_store);
+        let mut wintersections = BumpVec::new_in(&bump);
+        hit_store.process_hits(&store, &mut wintersections, true, &camera, f32x4::ZERO);
+
+        assert_eq!(wintersections.len(), 2);
+        assert!(wintersections
+            .iter()
+            .all(|(_, mat_id, _)| *mat_id == MaterialHandle(2)));
+
+        // and clearing it brings back each hitable's own material
+        store.set_material_override(None);
+        let mut hit_store = HitStore::from_hitable_store(&bump, &store);
+        store.add_hits(ray, t_range, RayKind::Camera, &mut hit_store);
+        let mut wintersections = BumpVec::new_in(&bump);
+        hit_store.process_hits(&store, &mut wintersections, true, &camera, f32x4::ZERO);
+        let materials = wintersections
+            .iter()
+            .map(|(_, mat_id, _)| mat_id.0)
+            .collect::<Vec<_>>();
+        assert_eq!(materials, vec![0, 1]);
+    }
+
+    #[test]
+    fn camera_invisible_hitables_still_cast_shadows() {
+        let mut store = HitableStore::new();
+        let sphere = store.push(Sphere::new(Vec3::zero(), 1.0, MaterialHandle(0)));
+        store.set_visibility(
+            sphere,
+            Visibility {
+                camera: false,
+                ..Visibility::ALL
+            },
+        );
+
+        let ray = WRay::along(Vec3::new(0.0, 0.0, 5.0), -Vec3::unit_z());
+        let t_range = f32x4::ZERO..f32x4::from(10.0);
+        let bump = Bump::new();
+        let hit_counts = |kind| {
+            let mut hit_store = HitStore::from_hitable_store(&bump, &store);
+            store.add_hits(ray, t_range.clone(), kind, &mut hit_store);
+            LANES - hit_store.misses().len()
+        };
+        assert_eq!(hit_counts(RayKind::Camera), 0);
+        assert_eq!(hit_counts(RayKind::Reflection), LANES);
+
+        let mut candidates = BumpVec::new_in(&bump);
+        let start = Wec3::splat(Vec3::new(0.0, 0.0, 5.0));
+        let end = Wec3::splat(Vec3::new(0.0, 0.0, -5.0));
+        let transmittance: [Srgb; 4] = store
+            .test_occluded(start, end, f32x4::ZERO, &mut candidates)
+            .into();
+        assert!(transmittance.iter().all(|t| t.max_channel() == 0.0));
+    }
+
+    #[test]
+    fn cached_shadows_match_traced_ones() {
+        // small enough cells that no two of the segments share a key
+        let mut cached = sphere_field();
+        for i in (0..25).step_by(2) {
+            cached.set_static(HitableHandle(i), true);
+        }
+        cached.set_occlusion_cache(Some(1e-3));
+        // the first pass fills the cache and the second one reads it
+        assert_matches_a_linear_scan(&cached);
+        assert_matches_a_linear_scan(&cached);
+    }
+
+    #[test]
+    fn lights_are_only_the_emitters() {
+        let sphere = |x: f32| Sphere::new(Vec3::unit_x() * x, 1.0, MaterialHandle(0));
+        let emitter = |x: f32| Emitter {
+            hitable: sphere(x),
+            emission: Vec3::broadcast(4.0),
+        };
+        let mut store = HitableStore::new();
+        store.push(sphere(0.0));
+        let light = store.push(emitter(3.0));
+        store.push(sphere(6.0));
+        let hidden = store.push(emitter(9.0));
+        let removed = store.push(emitter(12.0));
+        let glass = store.push(ThinGlass {
+            hitable: emitter(15.0),
+            tint: Srgb::new(0.5, 0.5, 0.5),
+        });
+        store.set_visibility(hidden, Visibility::NONE);
+        store.remove(removed);
+
+        // wrapped emitters still count
+        assert_eq!(store.lights(), vec![light, glass]);
+    }
+}
@@ -2,10 +2,13 @@ use bumpalo::collections::Vec as BumpVec;
 use bumpalo::Bump;
 
 use crate::film::ChannelSample;
-use crate::hitable::WShadingPoint;
-use crate::material::{MaterialHandle, BSDF};
-use crate::math::{f32x4, Vec2u, Vec3};
-use crate::ray::Ray;
+use crate::hitable::{float_error_bound, WShadingPoint};
+use crate::material::{MaterialHandle, Sided, BSDF};
+use crate::math::{
+    f32x4, saturate, shadow_terminator, BasisTransform, ComponentWise, Mask, RandomSample3d, Vec2u,
+    Vec3, Wec3,
+};
+use crate::ray::{Lobe, Ray, WRay};
 use crate::spectrum::{Srgb, WSrgb};
 use crate::world::World;
 
@@ -24,16 +27,141 @@ pub trait Integrator: Send + Sync {
         output_samples: &mut BumpVec<(Vec2u, ChannelSample)>,
     );
 
+    /// Called for each valid ray which escaped the scene without hitting anything.
+    fn miss(
+        &self,
+        ray: &Ray,
+        depth: usize,
+        background: Srgb,
+        output_samples: &mut BumpVec<(Vec2u, ChannelSample)>,
+    ) {
+        if depth == 0 {
+            output_samples.push((ray.tile_coord, ChannelSample::Background(background)));
+        } else {
+            push_color_sample(
+                ray,
+                ray.radiance + background * ray.throughput,
+                output_samples,
+            );
+        }
+    }
+
     fn requested_1d_sample_sets(&self) -> usize;
     fn requested_2d_sample_sets(&self) -> usize;
 }
 
+/// What decides how likely a path is to be terminated by russian roulette.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RouletteHeuristic {
+    /// The largest channel of the throughput accumulated along the path.
+    Throughput,
+    /// The luminance of the albedo at the current hit, so paths bouncing around strongly
+    /// colored surfaces aren't cut short because their other channels died out. Uses the
+    /// sampled BSDF weight for BSDFs which don't know their albedo.
+    Albedo,
+}
+
+/// Limit on the luminance that light can contribute to a path, which tightens with the
+/// number of bounces the light took to get to the camera. Fireflies mostly come from rare
+/// deep paths, so those can be clamped hard while direct lighting keeps its highlights.
+#[derive(Clone, Copy, Debug)]
+pub struct DepthClamp {
+    /// Limit for light reflected towards the camera off of the first hit
+    pub direct: f32,
+    /// Factor the limit is multiplied by for every further bounce, at most 1
+    pub falloff: f32,
+}
+
+impl DepthClamp {
+    #[allow(dead_code)]
+    pub fn new(direct: f32, falloff: f32) -> Self {
+        Self { direct, falloff }
+    }
+
+    /// Largest luminance allowed for light which bounced `bounces` times after the first
+    /// hit on its way to the camera.
+    pub fn limit(&self, bounces: usize) -> f32 {
+        self.direct * self.falloff.powi(bounces as i32)
+    }
+
+    /// Factor bringing light of luminance `luminance` down to the limit if it's above it.
+    /// Light within the limit is left alone, which also keeps a zero limit from dividing
+    /// black by zero.
+    fn scale(&self, luminance: f32, bounces: usize) -> f32 {
+        let limit = self.limit(bounces);
+        if luminance <= limit {
+            1.0
+        } else {
+            limit / luminance
+        }
+    }
+
+    fn wide_scale(&self, luminance: f32x4, bounces: usize) -> f32x4 {
+        let limit = f32x4::from(self.limit(bounces));
+        f32x4::merge(luminance.cmp_le(limit), f32x4::ONE, limit / luminance)
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct PathTracingIntegrator {
     pub max_bounces: usize,
+    /// Also output the diffuse/specular direct/indirect light path expression channels.
+    pub split_light_paths: bool,
+    /// Start shadow rays only the floating point error bound away from the surface rather
+    /// than the full `offset_by`, so that shadows right where objects touch are kept.
+    /// Only safe for primitives whose hit points are exact up to float error (not SDFs).
+    pub contact_shadows: bool,
+    pub roulette: RouletteHeuristic,
+    /// Clamps contributions to reduce fireflies, at the cost of some bias. Light seen
+    /// directly by the camera is never clamped.
+    pub clamp: Option<DepthClamp>,
+    /// Path regularization: glossy surfaces are made at least this fraction as rough as the
+    /// roughest surface the path scattered off of before them, which gets rid of fireflies
+    /// from caustics (like diffuse to mirror paths) at the cost of blurring them a bit.
+    pub regularization: Option<f32>,
+    /// Next event estimation samples taken and averaged at every hit. Each of the first four
+    /// has its own sample sets; more than that reuse those shifted along the R2 sequence.
+    pub light_samples: usize,
+}
+
+impl PathTracingIntegrator {
+    /// Factor which applies `clamp`, if any, to light which took `bounces` bounces after
+    /// the first hit.
+    fn clamp_scale(&self, contribution: WSrgb, bounces: usize) -> f32x4 {
+        match &self.clamp {
+            Some(clamp) => clamp.wide_scale(contribution.luminance(), bounces),
+            None => f32x4::ONE,
+        }
+    }
 }
 
 impl Integrator for PathTracingIntegrator {
+    fn miss(
+        &self,
+        ray: &Ray,
+        depth: usize,
+        background: Srgb,
+        output_samples: &mut BumpVec<(Vec2u, ChannelSample)>,
+    ) {
+        if depth == 0 {
+            output_samples.push((ray.tile_coord, ChannelSample::Background(background)));
+            return;
+        }
+
+        let contribution = background * ray.throughput;
+        let contribution = match &self.clamp {
+            Some(clamp) => contribution * clamp.scale(contribution.luminance(), depth - 1),
+            None => contribution,
+        };
+        push_color_sample(ray, ray.radiance + contribution, output_samples);
+
+        if self.split_light_paths {
+            if let Some(sample) = light_path_sample(ray.first_lobe, depth == 1, contribution) {
+                output_samples.push((ray.tile_coord, sample));
+            }
+        }
+    }
+
     fn requested_1d_sample_sets(&self) -> usize {
         (self.max_bounces + 1) * 3
     }
@@ -54,27 +182,121 @@ impl Integrator for PathTracingIntegrator {
         spawned_rays: &mut BumpVec<Ray>,
         output_samples: &mut BumpVec<(Vec2u, ChannelSample)>,
     ) {
+        // packets are padded with invalid rays, so whole packets can be dead
+        if intersection.active.none() {
+            return;
+        }
+
         let wo = -intersection.ray.dir;
         let material = world.materials.get(material);
+        let sided = material.sided();
+
+        let normal = intersection.normal;
+        if sided == Sided::Two {
+            intersection = intersection.faced_towards(wo);
+        }
 
         let bsdf = material.get_bsdf_at(&intersection, bump);
+        if let Some(strength) = self.regularization {
+            bsdf.regularize(intersection.ray.roughness * f32x4::from(strength));
+        }
 
-        intersection.ray.radiance += bsdf.le(wo, &intersection) * intersection.ray.throughput;
+        let le = visible_emission(sided, wo, normal, bsdf.le(wo, &intersection));
+        let emitted = le * intersection.ray.throughput;
+        let emitted = if depth > 0 {
+            emitted * self.clamp_scale(emitted, depth - 1)
+        } else {
+            emitted
+        };
+        intersection.ray.radiance += emitted;
+
+        if self.split_light_paths && depth > 0 {
+            push_light_path_samples(&intersection.ray, depth == 1, emitted, output_samples);
+        }
 
         if bsdf.receives_light() && world.lights.len() > 0 {
-            let lights = (samples_1d[0] * f32x4::from(world.lights.len() as f32)).floor();
+            let shadow_offset = if self.contact_shadows {
+                float_error_bound(&intersection.ray, intersection.t)
+            } else {
+                intersection.offset_by
+            };
 
-            let lights = lights.as_ref().iter().map(|i| *i as usize);
+            let light_samples = self.light_samples.max(1);
+            // all the shadow rays of the active lanes are traced together afterwards
+            let mut unshadowed = BumpVec::with_capacity_in(light_samples, bump);
+            let mut segments = BumpVec::with_capacity_in(light_samples * 4, bump);
+            let active = intersection.active.move_mask();
+            for k in 0..light_samples {
+                // every lane of the 1D set picks a light, and there's a 2D set for each of
+                // those four. Further rounds of four shift them along the R2 sequence.
+                let (i, round) = (k % 4, f32x4::from((k / 4) as f32));
+                let (lights, light_pmfs) = world
+                    .light_sampler
+                    .sample((samples_1d[0] + round * f32x4::from(0.618_034)).fract());
+                let samples = [
+                    (samples_2d[i * 2] + round * f32x4::from(0.754_877_7)).fract(),
+                    (samples_2d[i * 2 + 1] + round * f32x4::from(0.569_840_3)).fract(),
+                ];
 
-            for (i, light_idx) in lights.enumerate() {
-                // let (i, light_idx) = (0, lights.next().unwrap());
-                intersection.ray.radiance += sample_one_light(
+                let sample = sample_one_light(
                     world,
-                    light_idx,
-                    arrayref::array_ref![samples_2d, 0 + i * 2, 2],
+                    lights[i],
+                    light_pmfs.as_ref()[i] * light_samples as f32,
+                    shadow_offset,
+                    &samples,
                     &intersection,
                     bsdf,
                 );
+                let starts: [Vec3; 4] = sample.shadow_start.into();
+                let ends: [Vec3; 4] = sample.shadow_end.into();
+                let times = intersection.ray.time.as_ref();
+                for lane in (0..4).filter(|lane| active & (1 << *lane) != 0) {
+                    segments.push((starts[lane], ends[lane], times[lane]));
+                }
+                unshadowed.push(sample);
+            }
+
+            let mut transmittances = BumpVec::with_capacity_in(segments.len(), bump);
+            transmittances.resize(segments.len(), Srgb::zero());
+            let mut candidates = BumpVec::new_in(bump);
+            world
+                .hitables
+                .test_occluded_batch(&segments, &mut transmittances, &mut candidates);
+
+            let mut transmittances = transmittances.iter();
+            for sample in unshadowed.iter() {
+                let mut lanes = [Srgb::zero(); 4];
+                for lane in (0..4).filter(|lane| active & (1 << *lane) != 0) {
+                    lanes[lane] = *transmittances.next().unwrap();
+                }
+                let transmittance = WSrgb::from(lanes);
+                let (diffuse, specular) = (
+                    sample.diffuse * transmittance,
+                    sample.specular * transmittance,
+                );
+
+                // both parts are scaled the same, so that it's their sum which is clamped
+                let scale = self.clamp_scale(diffuse + specular, depth);
+                let (diffuse, specular) = (diffuse * scale, specular * scale);
+                intersection.ray.radiance += diffuse + specular;
+
+                if self.split_light_paths {
+                    if depth == 0 {
+                        push_direct_light_samples(
+                            &intersection.ray,
+                            diffuse,
+                            specular,
+                            output_samples,
+                        );
+                    } else {
+                        push_light_path_samples(
+                            &intersection.ray,
+                            false,
+                            diffuse + specular,
+                            output_samples,
+                        );
+                    }
+                }
             }
         }
 
@@ -86,13 +308,24 @@ impl Integrator for PathTracingIntegrator {
         );
 
         if let Some(se) = scattering_event {
-            let ndl = se.wi.dot(intersection.normal).abs();
+            let ndl = se.wi.dot(intersection.normal).abs().min(f32x4::ONE)
+                * shadow_terminator(intersection.geom_normal, intersection.normal, se.wi);
 
-            let mut new_throughput = intersection.ray.throughput * se.f / se.pdf * ndl;
+            let mut new_throughput = WSrgb::merge(
+                intersection.active,
+                intersection.ray.throughput * se.f / se.pdf * ndl,
+                WSrgb::zero(),
+            );
 
             let roulette_factor = if depth > 2 {
-                let roulette_factor =
-                    (f32x4::ONE - intersection.ray.throughput.max_channel()).max(f32x4::from(0.05));
+                let survival = match self.roulette {
+                    RouletteHeuristic::Throughput => intersection.ray.throughput.max_channel(),
+                    RouletteHeuristic::Albedo => bsdf
+                        .albedo()
+                        .unwrap_or_else(|| se.f / se.pdf * ndl)
+                        .luminance(),
+                };
+                let roulette_factor = (f32x4::ONE - survival).max(f32x4::from(0.05));
 
                 new_throughput /= f32x4::ONE - roulette_factor;
 
@@ -101,12 +334,27 @@ impl Integrator for PathTracingIntegrator {
                 f32x4::ZERO
             };
 
-            let mut new_rays: [Ray; 4] = intersection.create_rays(se.wi).into();
+            // anything other than a glossy lobe counts as fully rough
+            let lobe_roughness = bsdf.roughness().unwrap_or(f32x4::ONE);
+            let lobe_roughness = f32x4::merge(se.specular, lobe_roughness, f32x4::ONE);
+            let mut new_rays = intersection.create_rays(se.wi);
+            new_rays.roughness = new_rays.roughness.max(lobe_roughness);
+            let mut new_rays: [Ray; 4] = new_rays.into();
             let throughputs: [Srgb; 4] = new_throughput.into();
 
             if depth == 0 {
                 let normals: [Vec3; 4] = intersection.normal.into();
-                for (ray, normal) in new_rays.iter().zip(normals.iter()) {
+                let specular = f32x4::merge(se.specular, f32x4::ONE, f32x4::ZERO);
+                for ((ray, normal), specular) in new_rays
+                    .iter_mut()
+                    .zip(normals.iter())
+                    .zip(specular.as_ref().iter())
+                {
+                    ray.first_lobe = if *specular > 0.5 {
+                        Lobe::Specular
+                    } else {
+                        Lobe::Diffuse
+                    };
                     if ray.valid {
                         output_samples.push((ray.tile_coord, ChannelSample::Alpha(1.0)));
                         output_samples.push((ray.tile_coord, ChannelSample::WorldNormal(*normal)));
@@ -122,7 +370,7 @@ impl Integrator for PathTracingIntegrator {
             {
                 if ray.valid {
                     if depth >= self.max_bounces || *roulette_sample < *roulette_factor {
-                        output_samples.push((ray.tile_coord, ChannelSample::Color(ray.radiance)));
+                        push_color_sample(ray, ray.radiance, output_samples);
                     } else {
                         if !new_throughput.is_nan() {
                             ray.throughput = *new_throughput;
@@ -137,40 +385,731 @@ impl Integrator for PathTracingIntegrator {
 
             for ray in final_rays.iter() {
                 if ray.valid {
-                    let sample = if depth == 0 {
-                        ChannelSample::Background(ray.radiance)
+                    if depth == 0 {
+                        output_samples
+                            .push((ray.tile_coord, ChannelSample::Background(ray.radiance)));
                     } else {
-                        ChannelSample::Color(ray.radiance)
-                    };
-
-                    output_samples.push((ray.tile_coord, sample));
+                        push_color_sample(ray, ray.radiance, output_samples);
+                    }
                 }
             }
         }
     }
 }
 
+/// Renders just the ambient occlusion at the first hit, for reading the shapes in a scene
+/// without setting up materials or lights: the fraction of `samples` cosine weighted rays
+/// which get `radius` away from the surface without hitting anything.
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub struct AmbientOcclusionIntegrator {
+    pub samples: usize,
+    pub radius: f32,
+}
+
+impl Integrator for AmbientOcclusionIntegrator {
+    fn requested_1d_sample_sets(&self) -> usize {
+        3
+    }
+
+    fn requested_2d_sample_sets(&self) -> usize {
+        6
+    }
+
+    fn integrate(
+        &self,
+        world: &World,
+        _samples_1d: &[f32x4; 3],
+        samples_2d: &[f32x4; 12],
+        _depth: usize,
+        _material: MaterialHandle,
+        intersection: WShadingPoint,
+        bump: &Bump,
+        _spawned_rays: &mut BumpVec<Ray>,
+        output_samples: &mut BumpVec<(Vec2u, ChannelSample)>,
+    ) {
+        if intersection.active.none() {
+            return;
+        }
+
+        let intersection = intersection.faced_towards(-intersection.ray.dir);
+        let start = intersection.point + intersection.geom_normal * intersection.offset_by;
+        let radius = f32x4::from(self.radius);
+
+        let mut candidates = BumpVec::new_in(bump);
+        let mut unoccluded = f32x4::ZERO;
+        for k in 0..self.samples {
+            // the first pair of sample sets, shifted along the R2 sequence for each ray
+            let k = f32x4::from(k as f32);
+            let samples = [
+                (samples_2d[0] + k * f32x4::from(0.754_877_7)).fract(),
+                (samples_2d[1] + k * f32x4::from(0.569_840_3)).fract(),
+            ];
+            let local = Wec3::cosine_weighted_in_hemisphere(&samples);
+            let dir = intersection.basis.to_world(local).normalized();
+            let dist = world.hitables.occlusion_distance(
+                start,
+                start + dir * radius,
+                intersection.ray.time,
+                &mut candidates,
+            );
+            let blocked = dist.cmp_lt(f32x4::from(std::f32::INFINITY));
+            unoccluded += f32x4::merge(blocked, f32x4::ZERO, f32x4::ONE);
+        }
+        let ao = unoccluded / f32x4::from(self.samples.max(1) as f32);
+
+        let rays: [Ray; 4] = intersection.ray.into();
+        for (ray, ao) in rays.iter().zip(ao.as_ref().iter()) {
+            if ray.valid {
+                output_samples.push((ray.tile_coord, ChannelSample::Alpha(1.0)));
+                push_color_sample(ray, Srgb::new(*ao, *ao, *ao), output_samples);
+            }
+        }
+    }
+}
+
+/// Sample sets requested by the debug integrators. Nothing is sampled, but the film draws
+/// the sets of the first hit regardless.
+const DEBUG_1D_SAMPLE_SETS: usize = 3;
+const DEBUG_2D_SAMPLE_SETS: usize = 6;
+
+/// Debug view of the shading normal at the first hit, remapped from `[-1, 1]` to `[0, 1]`.
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub struct NormalsIntegrator;
+
+impl Integrator for NormalsIntegrator {
+    fn requested_1d_sample_sets(&self) -> usize {
+        DEBUG_1D_SAMPLE_SETS
+    }
+
+    fn requested_2d_sample_sets(&self) -> usize {
+        DEBUG_2D_SAMPLE_SETS
+    }
+
+    fn integrate(
+        &self,
+        _world: &World,
+        _samples_1d: &[f32x4; 3],
+        _samples_2d: &[f32x4; 12],
+        _depth: usize,
+        _material: MaterialHandle,
+        intersection: WShadingPoint,
+        _bump: &Bump,
+        _spawned_rays: &mut BumpVec<Ray>,
+        output_samples: &mut BumpVec<(Vec2u, ChannelSample)>,
+    ) {
+        let normals: [Vec3; 4] = intersection.normal.into();
+        push_debug_samples(
+            &intersection,
+            |i| Srgb::from(normals[i] * 0.5 + Vec3::broadcast(0.5)),
+            output_samples,
+        );
+    }
+}
+
+/// Debug view of the distance along the camera ray to the first hit, as a gray level.
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub struct DepthIntegrator;
+
+impl Integrator for DepthIntegrator {
+    fn requested_1d_sample_sets(&self) -> usize {
+        DEBUG_1D_SAMPLE_SETS
+    }
+
+    fn requested_2d_sample_sets(&self) -> usize {
+        DEBUG_2D_SAMPLE_SETS
+    }
+
+    fn integrate(
+        &self,
+        _world: &World,
+        _samples_1d: &[f32x4; 3],
+        _samples_2d: &[f32x4; 12],
+        _depth: usize,
+        _material: MaterialHandle,
+        intersection: WShadingPoint,
+        _bump: &Bump,
+        _spawned_rays: &mut BumpVec<Ray>,
+        output_samples: &mut BumpVec<(Vec2u, ChannelSample)>,
+    ) {
+        let ts = intersection.t.as_ref();
+        push_debug_samples(
+            &intersection,
+            |i| Srgb::new(ts[i], ts[i], ts[i]),
+            output_samples,
+        );
+    }
+}
+
+/// Debug view of the surface parameterization at the first hit, with u in red and v in
+/// green.
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub struct UvIntegrator;
+
+impl Integrator for UvIntegrator {
+    fn requested_1d_sample_sets(&self) -> usize {
+        DEBUG_1D_SAMPLE_SETS
+    }
+
+    fn requested_2d_sample_sets(&self) -> usize {
+        DEBUG_2D_SAMPLE_SETS
+    }
+
+    fn integrate(
+        &self,
+        _world: &World,
+        _samples_1d: &[f32x4; 3],
+        _samples_2d: &[f32x4; 12],
+        _depth: usize,
+        _material: MaterialHandle,
+        intersection: WShadingPoint,
+        _bump: &Bump,
+        _spawned_rays: &mut BumpVec<Ray>,
+        output_samples: &mut BumpVec<(Vec2u, ChannelSample)>,
+    ) {
+        let (us, vs) = (intersection.uv.x.as_ref(), intersection.uv.y.as_ref());
+        push_debug_samples(
+            &intersection,
+            |i| Srgb::new(us[i], vs[i], 0.0),
+            output_samples,
+        );
+    }
+}
+
+/// Pushes `color(lane)` as the final color of each valid lane, for integrators which only
+/// look at the first hit and don't spawn any rays.
+fn push_debug_samples<F: Fn(usize) -> Srgb>(
+    intersection: &WShadingPoint,
+    color: F,
+    output_samples: &mut BumpVec<(Vec2u, ChannelSample)>,
+) {
+    let rays: [Ray; 4] = intersection.ray.into();
+    for (i, ray) in rays.iter().enumerate() {
+        if ray.valid {
+            output_samples.push((ray.tile_coord, ChannelSample::Alpha(1.0)));
+            push_color_sample(ray, color(i), output_samples);
+        }
+    }
+}
+
+/// Pushes the final color of a path, and the same color weighted by 2 to the even or odd
+/// half buffer matching the parity of its sample index. With an even sample count each half
+/// averages half of the samples, so (even + odd) / 2 is the full color and their difference
+/// estimates the remaining noise.
+fn push_color_sample(ray: &Ray, color: Srgb, output_samples: &mut BumpVec<(Vec2u, ChannelSample)>) {
+    output_samples.push((ray.tile_coord, ChannelSample::Color(color)));
+
+    let half_sample = if ray.sample % 2 == 0 {
+        ChannelSample::ColorEven(color * 2.0)
+    } else {
+        ChannelSample::ColorOdd(color * 2.0)
+    };
+    output_samples.push((ray.tile_coord, half_sample));
+}
+
+/// Emission `le` towards `wo` from a surface with normal `normal`, which is black from
+/// behind (the side opposite of the normal) for one-sided materials.
+fn visible_emission(sided: Sided, wo: Wec3, normal: Wec3, le: WSrgb) -> WSrgb {
+    if sided == Sided::One {
+        WSrgb::merge(wo.dot(normal).cmp_lt(f32x4::ZERO), WSrgb::zero(), le)
+    } else {
+        le
+    }
+}
+
+/// Pushes a contribution from a path with at least one scattering event to the light path
+/// expression channel matching the lobe of the path's first vertex.
+fn push_light_path_samples(
+    ray: &WRay,
+    direct: bool,
+    contribution: WSrgb,
+    output_samples: &mut BumpVec<(Vec2u, ChannelSample)>,
+) {
+    let contributions: [Srgb; 4] = contribution.into();
+    for i in 0..4 {
+        if !ray.valid[i] {
+            continue;
+        }
+
+        if let Some(sample) = light_path_sample(ray.first_lobe[i], direct, contributions[i]) {
+            output_samples.push((ray.tile_coord[i], sample));
+        }
+    }
+}
+
+fn light_path_sample(first_lobe: Lobe, direct: bool, contribution: Srgb) -> Option<ChannelSample> {
+    match (first_lobe, direct) {
+        (Lobe::Diffuse, true) => Some(ChannelSample::DiffuseDirect(contribution)),
+        (Lobe::Diffuse, false) => Some(ChannelSample::DiffuseIndirect(contribution)),
+        (Lobe::Specular, true) => Some(ChannelSample::SpecularDirect(contribution)),
+        (Lobe::Specular, false) => Some(ChannelSample::SpecularIndirect(contribution)),
+        (Lobe::None, _) => None,
+    }
+}
+
+/// Pushes the direct lighting at the first vertex of a path, split by the lobe it was
+/// evaluated with.
+fn push_direct_light_samples(
+    ray: &WRay,
+    diffuse: WSrgb,
+    specular: WSrgb,
+    output_samples: &mut BumpVec<(Vec2u, ChannelSample)>,
+) {
+    let diffuse: [Srgb; 4] = diffuse.into();
+    let specular: [Srgb; 4] = specular.into();
+    for i in 0..4 {
+        if ray.valid[i] {
+            output_samples.push((ray.tile_coord[i], ChannelSample::DiffuseDirect(diffuse[i])));
+            output_samples.push((
+                ray.tile_coord[i],
+                ChannelSample::SpecularDirect(specular[i]),
+            ));
+        }
+    }
+}
+
+/// Light arriving from one light, as long as nothing is in the way.
+pub struct LightSample {
+    pub diffuse: WSrgb,
+    pub specular: WSrgb,
+    /// Segment which is tested for occlusion, see `HitableStore::test_occluded`
+    pub shadow_start: Wec3,
+    pub shadow_end: Wec3,
+}
+
+/// Samples the (diffuse, specular) parts of the light arriving from the given light, which
+/// was picked with probability `light_pmf`. When several light samples are averaged, that
+/// includes dividing by their count. Shadows are left to the caller; the shadow ray starts
+/// `shadow_offset` away from the surface.
 pub fn sample_one_light(
     world: &World,
     light_idx: usize,
+    light_pmf: f32,
+    shadow_offset: f32x4,
     samples: &[f32x4; 2],
     intersection: &WShadingPoint,
     bsdf: &dyn BSDF,
-) -> WSrgb {
+) -> LightSample {
     let (end_point, li, pdf) =
         world.lights[light_idx].sample(samples, intersection.point, intersection.normal);
 
     let wo = -intersection.ray.dir;
     let wi = (end_point - intersection.point).normalized();
 
-    let occlude_point = intersection.point
-        + intersection.normal * intersection.normal.dot(wi).signum() * intersection.offset_by;
-    let occluded = world
-        .hitables
-        .test_occluded(occlude_point, end_point, intersection.ray.time);
+    let geom_normal = intersection.geom_normal;
+    let occlude_point =
+        intersection.point + geom_normal * geom_normal.dot(wi).signum() * shadow_offset;
+
+    // lights which are above the shading normal but below the surface itself (or the other
+    // way around) would leak through it, so those get nothing
+    let cos_shading = intersection.normal.dot(wi);
+    let same_side = (cos_shading * geom_normal.dot(wi)).cmp_gt(f32x4::ZERO);
+
+    let (f_diffuse, f_specular) = bsdf.f_split(wo, wi, intersection.normal);
+    let weight = li
+        * saturate(cos_shading)
+        * shadow_terminator(geom_normal, intersection.normal, wi)
+        * f32x4::from(1.0 / light_pmf)
+        / pdf
+        * intersection.ray.throughput;
+    let weight = WSrgb::merge(intersection.active & same_side, weight, WSrgb::zero());
+    LightSample {
+        diffuse: f_diffuse * weight,
+        specular: f_specular * weight,
+        shadow_start: occlude_point,
+        shadow_end: end_point,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_sided_emitters_are_black_from_behind() {
+        let normal = Wec3::splat(Vec3::unit_z());
+        let le = WSrgb::splat(Srgb::new(1.0, 2.0, 3.0));
+        let in_front = Wec3::splat(Vec3::new(0.0, 0.6, 0.8));
+        let behind = Wec3::splat(Vec3::new(0.0, 0.6, -0.8));
+
+        let visible = |sided, wo| {
+            let radiance: [Srgb; 4] = visible_emission(sided, wo, normal, le).into();
+            radiance[0]
+        };
+        assert_eq!(visible(Sided::One, in_front).z, 3.0);
+        assert_eq!(visible(Sided::One, behind).max_channel(), 0.0);
+        assert_eq!(visible(Sided::Two, behind).z, 3.0);
+    }
+
+    #[test]
+    fn contact_shadows_reach_where_a_sphere_touches_the_ground() {
+        use crate::heightfield::Heightfield;
+        use crate::hitable::HitableStore;
+        use crate::sphere::Sphere;
+
+        let mut store = HitableStore::new();
+        let ground = Heightfield::new(
+            vec![0.0; 4],
+            Vec2u::new(2, 2),
+            Vec3::new(-5.0, 0.0, -5.0),
+            crate::math::Vec2::new(10.0, 10.0),
+            MaterialHandle(0),
+        )
+        .unwrap();
+        store.push(ground);
+        store.push(Sphere::new(Vec3::unit_y(), 1.0, MaterialHandle(0)));
+
+        // ground points straight below the camera rays, at growing distances from where the
+        // sphere touches down; the light is far enough above that only those under it are dark
+        let xs = [0.02, 0.5, 1.05, 2.0];
+        let origins = Wec3::from([
+            Vec3::new(xs[0], 5.0, 0.0),
+            Vec3::new(xs[1], 5.0, 0.0),
+            Vec3::new(xs[2], 5.0, 0.0),
+            Vec3::new(xs[3], 5.0, 0.0),
+        ]);
+        let ray = WRay::new(
+            origins,
+            Wec3::splat(-Vec3::unit_y()),
+            f32x4::ZERO,
+            [Vec2u::zero(); 4],
+            [true; 4],
+            [0.0; 4],
+            [0; 4],
+        );
+        let t = f32x4::from(5.0);
+        let point = ray.origin + ray.dir * t;
+        let start = point + Wec3::splat(Vec3::unit_y()) * float_error_bound(&ray, t);
+        let end = point + Wec3::splat(Vec3::unit_y() * 1000.0);
+
+        let bump = Bump::new();
+        let mut candidates = BumpVec::new_in(&bump);
+        let transmittance: [Srgb; 4] = store
+            .test_occluded(start, end, f32x4::ZERO, &mut candidates)
+            .into();
+        let lit = [false, false, true, true];
+        for (transmittance, lit) in transmittance.iter().zip(lit.iter()) {
+            assert_eq!(transmittance.max_channel() > 0.5, *lit);
+        }
+    }
+
+    /// Path tracer with no limits other than `max_bounces`, using `roulette`.
+    fn path_tracer(roulette: RouletteHeuristic) -> PathTracingIntegrator {
+        PathTracingIntegrator {
+            max_bounces: 8,
+            split_light_paths: false,
+            contact_shadows: false,
+            roulette,
+            clamp: None,
+            regularization: None,
+            light_samples: 1,
+        }
+    }
+
+    /// Lightless world with just `material` in its material store.
+    fn material_world<M: crate::material::Material + 'static>(material: M) -> World {
+        let mut materials = crate::material::MaterialStore::new();
+        materials.add_material(material);
+        World {
+            materials,
+            hitables: crate::hitable::HitableStore::new(),
+            lights: Vec::new(),
+            light_sampler: crate::light::LightSampler::new(&[]),
+            cameras: crate::camera::CameraStore::new(),
+        }
+    }
+
+    /// Hits on the z = 0 plane at the origin, seen head-on from +z.
+    fn facing_up() -> WShadingPoint {
+        let ray = WRay::new(
+            Wec3::splat(Vec3::unit_z()),
+            Wec3::splat(-Vec3::unit_z()),
+            f32x4::ZERO,
+            [Vec2u::zero(); 4],
+            [true; 4],
+            [0.0; 4],
+            [0; 4],
+        );
+        let hit = crate::hitable::WHit {
+            ray,
+            t: f32x4::ONE,
+            attributes: crate::hitable::WHitAttributes::none(),
+        };
+        WShadingPoint::new(
+            hit,
+            Wec3::zero(),
+            f32x4::from(1e-4),
+            Wec3::splat(Vec3::unit_z()),
+        )
+    }
+
+    /// Spawned rays and output samples of integrating `intersection` with the material at
+    /// handle 0, with every sample set filled with `sample`.
+    fn integrate_with(
+        integrator: &dyn Integrator,
+        world: &World,
+        depth: usize,
+        intersection: WShadingPoint,
+        sample: f32x4,
+    ) -> (Vec<Ray>, Vec<(Vec2u, ChannelSample)>) {
+        let bump = Bump::new();
+        let mut spawned_rays = BumpVec::new_in(&bump);
+        let mut output_samples = BumpVec::new_in(&bump);
+        integrator.integrate(
+            world,
+            &[sample; 3],
+            &[sample; 12],
+            depth,
+            MaterialHandle(0),
+            intersection,
+            &bump,
+            &mut spawned_rays,
+            &mut output_samples,
+        );
+        (
+            spawned_rays.into_iter().collect(),
+            output_samples.into_iter().collect(),
+        )
+    }
+
+    #[test]
+    fn roulette_keeps_the_energy_of_saturated_colors() {
+        use crate::material::Lambertian;
 
-    let f = bsdf.f(wo, wi, intersection.normal) * intersection.normal.dot(wi).max(f32x4::ZERO);
-    li * f * f32x4::from(world.lights.len() as f32 / 4.0) / pdf
-        * intersection.ray.throughput
-        * occluded
+        let red = Srgb::new(0.9, 0.0, 0.0);
+        let world = material_world(Lambertian::new(WSrgb::splat(red)));
+        for roulette in [RouletteHeuristic::Throughput, RouletteHeuristic::Albedo].iter() {
+            let integrator = path_tracer(*roulette);
+            // evenly spread roulette samples, so the survivors are exactly their share
+            let count = 1024;
+            let mut total = Srgb::zero();
+            for i in 0..count {
+                let sample = f32x4::from((i as f32 + 0.5) / count as f32);
+                let (spawned, _) = integrate_with(&integrator, &world, 3, facing_up(), sample);
+                for ray in spawned.iter() {
+                    total += ray.throughput;
+                }
+            }
+            // a cosine sampled lambertian scatters with a weight of its albedo
+            let mean = total / (count * 4) as f32;
+            assert!(
+                (mean.0 - red.0).mag() < 0.01,
+                "{:?} with {:?}",
+                mean,
+                roulette
+            );
+        }
+    }
+
+    #[test]
+    fn terminated_lanes_spawn_no_rays() {
+        use crate::material::Lambertian;
+
+        let world = material_world(Lambertian::new(WSrgb::splat(Srgb::new(0.5, 0.5, 0.5))));
+        let integrator = path_tracer(RouletteHeuristic::Throughput);
+        let mut intersection = facing_up();
+        // the second and fourth paths have ended, even though their rays are still valid
+        intersection.active = f32x4::from([1.0, 0.0, 1.0, 0.0]).cmp_gt(f32x4::ZERO);
+        let (spawned, output_samples) =
+            integrate_with(&integrator, &world, 0, intersection, f32x4::from(0.5));
+
+        assert_eq!(spawned.len(), 2);
+        let alphas = output_samples
+            .iter()
+            .filter(|(_, sample)| matches!(sample, ChannelSample::Alpha(_)))
+            .count();
+        assert_eq!(alphas, 2);
+    }
+
+    #[test]
+    fn depth_clamps_keep_direct_highlights_and_clamp_deep_fireflies() {
+        let integrator = PathTracingIntegrator {
+            clamp: Some(DepthClamp::new(10.0, 0.1)),
+            ..path_tracer(RouletteHeuristic::Throughput)
+        };
+        let scaled = |luminance: f32, bounces: usize| {
+            let light = WSrgb::splat(Srgb::new(luminance, luminance, luminance));
+            let scaled = light * integrator.clamp_scale(light, bounces);
+            scaled.luminance().as_ref()[0]
+        };
+
+        // a bright highlight reflected straight to the camera is under the direct limit
+        assert!((scaled(5.0, 0) - 5.0).abs() < 1e-5);
+        // the same light three bounces deep is brought down to the limit there
+        assert!((scaled(5.0, 3) - 0.01).abs() < 1e-6);
+        assert!((scaled(1000.0, 3) - 0.01).abs() < 1e-6);
+        // and black stays black, even with a zero limit
+        let zero = DepthClamp::new(0.0, 0.5);
+        assert_eq!(zero.wide_scale(f32x4::ZERO, 2).as_ref()[0], 1.0);
+    }
+
+    #[test]
+    fn lights_below_the_surface_dont_leak_through_bent_normals() {
+        use crate::light::SphereLight;
+        use crate::material::{Lambertian, Material};
+
+        let white = WSrgb::splat(Srgb::new(0.8, 0.8, 0.8));
+        let mut world = material_world(Lambertian::new(white));
+        // small lights 5 degrees below and above the horizon, on the side the shading
+        // normal leans towards, so both are above the shading normal
+        let (cos, sin) = (5f32.to_radians().cos(), 5f32.to_radians().sin());
+        let emission = Srgb::new(100.0, 100.0, 100.0);
+        world.lights = vec![
+            Box::new(SphereLight::new(
+                Vec3::new(cos, 0.0, -sin) * 10.0,
+                0.01,
+                emission,
+            )),
+            Box::new(SphereLight::new(
+                Vec3::new(cos, 0.0, sin) * 10.0,
+                0.01,
+                emission,
+            )),
+        ];
+        let bent = Vec3::new(0.5, 0.0, 0.75f32.sqrt());
+        let intersection = facing_up().with_shading_normal(Wec3::splat(bent));
+
+        let bump = Bump::new();
+        let material = world.materials.get(MaterialHandle(0));
+        let bsdf: &dyn BSDF = material.get_bsdf_at(&intersection, &bump);
+        let diffuse = |light_idx| {
+            let samples = [f32x4::from(0.5); 2];
+            let sample = sample_one_light(
+                &world,
+                light_idx,
+                1.0,
+                intersection.offset_by,
+                &samples,
+                &intersection,
+                bsdf,
+            );
+            let diffuse: [Srgb; 4] = sample.diffuse.into();
+            diffuse[0].max_channel()
+        };
+        assert_eq!(diffuse(0), 0.0);
+        assert!(diffuse(1) > 0.0);
+    }
+
+    #[test]
+    fn open_planes_are_unoccluded_and_corners_are_not() {
+        use crate::hitable::HitableStore;
+        use crate::material::Lambertian;
+        use crate::sphere::Sphere;
+
+        let integrator = AmbientOcclusionIntegrator {
+            samples: 64,
+            radius: 1.0,
+        };
+        let ao = |store: HitableStore| {
+            let white = WSrgb::splat(Srgb::new(0.8, 0.8, 0.8));
+            let mut world = material_world(Lambertian::new(white));
+            world.hitables = store;
+            let (_, samples) =
+                integrate_with(&integrator, &world, 0, facing_up(), f32x4::from(0.5));
+            let colors = samples
+                .iter()
+                .filter_map(|(_, sample)| match sample {
+                    ChannelSample::Color(color) => Some(color.x),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(colors.len(), 4);
+            colors[0]
+        };
+
+        // a big sphere whose top is the plane through the origin, which curves away from
+        // every ray leaving it
+        let ground = || Sphere::new(Vec3::new(0.0, 0.0, -100.0), 100.0, MaterialHandle(0));
+        let mut open = HitableStore::new();
+        open.push(ground());
+        assert!((ao(open) - 1.0).abs() < 1e-6);
+
+        // with a wall 0.3 away, the rays whose directions have an x above 0.3 hit it, which
+        // is about 31% of the cosine weighted hemisphere
+        let mut corner = HitableStore::new();
+        corner.push(ground());
+        corner.push(Sphere::new(
+            Vec3::new(100.3, 0.0, 0.0),
+            100.0,
+            MaterialHandle(0),
+        ));
+        let in_corner = ao(corner);
+        assert!(in_corner < 0.9);
+        assert!((in_corner - 0.69).abs() < 0.15);
+    }
+
+    #[test]
+    fn more_light_samples_keep_the_mean_and_lower_the_variance() {
+        use crate::light::{LightSampler, SphereLight};
+        use crate::material::Lambertian;
+
+        let white = WSrgb::splat(Srgb::new(0.8, 0.8, 0.8));
+        let mut world = material_world(Lambertian::new(white));
+        // a big light straight above, so that what a sample sees only depends on how far
+        // into the cone it is and not which way around, since all sample sets are the same
+        let emission = Srgb::new(5.0, 5.0, 5.0);
+        world.lights = vec![Box::new(SphereLight::new(
+            Vec3::unit_z() * 2.0,
+            1.5,
+            emission,
+        ))];
+        world.light_sampler = LightSampler::new(&world.lights);
+
+        let stats = |light_samples| {
+            let integrator = PathTracingIntegrator {
+                max_bounces: 0,
+                light_samples,
+                ..path_tracer(RouletteHeuristic::Throughput)
+            };
+            let colors = (0..64)
+                .map(|j| {
+                    let sample = f32x4::from((j as f32 + 0.5) / 64.0);
+                    let (_, samples) = integrate_with(&integrator, &world, 0, facing_up(), sample);
+                    samples
+                        .iter()
+                        .find_map(|(_, sample)| match sample {
+                            ChannelSample::Color(color) => Some(color.x),
+                            _ => None,
+                        })
+                        .unwrap()
+                })
+                .collect::<Vec<_>>();
+            let mean = colors.iter().sum::<f32>() / colors.len() as f32;
+            let variance =
+                colors.iter().map(|c| (c - mean) * (c - mean)).sum::<f32>() / colors.len() as f32;
+            (mean, variance)
+        };
+        let (one, eight) = (stats(1), stats(8));
+        assert!(one.0 > 0.0);
+        assert!((one.0 - eight.0).abs() < one.0 * 0.01);
+        assert!(eight.1 < one.1 * 0.5);
+    }
+
+    #[test]
+    fn normals_and_depth_show_the_first_hit() {
+        use crate::material::Lambertian;
+
+        let world = material_world(Lambertian::new(WSrgb::splat(Srgb::one())));
+        let debug_colors = |integrator: &dyn Integrator| {
+            let (spawned, samples) =
+                integrate_with(integrator, &world, 0, facing_up(), f32x4::from(0.5));
+            assert!(spawned.is_empty());
+            samples
+                .into_iter()
+                .filter_map(|(_, sample)| match sample {
+                    ChannelSample::Color(color) => Some(color.0),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // +z remaps to (0.5, 0.5, 1), and every hit of facing_up is 1 away
+        let normals = debug_colors(&NormalsIntegrator);
+        assert_eq!(normals, vec![Vec3::new(0.5, 0.5, 1.0); 4]);
+        let depths = debug_colors(&DepthIntegrator);
+        assert_eq!(depths, vec![Vec3::broadcast(1.0); 4]);
+    }
 }
@@ -1,9 +1,78 @@
-use crate::math::{f32x4, OrthonormalBasis, Vec3, Wec3};
+use crate::math::{f32x4, Distribution1D, Mask, OrthonormalBasis, RandomSample3d, Vec3, Wec3};
 use crate::spectrum::{Srgb, WSrgb};
 
 pub trait Light: Send + Sync {
     // returns (sampled point, output radiance toward ref, pdf of sample wrt solid angle wrt ref point)
     fn sample(&self, samples: &[f32x4; 2], point: Wec3, normal: Wec3) -> (Wec3, WSrgb, f32x4);
+
+    /// Pdf with respect to solid angle at `point` that `sample` picks the direction `wi`, for
+    /// weighting light samples against BSDF samples with MIS. Zero for delta lights, which
+    /// can't be hit by BSDF samples.
+    #[allow(dead_code)]
+    fn pdf(&self, _point: Wec3, _wi: Wec3) -> f32x4 {
+        f32x4::ZERO
+    }
+
+    /// Total power emitted by the light, averaged over the color channels. Only used
+    /// relative to other lights, to decide how often to sample each one.
+    fn power(&self) -> f32;
+}
+
+/// Picks lights for next event estimation with probability proportional to their power.
+/// Lights without any power are never picked, unless none of them have any.
+pub struct LightSampler {
+    distribution: Distribution1D,
+    pmfs: Vec<f32>,
+}
+
+impl LightSampler {
+    pub fn new(lights: &[Box<dyn Light>]) -> Self {
+        let powers = lights
+            .iter()
+            .map(|light| light.power().max(0.0))
+            .collect::<Vec<_>>();
+        let total_power = powers.iter().sum::<f32>();
+
+        let pmfs = powers
+            .iter()
+            .map(|power| {
+                // If nothing emits any power, fall back to picking uniformly
+                if total_power > 0.0 {
+                    *power / total_power
+                } else {
+                    1.0 / lights.len() as f32
+                }
+            })
+            .collect();
+
+        Self {
+            distribution: Distribution1D::new(&powers),
+            pmfs,
+        }
+    }
+
+    /// Picks one light per lane from uniform samples in [0, 1), returning the light indices
+    /// and the probability each of them had of being picked.
+    pub fn sample(&self, samples: f32x4) -> ([usize; 4], f32x4) {
+        let mut indices = [0; 4];
+        let mut pmfs = [0.0; 4];
+        for ((index, pmf), sample) in indices
+            .iter_mut()
+            .zip(pmfs.iter_mut())
+            .zip(samples.as_ref().iter())
+        {
+            let (item, item_pmf) = self.distribution.sample_discrete(*sample);
+            *index = item;
+            *pmf = item_pmf;
+        }
+        (indices, f32x4::from(pmfs))
+    }
+
+    /// Probability of picking light `idx` in a single draw
+    #[allow(dead_code)]
+    pub fn pmf(&self, idx: usize) -> f32 {
+        self.pmfs[idx]
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -21,9 +90,29 @@ impl SphereLight {
             rad: f32x4::from(rad),
         }
     }
+
+    /// Samples a point uniformly over the area of the sphere, returning it with its pdf
+    /// converted to solid angle at `p`. Used when `p` is inside of the sphere, where there
+    /// is no cone around it to sample.
+    fn sample_area(&self, samples: &[f32x4; 2], p: Wec3) -> (Wec3, f32x4) {
+        let normal = Wec3::rand_on_unit_sphere(samples);
+        let point = self.pos + normal * self.rad;
+        (point, self.area_pdf_to_solid_angle(p, point, normal))
+    }
+
+    fn area_pdf_to_solid_angle(&self, p: Wec3, point: Wec3, normal: Wec3) -> f32x4 {
+        let to_point = point - p;
+        let dist2 = to_point.mag_sq();
+        let cos_light = normal.dot(to_point / dist2.sqrt()).abs();
+        let area = f32x4::from(4.0) * f32x4::PI * self.rad * self.rad;
+        dist2 / (cos_light * area)
+    }
 }
 
 impl Light for SphereLight {
+    /// Samples the cone of directions subtended by the sphere from `p`, which is much less
+    /// noisy than sampling its area when the light is close or large. Falls back to area
+    /// sampling when `p` is inside of the sphere.
     fn sample(&self, samples: &[f32x4; 2], p: Wec3, _n: Wec3) -> (Wec3, WSrgb, f32x4) {
         let dir = self.pos - p;
         let dist2 = dir.mag_sq();
@@ -53,10 +142,202 @@ impl Light for SphereLight {
 
         let pdf = uniform_cone_pdf(cos_theta_max);
 
-        (point, self.emission, pdf)
+        let inside = dist2.cmp_le(r2);
+        if inside.none() {
+            return (point, self.emission, pdf);
+        }
+
+        let (area_point, area_pdf) = self.sample_area(samples, p);
+        (
+            Wec3::merge(inside, area_point, point),
+            self.emission,
+            inside.merge(area_pdf, pdf),
+        )
+    }
+
+    fn pdf(&self, p: Wec3, wi: Wec3) -> f32x4 {
+        let dir = self.pos - p;
+        let dist2 = dir.mag_sq();
+        let r2 = self.rad * self.rad;
+
+        let sin_theta_max_2 = r2 / dist2;
+        let cos_theta_max = f32x4::ZERO.max(f32x4::ONE - sin_theta_max_2).sqrt();
+        let in_cone = (dir / dist2.sqrt()).dot(wi).cmp_gt(cos_theta_max);
+        let cone_pdf = in_cone.merge(uniform_cone_pdf(cos_theta_max), f32x4::ZERO);
+
+        let inside = dist2.cmp_le(r2);
+        if inside.none() {
+            return cone_pdf;
+        }
+
+        // from inside, every direction hits the sphere where the ray exits it
+        let oc = p - self.pos;
+        let b = oc.dot(wi);
+        let c = oc.mag_sq() - r2;
+        let t = -b + f32x4::ZERO.max(b * b - c).sqrt();
+        let point = p + wi * t;
+        let normal = (point - self.pos) / self.rad;
+        let area_pdf = self.area_pdf_to_solid_angle(p, point, normal);
+
+        inside.merge(area_pdf, cone_pdf)
+    }
+
+    fn power(&self) -> f32 {
+        let emission: [Srgb; 4] = self.emission.into();
+        let rad = self.rad.as_ref()[0];
+        // lambertian emitter over the whole sphere: pi * L * area
+        let area = 4.0 * std::f32::consts::PI * rad * rad;
+        std::f32::consts::PI * area * (emission[0].x + emission[0].y + emission[0].z) / 3.0
+    }
+}
+
+/// A point light which only emits within a cone around `direction`. Emission is at full
+/// intensity inside the inner cone, falls off smoothly to zero at the outer cone and is
+/// zero everywhere outside of it.
+#[derive(Clone, Copy)]
+pub struct SpotLight {
+    pos: Wec3,
+    direction: Wec3,
+    cos_inner: f32x4,
+    cos_outer: f32x4,
+    intensity: WSrgb,
+}
+
+impl SpotLight {
+    /// Cone angles are the half-angles of the cones, in degrees.
+    #[allow(dead_code)]
+    pub fn new(
+        pos: Vec3,
+        direction: Vec3,
+        inner_angle: f32,
+        outer_angle: f32,
+        intensity: Srgb,
+    ) -> Self {
+        let outer_angle = outer_angle.max(inner_angle);
+        Self {
+            pos: Wec3::splat(pos),
+            direction: Wec3::splat(direction.normalized()),
+            cos_inner: f32x4::from(inner_angle.to_radians().cos()),
+            cos_outer: f32x4::from(outer_angle.to_radians().cos()),
+            intensity: WSrgb::splat(intensity),
+        }
+    }
+
+    /// Smoothstep between the outer and inner cones, where `cos_theta` is the cosine of the
+    /// angle between the spot direction and the direction to the lit point.
+    pub fn falloff(&self, cos_theta: f32x4) -> f32x4 {
+        let range = (self.cos_inner - self.cos_outer).max(f32x4::EPSILON);
+        let t = ((cos_theta - self.cos_outer) / range)
+            .max(f32x4::ZERO)
+            .min(f32x4::ONE);
+        t * t * (f32x4::from(3.0) - f32x4::from(2.0) * t)
+    }
+}
+
+impl Light for SpotLight {
+    fn sample(&self, _samples: &[f32x4; 2], p: Wec3, _n: Wec3) -> (Wec3, WSrgb, f32x4) {
+        let dir = p - self.pos;
+        let dist2 = dir.mag_sq();
+        let dir = dir / dist2.sqrt();
+
+        let falloff = self.falloff(dir.dot(self.direction));
+
+        // delta light, so the pdf is 1 and the radiance is the intensity attenuated by distance
+        (self.pos, self.intensity * (falloff / dist2), f32x4::ONE)
+    }
+
+    fn power(&self) -> f32 {
+        let intensity: [Srgb; 4] = self.intensity.into();
+        let cos_inner = self.cos_inner.as_ref()[0];
+        let cos_outer = self.cos_outer.as_ref()[0];
+        // solid angle of the cone, counting the falloff region at half weight
+        let solid_angle = 2.0 * std::f32::consts::PI * (1.0 - 0.5 * (cos_inner + cos_outer));
+        solid_angle * (intensity[0].x + intensity[0].y + intensity[0].z) / 3.0
     }
 }
 
 fn uniform_cone_pdf(cos_theta_max: f32x4) -> f32x4 {
     f32x4::ONE / (f32x4::TWO_PI * (f32x4::ONE - cos_theta_max))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampler_never_picks_lights_without_power() {
+        let lights: Vec<Box<dyn Light>> = vec![
+            Box::new(SphereLight::new(Vec3::zero(), 1.0, Srgb::zero())),
+            Box::new(SphereLight::new(Vec3::zero(), 1.0, Srgb::one())),
+            Box::new(SphereLight::new(Vec3::zero(), 1.0, Srgb::zero())),
+        ];
+        let sampler = LightSampler::new(&lights);
+        for i in 0..64 {
+            let samples = f32x4::from(i as f32 / 64.0);
+            let (indices, pmfs) = sampler.sample(samples);
+            assert_eq!(indices, [1; 4]);
+            assert!(pmfs.as_ref().iter().all(|pmf| (pmf - 1.0).abs() < 1e-4));
+        }
+    }
+
+    #[test]
+    fn spot_falloff_is_smooth_between_the_cones() {
+        let spot = SpotLight::new(Vec3::zero(), -Vec3::unit_y(), 20.0, 40.0, Srgb::one());
+        let falloff = |degrees: f32| {
+            let falloff = spot.falloff(f32x4::from(degrees.to_radians().cos()));
+            falloff.as_ref()[0]
+        };
+
+        for degrees in [0.0, 10.0, 19.9].iter() {
+            assert_eq!(falloff(*degrees), 1.0);
+        }
+        for degrees in [40.1, 60.0, 180.0].iter() {
+            assert_eq!(falloff(*degrees), 0.0);
+        }
+        // in between it falls off monotonically, and the smoothstep is half way at the middle
+        let mut last = 1.0;
+        for step in 1..20 {
+            let value = falloff(20.0 + step as f32);
+            assert!(value > 0.0 && value < last);
+            last = value;
+        }
+        let cos_middle = (20f32.to_radians().cos() + 40f32.to_radians().cos()) * 0.5;
+        let middle = spot.falloff(f32x4::from(cos_middle)).as_ref()[0];
+        assert!((middle - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn sphere_light_pdfs_integrate_to_one() {
+        let light = SphereLight::new(Vec3::zero(), 1.0, Srgb::one());
+        // evenly spread directions on a fibonacci spiral, each covering the same solid angle
+        let count = 1 << 14;
+        let direction = |i: usize| {
+            let z = 1.0 - (i as f32 + 0.5) * 2.0 / count as f32;
+            let phi = i as f32 * std::f32::consts::PI * (3.0 - 5f32.sqrt());
+            let r = (1.0 - z * z).sqrt();
+            Vec3::new(r * phi.cos(), r * phi.sin(), z)
+        };
+
+        // from outside of the light, and from inside of it
+        for point in [Vec3::new(0.0, 0.0, 3.0), Vec3::new(0.3, 0.2, 0.0)].iter() {
+            let mut total = 0.0;
+            for i in (0..count).step_by(4) {
+                let dirs = [
+                    direction(i),
+                    direction(i + 1),
+                    direction(i + 2),
+                    direction(i + 3),
+                ];
+                let pdfs = light.pdf(Wec3::splat(*point), Wec3::from(dirs));
+                total += pdfs.as_ref().iter().sum::<f32>();
+            }
+            let integral = total * 4.0 * std::f32::consts::PI / count as f32;
+            assert!(
+                (integral - 1.0).abs() < 0.02,
+                "{} from {:?}",
+                integral,
+                point
+            );
+        }
+    }
+}
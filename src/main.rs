@@ -1,27 +1,44 @@
 use generic_array::typenum::*;
 
 mod animation;
+mod bezier;
+mod bvh;
 mod camera;
+mod capsule;
 mod film;
 mod filter;
+mod framebuffer;
+mod furnace;
+mod grid;
+mod heightfield;
 mod hitable;
 mod integrator;
 mod light;
 mod material;
 mod math;
+mod metaballs;
+mod output;
+mod point_cloud;
+mod post;
+#[cfg(feature = "minifb")]
+mod preview;
 mod ray;
+mod rounded_box;
 mod sampler;
 mod sdf;
 mod spectrum;
 mod sphere;
+mod texture;
+mod torus;
 mod world;
 
+use animation::FrameTiming;
 use camera::{CameraHandle, CameraStore, OrthographicCamera};
 use film::{ChannelKind, Film};
 use filter::BlackmanHarrisFilter;
 use hitable::HitableStore;
-use integrator::PathTracingIntegrator;
-use light::{Light, SphereLight};
+use integrator::{PathTracingIntegrator, RouletteHeuristic};
+use light::{Light, LightSampler, SphereLight};
 use material::{Dielectric, MaterialStore, Sky};
 use math::{Extent2u, Vec2, Vec3};
 use sdf::{BoxFold, MandelBox, SphereFold, TracedSDF};
@@ -29,8 +46,6 @@ use spectrum::Srgb;
 use sphere::Sphere;
 use world::World;
 
-use std::time::Instant;
-
 const RES: (usize, usize) = (1920, 1080);
 const SAMPLES: usize = 2;
 
@@ -107,6 +122,8 @@ fn setup() -> (CameraHandle, World) {
         Vec3::new(0.0, 1.0, 0.0),
     );
 
+    let light_sampler = LightSampler::new(&lights);
+
     let mut cameras = CameraStore::new();
 
     let camera = cameras.add_camera(Box::new(camera));
@@ -117,6 +134,7 @@ fn setup() -> (CameraHandle, World) {
             materials,
             hitables,
             lights,
+            light_sampler,
             cameras,
         },
     )
@@ -141,48 +159,36 @@ fn main() {
     )
     .unwrap();
 
-    let frame_rate = 24;
-    let frame_range = 1..2;
-    let shutter_speed = 1.0 / 24.0;
+    let timing = FrameTiming {
+        frame_rate: 24.0,
+        shutter_speed: 1.0 / 24.0,
+    };
 
     let filter = BlackmanHarrisFilter::new(1.5);
     // let filter = BoxFilter::default();
-    let integrator = PathTracingIntegrator { max_bounces: 5 };
-
-    for frame in frame_range {
-        let start = Instant::now();
-
-        let frame_start = frame as f32 * (1.0 / frame_rate as f32);
-        let frame_end = frame_start + shutter_speed;
-
-        film.render_frame_into(
-            &world,
-            camera,
-            &integrator,
-            &filter,
-            Extent2u::new(16, 16),
-            frame,
-            frame_start..frame_end,
-            SAMPLES,
-        );
-
-        let time = Instant::now() - start;
-        let time_secs = time.as_secs();
-        let time_millis = time.subsec_millis();
-
-        println!(
-            "Done in {} seconds.",
-            time_secs as f32 + time_millis as f32 / 1000.0
-        );
-
-        println!("Post processing image...");
-
-        film.save_to(
-            &[ChannelKind::WorldNormal, ChannelKind::Color],
-            "renders",
-            format!("{}_spp", SAMPLES * 4),
-            false,
-        )
-        .unwrap();
-    }
+    let integrator = PathTracingIntegrator {
+        max_bounces: 5,
+        split_light_paths: false,
+        contact_shadows: false,
+        roulette: RouletteHeuristic::Throughput,
+        clamp: None,
+        regularization: None,
+        light_samples: 4,
+    };
+
+    film.render_animation(
+        &world,
+        camera,
+        &integrator,
+        &filter,
+        Extent2u::new(16, 16),
+        1..2,
+        timing,
+        SAMPLES,
+        Srgb::zero(),
+        &[ChannelKind::WorldNormal, ChannelKind::Color],
+        "renders",
+        format!("{}_spp", SAMPLES * 4),
+    )
+    .unwrap();
 }
@@ -3,7 +3,10 @@ use bumpalo::Bump;
 use arrayref::array_ref;
 
 use crate::hitable::WShadingPoint;
-use crate::math::{f32x4, f_schlick, OrthonormalBasis, RandomSample3d, Wec3};
+use crate::math::{
+    f32x4, f_schlick, octahedral_dir_to_uv, octahedral_uv_to_dir, saturate, BasisTransform,
+    Distribution2D, OrthonormalBasis, RandomSample3d, Vec3, Wec3,
+};
 use crate::spectrum::{Srgb, WSrgb};
 
 use std::f32::consts::PI;
@@ -23,9 +26,41 @@ pub trait BSDF {
 
     fn f(&self, wo: Wec3, wi: Wec3, n: Wec3) -> WSrgb;
 
+    /// `f` split into its (diffuse, specular) parts, which always sum to `f`.
+    fn f_split(&self, wo: Wec3, wi: Wec3, n: Wec3) -> (WSrgb, WSrgb) {
+        (self.f(wo, wi, n), WSrgb::zero())
+    }
+
     fn le(&self, _wo: Wec3, _intersection: &WShadingPoint) -> WSrgb {
         WSrgb::zero()
     }
+
+    /// Fraction of light the surface reflects overall, if the BSDF knows it up front.
+    fn albedo(&self) -> Option<WSrgb> {
+        None
+    }
+
+    /// Roughness of the glossy lobe, from 0 for a mirror to 1 for something as spread out as
+    /// diffuse. `None` for BSDFs which don't have a glossy lobe.
+    fn roughness(&self) -> Option<f32x4> {
+        None
+    }
+
+    /// Makes the glossy lobe at least `min_roughness` rough, if there is one.
+    fn regularize(&mut self, _min_roughness: f32x4) {}
+}
+
+/// Roughness of a cosine power lobe with `exponent`, using the usual match between Phong
+/// exponents and Beckmann roughness (`n = 2 / a^2 - 2`).
+fn exponent_to_roughness(exponent: f32x4) -> f32x4 {
+    let two = f32x4::from(2.0);
+    (two / (exponent + two)).sqrt().min(f32x4::ONE)
+}
+
+/// Inverse of `exponent_to_roughness`.
+fn roughness_to_exponent(roughness: f32x4) -> f32x4 {
+    let two = f32x4::from(2.0);
+    (two / (roughness * roughness).max(f32x4::EPSILON) - two).max(f32x4::ZERO)
 }
 
 pub trait Material: Send + Sync {
@@ -35,12 +70,28 @@ pub trait Material: Send + Sync {
         intersection: &WShadingPoint,
         bump: &'bump Bump,
     ) -> &'bump mut dyn BSDF;
+
+    /// Whether the back of a surface with this material behaves like its front.
+    fn sided(&self) -> Sided {
+        Sided::Two
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sided {
+    /// Only the side the geometric normal points out of is the front. Hits from behind
+    /// keep the geometric normal and don't emit.
+    One,
+    /// Both sides are the front: the shading normal is flipped to face the viewer.
+    Two,
 }
 
 pub struct WScatteringEvent {
     pub wi: Wec3,
     pub f: WSrgb,
     pub pdf: f32x4,
+    /// Mask of the lanes which sampled a specular (rather than diffuse) lobe
+    pub specular: f32x4,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -114,7 +165,7 @@ impl BSDF for LambertianBSDF {
         samples_2d: &[f32x4; 4],
     ) -> Option<WScatteringEvent> {
         let diffuse_sample = Wec3::cosine_weighted_in_hemisphere(array_ref![samples_2d, 0, 2]);
-        let diffuse_bounce = (intersection.basis * diffuse_sample).normalized();
+        let diffuse_bounce = intersection.basis.to_world(diffuse_sample).normalized();
         // in this case diffuse_sample.z = diffuse_sample.dot(Wec3::unit_z())
         // because using intersection coordinate system basis
         let diffuse_pdf = diffuse_sample.z / f32x4::from(PI);
@@ -124,12 +175,117 @@ impl BSDF for LambertianBSDF {
             wi: diffuse_bounce,
             f: diffuse_f,
             pdf: diffuse_pdf,
+            specular: f32x4::ZERO,
         })
     }
 
     fn f(&self, _wi: Wec3, _wo: Wec3, _n: Wec3) -> WSrgb {
         self.albedo / f32x4::PI
     }
+
+    fn albedo(&self) -> Option<WSrgb> {
+        Some(self.albedo)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct OrenNayarBSDF {
+    albedo: WSrgb,
+    a: f32x4,
+    b: f32x4,
+}
+
+/// Rough diffuse reflection following the Oren-Nayar microfacet model. Sigma is the standard
+/// deviation of the microfacet orientation angle, in radians. A sigma of 0 is Lambertian.
+#[allow(dead_code)]
+pub struct OrenNayar<AG, SG> {
+    pub albedo_gen: AG,
+    pub sigma_gen: SG,
+}
+
+impl<AG, SG> OrenNayar<AG, SG> {
+    #[allow(dead_code)]
+    pub fn new(albedo_gen: AG, sigma_gen: SG) -> Self {
+        Self {
+            albedo_gen,
+            sigma_gen,
+        }
+    }
+}
+
+impl<AG, SG> Material for OrenNayar<AG, SG>
+where
+    AG: WShadingParamGenerator<WSrgb> + Send + Sync,
+    SG: WShadingParamGenerator<f32x4> + Send + Sync,
+{
+    fn get_bsdf_at<'bump>(
+        &self,
+        intersection: &WShadingPoint,
+        bump: &'bump Bump,
+    ) -> &'bump mut dyn BSDF {
+        let sigma = self.sigma_gen.gen(intersection);
+        let sigma2 = sigma * sigma;
+        bump.alloc_with(|| OrenNayarBSDF {
+            albedo: self.albedo_gen.gen(intersection),
+            a: f32x4::ONE - sigma2 / (f32x4::from(2.0) * (sigma2 + f32x4::from(0.33))),
+            b: f32x4::from(0.45) * sigma2 / (sigma2 + f32x4::from(0.09)),
+        })
+    }
+}
+
+impl BSDF for OrenNayarBSDF {
+    fn albedo(&self) -> Option<WSrgb> {
+        Some(self.albedo)
+    }
+
+    fn scatter(
+        &self,
+        wo: Wec3,
+        intersection: &WShadingPoint,
+        _samples_1d: f32x4,
+        samples_2d: &[f32x4; 4],
+    ) -> Option<WScatteringEvent> {
+        let diffuse_sample = Wec3::cosine_weighted_in_hemisphere(array_ref![samples_2d, 0, 2]);
+        let wi = intersection.basis.to_world(diffuse_sample).normalized();
+        // in this case diffuse_sample.z = diffuse_sample.dot(Wec3::unit_z())
+        // because using intersection coordinate system basis
+        let pdf = diffuse_sample.z / f32x4::PI;
+
+        Some(WScatteringEvent {
+            wi,
+            f: self.f(wo, wi, intersection.normal),
+            pdf,
+            specular: f32x4::ZERO,
+        })
+    }
+
+    fn f(&self, wo: Wec3, wi: Wec3, n: Wec3) -> WSrgb {
+        let cos_o = wo.dot(n).abs().min(f32x4::ONE);
+        let cos_i = wi.dot(n).abs().min(f32x4::ONE);
+        let sin_o = (f32x4::ONE - cos_o * cos_o).max(f32x4::ZERO).sqrt();
+        let sin_i = (f32x4::ONE - cos_i * cos_i).max(f32x4::ZERO).sqrt();
+
+        // cosine of the azimuthal angle between wo and wi, from their projections
+        // onto the tangent plane (which have lengths sin_o and sin_i)
+        let sin_prod = sin_o * sin_i;
+        let proj_dot = (wo - n * wo.dot(n)).dot(wi - n * wi.dot(n));
+        let max_cos = f32x4::merge(
+            sin_prod.cmp_gt(f32x4::from(1e-4)),
+            (proj_dot / sin_prod).max(f32x4::ZERO),
+            f32x4::ZERO,
+        );
+
+        // alpha = max(theta_i, theta_o), beta = min(theta_i, theta_o)
+        let i_steeper = cos_i.cmp_gt(cos_o);
+        let sin_alpha = f32x4::merge(i_steeper, sin_o, sin_i);
+        let tan_beta = f32x4::merge(
+            i_steeper,
+            sin_i / cos_i.max(f32x4::EPSILON),
+            sin_o / cos_o.max(f32x4::EPSILON),
+        );
+
+        self.albedo / f32x4::PI * (self.a + self.b * max_cos * sin_alpha * tan_beta)
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -183,16 +339,36 @@ where
 }
 
 impl BSDF for DielectricBSDF {
-    fn f(&self, wi: Wec3, wo: Wec3, n: Wec3) -> WSrgb {
-        let dot = f32x4::ZERO.max(wi.dot(n));
+    /// Only the diffuse base; the specular coating is white and adds up to 4% at normal
+    /// incidence.
+    fn albedo(&self) -> Option<WSrgb> {
+        Some(self.albedo)
+    }
+
+    fn roughness(&self) -> Option<f32x4> {
+        Some(exponent_to_roughness(self.roughness))
+    }
+
+    fn regularize(&mut self, min_roughness: f32x4) {
+        self.roughness = self.roughness.min(roughness_to_exponent(min_roughness));
+    }
+
+    fn f(&self, wo: Wec3, wi: Wec3, n: Wec3) -> WSrgb {
+        let (diffuse_f, spec_f) = self.f_split(wo, wi, n);
+        spec_f + diffuse_f
+    }
+
+    fn f_split(&self, wo: Wec3, wi: Wec3, n: Wec3) -> (WSrgb, WSrgb) {
+        // rounding can push the cosines of unit vectors slightly past 1
+        let dot = saturate(wo.dot(n));
         let fresnel = f_schlick(dot, f32x4::from(0.04));
         let half = (wo + wi).normalized();
-        let cos_alpha = f32x4::ZERO.max(half.dot(n)).powf(self.roughness);
+        let cos_alpha = saturate(half.dot(n)).powf(self.roughness);
         let two = f32x4::from(2.0);
         let spec_factor = cos_alpha * (self.roughness + two) / (two * f32x4::PI);
         let spec_f = WSrgb::one() * spec_factor * fresnel;
         let diffuse_f = self.albedo / f32x4::PI * (f32x4::ONE - fresnel);
-        spec_f + diffuse_f
+        (diffuse_f, spec_f)
     }
 
     fn scatter(
@@ -208,7 +384,7 @@ impl BSDF for DielectricBSDF {
 
         // diffuse part
         let diffuse_sample = Wec3::cosine_weighted_in_hemisphere(array_ref![samples_2d, 0, 2]);
-        let diffuse_bounce = (intersection.basis * diffuse_sample).normalized();
+        let diffuse_bounce = intersection.basis.to_world(diffuse_sample).normalized();
         // in this case diffuse_sample.z = diffuse_sample.dot(Wec3::unit_z())
         // because using intersection coordinate system basis
         let diffuse_pdf = (diffuse_sample.z / f32x4::PI).max(f32x4::from(0.00001));
@@ -220,7 +396,7 @@ impl BSDF for DielectricBSDF {
         let reflection = wo.reflected(norm);
         let basis = reflection.get_orthonormal_basis();
 
-        let spec_bounce = (basis * spec_sample).normalized();
+        let spec_bounce = basis.to_world(spec_sample).normalized();
 
         // in this case spec_sample.z = spec_sample.dot(Wec3::unit_z()) = cos_alpha
         // because using reflection coordinate system basis
@@ -243,6 +419,108 @@ impl BSDF for DielectricBSDF {
             wi: Wec3::merge(fresnel_mask, spec_bounce, diffuse_bounce),
             f: WSrgb::merge(fresnel_mask, spec_f, diffuse_f),
             pdf: fresnel * spec_pdf + (f32x4::ONE - fresnel) * diffuse_pdf,
+            specular: fresnel_mask,
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct PhongBSDF {
+    specular: WSrgb,
+    shininess: f32x4,
+}
+
+/// Glossy reflection with the normalized Phong lobe, `cos^shininess` of the angle to the
+/// mirror direction, for matching renders made with it. Higher shininess is sharper, and
+/// it's energy conserving as long as the specular color is at most one.
+#[allow(dead_code)]
+pub struct Phong<SG, NG> {
+    pub specular_gen: SG,
+    pub shininess_gen: NG,
+}
+
+impl<SG, NG> Phong<SG, NG> {
+    #[allow(dead_code)]
+    pub fn new(specular_gen: SG, shininess_gen: NG) -> Self {
+        Self {
+            specular_gen,
+            shininess_gen,
+        }
+    }
+}
+
+impl<SG, NG> Material for Phong<SG, NG>
+where
+    SG: WShadingParamGenerator<WSrgb> + Send + Sync,
+    NG: WShadingParamGenerator<f32x4> + Send + Sync,
+{
+    fn get_bsdf_at<'bump>(
+        &self,
+        intersection: &WShadingPoint,
+        bump: &'bump Bump,
+    ) -> &'bump mut dyn BSDF {
+        bump.alloc_with(|| PhongBSDF {
+            specular: self.specular_gen.gen(intersection),
+            shininess: self.shininess_gen.gen(intersection).max(f32x4::ZERO),
+        })
+    }
+}
+
+impl PhongBSDF {
+    /// `cos^shininess` of the angle between `wi` and the mirror of `wo`, which is zero for
+    /// `wi` below the surface.
+    fn lobe(&self, wo: Wec3, wi: Wec3, n: Wec3) -> f32x4 {
+        let mirror = n * (f32x4::from(2.0) * wo.dot(n)) - wo;
+        let lobe = saturate(mirror.dot(wi)).powf(self.shininess);
+        f32x4::merge(wi.dot(n).cmp_gt(f32x4::ZERO), lobe, f32x4::ZERO)
+    }
+}
+
+impl BSDF for PhongBSDF {
+    fn albedo(&self) -> Option<WSrgb> {
+        Some(self.specular)
+    }
+
+    fn roughness(&self) -> Option<f32x4> {
+        Some(exponent_to_roughness(self.shininess))
+    }
+
+    fn regularize(&mut self, min_roughness: f32x4) {
+        self.shininess = self.shininess.min(roughness_to_exponent(min_roughness));
+    }
+
+    fn f(&self, wo: Wec3, wi: Wec3, n: Wec3) -> WSrgb {
+        let (_, spec_f) = self.f_split(wo, wi, n);
+        spec_f
+    }
+
+    fn f_split(&self, wo: Wec3, wi: Wec3, n: Wec3) -> (WSrgb, WSrgb) {
+        let norm = (self.shininess + f32x4::from(2.0)) / f32x4::TWO_PI;
+        (WSrgb::zero(), self.specular * (norm * self.lobe(wo, wi, n)))
+    }
+
+    fn scatter(
+        &self,
+        wo: Wec3,
+        intersection: &WShadingPoint,
+        _samples_1d: f32x4,
+        samples_2d: &[f32x4; 4],
+    ) -> Option<WScatteringEvent> {
+        let n = intersection.normal;
+        // wo is in the same hemisphere as the shading normal, so the mirror direction is too
+        let mirror = n * (f32x4::from(2.0) * wo.dot(n)) - wo;
+        let sample = Wec3::cosine_power_weighted(array_ref![samples_2d, 0, 2], self.shininess);
+        let wi = mirror.get_orthonormal_basis().to_world(sample).normalized();
+
+        // sample.z is the cosine to the mirror direction, since it's the z axis of the basis
+        let pdf =
+            (self.shininess + f32x4::ONE) / f32x4::TWO_PI * saturate(sample.z).powf(self.shininess);
+
+        Some(WScatteringEvent {
+            wi,
+            f: self.f(wo, wi, n),
+            pdf: pdf.max(f32x4::EPSILON),
+            specular: f32x4::ZERO.cmp_eq(f32x4::ZERO),
         })
     }
 }
@@ -407,6 +685,134 @@ impl Material for Sky {
     }
 }
 
+/// An environment stored as an equal-area octahedral map, a square image in which every
+/// texel covers the same solid angle (see `octahedral_uv_to_dir`), with the center of the
+/// image at +y. Like `Sky`, it's meant to be put on a large sphere around the scene.
+pub struct OctahedralEnvironment {
+    texels: Vec<Srgb>,
+    resolution: usize,
+    distribution: Distribution2D,
+}
+
+impl OctahedralEnvironment {
+    /// `texels` holds `resolution * resolution` linear radiance values, row by row.
+    #[allow(dead_code)]
+    pub fn new(texels: Vec<Srgb>, resolution: usize) -> Result<Self, String> {
+        if resolution == 0 || texels.len() != resolution * resolution {
+            return Err(format!(
+                "Octahedral environment of resolution {} needs {} texels, got {}",
+                resolution,
+                resolution * resolution,
+                texels.len()
+            ));
+        }
+
+        let luminances = texels
+            .iter()
+            .map(|texel| texel.luminance())
+            .collect::<Vec<_>>();
+        let distribution = Distribution2D::new(&luminances, resolution, resolution);
+
+        Ok(Self {
+            texels,
+            resolution,
+            distribution,
+        })
+    }
+
+    /// Loads a square octahedral map from an image file, scaling it by `intensity`.
+    #[allow(dead_code)]
+    pub fn open<P: AsRef<std::path::Path>>(path: P, intensity: f32) -> Result<Self, String> {
+        let image = image::open(path.as_ref())
+            .map_err(|e| format!("Failed to open environment image: {}", e))?
+            .to_rgb();
+        let (width, height) = image.dimensions();
+        if width != height {
+            return Err(format!(
+                "Octahedral environment images must be square, got {}x{}",
+                width, height
+            ));
+        }
+
+        let texels = image
+            .pixels()
+            .map(|pixel| {
+                let [r, g, b] = pixel.0;
+                let srgb = Srgb::new(f32::from(r), f32::from(g), f32::from(b)) / 255.0;
+                srgb.gamma_corrected(1.0 / 2.2) * intensity
+            })
+            .collect::<Vec<_>>();
+        Self::new(texels, width as usize)
+    }
+
+    /// Radiance arriving from `dir`, looked up from the nearest texel.
+    pub fn radiance(&self, dir: Vec3) -> Srgb {
+        let uv = octahedral_dir_to_uv(Vec3::new(dir.x, dir.z, dir.y).normalized());
+        let max = self.resolution - 1;
+        let x = ((uv.x * self.resolution as f32) as usize).min(max);
+        let y = ((uv.y * self.resolution as f32) as usize).min(max);
+        self.texels[x + y * self.resolution]
+    }
+
+    /// Importance samples a direction proportionally to the luminance of the map, returning
+    /// it with the radiance from it and its pdf with respect to solid angle. Because the
+    /// mapping is equal-area, that's just the pdf over the image divided by 4 pi.
+    #[allow(dead_code)]
+    pub fn sample_direction(&self, u: [f32; 2]) -> (Vec3, Srgb, f32) {
+        let (uv, pdf) = self.distribution.sample_continuous(u);
+        let dir = octahedral_uv_to_dir(uv);
+        let dir = Vec3::new(dir.x, dir.z, dir.y);
+        (dir, self.radiance(dir), pdf / (4.0 * PI))
+    }
+}
+
+impl Material for OctahedralEnvironment {
+    fn get_bsdf_at<'bump>(
+        &self,
+        intersection: &WShadingPoint,
+        bump: &'bump Bump,
+    ) -> &'bump mut dyn BSDF {
+        let dirs: [Vec3; 4] = intersection.ray.dir.into();
+        let mut radiances = [Srgb::zero(); 4];
+        for (radiance, dir) in radiances.iter_mut().zip(dirs.iter()) {
+            *radiance = self.radiance(*dir);
+        }
+        bump.alloc_with(|| EnvironmentBSDF {
+            radiance: WSrgb::from(radiances),
+        })
+    }
+}
+
+/// Emits radiance looked up ahead of time for the direction of each lane's ray.
+#[derive(Clone, Copy)]
+pub struct EnvironmentBSDF {
+    radiance: WSrgb,
+}
+
+impl BSDF for EnvironmentBSDF {
+    fn receives_light(&self) -> bool {
+        false
+    }
+
+    fn f(&self, _: Wec3, _: Wec3, _: Wec3) -> WSrgb {
+        panic!()
+    }
+
+    fn scatter(
+        &self,
+        _wo: Wec3,
+        _intersection: &WShadingPoint,
+        _samples_1d: f32x4,
+        _samples_2d: &[f32x4; 4],
+    ) -> Option<WScatteringEvent> {
+        None
+    }
+
+    fn le(&self, _wo: Wec3, _intersection: &WShadingPoint) -> WSrgb {
+        self.radiance
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct SkyBSDF {
     top: WSrgb,
@@ -442,12 +848,22 @@ impl BSDF for SkyBSDF {
 
 pub struct Emissive<EG> {
     pub emission_gen: EG,
+    pub sided: Sided,
 }
 
 impl<EG> Emissive<EG> {
     #[allow(dead_code)]
     pub fn new(emission_gen: EG) -> Self {
-        Self { emission_gen }
+        Self {
+            emission_gen,
+            sided: Sided::Two,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_sided(mut self, sided: Sided) -> Self {
+        self.sided = sided;
+        self
     }
 }
 
@@ -467,6 +883,10 @@ where
             },
         })
     }
+
+    fn sided(&self) -> Sided {
+        self.sided
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -501,3 +921,98 @@ where
         self.emission
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hitable::{WHit, WHitAttributes};
+    use crate::math::Vec2u;
+    use crate::ray::WRay;
+
+    /// Shading point at the origin with a +Z normal, hit by a ray coming in along `-wo`.
+    fn shading_point(wo: Wec3) -> WShadingPoint {
+        let ray = WRay::new(
+            wo,
+            -wo,
+            f32x4::ZERO,
+            [Vec2u::zero(); 4],
+            [true; 4],
+            [0.0; 4],
+            [0; 4],
+        );
+        let hit = WHit {
+            ray,
+            t: f32x4::ONE,
+            attributes: WHitAttributes::none(),
+        };
+        WShadingPoint::new(hit, Wec3::zero(), f32x4::ZERO, Wec3::splat(Vec3::unit_z()))
+    }
+
+    #[test]
+    fn smooth_oren_nayar_is_lambertian() {
+        let albedo = WSrgb::splat(Srgb::new(0.9, 0.5, 0.2));
+        let oren_nayar = OrenNayar::new(albedo, f32x4::ZERO);
+        let lambertian = Lambertian::new(albedo);
+
+        let wo = Wec3::from([
+            Vec3::unit_z(),
+            Vec3::new(0.6, 0.0, 0.8),
+            Vec3::new(-0.3, 0.4, 0.866).normalized(),
+            Vec3::new(0.0, 0.995, 0.1).normalized(),
+        ]);
+        let wi = Wec3::from([
+            Vec3::new(0.6, 0.0, 0.8),
+            Vec3::new(-0.6, 0.0, 0.8),
+            Vec3::new(0.9, 0.1, 0.1).normalized(),
+            Vec3::unit_z(),
+        ]);
+        let intersection = shading_point(wo);
+        let bump = Bump::new();
+        let oren_nayar = oren_nayar.get_bsdf_at(&intersection, &bump);
+        let lambertian = lambertian.get_bsdf_at(&intersection, &bump);
+
+        let n = intersection.normal;
+        let diff: [Srgb; 4] = (oren_nayar.f(wo, wi, n) - lambertian.f(wo, wi, n)).into();
+        for diff in diff.iter() {
+            assert!([diff.x, diff.y, diff.z].iter().all(|d| d.abs() < 1e-6));
+        }
+
+        // and it's sampled the same way, so the pdfs match too
+        let samples = [f32x4::from([0.1, 0.4, 0.7, 0.95]); 4];
+        let a = oren_nayar
+            .scatter(wo, &intersection, f32x4::ZERO, &samples)
+            .unwrap();
+        let b = lambertian
+            .scatter(wo, &intersection, f32x4::ZERO, &samples)
+            .unwrap();
+        assert_eq!(a.pdf.as_ref(), b.pdf.as_ref());
+    }
+
+    #[test]
+    fn regularizing_only_ever_roughens() {
+        let white = WSrgb::splat(Srgb::new(0.9, 0.9, 0.9));
+        let intersection = shading_point(Wec3::splat(Vec3::unit_z()));
+        let bump = Bump::new();
+        // close to mirrors, and a lobe which is already rough
+        let bsdfs = [
+            Dielectric::new(white, f32x4::from(2000.0)).get_bsdf_at(&intersection, &bump),
+            Phong::new(white, f32x4::from(5000.0)).get_bsdf_at(&intersection, &bump),
+            Phong::new(white, f32x4::from(1.0)).get_bsdf_at(&intersection, &bump),
+        ];
+
+        for bsdf in bsdfs.iter_mut() {
+            let before = bsdf.roughness().unwrap().as_ref()[0];
+            bsdf.regularize(f32x4::from(0.3));
+            let after = bsdf.roughness().unwrap().as_ref()[0];
+            assert!(after >= before.max(0.3) - 1e-4, "{} -> {}", before, after);
+            if before >= 0.3 {
+                assert!((after - before).abs() < 1e-6);
+            }
+        }
+
+        // diffuse BSDFs have no lobe to roughen
+        let lambertian = Lambertian::new(white).get_bsdf_at(&intersection, &bump);
+        lambertian.regularize(f32x4::from(0.3));
+        assert!(lambertian.roughness().is_none());
+    }
+}
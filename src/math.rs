@@ -1,3 +1,4 @@
+use crate::ray::WRay;
 use crate::spectrum::WSrgb;
 
 use std::f32::consts::PI;
@@ -13,12 +14,248 @@ pub type Vec2u = vec::repr_c::Vec2<usize>;
 pub type Aabru = vek::geom::repr_c::Aabr<usize>;
 pub type Extent2u = vek::vec::repr_c::Extent2<usize>;
 
+/// Number of lanes in the wide types, and so rays in a packet.
+pub const LANES: usize = 4;
+
+pub type Mat3 = ultraviolet::Mat3;
 pub type Wat3 = ultraviolet::Wat3;
 
-#[derive(Clone, Copy)]
-pub struct Transform {
-    pub position: Wec3,
-    // pub orientation: Quat,
+macro_rules! transforms {
+    ($($(#[$attr:meta])* $n:ident => $vt:ident, $mt:ident),+) => {
+        $(/// A scale along the local axes, followed by a rotation and then a translation.
+        #[derive(Clone, Copy, Debug)]
+        $(#[$attr])*
+        pub struct $n {
+            pub position: $vt,
+            /// Must be orthonormal
+            pub rotation: $mt,
+            pub scale: $vt,
+        }
+
+        #[allow(dead_code)]
+        impl $n {
+            pub fn identity() -> Self {
+                Self {
+                    position: $vt::zero(),
+                    rotation: $mt::identity(),
+                    scale: $vt::one(),
+                }
+            }
+
+            pub fn from_trs(position: $vt, rotation: $mt, scale: $vt) -> Self {
+                Self {
+                    position,
+                    rotation,
+                    scale,
+                }
+            }
+
+            /// Builds a transform from an arbitrary linear part and translation. See
+            /// `decompose_linear` for which matrices can be reproduced.
+            pub fn from_linear(linear: $mt, position: $vt) -> Self {
+                let (rotation, scale) = decompose_linear(linear);
+                Self {
+                    position,
+                    rotation,
+                    scale,
+                }
+            }
+
+            /// The rotation and scale combined into a single matrix.
+            pub fn linear(&self) -> $mt {
+                $mt::new(
+                    self.rotation.cols[0] * self.scale.x,
+                    self.rotation.cols[1] * self.scale.y,
+                    self.rotation.cols[2] * self.scale.z,
+                )
+            }
+
+            pub fn transform_point(&self, point: $vt) -> $vt {
+                self.linear() * point + self.position
+            }
+
+            pub fn transform_vector(&self, vector: $vt) -> $vt {
+                self.linear() * vector
+            }
+
+            /// Transforms a normal by the inverse transpose of the linear part, which for a
+            /// rotation and scale is the rotation applied to the normal divided by the scale.
+            pub fn transform_normal(&self, normal: $vt) -> $vt {
+                (self.rotation * (normal / self.scale)).normalized()
+            }
+        })+
+
+        $(impl MatrixDecomposition for $mt {
+            type Vector = $vt;
+
+            fn decompose(&self) -> ($mt, $vt) {
+                let cols = self.cols;
+                let scale = $vt::new(cols[0].mag(), cols[1].mag(), cols[2].mag());
+                // A negative determinant means the matrix mirrors, which a rotation can't do,
+                // so fold the mirroring into the scale on x.
+                let flip = cols[0].dot(cols[1].cross(cols[2])).signum();
+                let scale = $vt::new(scale.x * flip, scale.y, scale.z);
+                let rotation = $mt::new(cols[0] / scale.x, cols[1] / scale.y, cols[2] / scale.z);
+                (rotation, scale)
+            }
+        })+
+    }
+}
+
+transforms!(
+    #[cfg_attr(
+        feature = "serde",
+        derive(serde::Serialize, serde::Deserialize),
+        serde(from = "serde_impls::TransformData", into = "serde_impls::TransformData")
+    )]
+    Transform => Vec3, Mat3,
+    WTransform => Wec3, Wat3
+);
+
+/// (De)serialization of the scalar math types. `Vec2` and `Vec3` belong to ultraviolet, so
+/// they can't derive serde themselves; fields of those types can use
+/// `#[serde(with = "Vec3Def")]` instead.
+#[cfg(feature = "serde")]
+pub use serde_impls::{Vec2Def, Vec3Def};
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use super::{Mat3, Transform, Vec2, Vec3};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(remote = "Vec2")]
+    pub struct Vec2Def {
+        pub x: f32,
+        pub y: f32,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(remote = "Vec3")]
+    pub struct Vec3Def {
+        pub x: f32,
+        pub y: f32,
+        pub z: f32,
+    }
+
+    /// What a `Transform` is stored as, with the rotation as its columns.
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename = "Transform")]
+    pub struct TransformData {
+        #[serde(with = "Vec3Def")]
+        position: Vec3,
+        rotation: [[f32; 3]; 3],
+        #[serde(with = "Vec3Def")]
+        scale: Vec3,
+    }
+
+    impl From<Transform> for TransformData {
+        fn from(transform: Transform) -> Self {
+            let cols = transform.rotation.cols;
+            let col = |c: Vec3| [c.x, c.y, c.z];
+            TransformData {
+                position: transform.position,
+                rotation: [col(cols[0]), col(cols[1]), col(cols[2])],
+                scale: transform.scale,
+            }
+        }
+    }
+
+    impl From<TransformData> for Transform {
+        fn from(data: TransformData) -> Self {
+            let col = |c: [f32; 3]| Vec3::new(c[0], c[1], c[2]);
+            let [x, y, z] = data.rotation;
+            Transform::from_trs(data.position, Mat3::new(col(x), col(y), col(z)), data.scale)
+        }
+    }
+}
+
+/// Splitting a linear transformation into a rotation and a scale along its (rotated) axes.
+pub trait MatrixDecomposition: Sized {
+    type Vector;
+
+    fn decompose(&self) -> (Self, Self::Vector);
+}
+
+/// Decomposes `linear` into `(rotation, scale)` such that scaling by `scale` along the
+/// local axes and then rotating by `rotation` reproduces `linear`. This is exact for any
+/// product of a rotation and a scale, possibly mirrored; matrices with shear come back
+/// with a rotation which isn't orthonormal. Zero scales can't be decomposed.
+pub fn decompose_linear<M: MatrixDecomposition>(linear: M) -> (M, M::Vector) {
+    linear.decompose()
+}
+
+/// Axis-aligned bounding box.
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+#[allow(dead_code)]
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Self { min, max }
+    }
+
+    pub fn from_center_half_size(center: Vec3, half_size: Vec3) -> Self {
+        Self {
+            min: center - half_size,
+            max: center + half_size,
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Self {
+        Self {
+            min: self.min.min_by_component(other.min),
+            max: self.max.max_by_component(other.max),
+        }
+    }
+
+    pub fn grown_to(&self, point: Vec3) -> Self {
+        Self {
+            min: self.min.min_by_component(point),
+            max: self.max.max_by_component(point),
+        }
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn size(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn is_finite(&self) -> bool {
+        [self.min, self.max]
+            .iter()
+            .all(|v| v.x.is_finite() && v.y.is_finite() && v.z.is_finite())
+    }
+
+    /// Lanes of `ray` which pass through the box somewhere in `t_range`. Uses the ray's
+    /// precomputed reciprocal direction and signs, so there are no divisions.
+    pub fn hit_wide(&self, ray: &WRay, t_range: std::ops::Range<f32x4>) -> f32x4 {
+        let (min, max) = (Wec3::splat(self.min), Wec3::splat(self.max));
+        let neg = ray.dir_is_neg;
+        let near = Wec3::new(
+            f32x4::merge(neg.x, max.x, min.x),
+            f32x4::merge(neg.y, max.y, min.y),
+            f32x4::merge(neg.z, max.z, min.z),
+        );
+        let far = Wec3::new(
+            f32x4::merge(neg.x, min.x, max.x),
+            f32x4::merge(neg.y, min.y, max.y),
+            f32x4::merge(neg.z, min.z, max.z),
+        );
+        // origins exactly on a slab plane of an axis the ray is parallel to give 0 * inf, and
+        // those slabs don't limit the ray at all
+        let nan_to = |t: f32x4, value: f32| f32x4::merge(t.cmp_nan(t), f32x4::from(value), t);
+        let t_near = ((near - ray.origin) * ray.inv_dir).map(|t| nan_to(t, std::f32::MIN));
+        let t_far = ((far - ray.origin) * ray.inv_dir).map(|t| nan_to(t, std::f32::MAX));
+        let t_enter = t_range.start.max(t_near.x).max(t_near.y).max(t_near.z);
+        let t_exit = t_range.end.min(t_far.x).min(t_far.y).min(t_far.z);
+        t_enter.cmp_le(t_exit)
+    }
 }
 
 pub trait OrthonormalBasis<M>: Sized {
@@ -37,6 +274,195 @@ impl OrthonormalBasis<Wat3> for Wec3 {
     }
 }
 
+/// Transforms directions between world space and the frame of an orthonormal basis, in
+/// which +z is the direction the basis was built around. Shading points keep their basis,
+/// so sampling code can reuse it rather than building a new one.
+pub trait BasisTransform {
+    fn to_world(&self, local: Wec3) -> Wec3;
+    /// Inverse of `to_world`, which for an orthonormal basis is its transpose.
+    #[allow(dead_code)]
+    fn to_local(&self, world: Wec3) -> Wec3;
+}
+
+impl BasisTransform for Wat3 {
+    #[inline]
+    fn to_world(&self, local: Wec3) -> Wec3 {
+        self.cols[0] * local.x + self.cols[1] * local.y + self.cols[2] * local.z
+    }
+
+    #[inline]
+    fn to_local(&self, world: Wec3) -> Wec3 {
+        Wec3::new(
+            self.cols[0].dot(world),
+            self.cols[1].dot(world),
+            self.cols[2].dot(world),
+        )
+    }
+}
+
+/// Loads and stores the lanes of a wide type from/to (possibly scattered) elements of a slice,
+/// for example to fetch the vertices of four different triangles at once.
+#[allow(dead_code)]
+pub trait Gather<T>: Sized {
+    fn gather(base: &[T], indices: [usize; 4]) -> Self;
+    fn scatter(&self, base: &mut [T], indices: [usize; 4]);
+}
+
+impl Gather<Vec3> for Wec3 {
+    #[inline]
+    fn gather(base: &[Vec3], indices: [usize; 4]) -> Self {
+        Wec3::from([
+            base[indices[0]],
+            base[indices[1]],
+            base[indices[2]],
+            base[indices[3]],
+        ])
+    }
+
+    #[inline]
+    fn scatter(&self, base: &mut [Vec3], indices: [usize; 4]) {
+        let lanes: [Vec3; 4] = (*self).into();
+        for (lane, idx) in lanes.iter().zip(indices.iter()) {
+            base[*idx] = *lane;
+        }
+    }
+}
+
+impl Gather<f32> for f32x4 {
+    #[inline]
+    fn gather(base: &[f32], indices: [usize; 4]) -> Self {
+        f32x4::from([
+            base[indices[0]],
+            base[indices[1]],
+            base[indices[2]],
+            base[indices[3]],
+        ])
+    }
+
+    #[inline]
+    fn scatter(&self, base: &mut [f32], indices: [usize; 4]) {
+        for (lane, idx) in self.as_ref().iter().zip(indices.iter()) {
+            base[*idx] = *lane;
+        }
+    }
+}
+
+/// Horizontal reductions over the lanes of a comparison mask, as returned by `cmp_*`.
+pub trait Mask: Copy {
+    /// Whether any lane is set.
+    fn any(self) -> bool;
+    /// Whether every lane is set.
+    fn all(self) -> bool;
+    /// Whether no lane is set.
+    fn none(self) -> bool {
+        !self.any()
+    }
+}
+
+impl Mask for f32x4 {
+    #[inline]
+    fn any(self) -> bool {
+        self.move_mask() != 0
+    }
+
+    #[inline]
+    fn all(self) -> bool {
+        self.move_mask() == 0b1111
+    }
+}
+
+/// Per-lane blend of two wide values, for branchless code that is generic over the type.
+#[allow(dead_code)]
+pub trait Select: Sized {
+    /// Picks `a` in the lanes where `mask` is set and `b` in the others.
+    fn select(mask: f32x4, a: Self, b: Self) -> Self;
+}
+
+impl Select for f32x4 {
+    #[inline]
+    fn select(mask: f32x4, a: Self, b: Self) -> Self {
+        f32x4::merge(mask, a, b)
+    }
+}
+
+impl Select for Wec3 {
+    #[inline]
+    fn select(mask: f32x4, a: Self, b: Self) -> Self {
+        Wec3::merge(mask, a, b)
+    }
+}
+
+impl Select for WSrgb {
+    #[inline]
+    fn select(mask: f32x4, a: Self, b: Self) -> Self {
+        WSrgb::merge(mask, a, b)
+    }
+}
+
+/// Component-wise math for procedural textures.
+#[allow(dead_code)]
+pub trait ComponentWise: Sized {
+    fn abs(&self) -> Self;
+    /// -1, 0 or 1 depending on whether each component is negative, zero or positive.
+    fn sign(&self) -> Self;
+    /// Rounds each component towards negative infinity.
+    fn floor(&self) -> Self;
+    /// `self - self.floor()`, which is always in [0, 1), even for negative components.
+    fn fract(&self) -> Self;
+}
+
+impl ComponentWise for f32x4 {
+    #[inline]
+    fn abs(&self) -> Self {
+        f32x4::abs(*self)
+    }
+
+    #[inline]
+    fn sign(&self) -> Self {
+        let positive = f32x4::merge(self.cmp_gt(f32x4::ZERO), f32x4::ONE, f32x4::ZERO);
+        f32x4::merge(self.cmp_lt(f32x4::ZERO), f32x4::from(-1.0), positive)
+    }
+
+    #[inline]
+    fn floor(&self) -> Self {
+        let lanes = self.as_ref();
+        f32x4::from([
+            lanes[0].floor(),
+            lanes[1].floor(),
+            lanes[2].floor(),
+            lanes[3].floor(),
+        ])
+    }
+
+    #[inline]
+    fn fract(&self) -> Self {
+        // can round up to exactly 1 for tiny negative values
+        (*self - ComponentWise::floor(self)).min(f32x4::from(1.0 - std::f32::EPSILON / 2.0))
+    }
+}
+
+impl ComponentWise for Wec3 {
+    #[inline]
+    fn abs(&self) -> Self {
+        self.map(|x| ComponentWise::abs(&x))
+    }
+
+    #[inline]
+    fn sign(&self) -> Self {
+        self.map(|x| x.sign())
+    }
+
+    #[inline]
+    fn floor(&self) -> Self {
+        self.map(|x| ComponentWise::floor(&x))
+    }
+
+    #[inline]
+    fn fract(&self) -> Self {
+        self.map(|x| x.fract())
+    }
+}
+
 pub trait RandomSample2d {
     type Sample;
     fn rand_in_unit_disk(samples: &Self::Sample) -> Self;
@@ -83,11 +509,10 @@ impl RandomSample3d<f32x4> for Wec3 {
 
     // pdf: (power+1)/ 2pi * cos^power(alpha)
     fn cosine_power_weighted(samples: &[f32x4; 2], power: f32x4) -> Self {
-        let two = f32x4::from(2.0);
         let a = samples[0].powf(f32x4::ONE / (power + f32x4::ONE));
         let a2 = a * a;
         let b = (f32x4::ONE - a2).sqrt();
-        let (s, c) = (two * samples[1]).sin_cos();
+        let (s, c) = (f32x4::from(2.0 * PI) * samples[1]).sin_cos();
         Wec3::new(b * c, b * s, a)
     }
 }
@@ -107,11 +532,35 @@ pub fn f_schlick_c(cos: f32x4, f0: WSrgb) -> WSrgb {
     f0 + (WSrgb::one() - f0) * (f32x4::ONE - cos).powi([5, 5, 5, 5])
 }
 
+/// Bound on the relative error accumulated by `n` floating point operations, as in PBRT.
+#[inline]
+pub fn gamma(n: u32) -> f32 {
+    let n_eps = n as f32 * std::f32::EPSILON * 0.5;
+    n_eps / (1.0 - n_eps)
+}
+
 #[allow(dead_code)]
 pub fn saturate(v: f32x4) -> f32x4 {
     v.min(f32x4::ONE).max(f32x4::ZERO)
 }
 
+/// Shadowing of light arriving along `wi` at a surface whose shading normal is bent away
+/// from its geometric normal (Chiang et al. 2019, "Taming the Shadow Terminator"). It
+/// fades light out smoothly towards the terminator, where the cutoff of the geometric
+/// surface would otherwise show up as a hard dark band. 1 where the normals agree, or where
+/// `wi` isn't above both of them.
+pub fn shadow_terminator(geom_normal: Wec3, shading_normal: Wec3, wi: Wec3) -> f32x4 {
+    let cos_geom = geom_normal.dot(wi);
+    let cos_shading = shading_normal.dot(wi);
+    let cos_normals = geom_normal.dot(shading_normal);
+    let g = (cos_geom / (cos_shading * cos_normals)).min(f32x4::ONE);
+    let term = g + g * g - g * g * g;
+    let above = cos_geom.cmp_gt(f32x4::ZERO)
+        & cos_shading.cmp_gt(f32x4::ZERO)
+        & cos_normals.cmp_gt(f32x4::ZERO);
+    f32x4::merge(above, saturate(term), f32x4::ONE)
+}
+
 pub struct CDF {
     items: Vec<(f32, f32)>,
     densities: Vec<f32>,
@@ -169,6 +618,138 @@ impl CDF {
     }
 }
 
+/// Piecewise constant distribution over [0, 1), sampled by inverting its CDF.
+#[derive(Clone, Debug)]
+pub struct Distribution1D {
+    func: Vec<f32>,
+    cdf: Vec<f32>,
+    integral: f32,
+}
+
+impl Distribution1D {
+    /// `func` holds the (non-negative) values of the equally sized pieces. Without any it's
+    /// uniform, like a single piece.
+    pub fn new(func: &[f32]) -> Self {
+        if func.is_empty() {
+            return Self::new(&[1.0]);
+        }
+        let n = func.len();
+        let mut cdf = Vec::with_capacity(n + 1);
+        cdf.push(0.0);
+        for (i, value) in func.iter().enumerate() {
+            cdf.push(cdf[i] + value.max(0.0) / n as f32);
+        }
+
+        let integral = cdf[n];
+        for (i, c) in cdf.iter_mut().enumerate() {
+            // If everything was zero, fall back to uniform
+            *c = if integral > 0.0 {
+                *c / integral
+            } else {
+                i as f32 / n as f32
+            };
+        }
+
+        Self {
+            func: func.iter().map(|v| v.max(0.0)).collect(),
+            cdf,
+            integral,
+        }
+    }
+
+    /// Average value of the function over [0, 1).
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    /// Maps a uniform sample in [0, 1) to (sampled point in [0, 1), pdf, index of the piece).
+    pub fn sample_continuous(&self, u: f32) -> (f32, f32, usize) {
+        let n = self.func.len();
+        // largest piece whose cdf starts at or below u
+        let (mut lo, mut hi) = (0, n);
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if self.cdf[mid] <= u {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+
+        let width = self.cdf[lo + 1] - self.cdf[lo];
+        let du = if width > 0.0 {
+            (u - self.cdf[lo]) / width
+        } else {
+            0.0
+        };
+        let pdf = if self.integral > 0.0 {
+            self.func[lo] / self.integral
+        } else {
+            1.0
+        };
+
+        (
+            ((lo as f32 + du) / n as f32).min(1.0 - std::f32::EPSILON),
+            pdf,
+            lo,
+        )
+    }
+
+    /// Picks a piece from a uniform sample in [0, 1), returning its index and the chance it
+    /// had of being picked. Pieces which are zero are never picked, unless all of them are.
+    pub fn sample_discrete(&self, u: f32) -> (usize, f32) {
+        let (_, pdf, index) = self.sample_continuous(u);
+        (index, pdf / self.func.len() as f32)
+    }
+}
+
+/// Piecewise constant distribution over [0, 1)^2, such as an image, sampled by first
+/// picking a row from the marginal distribution and then a column within that row.
+#[derive(Clone, Debug)]
+pub struct Distribution2D {
+    conditionals: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    /// `values` holds `width * height` values, row by row. Missing values count as zero, and
+    /// without any rows or columns the distribution is uniform.
+    pub fn new(values: &[f32], width: usize, height: usize) -> Self {
+        if width == 0 || height == 0 {
+            return Self::new(&[1.0], 1, 1);
+        }
+
+        let values = values
+            .iter()
+            .copied()
+            .chain(std::iter::repeat(0.0))
+            .take(width * height)
+            .collect::<Vec<_>>();
+        let conditionals = values
+            .chunks(width)
+            .map(Distribution1D::new)
+            .collect::<Vec<_>>();
+        let marginal = Distribution1D::new(
+            &conditionals
+                .iter()
+                .map(Distribution1D::integral)
+                .collect::<Vec<_>>(),
+        );
+        Self {
+            conditionals,
+            marginal,
+        }
+    }
+
+    /// Maps uniform samples in [0, 1)^2 to (sampled point in [0, 1)^2, pdf). The y of the
+    /// point is the row.
+    pub fn sample_continuous(&self, u: [f32; 2]) -> (Vec2, f32) {
+        let (y, pdf_y, row) = self.marginal.sample_continuous(u[1]);
+        let (x, pdf_x, _) = self.conditionals[row].sample_continuous(u[0]);
+        (Vec2::new(x, y), pdf_x * pdf_y)
+    }
+}
+
 #[inline]
 #[allow(dead_code)]
 pub fn power_heuristic(n_samples_f: usize, f_pdf: f32, n_samples_g: usize, g_pdf: f32) -> f32 {
@@ -195,4 +776,330 @@ pub fn concentric_circle_map(uv: &[f32x4; 2]) -> Wec2 {
 
     let (s, c) = phi.sin_cos();
     Wec2::new(r * c, r * s)
-}
\ No newline at end of file
+}
+/// Maps a point in the unit square to a direction with the equal-area octahedral mapping
+/// (Clarberg, "Fast Equal-Area Mapping of the (Hemi)Sphere using SIMD"). Equal areas of the
+/// square map to equal solid angles, so texels of an octahedral map all cover the same
+/// solid angle, unlike equirectangular maps which pile texels up at the poles.
+#[allow(dead_code)]
+pub fn octahedral_uv_to_dir(uv: Vec2) -> Vec3 {
+    let u = uv.x * 2.0 - 1.0;
+    let v = uv.y * 2.0 - 1.0;
+    let up = u.abs();
+    let vp = v.abs();
+
+    // distance from the diamond splitting the square into the two hemispheres
+    let signed_distance = 1.0 - (up + vp);
+    let r = 1.0 - signed_distance.abs();
+
+    let phi = if r == 0.0 { 1.0 } else { (vp - up) / r + 1.0 };
+    let phi = phi * PI / 4.0;
+    let z = (1.0 - r * r).copysign(signed_distance);
+
+    let (sin_phi, cos_phi) = phi.sin_cos();
+    let scale = r * (2.0 - r * r).max(0.0).sqrt();
+    Vec3::new(cos_phi.copysign(u) * scale, sin_phi.copysign(v) * scale, z)
+}
+
+/// Inverse of `octahedral_uv_to_dir`. `dir` must be normalized.
+#[allow(dead_code)]
+pub fn octahedral_dir_to_uv(dir: Vec3) -> Vec2 {
+    let x = dir.x.abs();
+    let y = dir.y.abs();
+    let z = dir.z.abs();
+
+    let r = (1.0 - z).max(0.0).sqrt();
+    let a = x.max(y);
+    let b = if a == 0.0 { 0.0 } else { x.min(y) / a };
+
+    let phi = b.atan() * 2.0 / PI;
+    let phi = if x < y { 1.0 - phi } else { phi };
+
+    let v = phi * r;
+    let u = r - v;
+    let (u, v) = if dir.z < 0.0 {
+        (1.0 - v, 1.0 - u)
+    } else {
+        (u, v)
+    };
+
+    let u = u.copysign(dir.x);
+    let v = v.copysign(dir.y);
+    Vec2::new((u + 1.0) * 0.5, (v + 1.0) * 0.5)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hit_wide_handles_negative_zero_directions() {
+        let aabb = Aabb::new(Vec3::zero(), Vec3::broadcast(1.0));
+        let range = f32x4::ZERO..f32x4::from(std::f32::MAX);
+
+        let ray = WRay::along(Vec3::new(0.5, 0.5, -1.0), Vec3::new(0.0, -0.0, 1.0));
+        assert!(aabb.hit_wide(&ray, range.clone()).all());
+
+        // the origin is on the y = 0 slab plane, which the ray runs along
+        let ray = WRay::along(Vec3::new(0.5, 0.0, -1.0), Vec3::new(0.0, -0.0, 1.0));
+        assert!(aabb.hit_wide(&ray, range).all());
+    }
+
+    #[test]
+    fn degenerate_distributions_are_uniform() {
+        for (values, width, height) in [(vec![], 0, 0), (vec![1.0], 0, 4), (vec![2.0], 2, 2)] {
+            let (point, pdf) =
+                Distribution2D::new(&values, width, height).sample_continuous([0.3, 0.8]);
+            assert!(point.x >= 0.0 && point.x < 1.0 && point.y >= 0.0 && point.y < 1.0);
+            assert!(pdf.is_finite() && pdf > 0.0);
+        }
+    }
+
+    #[test]
+    fn cosine_power_samples_cover_every_azimuth() {
+        let mut quadrants = [false; 4];
+        for i in 0..64 {
+            let u = f32x4::from(i as f32 / 64.0);
+            let dirs: [Vec3; 4] =
+                Wec3::cosine_power_weighted(&[f32x4::from(0.5), u], f32x4::ONE).into();
+            let dir = dirs[0];
+            quadrants[(dir.x < 0.0) as usize + 2 * (dir.y < 0.0) as usize] = true;
+        }
+        assert_eq!(quadrants, [true; 4]);
+    }
+
+    #[test]
+    fn gather_and_scatter_round_trip() {
+        let points = (0..8)
+            .map(|i| Vec3::new(i as f32, 10.0 * i as f32, -(i as f32)))
+            .collect::<Vec<_>>();
+        // out of order and with a repeat
+        let indices = [5, 0, 7, 5];
+        let gathered: [Vec3; 4] = Wec3::gather(&points, indices).into();
+        for (lane, index) in gathered.iter().zip(indices.iter()) {
+            assert_eq!(*lane, points[*index]);
+        }
+
+        let indices = [6, 1, 3, 2];
+        let mut scattered = vec![Vec3::zero(); 8];
+        Wec3::gather(&points, indices).scatter(&mut scattered, indices);
+        for (i, point) in scattered.iter().enumerate() {
+            let expected = if indices.contains(&i) {
+                points[i]
+            } else {
+                Vec3::zero()
+            };
+            assert_eq!(*point, expected);
+        }
+
+        let values = (0..8).map(|i| i as f32 * 0.5).collect::<Vec<_>>();
+        let gathered = f32x4::gather(&values, [3, 3, 0, 7]);
+        assert_eq!(*gathered.as_ref(), [1.5, 1.5, 0.0, 3.5]);
+        let mut scattered = vec![0.0; 8];
+        gathered.scatter(&mut scattered, [4, 5, 6, 7]);
+        assert_eq!(scattered, vec![0.0, 0.0, 0.0, 0.0, 1.5, 1.5, 0.0, 3.5]);
+    }
+
+    #[test]
+    fn decomposed_transforms_recompose() {
+        // an orthonormal basis, scaled unevenly and mirrored along the third axis
+        let a = Vec3::new(1.0, 2.0, 2.0) / 3.0;
+        let b = Vec3::new(2.0, 1.0, -2.0) / 3.0;
+        let c = a.cross(b);
+        let linear = Mat3::new(a * 2.0, b * 0.5, c * -3.0);
+
+        let position = Vec3::new(1.0, -2.0, 3.0);
+        let transform = Transform::from_linear(linear, position);
+        let cols = transform.rotation.cols;
+        for (i, col) in cols.iter().enumerate() {
+            assert!((col.mag() - 1.0).abs() < 1e-5);
+            assert!(col.dot(cols[(i + 1) % 3]).abs() < 1e-5);
+            assert!((transform.linear().cols[i] - linear.cols[i]).mag() < 1e-5);
+        }
+        let point = Vec3::new(0.3, 0.7, -1.1);
+        let expected = linear * point + position;
+        assert!((transform.transform_point(point) - expected).mag() < 1e-5);
+
+        let wide = Wat3::new(
+            Wec3::splat(linear.cols[0]),
+            Wec3::splat(linear.cols[1]),
+            Wec3::splat(linear.cols[2]),
+        );
+        let wide = WTransform::from_linear(wide, Wec3::splat(position)).linear();
+        for (wide, col) in wide.cols.iter().zip(linear.cols.iter()) {
+            let lanes: [Vec3; 4] = (*wide).into();
+            assert!(lanes.iter().all(|lane| (*lane - *col).mag() < 1e-5));
+        }
+    }
+
+    #[test]
+    fn masks_and_selects_follow_every_lane_pattern() {
+        let (a, b) = (
+            f32x4::from([1.0, 2.0, 3.0, 4.0]),
+            f32x4::from([-1.0, -2.0, -3.0, -4.0]),
+        );
+        for bits in 0..16 {
+            let set = [bits & 1 != 0, bits & 2 != 0, bits & 4 != 0, bits & 8 != 0];
+            let lane = |i: usize| if set[i] { 1.0 } else { 0.0 };
+            let mask = f32x4::from([lane(0), lane(1), lane(2), lane(3)]).cmp_gt(f32x4::ZERO);
+            assert_eq!(mask.move_mask(), bits);
+
+            assert_eq!(mask.any(), bits != 0);
+            assert_eq!(mask.all(), bits == 0b1111);
+            assert_eq!(mask.none(), bits == 0);
+
+            let selected = f32x4::select(mask, a, b);
+            let vectors: [Vec3; 4] =
+                Wec3::select(mask, Wec3::broadcast(a), Wec3::broadcast(b)).into();
+            let colors = WSrgb::select(mask, WSrgb(Wec3::broadcast(a)), WSrgb(Wec3::broadcast(b)));
+            let colors: [crate::spectrum::Srgb; 4] = colors.into();
+            for (i, set) in set.iter().enumerate() {
+                let expected = if *set { a.as_ref()[i] } else { b.as_ref()[i] };
+                assert_eq!(selected.as_ref()[i], expected);
+                assert_eq!(vectors[i], Vec3::broadcast(expected));
+                assert_eq!(colors[i].0, Vec3::broadcast(expected));
+            }
+        }
+    }
+
+    #[test]
+    fn octahedral_mapping_round_trips() {
+        for i in 0..16 {
+            for j in 0..16 {
+                let uv = Vec2::new((i as f32 + 0.5) / 16.0, (j as f32 + 0.5) / 16.0);
+                let dir = octahedral_uv_to_dir(uv);
+                assert!((dir.mag() - 1.0).abs() < 1e-5, "{:?} from {:?}", dir, uv);
+                assert!((octahedral_dir_to_uv(dir) - uv).mag() < 1e-5, "{:?}", uv);
+            }
+        }
+
+        // including the poles and the seams between the faces of the octahedron
+        let dirs = [
+            Vec3::unit_z(),
+            -Vec3::unit_z(),
+            Vec3::unit_x(),
+            -Vec3::unit_y(),
+            Vec3::new(1.0, 1.0, 0.0),
+            Vec3::new(-0.3, 0.5, -0.8),
+            Vec3::new(0.2, -0.9, 0.1),
+        ];
+        for dir in dirs.iter() {
+            let dir = dir.normalized();
+            let uv = octahedral_dir_to_uv(dir);
+            assert!((0.0..=1.0).contains(&uv.x) && (0.0..=1.0).contains(&uv.y));
+            assert!((octahedral_uv_to_dir(uv) - dir).mag() < 1e-5, "{:?}", dir);
+        }
+    }
+
+    /// Unit normals, including both poles where the basis construction switches sign.
+    fn test_normals() -> Wec3 {
+        Wec3::from([
+            Vec3::unit_z(),
+            -Vec3::unit_z(),
+            Vec3::unit_x(),
+            Vec3::new(0.3, -0.5, -0.8).normalized(),
+        ])
+    }
+
+    #[test]
+    fn bases_map_local_z_to_the_normal() {
+        let normals = test_normals();
+        let basis = normals.get_orthonormal_basis();
+        let z: [Vec3; 4] = basis.to_world(Wec3::splat(Vec3::unit_z())).into();
+        let x: [Vec3; 4] = basis.to_world(Wec3::splat(Vec3::unit_x())).into();
+        let y: [Vec3; 4] = basis.to_world(Wec3::splat(Vec3::unit_y())).into();
+        let normals: [Vec3; 4] = normals.into();
+        for (((z, x), y), normal) in z.iter().zip(&x).zip(&y).zip(&normals) {
+            assert!((*z - *normal).mag() < 1e-6, "{:?}", normal);
+            // and the tangents are a right-handed frame around it
+            assert!((x.mag() - 1.0).abs() < 1e-5 && (y.mag() - 1.0).abs() < 1e-5);
+            assert!((x.cross(*y) - *normal).mag() < 1e-5, "{:?}", normal);
+        }
+    }
+
+    #[test]
+    fn bases_round_trip_between_local_and_world() {
+        let basis = test_normals().get_orthonormal_basis();
+        let vectors = [
+            Vec3::new(1.0, 2.0, 3.0),
+            Vec3::new(-0.5, 0.0, 0.25),
+            Vec3::unit_y(),
+            Vec3::new(4.0, -3.0, 0.0),
+        ];
+        let round_trip: [Vec3; 4] = basis.to_world(basis.to_local(Wec3::from(vectors))).into();
+        let local_trip: [Vec3; 4] = basis.to_local(basis.to_world(Wec3::from(vectors))).into();
+        for ((v, world), local) in vectors.iter().zip(&round_trip).zip(&local_trip) {
+            assert!((*world - *v).mag() < 1e-5, "{:?}", v);
+            assert!((*local - *v).mag() < 1e-5, "{:?}", v);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn transforms_round_trip_through_ron() {
+        let a = Vec3::new(1.0, 2.0, 2.0) / 3.0;
+        let b = Vec3::new(2.0, 1.0, -2.0) / 3.0;
+        let rotation = Mat3::new(a, b, a.cross(b));
+        let transform = Transform::from_trs(
+            Vec3::new(1.5, -2.0, 0.1),
+            rotation,
+            Vec3::new(2.0, 0.5, 3.0),
+        );
+
+        let text = ron::ser::to_string(&transform).unwrap();
+        let parsed: Transform = ron::de::from_str(&text).unwrap();
+        assert_eq!(parsed.position, transform.position);
+        assert_eq!(parsed.rotation.cols, transform.rotation.cols);
+        assert_eq!(parsed.scale, transform.scale);
+    }
+
+    #[test]
+    fn component_wise_rounding_handles_negatives_and_integers() {
+        let x = f32x4::from([-1.5, -1.0, -0.25, 2.75]);
+        assert_eq!(ComponentWise::abs(&x).as_ref(), &[1.5, 1.0, 0.25, 2.75]);
+        assert_eq!(ComponentWise::sign(&x).as_ref(), &[-1.0, -1.0, -1.0, 1.0]);
+        assert_eq!(ComponentWise::floor(&x).as_ref(), &[-2.0, -1.0, -1.0, 2.0]);
+        assert_eq!(ComponentWise::fract(&x).as_ref(), &[0.5, 0.0, 0.75, 0.75]);
+
+        // right at and just below integers
+        let x = f32x4::from([0.0, -1e-9, 1.0, 0.999]);
+        assert_eq!(ComponentWise::sign(&x).as_ref(), &[0.0, -1.0, 1.0, 1.0]);
+        assert_eq!(ComponentWise::floor(&x).as_ref(), &[0.0, -1.0, 1.0, 0.0]);
+        let fract = ComponentWise::fract(&x);
+        assert!(fract.as_ref().iter().all(|f| *f >= 0.0 && *f < 1.0));
+        assert_eq!(fract.as_ref()[2], 0.0);
+
+        // vectors round each component the same way
+        let v = Wec3::new(f32x4::from(-0.5), f32x4::from(1.0), f32x4::from(-3.0));
+        let floor: [Vec3; 4] = ComponentWise::floor(&v).into();
+        let fract: [Vec3; 4] = ComponentWise::fract(&v).into();
+        let sign: [Vec3; 4] = ComponentWise::sign(&v).into();
+        assert_eq!(floor[0], Vec3::new(-1.0, 1.0, -3.0));
+        assert_eq!(fract[0], Vec3::new(0.5, 0.0, 0.0));
+        assert_eq!(sign[0], Vec3::new(-1.0, 1.0, -1.0));
+    }
+
+    #[test]
+    fn shadow_terminator_fades_out_at_the_terminator() {
+        let up = Wec3::splat(Vec3::unit_z());
+        let bent = Wec3::splat(Vec3::new(0.5, 0.0, 0.75f32.sqrt()));
+        let dirs = [
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(0.6, 0.0, 0.8),
+            Vec3::new(-0.6, 0.0, 0.8),
+            // grazing the geometric surface, still well above the shading normal's horizon
+            Vec3::new(1.0, 0.0, 1e-5).normalized(),
+        ];
+        let wi = Wec3::from(dirs);
+
+        // normals which agree don't shadow anything
+        let agreeing = shadow_terminator(up, up, wi);
+        assert!(agreeing.as_ref().iter().all(|s| (s - 1.0).abs() < 1e-6));
+
+        let bent = shadow_terminator(up, bent, wi);
+        let bent = bent.as_ref();
+        assert!(bent.iter().all(|s| *s >= 0.0 && *s <= 1.0));
+        assert!(bent[0] > 0.99);
+        assert!(bent[3] < 1e-3);
+    }
+}
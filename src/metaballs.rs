@@ -0,0 +1,191 @@
+use crate::camera::Camera;
+use crate::hitable::{intersection_error_bound, Hitable, WHit, WShadingPoint};
+use crate::material::MaterialHandle;
+use crate::math::{f32x4, Aabb, Mask, Vec3, Wec3};
+use crate::ray::WRay;
+
+const MAX_MARCHES: usize = 256;
+/// How close to the surface marching stops, relative to the largest ball.
+const RELATIVE_EPSILON: f32 = 1e-5;
+/// Steepest slope of the kernel `(1 - s^2)^3` over `s`, at `s = 1 / sqrt(5)`.
+const KERNEL_MAX_SLOPE: f32 = 1.717_267_4;
+
+struct Ball {
+    center: Vec3,
+    radius: f32,
+    strength: f32,
+}
+
+/// Blobby surface where the summed field of a set of balls equals `threshold`. Each ball
+/// adds `strength * (1 - (d / radius)^2)^3` at distance `d` from its center, which falls
+/// to zero at its radius, so balls close to each other melt together.
+///
+/// The field isn't a distance, so it's sphere traced with a step that's bounded by how
+/// steep the field can get, and the normal is the gradient of the field.
+pub struct Metaballs {
+    balls: Vec<Ball>,
+    threshold: f32,
+    // upper bound on the gradient of the field
+    lipschitz: f32,
+    epsilon: f32,
+    material: MaterialHandle,
+}
+
+#[allow(dead_code)]
+impl Metaballs {
+    /// No balls yet. `threshold` should be positive and lower than the strength of the
+    /// balls, or they won't show up.
+    pub fn new(threshold: f32, material: MaterialHandle) -> Self {
+        Metaballs {
+            balls: Vec::new(),
+            threshold,
+            lipschitz: 0.0,
+            epsilon: 0.0,
+            material,
+        }
+    }
+
+    pub fn with_ball(mut self, center: Vec3, radius: f32, strength: f32) -> Self {
+        self.lipschitz += KERNEL_MAX_SLOPE * strength.abs() / radius;
+        self.epsilon = self.epsilon.max(RELATIVE_EPSILON * radius);
+        self.balls.push(Ball {
+            center,
+            radius,
+            strength,
+        });
+        self
+    }
+
+    /// Summed field of all the balls and its gradient.
+    fn field(&self, point: Wec3) -> (f32x4, Wec3) {
+        let mut field = f32x4::ZERO;
+        let mut gradient = Wec3::zero();
+        for ball in self.balls.iter() {
+            let offset = point - Wec3::splat(ball.center);
+            let inv_radius_sq = f32x4::from(1.0 / (ball.radius * ball.radius));
+            let falloff = (f32x4::ONE - offset.mag_sq() * inv_radius_sq).max(f32x4::ZERO);
+            let strength = f32x4::from(ball.strength);
+            field += strength * falloff * falloff * falloff;
+            gradient += offset * (f32x4::from(-6.0) * strength * falloff * falloff * inv_radius_sq);
+        }
+        (field, gradient)
+    }
+
+    /// Lower bound of the distance to the surface, which is negative inside.
+    fn dist(&self, point: Wec3) -> f32x4 {
+        let (field, _) = self.field(point);
+        let field_bound = (f32x4::from(self.threshold) - field) / f32x4::from(self.lipschitz);
+
+        // the surface is inside of the union of the balls, which is much quicker to get to
+        // than the field steps allow
+        let balls_bound = self
+            .balls
+            .iter()
+            .map(|ball| (point - Wec3::splat(ball.center)).mag() - f32x4::from(ball.radius))
+            .fold(f32x4::from(std::f32::MAX), |closest, dist| {
+                closest.min(dist)
+            });
+        field_bound.max(balls_bound)
+    }
+
+    /// Closest intersection with `t` in `(t_start, t_end]`, or `f32::MAX` where there is none.
+    fn intersect(&self, origin: Wec3, dir: Wec3, t_start: f32x4, t_end: f32x4) -> f32x4 {
+        let miss = f32x4::from(std::f32::MAX);
+        if self.balls.is_empty() {
+            return miss;
+        }
+
+        let epsilon = f32x4::from(self.epsilon);
+        let mut t = t_start;
+        let mut hit = f32x4::ZERO;
+        let mut done = f32x4::ZERO;
+        for _march in 0..MAX_MARCHES {
+            let dist = self.dist(origin + dir * t).abs();
+            let close = dist.cmp_lt(epsilon) & !done;
+            hit = hit | close;
+            done = done | close;
+            t = f32x4::merge(done, t, t + dist);
+            done = done | t.cmp_gt(t_end);
+            if done.all() {
+                break;
+            }
+        }
+        f32x4::merge(hit & t.cmp_le(t_end) & t.cmp_gt(t_start), t, miss)
+    }
+}
+
+impl Hitable for Metaballs {
+    fn occluded(&self, start: Wec3, end: Wec3, _time: f32x4) -> f32x4 {
+        let dir = end - start;
+        let dist = dir.mag();
+        let dir = dir / dist;
+
+        // marching can't tell the surface the ray started on apart from closer than this
+        let t_min = f32x4::from(2.0 * self.epsilon);
+        let t = self.intersect(start, dir, t_min, dist);
+        f32x4::merge(
+            t.cmp_lt(f32x4::from(std::f32::MAX)),
+            f32x4::ZERO,
+            f32x4::ONE,
+        )
+    }
+
+    fn hit(&self, ray: &WRay, t_range: ::std::ops::Range<f32x4>) -> f32x4 {
+        self.intersect(ray.origin, ray.dir, t_range.start, t_range.end)
+    }
+
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<Aabb> {
+        self.balls
+            .iter()
+            .map(|ball| Aabb::from_center_half_size(ball.center, Vec3::broadcast(ball.radius)))
+            .fold(None, |acc: Option<Aabb>, bounds| match acc {
+                Some(acc) => Some(acc.union(&bounds)),
+                None => Some(bounds),
+            })
+    }
+
+    fn get_shading_info(
+        &self,
+        hit: WHit,
+        primary: bool,
+        camera: &dyn Camera,
+    ) -> (MaterialHandle, WShadingPoint) {
+        let point = hit.point();
+        let offset_by =
+            (f32x4::from(2.0 * self.epsilon)).max(intersection_error_bound(&hit, primary, camera));
+
+        // the field grows towards the centers, so the outward normal is against the gradient
+        let (_, gradient) = self.field(point);
+        let normal = (-gradient).normalized();
+
+        (
+            self.material,
+            WShadingPoint::new(hit, point, offset_by, normal),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit_from_above(metaballs: &Metaballs) -> f32 {
+        let ray = WRay::along(Vec3::new(0.0, 0.0, 5.0), -Vec3::unit_z());
+        metaballs.hit(&ray, f32x4::ZERO..f32x4::from(10.0)).as_ref()[0]
+    }
+
+    #[test]
+    fn overlapping_balls_merge() {
+        // the surface of each ball alone is 0.454 from its center, so a ray between them
+        // misses either on its own
+        let left =
+            Metaballs::new(0.5, MaterialHandle(0)).with_ball(-Vec3::unit_x() * 0.5, 1.0, 1.0);
+        assert_eq!(hit_from_above(&left), std::f32::MAX);
+
+        // but together their fields add up past the threshold there, where
+        // 2 (0.75 - z^2)^3 = 0.5
+        let both = left.with_ball(Vec3::unit_x() * 0.5, 1.0, 1.0);
+        let z = (0.75 - 0.25f32.cbrt()).sqrt();
+        assert!((hit_from_above(&both) - (5.0 - z)).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,121 @@
+//! Writers for image formats which keep the full range of linear radiance.
+
+use std::path::Path;
+
+use crate::framebuffer::Framebuffer;
+use crate::math::Vec3;
+
+/// Scanlines of these widths can be run length encoded, others have to be written flat.
+const RLE_WIDTHS: std::ops::RangeInclusive<usize> = 8..=0x7fff;
+/// Most bytes in a single literal run of an RLE scanline.
+const MAX_LITERAL: usize = 128;
+
+/// Writes the averages of `framebuffer` as a Radiance `.hdr` file, which stores each pixel as
+/// three 8 bit mantissas sharing an exponent (RGBE). Negative and non-finite radiance is
+/// written as black.
+#[allow(dead_code)]
+pub fn save_hdr<P: AsRef<Path>>(path: P, framebuffer: &Framebuffer) -> Result<(), String> {
+    let path = path.as_ref();
+    let width = framebuffer.width();
+    let pixels = framebuffer
+        .pixels()
+        .map(|(_, _, radiance)| to_rgbe(radiance))
+        .collect::<Vec<_>>();
+
+    let header = format!(
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        framebuffer.height(),
+        width
+    );
+    let mut bytes = header.into_bytes();
+    for scanline in pixels.chunks(width.max(1)) {
+        if RLE_WIDTHS.contains(&width) {
+            encode_scanline(scanline, &mut bytes);
+        } else {
+            bytes.extend(scanline.iter().flatten());
+        }
+    }
+
+    std::fs::write(path, bytes).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+fn to_rgbe(radiance: Vec3) -> [u8; 4] {
+    let channel = |x: f32| if x.is_finite() { x.max(0.0) } else { 0.0 };
+    let (r, g, b) = (
+        channel(radiance.x),
+        channel(radiance.y),
+        channel(radiance.z),
+    );
+    let max = r.max(g).max(b);
+    if max < 1e-32 {
+        return [0; 4];
+    }
+
+    // max = mantissa * 2^exponent with the mantissa in [0.5, 1)
+    let mut exponent = max.log2().floor() as i32 + 1;
+    if max / 2f32.powi(exponent) >= 1.0 {
+        exponent += 1;
+    }
+    let scale = 256.0 / 2f32.powi(exponent);
+    let mantissa = |x: f32| (x * scale).min(255.0) as u8;
+    [
+        mantissa(r),
+        mantissa(g),
+        mantissa(b),
+        (exponent + 128).max(0).min(255) as u8,
+    ]
+}
+
+/// Appends a new style RLE scanline, where each of the four components is written
+/// separately. Only literal runs are used, which is valid but doesn't shrink the data.
+fn encode_scanline(scanline: &[[u8; 4]], bytes: &mut Vec<u8>) {
+    let width = scanline.len();
+    bytes.extend(&[2, 2, (width >> 8) as u8, (width & 0xff) as u8]);
+    for component in 0..4 {
+        let values = scanline
+            .iter()
+            .map(|pixel| pixel[component])
+            .collect::<Vec<_>>();
+        for literal in values.chunks(MAX_LITERAL) {
+            bytes.push(literal.len() as u8);
+            bytes.extend(literal);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_rgbe([r, g, b, e]: [u8; 4]) -> Vec3 {
+        if e == 0 {
+            return Vec3::zero();
+        }
+        let scale = 2f32.powi(i32::from(e) - 136);
+        Vec3::new(
+            (f32::from(r) + 0.5) * scale,
+            (f32::from(g) + 0.5) * scale,
+            (f32::from(b) + 0.5) * scale,
+        )
+    }
+
+    #[test]
+    fn rgbe_round_trip() {
+        for radiance in &[
+            Vec3::new(1.0, 0.5, 0.25),
+            Vec3::new(0.001, 0.002, 0.003),
+            Vec3::new(1000.0, 0.0, 12.5),
+            Vec3::broadcast(255.0 / 256.0),
+        ] {
+            let decoded = from_rgbe(to_rgbe(*radiance));
+            // the channel setting the exponent keeps 8 bits of precision, so the error is
+            // at most half a step of it
+            let max = radiance.x.max(radiance.y).max(radiance.z);
+            let error = decoded - *radiance;
+            assert!([error.x, error.y, error.z]
+                .iter()
+                .all(|e| e.abs() <= max / 256.0));
+        }
+        assert_eq!(to_rgbe(Vec3::new(-1.0, std::f32::NAN, 0.0)), [0; 4]);
+    }
+}
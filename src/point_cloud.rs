@@ -0,0 +1,214 @@
+use crate::camera::Camera;
+use crate::grid::UniformGrid;
+use crate::hitable::{
+    intersection_error_bound, Hitable, WHit, WHitAttributes, WShadingPoint, SHADOW_T_MIN,
+};
+use crate::material::MaterialHandle;
+use crate::math::{f32x4, gamma, Aabb, Mask, Vec2u, Vec3, Wec3};
+use crate::ray::WRay;
+
+/// Lots of spheres of the same `radius`, such as the particles of a simulation. Only the
+/// positions are stored, bucketed into a `UniformGrid` of their own, so rays only test the
+/// points near them. The index of the point which was hit is its prim id.
+pub struct PointCloud {
+    positions: Vec<Vec3>,
+    radius: f32,
+    grid: UniformGrid,
+    bounds: Option<Aabb>,
+    material: MaterialHandle,
+}
+
+impl PointCloud {
+    #[allow(dead_code)]
+    pub fn new(positions: Vec<Vec3>, radius: f32, material: MaterialHandle) -> Self {
+        let half_size = Vec3::broadcast(radius);
+        let point_bounds = |position: &Vec3| Aabb::from_center_half_size(*position, half_size);
+        let grid = UniformGrid::from_bounds(positions.iter().map(|p| Some(point_bounds(p))));
+        let bounds = positions
+            .iter()
+            .map(point_bounds)
+            .fold(None, |acc: Option<Aabb>, bounds| match acc {
+                Some(acc) => Some(acc.union(&bounds)),
+                None => Some(bounds),
+            });
+        PointCloud {
+            positions,
+            radius,
+            grid,
+            bounds,
+            material,
+        }
+    }
+
+    /// Entry and exit distances of the rays through the sphere around `center`, which are
+    /// NaN where they miss it.
+    fn roots(&self, origin: Wec3, dir: Wec3, center: Vec3) -> (f32x4, f32x4) {
+        let oc = origin - Wec3::splat(center);
+        let b = oc.dot(dir);
+        let c = oc.mag_sq() - f32x4::from(self.radius * self.radius);
+        let desc_sqrt = (b * b - c).sqrt();
+        (-b - desc_sqrt, -b + desc_sqrt)
+    }
+}
+
+impl Hitable for PointCloud {
+    fn hit(&self, rays: &WRay, t_ranges: ::std::ops::Range<f32x4>) -> f32x4 {
+        self.hit_with_attributes(rays, t_ranges).0
+    }
+
+    fn hit_with_attributes(
+        &self,
+        rays: &WRay,
+        t_ranges: ::std::ops::Range<f32x4>,
+    ) -> (f32x4, WHitAttributes) {
+        let miss = f32x4::from(std::f32::MAX);
+        let mut closest = miss;
+        let mut prim_ids = [0; 4];
+        // points in several of the cells the rays cross come up more than once, but they
+        // aren't any closer the second time
+        self.grid.visit_candidates(rays, t_ranges.clone(), |index| {
+            let (t1, t2) = self.roots(rays.origin, rays.dir, self.positions[index]);
+            let t1_valid = t1.cmp_gt(t_ranges.start) & t1.cmp_le(t_ranges.end);
+            let t2_valid = t2.cmp_gt(t_ranges.start) & t2.cmp_le(t_ranges.end);
+            let t = f32x4::merge(t1_valid, t1, f32x4::merge(t2_valid, t2, miss));
+
+            let closer = t.cmp_lt(closest);
+            if closer.none() {
+                return;
+            }
+            closest = f32x4::merge(closer, t, closest);
+            let closer = closer.move_mask();
+            for (lane, prim_id) in prim_ids.iter_mut().enumerate() {
+                if closer & (1 << lane) != 0 {
+                    *prim_id = index;
+                }
+            }
+        });
+
+        let mut attributes = WHitAttributes::none();
+        attributes.prim_id = prim_ids;
+        (closest, attributes)
+    }
+
+    fn occluded(&self, start: Wec3, end: Wec3, time: f32x4) -> f32x4 {
+        let dir = end - start;
+        let dist = dir.mag();
+        let dir = dir / dist;
+        let ray = WRay::new(
+            start,
+            dir,
+            time,
+            [Vec2u::zero(); 4],
+            [true; 4],
+            [0.0; 4],
+            [0; 4],
+        );
+
+        // roots close to the start are only as precise as the cancellation in -b +- sqrt
+        // allows, so anything closer than that (or than `SHADOW_T_MIN`) is treated as the
+        // surface we started on
+        let scale = start
+            .map(|x| x.abs())
+            .component_max()
+            .max(f32x4::from(self.radius));
+        let t_min = (f32x4::from(2.0 * gamma(7)) * scale).max(f32x4::from(SHADOW_T_MIN));
+
+        let mut blocked = f32x4::ZERO;
+        self.grid.visit_candidates(&ray, t_min..dist, |index| {
+            // the rest of the points don't matter once every lane is blocked
+            if blocked.all() {
+                return;
+            }
+            let (t1, t2) = self.roots(start, dir, self.positions[index]);
+            let t1_valid = t1.cmp_gt(t_min) & t1.cmp_le(dist);
+            let t2_valid = t2.cmp_gt(t_min) & t2.cmp_le(dist);
+            blocked = blocked | t1_valid | t2_valid;
+        });
+        f32x4::merge(blocked, f32x4::ZERO, f32x4::ONE)
+    }
+
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<Aabb> {
+        self.bounds
+    }
+
+    fn get_shading_info(
+        &self,
+        hit: WHit,
+        primary: bool,
+        camera: &dyn Camera,
+    ) -> (MaterialHandle, WShadingPoint) {
+        let point = hit.point();
+        let offset_by = intersection_error_bound(&hit, primary, camera);
+        let mut centers = [Vec3::zero(); 4];
+        for (center, prim_id) in centers.iter_mut().zip(hit.attributes.prim_id.iter()) {
+            *center = self.positions[*prim_id];
+        }
+        let normal = (point - Wec3::from(centers)).normalized();
+
+        let shading_point = WShadingPoint::new(hit, point, offset_by, normal);
+        let inv_radius = f32x4::from(1.0 / self.radius);
+        let (dndx, dndy) = (
+            shading_point.dpdx * inv_radius,
+            shading_point.dpdy * inv_radius,
+        );
+        (
+            self.material,
+            shading_point.with_normal_differentials(dndx, dndy),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sphere::Sphere;
+
+    #[test]
+    fn clouds_match_individual_spheres() {
+        let positions = (0..12)
+            .map(|i| {
+                let angle = i as f32 * 0.9;
+                Vec3::new(angle.cos(), i as f32 * 0.2 - 1.0, angle.sin()) * 1.5
+            })
+            .collect::<Vec<_>>();
+        let cloud = PointCloud::new(positions.clone(), 0.3, MaterialHandle(0));
+        let spheres = positions
+            .iter()
+            .map(|p| Sphere::new(*p, 0.3, MaterialHandle(0)))
+            .collect::<Vec<_>>();
+        let range = f32x4::ZERO..f32x4::from(std::f32::MAX);
+
+        for i in 0..32 {
+            let angle = i as f32 * 0.41;
+            let origin = Vec3::new(
+                angle.cos() * 5.0,
+                (i as f32 * 1.3).sin() * 2.0,
+                angle.sin() * 5.0,
+            );
+            let dir = (Vec3::new(0.0, (i as f32).cos(), 0.0) - origin).normalized();
+            let ray = WRay::along(origin, dir);
+
+            let (t, attributes) = cloud.hit_with_attributes(&ray, range.clone());
+            let closest = spheres
+                .iter()
+                .map(|sphere| sphere.hit(&ray, range.clone()).as_ref()[0])
+                .enumerate()
+                .fold(
+                    (0, std::f32::MAX),
+                    |acc, (i, t)| if t < acc.1 { (i, t) } else { acc },
+                );
+            let t = t.as_ref()[0];
+            assert!(t == closest.1 || (t - closest.1).abs() < 1e-4);
+            if closest.1 < std::f32::MAX {
+                assert_eq!(attributes.prim_id[0], closest.0);
+            }
+
+            let (start, end) = (Wec3::splat(origin), Wec3::splat(origin + dir * 10.0));
+            let blocked = spheres
+                .iter()
+                .any(|sphere| sphere.occluded(start, end, f32x4::ZERO).as_ref()[0] == 0.0);
+            let cloud_blocked = cloud.occluded(start, end, f32x4::ZERO).as_ref()[0] == 0.0;
+            assert_eq!(cloud_blocked, blocked);
+        }
+    }
+}
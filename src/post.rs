@@ -0,0 +1,550 @@
+//! Post processing applied to the linear HDR film, before it's tone mapped for saving.
+
+use rayon::prelude::*;
+
+use crate::math::{Extent2u, Vec3};
+use crate::spectrum::Srgb;
+
+/// Glow around bright highlights, like light scattering inside of a lens. The parts of
+/// pixels brighter than `threshold` (in luminance) are blurred with a gaussian whose
+/// standard deviation is `radius` pixels and added back on top, scaled by `intensity`.
+#[derive(Clone, Copy, Debug)]
+pub struct Bloom {
+    pub threshold: f32,
+    pub intensity: f32,
+    pub radius: f32,
+}
+
+impl Bloom {
+    #[allow(dead_code)]
+    pub fn new(threshold: f32, intensity: f32, radius: f32) -> Self {
+        Self {
+            threshold,
+            intensity,
+            radius,
+        }
+    }
+
+    pub fn apply(&self, buf: &mut [Srgb], res: Extent2u) {
+        let kernel = gaussian_kernel(self.radius);
+        if kernel.len() < 2 || self.intensity <= 0.0 || res.w == 0 || res.h == 0 {
+            return;
+        }
+        let reach = (kernel.len() / 2) as isize;
+        // below 0 black pixels would bloom too, dividing by their zero luminance
+        let threshold = self.threshold.max(0.0);
+
+        // only the part of each pixel above the threshold blooms, keeping its hue
+        let bright = buf
+            .par_iter()
+            .map(|color| {
+                let luminance = color.luminance();
+                if luminance > threshold {
+                    *color * ((luminance - threshold) / luminance)
+                } else {
+                    Srgb::zero()
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // separable blur, horizontally and then vertically. Pixels outside of the image
+        // count as black so the glow stays symmetric at the edges.
+        let mut horizontal = vec![Srgb::zero(); bright.len()];
+        horizontal
+            .par_chunks_mut(res.w)
+            .zip(bright.par_chunks(res.w))
+            .for_each(|(out_row, row)| {
+                for (x, out) in out_row.iter_mut().enumerate() {
+                    for (k, weight) in kernel.iter().enumerate() {
+                        let sx = x as isize + k as isize - reach;
+                        if sx >= 0 && (sx as usize) < res.w {
+                            *out += row[sx as usize] * *weight;
+                        }
+                    }
+                }
+            });
+
+        let intensity = self.intensity;
+        buf.par_chunks_mut(res.w)
+            .enumerate()
+            .for_each(|(y, out_row)| {
+                for (x, out) in out_row.iter_mut().enumerate() {
+                    let mut glow = Srgb::zero();
+                    for (k, weight) in kernel.iter().enumerate() {
+                        let sy = y as isize + k as isize - reach;
+                        if sy >= 0 && (sy as usize) < res.h {
+                            glow += horizontal[x + sy as usize * res.w] * *weight;
+                        }
+                    }
+                    *out += glow * intensity;
+                }
+            });
+    }
+}
+
+/// Normalized weights of a gaussian with standard deviation `sigma`, cut off at 3 sigma.
+fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    if sigma <= 0.0 {
+        return vec![1.0];
+    }
+
+    let reach = (sigma * 3.0).ceil() as isize;
+    let weights = (-reach..=reach)
+        .map(|x| (-(x * x) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect::<Vec<_>>();
+    let total = weights.iter().sum::<f32>();
+    weights.iter().map(|w| w / total).collect()
+}
+
+/// Artist controlled haze, blending each pixel towards `color` by `1 - exp(-density * depth)`
+/// of its `Depth`. Pixels where nothing was hit (with a depth of 0) are left alone, so the
+/// background shows through.
+#[derive(Clone, Copy, Debug)]
+pub struct DistanceFog {
+    pub density: f32,
+    pub color: Srgb,
+}
+
+impl DistanceFog {
+    #[allow(dead_code)]
+    pub fn new(density: f32, color: Srgb) -> Self {
+        Self { density, color }
+    }
+
+    pub fn apply(&self, buf: &mut [Srgb], depths: &[f32]) {
+        buf.par_iter_mut()
+            .zip(depths.par_iter())
+            .for_each(|(color, depth)| {
+                if *depth > 0.0 {
+                    let fog = 1.0 - (-self.density * depth).exp();
+                    *color = *color * (1.0 - fog) + self.color * fog;
+                }
+            });
+    }
+}
+
+/// Quick look-dev adjustments of the linear color, without rendering again: white balance,
+/// then a gain and offset per channel, then optionally a clamp per channel. The default
+/// changes nothing.
+#[derive(Clone, Copy, Debug)]
+pub struct ColorGrade {
+    /// Warms the image (towards orange) when positive and cools it (towards blue) when
+    /// negative. The difference between the red and blue channels is this many stops.
+    pub temperature: f32,
+    /// Shifts the image towards magenta when positive and green when negative, in stops of
+    /// green against red and blue.
+    pub tint: f32,
+    pub gain: Srgb,
+    pub offset: Srgb,
+    /// Highest value of each channel, applied last
+    pub max: Option<Srgb>,
+}
+
+impl Default for ColorGrade {
+    fn default() -> Self {
+        Self {
+            temperature: 0.0,
+            tint: 0.0,
+            gain: Srgb::one(),
+            offset: Srgb::zero(),
+            max: None,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl ColorGrade {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_white_balance(mut self, temperature: f32, tint: f32) -> Self {
+        self.temperature = temperature;
+        self.tint = tint;
+        self
+    }
+
+    pub fn with_gain(mut self, gain: Srgb) -> Self {
+        self.gain = gain;
+        self
+    }
+
+    pub fn with_offset(mut self, offset: Srgb) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn with_clamp(mut self, max: Srgb) -> Self {
+        self.max = Some(max);
+        self
+    }
+
+    /// Per channel scale of the white balance, normalized so that it keeps the luminance of
+    /// white the same.
+    fn white_balance(&self) -> Srgb {
+        let balance = Srgb::new(
+            (self.temperature / 2.0 + self.tint / 2.0).exp2(),
+            (-self.tint / 2.0).exp2(),
+            (-self.temperature / 2.0 + self.tint / 2.0).exp2(),
+        );
+        balance / balance.luminance()
+    }
+
+    pub fn apply(&self, buf: &mut [Srgb]) {
+        let scale = self.white_balance() * self.gain;
+        buf.par_iter_mut().for_each(|color| {
+            let graded = *color * scale + self.offset;
+            *color = match self.max {
+                Some(max) => Srgb::new(
+                    graded.x.min(max.x),
+                    graded.y.min(max.y),
+                    graded.z.min(max.z),
+                ),
+                None => graded,
+            };
+        });
+    }
+}
+
+/// Edge-aware smoothing of the color, guided by the normal and depth channels so that it
+/// only blurs between pixels which see the same surface. It runs as a horizontal and then
+/// a vertical pass, which isn't exactly a 2D bilateral filter but is much cheaper.
+#[derive(Clone, Copy, Debug)]
+pub struct CrossBilateral {
+    /// Standard deviation of the spatial gaussian, in pixels
+    pub radius: f32,
+    /// How different in luminance, relative to the brighter pixel, neighbors may be
+    pub sigma_color: f32,
+    /// How far apart (as vectors) the normals of neighbors may be
+    pub sigma_normal: f32,
+    /// How different neighbors' depths may be, relative to the nearer depth
+    pub sigma_depth: f32,
+}
+
+impl CrossBilateral {
+    #[allow(dead_code)]
+    pub fn new(radius: f32, sigma_color: f32, sigma_normal: f32, sigma_depth: f32) -> Self {
+        Self {
+            radius,
+            sigma_color,
+            sigma_normal,
+            sigma_depth,
+        }
+    }
+
+    pub fn apply(&self, buf: &mut [Srgb], normals: &[Vec3], depths: &[f32], res: Extent2u) {
+        let kernel = gaussian_kernel(self.radius);
+        if kernel.len() < 2 {
+            return;
+        }
+
+        let horizontal = self.pass(buf, normals, depths, res, &kernel, 1, res.w);
+        let vertical = self.pass(&horizontal, normals, depths, res, &kernel, res.w, res.h);
+        buf.copy_from_slice(&vertical);
+    }
+
+    /// Filters along one axis, where neighbors are `stride` pixels apart and there are
+    /// `extent` pixels along the axis.
+    #[allow(clippy::too_many_arguments)]
+    fn pass(
+        &self,
+        buf: &[Srgb],
+        normals: &[Vec3],
+        depths: &[f32],
+        res: Extent2u,
+        kernel: &[f32],
+        stride: usize,
+        extent: usize,
+    ) -> Vec<Srgb> {
+        let reach = (kernel.len() / 2) as isize;
+        (0..buf.len())
+            .into_par_iter()
+            .map(|idx| {
+                let coord = (if stride == 1 {
+                    idx % res.w
+                } else {
+                    idx / res.w
+                }) as isize;
+                let (color, normal, depth) = (buf[idx], normals[idx], depths[idx]);
+
+                let mut total = Srgb::zero();
+                let mut total_weight = 0.0;
+                for (k, spatial) in kernel.iter().enumerate() {
+                    let offset = k as isize - reach;
+                    let neighbor_coord = coord + offset;
+                    if neighbor_coord < 0 || neighbor_coord >= extent as isize {
+                        continue;
+                    }
+                    let n = (idx as isize + offset * stride as isize) as usize;
+
+                    let luminance = color.luminance().max(buf[n].luminance()).max(1e-4);
+                    let color_diff = (color.luminance() - buf[n].luminance()) / luminance;
+                    let normal_diff = (normal - normals[n]).mag();
+                    let depth_diff = (depth - depths[n]) / depth.min(depths[n]).max(1e-4);
+
+                    let weight = spatial
+                        * gaussian(color_diff, self.sigma_color)
+                        * gaussian(normal_diff, self.sigma_normal)
+                        * gaussian(depth_diff, self.sigma_depth);
+                    total += buf[n] * weight;
+                    total_weight += weight;
+                }
+
+                if total_weight > 0.0 {
+                    total / total_weight
+                } else {
+                    color
+                }
+            })
+            .collect()
+    }
+}
+
+/// Unnormalized gaussian of standard deviation `sigma`, which is 1 at zero.
+fn gaussian(x: f32, sigma: f32) -> f32 {
+    (-(x * x) / (2.0 * sigma * sigma)).exp()
+}
+
+/// Weights of the B3 spline, which is what each level of the à-trous filter spreads out.
+const B3_SPLINE: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Edge-aware à-trous wavelet filter for cleaner previews at low sample counts. Each of the
+/// `iterations` levels blurs with a 5x5 kernel whose taps are twice as far apart as the last
+/// one's, stopping at edges in the normal and depth channels like `CrossBilateral`.
+///
+/// Color differences are judged relative to the noise expected at each pixel's sample
+/// count, so that the filter blurs less and less as samples are added and converges to the
+/// unfiltered image.
+#[derive(Clone, Copy, Debug)]
+pub struct ATrous {
+    pub iterations: usize,
+    /// How different in luminance, relative to the brighter pixel, neighbors may be at one
+    /// sample per pixel
+    pub sigma_color: f32,
+    /// How far apart (as vectors) the normals of neighbors may be
+    pub sigma_normal: f32,
+    /// How different neighbors' depths may be, relative to the nearer depth
+    pub sigma_depth: f32,
+}
+
+impl ATrous {
+    #[allow(dead_code)]
+    pub fn new(iterations: usize, sigma_color: f32, sigma_normal: f32, sigma_depth: f32) -> Self {
+        Self {
+            iterations,
+            sigma_color,
+            sigma_normal,
+            sigma_depth,
+        }
+    }
+
+    pub fn apply(
+        &self,
+        buf: &mut [Srgb],
+        normals: &[Vec3],
+        depths: &[f32],
+        sample_counts: &[usize],
+        res: Extent2u,
+    ) {
+        let mut filtered = buf.to_vec();
+        for level in 0..self.iterations {
+            filtered = self.level(&filtered, normals, depths, sample_counts, res, 1 << level);
+        }
+        buf.copy_from_slice(&filtered);
+    }
+
+    /// One level of the filter, with `step` pixels between taps.
+    fn level(
+        &self,
+        buf: &[Srgb],
+        normals: &[Vec3],
+        depths: &[f32],
+        sample_counts: &[usize],
+        res: Extent2u,
+        step: isize,
+    ) -> Vec<Srgb> {
+        (0..buf.len())
+            .into_par_iter()
+            .map(|idx| {
+                let (x, y) = ((idx % res.w) as isize, (idx / res.w) as isize);
+                let (color, normal, depth) = (buf[idx], normals[idx], depths[idx]);
+                let sigma_color = self.sigma_color / (sample_counts[idx].max(1) as f32).sqrt();
+
+                let mut total = Srgb::zero();
+                let mut total_weight = 0.0;
+                for (ky, wy) in B3_SPLINE.iter().enumerate() {
+                    for (kx, wx) in B3_SPLINE.iter().enumerate() {
+                        let sx = x + (kx as isize - 2) * step;
+                        let sy = y + (ky as isize - 2) * step;
+                        if sx < 0 || sy < 0 || sx >= res.w as isize || sy >= res.h as isize {
+                            continue;
+                        }
+                        let n = sx as usize + sy as usize * res.w;
+
+                        let luminance = color.luminance().max(buf[n].luminance()).max(1e-4);
+                        let color_diff = (color.luminance() - buf[n].luminance()) / luminance;
+                        let normal_diff = (normal - normals[n]).mag();
+                        let depth_diff = (depth - depths[n]) / depth.min(depths[n]).max(1e-4);
+
+                        let weight = wx
+                            * wy
+                            * gaussian(color_diff, sigma_color)
+                            * gaussian(normal_diff, self.sigma_normal)
+                            * gaussian(depth_diff, self.sigma_depth);
+                        total += buf[n] * weight;
+                        total_weight += weight;
+                    }
+                }
+
+                if total_weight > 0.0 {
+                    total / total_weight
+                } else {
+                    color
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bloom_of_a_single_pixel_is_symmetric() {
+        let res = Extent2u::new(9, 9);
+        let mut buf = vec![Srgb::zero(); res.w * res.h];
+        buf[4 + 4 * res.w] = Srgb::new(10.0, 10.0, 10.0);
+        Bloom::new(1.0, 1.0, 1.0).apply(&mut buf, res);
+
+        let at = |x: usize, y: usize| buf[x + y * res.w].x;
+        assert!(at(5, 4) > 0.0);
+        for y in 0..res.h {
+            for x in 0..res.w {
+                let mirrored = [at(8 - x, y), at(x, 8 - y), at(y, x)];
+                assert!(mirrored.iter().all(|m| (m - at(x, y)).abs() < 1e-5));
+            }
+        }
+    }
+
+    #[test]
+    fn bloom_ignores_empty_images_and_negative_thresholds() {
+        Bloom::new(1.0, 1.0, 1.0).apply(&mut [], Extent2u::new(0, 0));
+
+        let res = Extent2u::new(4, 4);
+        let mut buf = vec![Srgb::zero(); res.w * res.h];
+        Bloom::new(-1.0, 1.0, 1.0).apply(&mut buf, res);
+        assert!(buf.iter().all(|color| !color.is_nan() && color.x == 0.0));
+    }
+
+    #[test]
+    fn cross_bilateral_smooths_flat_regions_but_keeps_depth_edges() {
+        // a checkerboard of noise around 1 on a near surface on the left and around 3 on a
+        // far one on the right, all facing the camera
+        let res = Extent2u::new(16, 8);
+        let is_near = |i: usize| i % res.w < 8;
+        let mut buf = (0..res.w * res.h)
+            .map(|i| {
+                let mean = if is_near(i) { 1.0 } else { 3.0 };
+                let noise = if (i % res.w + i / res.w) % 2 == 0 {
+                    0.5
+                } else {
+                    -0.5
+                };
+                Srgb::new(mean + noise, mean + noise, mean + noise)
+            })
+            .collect::<Vec<_>>();
+        let depths = (0..res.w * res.h)
+            .map(|i| if is_near(i) { 1.0 } else { 4.0 })
+            .collect::<Vec<_>>();
+        let normals = vec![Vec3::unit_z(); res.w * res.h];
+        // colors barely matter, so only the depth can keep the two sides apart
+        CrossBilateral::new(1.5, 1e3, 0.5, 0.1).apply(&mut buf, &normals, &depths, res);
+
+        for (i, color) in buf.iter().enumerate() {
+            let mean = if is_near(i) { 1.0 } else { 3.0 };
+            assert!((color.x - mean).abs() < 0.1);
+        }
+    }
+
+    #[test]
+    fn distance_fog_thickens_with_depth() {
+        let (red, gray) = (Srgb::new(1.0, 0.0, 0.0), Srgb::new(0.5, 0.5, 0.5));
+        let depths = [0.0, 0.01, 1.0, 1e4];
+        let mut buf = vec![red; depths.len()];
+        DistanceFog::new(0.7, gray).apply(&mut buf, &depths);
+
+        // the background is left alone, near pixels barely change and far ones are all fog
+        assert_eq!(buf[0].0, red.0);
+        assert!((buf[1].0 - red.0).mag() < 0.01);
+        assert!((buf[3].0 - gray.0).mag() < 1e-6);
+        let fog = 1.0 - (-0.7f32).exp();
+        assert!((buf[2].0 - (red.0 * (1.0 - fog) + gray.0 * fog)).mag() < 1e-6);
+    }
+
+    #[test]
+    fn a_trous_converges_to_the_unfiltered_image() {
+        // noise of about 10% around a mid gray, on a single flat surface
+        let res = Extent2u::new(16, 16);
+        let noisy = (0..res.w * res.h)
+            .map(|i| {
+                let noise = ((i * 7919) % 13) as f32 / 12.0 * 0.2 - 0.1;
+                Srgb::new(0.5 + noise, 0.5 + noise, 0.5 + noise)
+            })
+            .collect::<Vec<_>>();
+        let normals = vec![Vec3::unit_z(); res.w * res.h];
+        let depths = vec![2.0; res.w * res.h];
+
+        let change = |samples: usize| {
+            let mut buf = noisy.clone();
+            let counts = vec![samples; res.w * res.h];
+            ATrous::new(3, 0.5, 0.5, 0.1).apply(&mut buf, &normals, &depths, &counts, res);
+            buf.iter()
+                .zip(&noisy)
+                .map(|(filtered, noisy)| (filtered.x - noisy.x).abs())
+                .sum::<f32>()
+        };
+        let changes = [change(1), change(16), change(1 << 16)];
+        assert!(changes[0] > changes[1] && changes[1] > changes[2]);
+        assert!(changes[2] < changes[0] * 1e-3);
+    }
+
+    #[test]
+    fn default_grades_change_nothing() {
+        let colors = [
+            Srgb::new(0.25, 0.5, 0.75),
+            Srgb::new(4.0, 0.0, 1.5),
+            Srgb::zero(),
+        ];
+        let mut buf = colors.to_vec();
+        ColorGrade::default().apply(&mut buf);
+
+        for (graded, color) in buf.iter().zip(colors.iter()) {
+            assert!(
+                (graded.0 - color.0).mag() < 1e-6,
+                "{:?} != {:?}",
+                graded,
+                color
+            );
+        }
+    }
+
+    #[test]
+    fn temperature_warms_and_cools_white() {
+        let graded = |temperature: f32| {
+            let mut buf = vec![Srgb::one()];
+            ColorGrade::new()
+                .with_white_balance(temperature, 0.0)
+                .apply(&mut buf);
+            buf[0]
+        };
+
+        // a stop apart, with the luminance of white kept
+        let warm = graded(1.0);
+        assert!((warm.x / warm.z - 2.0).abs() < 1e-5, "{:?}", warm);
+        assert!((warm.luminance() - 1.0).abs() < 1e-5);
+        let cool = graded(-1.0);
+        assert!((cool.z / cool.x - 2.0).abs() < 1e-5, "{:?}", cool);
+        assert!((cool.luminance() - 1.0).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,118 @@
+//! A window showing the render as it progresses, behind the `minifb` feature.
+
+use minifb::{Key, Window, WindowOptions};
+
+use crate::framebuffer::Framebuffer;
+use crate::math::Vec3;
+
+/// Packs linear radiance into the opaque ARGB `u32`s minifb displays, scaled by `exposure`
+/// and tone mapped the same way as the saved color channel: clamped and gamma corrected.
+pub fn to_argb(framebuffer: &Framebuffer, exposure: f32) -> Vec<u32> {
+    framebuffer
+        .pixels()
+        .map(|(_, _, radiance)| {
+            let channel = |x: f32| {
+                let x = (x * exposure).max(0.0).min(1.0).powf(1.0 / 2.2);
+                (x * 255.0).round() as u32
+            };
+            let Vec3 { x, y, z } = radiance;
+            0xff00_0000 | (channel(x) << 16) | (channel(y) << 8) | channel(z)
+        })
+        .collect()
+}
+
+pub struct Preview {
+    window: Window,
+    exposure: f32,
+}
+
+#[allow(dead_code)]
+impl Preview {
+    pub fn open(title: &str, width: usize, height: usize) -> Result<Self, String> {
+        let options = WindowOptions {
+            resize: true,
+            ..WindowOptions::default()
+        };
+        let window = Window::new(title, width, height, options)
+            .map_err(|e| format!("Failed to open preview window: {}", e))?;
+        Ok(Preview {
+            window,
+            exposure: 1.0,
+        })
+    }
+
+    pub fn with_exposure(mut self, exposure: f32) -> Self {
+        self.exposure = exposure;
+        self
+    }
+
+    /// Whether the window is still open. Escape closes it too.
+    pub fn is_open(&self) -> bool {
+        self.window.is_open() && !self.window.is_key_down(Key::Escape)
+    }
+
+    /// Current size of the inside of the window.
+    pub fn size(&self) -> (usize, usize) {
+        self.window.get_size()
+    }
+
+    pub fn show(&mut self, framebuffer: &Framebuffer) -> Result<(), String> {
+        let buffer = to_argb(framebuffer, self.exposure);
+        self.window
+            .update_with_buffer(&buffer, framebuffer.width(), framebuffer.height())
+            .map_err(|e| format!("Failed to update preview window: {}", e))
+    }
+
+    /// Calls `render_pass` with the window size over and over, showing the average of all
+    /// the passes so far after each one, until the window is closed. When the window is
+    /// resized, the passes so far are thrown away and it starts over at the new size.
+    pub fn run<F>(&mut self, mut render_pass: F) -> Result<(), String>
+    where
+        F: FnMut(usize, usize) -> Result<Framebuffer, String>,
+    {
+        let mut accumulated: Option<Framebuffer> = None;
+        while self.is_open() {
+            let (width, height) = self.size();
+            if width == 0 || height == 0 {
+                // minimized, nothing to render into
+                self.window.update();
+                continue;
+            }
+
+            let pass = render_pass(width, height)?;
+            match &mut accumulated {
+                Some(acc) if (acc.width(), acc.height()) == (pass.width(), pass.height()) => {
+                    acc.accumulate(&pass)?
+                }
+                _ => accumulated = Some(pass),
+            }
+            if let Some(acc) = &accumulated {
+                self.show(acc)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn argb_packing() {
+        let mut framebuffer = Framebuffer::new(3, 1);
+        framebuffer
+            .add_sample(0, 0, Vec3::new(1.0, 0.0, 4.0))
+            .unwrap();
+        framebuffer
+            .add_sample(1, 0, Vec3::new(0.0, 0.5, -1.0))
+            .unwrap();
+
+        // out of range channels clamp, and the unsampled pixel is opaque black
+        assert_eq!(
+            to_argb(&framebuffer, 1.0),
+            vec![0xffff_00ff, 0xff00_ba00, 0xff00_0000]
+        );
+        assert_eq!(to_argb(&framebuffer, 2.0)[1], 0xff00_ff00);
+    }
+}
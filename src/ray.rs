@@ -1,19 +1,139 @@
 use crate::math::{f32x4, Vec2u, Vec3, Wec3};
 use crate::spectrum::{Srgb, WSrgb};
 
+/// The kind of scattering event at the first vertex of a path, used to route contributions
+/// into light path expression channels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lobe {
+    /// The ray hasn't scattered off of anything yet
+    None,
+    Diffuse,
+    Specular,
+}
+
+/// What a ray is being traced for, which decides which hitables it can see (see
+/// `Visibility`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RayKind {
+    /// Primary rays from the camera
+    Camera,
+    /// Rays towards lights, or anything else only looking for occluders
+    Shadow,
+    /// Rays scattered off of a surface
+    Reflection,
+}
+
+/// Auxiliary rays one pixel step over along x and y of the image from the ray they belong
+/// to, which the footprint of the pixel on the surfaces the ray hits is estimated from. The
+/// directions aren't necessarily unit length. Rays which didn't come from a camera have
+/// auxiliary rays equal to themselves, i.e. no footprint.
+#[derive(Clone, Copy, Debug)]
+pub struct RayDifferential {
+    pub dx_origin: Vec3,
+    pub dx_dir: Vec3,
+    pub dy_origin: Vec3,
+    pub dy_dir: Vec3,
+}
+
+impl RayDifferential {
+    /// Auxiliary rays which coincide with the ray from `origin` along `dir`.
+    pub fn degenerate(origin: Vec3, dir: Vec3) -> Self {
+        RayDifferential {
+            dx_origin: origin,
+            dx_dir: dir,
+            dy_origin: origin,
+            dy_dir: dir,
+        }
+    }
+}
+
+/// Four lanes of `RayDifferential`.
+#[derive(Clone, Copy, Debug)]
+pub struct WRayDifferential {
+    pub dx_origin: Wec3,
+    pub dx_dir: Wec3,
+    pub dy_origin: Wec3,
+    pub dy_dir: Wec3,
+}
+
+impl WRayDifferential {
+    /// Auxiliary rays which coincide with the rays from `origin` along `dir`.
+    pub fn degenerate(origin: Wec3, dir: Wec3) -> Self {
+        WRayDifferential {
+            dx_origin: origin,
+            dx_dir: dir,
+            dy_origin: origin,
+            dy_dir: dir,
+        }
+    }
+}
+
+impl From<[RayDifferential; 4]> for WRayDifferential {
+    fn from(d: [RayDifferential; 4]) -> Self {
+        WRayDifferential {
+            dx_origin: Wec3::from([
+                d[0].dx_origin,
+                d[1].dx_origin,
+                d[2].dx_origin,
+                d[3].dx_origin,
+            ]),
+            dx_dir: Wec3::from([d[0].dx_dir, d[1].dx_dir, d[2].dx_dir, d[3].dx_dir]),
+            dy_origin: Wec3::from([
+                d[0].dy_origin,
+                d[1].dy_origin,
+                d[2].dy_origin,
+                d[3].dy_origin,
+            ]),
+            dy_dir: Wec3::from([d[0].dy_dir, d[1].dy_dir, d[2].dy_dir, d[3].dy_dir]),
+        }
+    }
+}
+
+impl Into<[RayDifferential; 4]> for WRayDifferential {
+    fn into(self) -> [RayDifferential; 4] {
+        let dx_origins: [Vec3; 4] = self.dx_origin.into();
+        let dx_dirs: [Vec3; 4] = self.dx_dir.into();
+        let dy_origins: [Vec3; 4] = self.dy_origin.into();
+        let dy_dirs: [Vec3; 4] = self.dy_dir.into();
+        let lane = |i: usize| RayDifferential {
+            dx_origin: dx_origins[i],
+            dx_dir: dx_dirs[i],
+            dy_origin: dy_origins[i],
+            dy_dir: dy_dirs[i],
+        };
+        [lane(0), lane(1), lane(2), lane(3)]
+    }
+}
+
+/// Most that the squared length of the direction of a ray may be off from 1 by.
+const UNIT_DIR_TOLERANCE: f32 = 1e-3;
+
 macro_rules! rays {
-    ($($n:ident => $t:ident, $st:ident, $tt:ident, $tc:ty, $bt:ty, $scramt:ty, $samplet:ty),+) => {
+    ($($n:ident => $t:ident, $st:ident, $tt:ident, $tc:ty, $bt:ty, $scramt:ty, $samplet:ty, $lobet:ty, $negt:ty, $rought:ty, $difft:ty),+) => {
         $(#[derive(Clone, Copy, Debug)]
         pub struct $n {
             pub time: $tt,
             pub origin: $t,
+            /// Unit length (in valid lanes), so that `t` along the ray is the distance from its
+            /// origin. Hit distances, error bounds and t ranges all rely on this, so anything
+            /// which transforms rays has to renormalize them and convert `t`s to match.
             pub dir: $t,
+            /// Componentwise reciprocal of `dir`, so box tests can multiply instead of divide
+            pub inv_dir: $t,
+            /// Which components of `dir` are negative, i.e. which slab plane is hit first
+            pub dir_is_neg: $negt,
             pub radiance: $st,
             pub throughput: $st,
             pub tile_coord: $tc,
             pub valid: $bt,
             pub scramble: $scramt,
             pub sample: $samplet,
+            pub first_lobe: $lobet,
+            /// Largest roughness (0 for mirrors, 1 for diffuse) of the surfaces the path has
+            /// scattered off of so far, which path regularization raises later ones to
+            pub roughness: $rought,
+            /// Rays one pixel over from this one (see `RayDifferential`)
+            pub differential: $difft,
         }
 
         impl $n {
@@ -26,7 +146,7 @@ macro_rules! rays {
     }
 }
 
-rays!(Ray => Vec3, Srgb, f32, Vec2u, bool, f32, usize, WRay => Wec3, WSrgb, f32x4, [Vec2u; 4], [bool; 4], [f32; 4], [usize; 4]);
+rays!(Ray => Vec3, Srgb, f32, Vec2u, bool, f32, usize, Lobe, [bool; 3], f32, RayDifferential, WRay => Wec3, WSrgb, f32x4, [Vec2u; 4], [bool; 4], [f32; 4], [usize; 4], [Lobe; 4], Wec3, f32x4, WRayDifferential);
 
 impl Ray {
     #[allow(dead_code)]
@@ -38,16 +158,29 @@ impl Ray {
         scramble: f32,
         sample: usize,
     ) -> Self {
+        let length_sq = dir.mag_sq();
+        // NaN directions come from degenerate segments rather than a missed normalization
+        debug_assert!(
+            length_sq.is_nan() || (length_sq - 1.0).abs() <= UNIT_DIR_TOLERANCE,
+            "Ray direction isn't unit length: |dir|^2 = {}",
+            length_sq
+        );
+        let inv_dir = Vec3::broadcast(1.0) / dir;
         Self {
             time,
             origin,
             dir,
+            inv_dir,
+            dir_is_neg: [inv_dir.x < 0.0, inv_dir.y < 0.0, inv_dir.z < 0.0],
             radiance: Srgb::zero(),
             throughput: Srgb::one(),
             tile_coord,
             valid: true,
             scramble,
             sample,
+            first_lobe: Lobe::None,
+            roughness: 0.0,
+            differential: RayDifferential::degenerate(origin, dir),
         }
     }
 
@@ -56,12 +189,20 @@ impl Ray {
             time: std::f32::NAN,
             origin: Vec3::broadcast(std::f32::NAN),
             dir: Vec3::broadcast(std::f32::NAN),
+            inv_dir: Vec3::broadcast(std::f32::NAN),
+            dir_is_neg: [false; 3],
             radiance: Srgb::zero(),
             throughput: Srgb::zero(),
             tile_coord: Vec2u::zero(),
             valid: false,
             scramble: 0f32,
             sample: 0,
+            first_lobe: Lobe::None,
+            roughness: 0.0,
+            differential: RayDifferential::degenerate(
+                Vec3::broadcast(std::f32::NAN),
+                Vec3::broadcast(std::f32::NAN),
+            ),
         }
     }
 }
@@ -76,19 +217,83 @@ impl WRay {
         scramble: [f32; 4],
         sample: [usize; 4],
     ) -> Self {
+        debug_assert_unit(dir, &valid);
+        let inv_dir = Wec3::broadcast(f32x4::ONE) / dir;
         Self {
             time,
             origin,
             dir,
+            inv_dir,
+            dir_is_neg: dir_is_neg(inv_dir),
             radiance: WSrgb::zero(),
             throughput: WSrgb::one(),
             tile_coord,
             valid,
             scramble,
             sample,
+            first_lobe: [Lobe::None; 4],
+            roughness: f32x4::ZERO,
+            differential: WRayDifferential::degenerate(origin, dir),
         }
     }
 
+    /// A packet in which every lane is invalid, same as four `Ray::new_invalid`s.
+    #[allow(dead_code)]
+    pub fn new_invalid() -> Self {
+        let nan = Wec3::broadcast(f32x4::from(std::f32::NAN));
+        Self {
+            time: f32x4::from(std::f32::NAN),
+            origin: nan,
+            dir: nan,
+            inv_dir: nan,
+            dir_is_neg: Wec3::zero(),
+            radiance: WSrgb::zero(),
+            throughput: WSrgb::zero(),
+            tile_coord: [Vec2u::zero(); 4],
+            valid: [false; 4],
+            scramble: [0.0; 4],
+            sample: [0; 4],
+            first_lobe: [Lobe::None; 4],
+            roughness: f32x4::ZERO,
+            differential: WRayDifferential::degenerate(nan, nan),
+        }
+    }
+
+    pub fn with_differential(mut self, differential: WRayDifferential) -> Self {
+        self.differential = differential;
+        self
+    }
+
+    /// Half the distance from the rays to their auxiliary rays at distance `t` along them,
+    /// i.e. roughly the radius of a pixel there. It's zero for rays without differentials.
+    pub fn half_footprint_at(&self, t: f32x4) -> f32x4 {
+        let point = self.point_at(t);
+        let d = &self.differential;
+        let dx = (d.dx_dir.mul_add(Wec3::broadcast(t), d.dx_origin) - point).mag();
+        let dy = (d.dy_dir.mul_add(Wec3::broadcast(t), d.dy_origin) - point).mag();
+        dx.max(dy) * f32x4::from(0.5)
+    }
+
+    /// Points the rays along `dir`, keeping the precomputed reciprocals in sync.
+    pub fn set_dir(&mut self, dir: Wec3) {
+        debug_assert_unit(dir, &self.valid);
+        self.dir = dir;
+        self.inv_dir = Wec3::broadcast(f32x4::ONE) / dir;
+        self.dir_is_neg = dir_is_neg(self.inv_dir);
+    }
+
+    /// Mask with all bits set in the lanes which hold a valid ray, for use with `merge`.
+    pub fn valid_mask(&self) -> f32x4 {
+        let lane = |valid: bool| if valid { 1.0 } else { 0.0 };
+        f32x4::from([
+            lane(self.valid[0]),
+            lane(self.valid[1]),
+            lane(self.valid[2]),
+            lane(self.valid[3]),
+        ])
+        .cmp_gt(f32x4::ZERO)
+    }
+
     #[allow(dead_code)]
     pub fn is_nan(&self) -> f32x4 {
         self.time.cmp_nan(self.time)
@@ -111,6 +316,46 @@ impl WRay {
     }
 }
 
+#[cfg(test)]
+impl WRay {
+    /// A valid packet with every lane along the same ray, for tests.
+    pub fn along(origin: Vec3, dir: Vec3) -> Self {
+        WRay::new(
+            Wec3::splat(origin),
+            Wec3::splat(dir),
+            f32x4::ZERO,
+            [Vec2u::zero(); 4],
+            [true; 4],
+            [0.0; 4],
+            [0; 4],
+        )
+    }
+}
+
+/// Checks that the valid lanes of `dir` are unit length, the invariant on `WRay::dir`.
+#[inline]
+fn debug_assert_unit(dir: Wec3, valid: &[bool; 4]) {
+    if cfg!(debug_assertions) {
+        for (length_sq, valid) in dir.mag_sq().as_ref().iter().zip(valid.iter()) {
+            debug_assert!(
+                !*valid || length_sq.is_nan() || (length_sq - 1.0).abs() <= UNIT_DIR_TOLERANCE,
+                "Ray direction isn't unit length: |dir|^2 = {}",
+                length_sq
+            );
+        }
+    }
+}
+
+/// Lane masks of the negative components of a direction, from its reciprocal `inv_dir` so
+/// that a -0 component (whose reciprocal is -inf) counts as negative too.
+fn dir_is_neg(inv_dir: Wec3) -> Wec3 {
+    Wec3::new(
+        inv_dir.x.cmp_lt(f32x4::ZERO),
+        inv_dir.y.cmp_lt(f32x4::ZERO),
+        inv_dir.z.cmp_lt(f32x4::ZERO),
+    )
+}
+
 impl From<[Ray; 4]> for WRay {
     fn from(rays: [Ray; 4]) -> Self {
         Self {
@@ -122,6 +367,18 @@ impl From<[Ray; 4]> for WRay {
                 rays[3].origin,
             ]),
             dir: Wec3::from([rays[0].dir, rays[1].dir, rays[2].dir, rays[3].dir]),
+            inv_dir: Wec3::from([
+                rays[0].inv_dir,
+                rays[1].inv_dir,
+                rays[2].inv_dir,
+                rays[3].inv_dir,
+            ]),
+            dir_is_neg: dir_is_neg(Wec3::from([
+                rays[0].inv_dir,
+                rays[1].inv_dir,
+                rays[2].inv_dir,
+                rays[3].inv_dir,
+            ])),
             radiance: WSrgb::from([
                 rays[0].radiance,
                 rays[1].radiance,
@@ -153,6 +410,24 @@ impl From<[Ray; 4]> for WRay {
                 rays[2].sample,
                 rays[3].sample,
             ],
+            first_lobe: [
+                rays[0].first_lobe,
+                rays[1].first_lobe,
+                rays[2].first_lobe,
+                rays[3].first_lobe,
+            ],
+            roughness: f32x4::from([
+                rays[0].roughness,
+                rays[1].roughness,
+                rays[2].roughness,
+                rays[3].roughness,
+            ]),
+            differential: WRayDifferential::from([
+                rays[0].differential,
+                rays[1].differential,
+                rays[2].differential,
+                rays[3].differential,
+            ]),
         }
     }
 }
@@ -162,53 +437,129 @@ impl Into<[Ray; 4]> for WRay {
         let times = self.time.as_ref();
         let origins: [Vec3; 4] = self.origin.into();
         let dirs: [Vec3; 4] = self.dir.into();
+        let inv_dirs: [Vec3; 4] = self.inv_dir.into();
+        let is_neg = |d: Vec3| [d.x < 0.0, d.y < 0.0, d.z < 0.0];
         let throughputs: [Srgb; 4] = self.throughput.into();
         let radiances: [Srgb; 4] = self.radiance.into();
+        let roughnesses = self.roughness.as_ref();
+        let differentials: [RayDifferential; 4] = self.differential.into();
         [
             Ray {
                 time: times[0],
                 origin: origins[0],
                 dir: dirs[0],
+                inv_dir: inv_dirs[0],
+                dir_is_neg: is_neg(inv_dirs[0]),
                 radiance: radiances[0],
                 throughput: throughputs[0],
                 tile_coord: self.tile_coord[0],
                 valid: self.valid[0],
                 scramble: self.scramble[0],
                 sample: self.sample[0],
+                first_lobe: self.first_lobe[0],
+                roughness: roughnesses[0],
+                differential: differentials[0],
             },
             Ray {
                 time: times[1],
                 origin: origins[1],
                 dir: dirs[1],
+                inv_dir: inv_dirs[1],
+                dir_is_neg: is_neg(inv_dirs[1]),
                 radiance: radiances[1],
                 throughput: throughputs[1],
                 tile_coord: self.tile_coord[1],
                 valid: self.valid[1],
                 scramble: self.scramble[1],
                 sample: self.sample[1],
+                first_lobe: self.first_lobe[1],
+                roughness: roughnesses[1],
+                differential: differentials[1],
             },
             Ray {
                 time: times[2],
                 origin: origins[2],
                 dir: dirs[2],
+                inv_dir: inv_dirs[2],
+                dir_is_neg: is_neg(inv_dirs[2]),
                 radiance: radiances[2],
                 throughput: throughputs[2],
                 tile_coord: self.tile_coord[2],
                 valid: self.valid[2],
                 scramble: self.scramble[2],
                 sample: self.sample[2],
+                first_lobe: self.first_lobe[2],
+                roughness: roughnesses[2],
+                differential: differentials[2],
             },
             Ray {
                 time: times[3],
                 origin: origins[3],
                 dir: dirs[3],
+                inv_dir: inv_dirs[3],
+                dir_is_neg: is_neg(inv_dirs[3]),
                 radiance: radiances[3],
                 throughput: throughputs[3],
                 tile_coord: self.tile_coord[3],
                 valid: self.valid[3],
                 scramble: self.scramble[3],
                 sample: self.sample[3],
+                first_lobe: self.first_lobe[3],
+                roughness: roughnesses[3],
+                differential: differentials[3],
             },
         ]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(dirs: [Vec3; 4], valid: [bool; 4]) -> WRay {
+        WRay::new(
+            Wec3::zero(),
+            Wec3::from(dirs),
+            f32x4::ZERO,
+            [Vec2u::zero(); 4],
+            valid,
+            [0.0; 4],
+            [0; 4],
+        )
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "isn't unit length")]
+    fn non_unit_directions_panic_in_debug_builds() {
+        packet(
+            [
+                Vec3::unit_x(),
+                Vec3::unit_x() * 2.0,
+                Vec3::unit_y(),
+                Vec3::unit_z(),
+            ],
+            [true; 4],
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "isn't unit length")]
+    fn setting_non_unit_directions_panics_in_debug_builds() {
+        let mut rays = packet([Vec3::unit_x(); 4], [true; 4]);
+        rays.set_dir(Wec3::splat(Vec3::new(1.0, 1.0, 0.0)));
+    }
+
+    #[test]
+    fn invalid_lanes_and_nan_directions_are_not_checked() {
+        let dirs = [
+            Vec3::unit_x(),
+            Vec3::zero(),
+            Vec3::broadcast(std::f32::NAN),
+            Vec3::unit_z(),
+        ];
+        let mut rays = packet(dirs, [true, false, true, true]);
+        rays.set_dir(Wec3::from(dirs));
+    }
+}
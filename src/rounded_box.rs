@@ -0,0 +1,286 @@
+use crate::camera::Camera;
+use crate::hitable::{intersection_error_bound, Hitable, WHit, WShadingPoint};
+use crate::material::MaterialHandle;
+use crate::math::{f32x4, Aabb, ComponentWise, Mask, Vec3, Wec3};
+use crate::ray::WRay;
+
+const MAX_MARCHES: usize = 256;
+/// How close to the surface marching stops, relative to the size of the box.
+const RELATIVE_EPSILON: f32 = 1e-5;
+
+/// Axis-aligned box whose edges and corners are rounded off with `radius`. It's found by
+/// sphere tracing its exact distance field between where the ray enters and leaves the
+/// bounds, and the normals are analytic so they're continuous around the corners. With a
+/// radius of 0 it's a sharp box, which is intersected exactly instead.
+pub struct RoundedBox {
+    center: Vec3,
+    half_extent: Vec3,
+    radius: f32,
+    // the box which gets rounded off, `half_extent - radius`
+    core: Vec3,
+    epsilon: f32,
+    cull_backfaces: bool,
+    material: MaterialHandle,
+}
+
+impl RoundedBox {
+    /// `radius` is clamped to the smallest half extent, at which point the box becomes a
+    /// capsule or sphere along that axis.
+    #[allow(dead_code)]
+    pub fn new(center: Vec3, half_extent: Vec3, radius: f32, material: MaterialHandle) -> Self {
+        let radius = radius.max(0.0).min(half_extent.component_min());
+        RoundedBox {
+            center,
+            half_extent,
+            radius,
+            core: half_extent - Vec3::broadcast(radius),
+            epsilon: RELATIVE_EPSILON * half_extent.component_max(),
+            cull_backfaces: false,
+            material,
+        }
+    }
+
+    /// Makes camera and bounce rays which start inside the box go straight through its
+    /// walls, since the box is convex and those are the only ones to see the inside. Shadow
+    /// rays still hit them. Off by default.
+    #[allow(dead_code)]
+    pub fn with_backface_culling(mut self, cull: bool) -> Self {
+        self.cull_backfaces = cull;
+        self
+    }
+
+    fn dist(&self, point: Wec3) -> f32x4 {
+        let q = (point - Wec3::splat(self.center)).abs() - Wec3::splat(self.core);
+        let outside = Wec3::new(
+            q.x.max(f32x4::ZERO),
+            q.y.max(f32x4::ZERO),
+            q.z.max(f32x4::ZERO),
+        );
+        let inside = q.x.max(q.y).max(q.z).min(f32x4::ZERO);
+        outside.mag() + inside - f32x4::from(self.radius)
+    }
+
+    /// Distances along the rays to where they enter and leave the bounds.
+    fn slab(&self, origin: Wec3, dir: Wec3) -> (f32x4, f32x4) {
+        let local = origin - Wec3::splat(self.center);
+        let t0 = (Wec3::splat(-self.half_extent) - local) / dir;
+        let t1 = (Wec3::splat(self.half_extent) - local) / dir;
+        let enter = t0.x.min(t1.x).max(t0.y.min(t1.y)).max(t0.z.min(t1.z));
+        let exit = t0.x.max(t1.x).min(t0.y.max(t1.y)).min(t0.z.max(t1.z));
+        (enter, exit)
+    }
+
+    /// Closest intersection with `t` in `(t_start, t_end]`, or `f32::MAX` where there is none.
+    /// With `cull`, rays starting inside the box miss it.
+    fn intersect(
+        &self,
+        origin: Wec3,
+        dir: Wec3,
+        t_start: f32x4,
+        t_end: f32x4,
+        cull: bool,
+    ) -> f32x4 {
+        let miss = f32x4::from(std::f32::MAX);
+        let (enter, exit) = self.slab(origin, dir);
+        let overlaps = enter.cmp_le(exit) & exit.cmp_gt(t_start) & enter.cmp_le(t_end);
+        if overlaps.none() {
+            return miss;
+        }
+
+        if self.radius == 0.0 {
+            // rays starting inside leave through the far side
+            let inside = enter.cmp_le(t_start);
+            let overlaps = if cull { overlaps & !inside } else { overlaps };
+            let t = f32x4::merge(inside, exit, enter);
+            let hit = overlaps & t.cmp_gt(t_start) & t.cmp_le(t_end);
+            return f32x4::merge(hit, t, miss);
+        }
+
+        let epsilon = f32x4::from(self.epsilon);
+        let t_max = t_end.min(exit);
+        let mut t = t_start.max(enter);
+        let mut hit = f32x4::ZERO;
+        let mut done = !overlaps;
+        if cull {
+            // where marching starts is either on the bounds or, if the ray starts inside
+            // them, the start of the ray, which is only inside the box itself for back faces
+            done = done | self.dist(origin + dir * t).cmp_lt(f32x4::ZERO);
+        }
+        for _march in 0..MAX_MARCHES {
+            let dist = self.dist(origin + dir * t).abs();
+            let close = dist.cmp_lt(epsilon) & !done;
+            hit = hit | close;
+            done = done | close;
+            t = f32x4::merge(done, t, t + dist);
+            done = done | t.cmp_gt(t_max);
+            if done.all() {
+                break;
+            }
+        }
+        f32x4::merge(hit & t.cmp_le(t_max) & t.cmp_gt(t_start), t, miss)
+    }
+}
+
+impl Hitable for RoundedBox {
+    fn occluded(&self, start: Wec3, end: Wec3, _time: f32x4) -> f32x4 {
+        let dir = end - start;
+        let dist = dir.mag();
+        let dir = dir / dist;
+
+        // marching can't tell the surface the ray started on apart from closer than this
+        let t_min = f32x4::from(2.0 * self.epsilon);
+        let t = self.intersect(start, dir, t_min, dist, false);
+        f32x4::merge(
+            t.cmp_lt(f32x4::from(std::f32::MAX)),
+            f32x4::ZERO,
+            f32x4::ONE,
+        )
+    }
+
+    fn hit(&self, ray: &WRay, t_range: ::std::ops::Range<f32x4>) -> f32x4 {
+        let (t_start, t_end) = (t_range.start, t_range.end);
+        self.intersect(ray.origin, ray.dir, t_start, t_end, self.cull_backfaces)
+    }
+
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<Aabb> {
+        Some(Aabb::from_center_half_size(self.center, self.half_extent))
+    }
+
+    fn get_shading_info(
+        &self,
+        hit: WHit,
+        primary: bool,
+        camera: &dyn Camera,
+    ) -> (MaterialHandle, WShadingPoint) {
+        let point = hit.point();
+        let offset_by =
+            (f32x4::from(2.0 * self.epsilon)).max(intersection_error_bound(&hit, primary, camera));
+
+        // on the rounded parts the normal points away from the closest point of the core
+        // box. On the flat faces that's the axis the point is furthest out along.
+        let local = point - Wec3::splat(self.center);
+        let sign = local.sign();
+        let q = local.abs() - Wec3::splat(self.core);
+        let outside = Wec3::new(
+            q.x.max(f32x4::ZERO),
+            q.y.max(f32x4::ZERO),
+            q.z.max(f32x4::ZERO),
+        );
+        let outside_dist = outside.mag();
+        let rounded = Wec3::new(outside.x * sign.x, outside.y * sign.y, outside.z * sign.z)
+            / outside_dist.max(f32x4::from(std::f32::MIN_POSITIVE));
+
+        let along_x = q.x.cmp_ge(q.y) & q.x.cmp_ge(q.z);
+        let along_y = !along_x & q.y.cmp_ge(q.z);
+        let along_z = !along_x & !along_y;
+        let flat = Wec3::new(
+            f32x4::merge(along_x, sign.x, f32x4::ZERO),
+            f32x4::merge(along_y, sign.y, f32x4::ZERO),
+            f32x4::merge(along_z, sign.z, f32x4::ZERO),
+        );
+
+        let is_rounded = outside_dist.cmp_gt(f32x4::from(self.epsilon));
+        let normal = Wec3::merge(is_rounded, rounded, flat).normalized();
+
+        (
+            self.material,
+            WShadingPoint::new(hit, point, offset_by, normal),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2x2x2 box around the origin.
+    fn cube(radius: f32, cull: bool) -> RoundedBox {
+        RoundedBox::new(
+            Vec3::zero(),
+            Vec3::broadcast(1.0),
+            radius,
+            MaterialHandle(0),
+        )
+        .with_backface_culling(cull)
+    }
+
+    fn hit_along_x(cube: &RoundedBox, origin: Vec3) -> f32 {
+        let ray = WRay::along(origin, Vec3::unit_x());
+        let t = cube.hit(&ray, f32x4::ZERO..f32x4::from(10.0));
+        t.as_ref()[0]
+    }
+
+    #[test]
+    fn culled_walls_are_invisible_from_inside() {
+        for radius in [0.0, 0.25].iter() {
+            assert!((hit_along_x(&cube(*radius, false), Vec3::zero()) - 1.0).abs() < 1e-3);
+            assert_eq!(
+                hit_along_x(&cube(*radius, true), Vec3::zero()),
+                std::f32::MAX
+            );
+        }
+    }
+
+    #[test]
+    fn culling_keeps_the_front_faces() {
+        for radius in [0.0, 0.25].iter() {
+            let cube = cube(*radius, true);
+            let t = hit_along_x(&cube, Vec3::new(-3.0, 0.0, 0.0));
+            assert!((t - 2.0).abs() < 1e-3);
+
+            // shadow rays still stop at the walls
+            let end = Wec3::splat(Vec3::unit_x() * 3.0);
+            let occluded = cube.occluded(Wec3::zero(), end, f32x4::ZERO);
+            assert_eq!(occluded.as_ref()[0], 0.0);
+        }
+    }
+
+    fn normal_along_z(cube: &RoundedBox, origin: Vec3) -> Vec3 {
+        let ray = WRay::along(origin, -Vec3::unit_z());
+        let hit = WHit {
+            ray,
+            t: cube.hit(&ray, f32x4::ZERO..f32x4::from(10.0)),
+            attributes: crate::hitable::WHitAttributes::none(),
+        };
+        let camera = crate::camera::OrthographicCamera::new(
+            crate::math::Vec2::new(1.0, 1.0),
+            1.0,
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::zero(),
+            Vec3::unit_y(),
+        );
+        let (_, point) = cube.get_shading_info(hit, false, &camera);
+        let normals: [Vec3; 4] = point.normal.into();
+        normals[0]
+    }
+
+    #[test]
+    fn zero_radius_boxes_have_sharp_corners() {
+        let cube = cube(0.0, false);
+        for xy in [0.0, 0.9, 0.999].iter() {
+            let t = cube.hit(
+                &WRay::along(Vec3::new(*xy, *xy, 5.0), -Vec3::unit_z()),
+                f32x4::ZERO..f32x4::from(10.0),
+            );
+            assert!((t.as_ref()[0] - 4.0).abs() < 1e-5);
+            let normal = normal_along_z(&cube, Vec3::new(*xy, *xy, 5.0));
+            assert!((normal - Vec3::unit_z()).mag() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn normals_turn_smoothly_around_rounded_edges() {
+        // across the edge between the top and the +x face, which starts rounding off at 0.75
+        let cube = cube(0.25, false);
+        let normals = (0..57)
+            .map(|i| normal_along_z(&cube, Vec3::new(0.7 + i as f32 * 0.005, 0.0, 5.0)))
+            .collect::<Vec<_>>();
+
+        assert!((normals[0] - Vec3::unit_z()).mag() < 1e-5);
+        assert!(normals[56].x > 0.8 && normals[56].y.abs() < 1e-5);
+        for pair in normals.windows(2) {
+            assert!((pair[1] - pair[0]).mag() < 0.1, "{:?}", pair);
+            assert!(pair[1].x >= pair[0].x - 1e-5);
+        }
+    }
+}
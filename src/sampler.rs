@@ -2,6 +2,8 @@ use rand::prelude::*;
 
 use ultraviolet::f32x4;
 
+use std::ops::Range;
+
 // pub trait Sampler {
 //     fn begin_pixel(&mut self, pixel: Vec2u);
 //     fn request_samples_2d(&mut self, samples: usize);
@@ -25,7 +27,8 @@ impl Samples {
         }
 
         for i in 0..sets_2d {
-            let mut seq_2d = quasi_rd::Sequence::new_with_offset(2, (offset + sets_1d as u64 + i as u64) << 32);
+            let mut seq_2d =
+                quasi_rd::Sequence::new_with_offset(2, (offset + sets_1d as u64 + i as u64) << 32);
             seq_2d.fill_with_samples_f32(&mut samples_2d[samples * 2 * i..2 * samples * (i + 1)]);
         }
 
@@ -36,12 +39,14 @@ impl Samples {
         }
     }
 
+    /// Independent uniform random samples, which are the same every time for the same
+    /// `seed`.
     #[allow(dead_code)]
-    pub fn new_random(samples: usize, sets_1d: usize, sets_2d: usize) -> Self {
+    pub fn new_random(samples: usize, sets_1d: usize, sets_2d: usize, seed: u64) -> Self {
         let mut samples_1d = vec![0f32; samples * sets_1d];
         let mut samples_2d = vec![0f32; samples * 2 * sets_2d];
 
-        let mut rng = SmallRng::from_rng(thread_rng()).unwrap();
+        let mut rng = SmallRng::seed_from_u64(seed);
 
         for s in samples_1d.iter_mut() {
             *s = rng.gen();
@@ -125,3 +130,103 @@ impl Samples {
         ])
     }
 }
+
+/// Assigns the sample sets of `Samples` to the decisions made along a path, so that every
+/// decision always draws from the same sets and no two of them share one, which would
+/// correlate them. The camera gets the first sets, followed by the same number of sets
+/// for each bounce.
+#[derive(Clone, Copy, Debug)]
+pub struct SampleDimensions {
+    per_bounce_1d: usize,
+    per_bounce_2d: usize,
+    bounces: usize,
+}
+
+impl SampleDimensions {
+    /// 1D set of the time within the shutter interval.
+    pub const TIME: usize = 0;
+    /// 2D set of the position within the pixel.
+    pub const PIXEL_OFFSET: usize = 0;
+    /// 2D set of the position on the lens.
+    pub const LENS: usize = 1;
+
+    const CAMERA_1D: usize = 1;
+    const CAMERA_2D: usize = 2;
+
+    /// Room for `bounces` bounces of `per_bounce_1d` and `per_bounce_2d` sets each.
+    pub fn new(per_bounce_1d: usize, per_bounce_2d: usize, bounces: usize) -> Self {
+        Self {
+            per_bounce_1d,
+            per_bounce_2d,
+            bounces,
+        }
+    }
+
+    /// The 1D sets of the bounce at `depth`.
+    pub fn bounce_1d(&self, depth: usize) -> Range<usize> {
+        let start = Self::CAMERA_1D + depth * self.per_bounce_1d;
+        start..start + self.per_bounce_1d
+    }
+
+    /// The 2D sets of the bounce at `depth`.
+    pub fn bounce_2d(&self, depth: usize) -> Range<usize> {
+        let start = Self::CAMERA_2D + depth * self.per_bounce_2d;
+        start..start + self.per_bounce_2d
+    }
+
+    /// Number of 1D sets to generate for all of the decisions.
+    pub fn sets_1d(&self) -> usize {
+        Self::CAMERA_1D + self.bounces * self.per_bounce_1d
+    }
+
+    /// Number of 2D sets to generate for all of the decisions.
+    pub fn sets_2d(&self) -> usize {
+        Self::CAMERA_2D + self.bounces * self.per_bounce_2d
+    }
+}
+
+/// Mixes `values` into a well distributed seed. Seeding from this with, say, the pixel
+/// coordinates gives every pixel its own random stream, which doesn't depend on which
+/// thread renders it or in what order.
+pub fn hash_seed(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e37_79b9_7f4a_7c15, |hash, value| {
+        splitmix64(hash ^ value)
+    })
+}
+
+/// The SplitMix64 step, which scrambles all bits of `x` into all bits of the result.
+fn splitmix64(x: u64) -> u64 {
+    let x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    let x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounces_get_disjoint_sample_sets() {
+        let dimensions = SampleDimensions::new(3, 6, 4);
+        let mut used_1d = vec![SampleDimensions::TIME];
+        let mut used_2d = vec![SampleDimensions::PIXEL_OFFSET, SampleDimensions::LENS];
+        for depth in 0..4 {
+            used_1d.extend(dimensions.bounce_1d(depth));
+            used_2d.extend(dimensions.bounce_2d(depth));
+        }
+
+        // every set is taken by exactly one decision, and all of them are generated
+        for (used, sets) in [
+            (used_1d, dimensions.sets_1d()),
+            (used_2d, dimensions.sets_2d()),
+        ]
+        .iter_mut()
+        {
+            assert_eq!(used.len(), *sets);
+            used.sort_unstable();
+            used.dedup();
+            assert_eq!(*used, (0..*sets).collect::<Vec<_>>());
+        }
+    }
+}
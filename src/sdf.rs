@@ -1,7 +1,7 @@
 use crate::camera::Camera;
-use crate::hitable::{Hitable, WHit, WShadingPoint};
+use crate::hitable::{intersection_error_bound, Hitable, WHit, WShadingPoint};
 use crate::material::MaterialHandle;
-use crate::math::{f32x4, Wec3};
+use crate::math::{f32x4, Mask, Vec3, Wec3};
 use crate::ray::WRay;
 use ultraviolet::wide::*;
 
@@ -12,6 +12,13 @@ const MAX_VIS_MARCHES: u32 = 100;
 
 const_f32_as_f32x4!(EPSILON_MIN, 0.0001);
 
+/// Points of a Mandelbulb further out than this escape on the first iteration, so the
+/// whole fractal is inside of it.
+const BULB_RADIUS: f32 = 2.0;
+/// How far outside of `BULB_RADIUS` the distance to the bounding sphere is used instead
+/// of the distance estimate, which is far too large away from the fractal.
+const BULB_MARGIN: f32 = 0.1;
+
 pub struct TracedSDF<S> {
     sdf: S,
     material: MaterialHandle,
@@ -41,14 +48,14 @@ impl<S: SDF<f32x4, Wec3> + Send + Sync> Hitable for TracedSDF<S> {
         for _march in 0..MAX_VIS_MARCHES {
             let gt_mask = t.cmp_gt(max_dist);
             gt_nan_mask = gt_mask | nan_mask;
-            if gt_nan_mask.move_mask() == 0b1111 {
+            if gt_nan_mask.all() {
                 break;
             }
             let point = dir.mul_add(Wec3::broadcast(t), start);
             let dist = self.sdf.dist(point).abs();
             hit_mask = dist.cmp_lt(f32x4::from(0.0001));
             let hit_gt_nan_mask = hit_mask | gt_nan_mask;
-            if hit_gt_nan_mask.move_mask() == 0b1111 {
+            if hit_gt_nan_mask.all() {
                 break;
             }
             t = f32x4::merge(hit_gt_nan_mask, t, t + dist);
@@ -56,26 +63,30 @@ impl<S: SDF<f32x4, Wec3> + Send + Sync> Hitable for TracedSDF<S> {
         f32x4::merge(hit_mask & !gt_nan_mask, f32x4::ZERO, f32x4::ONE)
     }
 
+    /// Lanes which don't get close enough to the surface within `MAX_MARCHES` steps are
+    /// misses.
     fn hit(&self, ray: &WRay, t_range: ::std::ops::Range<f32x4>) -> f32x4 {
-        let dist = self.sdf.dist(ray.origin).abs();
-        let mut t = dist;
+        let dist = self.sdf.dist(ray.point_at(t_range.start)).abs();
+        let mut t = t_range.start + dist;
         let nan_mask = t.cmp_nan(t);
+        let mut hit = f32x4::ZERO;
         for _march in 0..MAX_MARCHES {
             let gt_mask = t.cmp_gt(t_range.end);
             let gt_nan_mask = gt_mask | nan_mask;
-            if gt_nan_mask.move_mask() == 0b1111 {
+            if gt_nan_mask.all() {
                 break;
             }
             let point = ray.point_at(t);
             let dist = self.sdf.dist(point).abs();
-            let hit_mask = dist.cmp_lt(t_range.start);
-            let hit_gt_nan_mask = hit_mask | gt_nan_mask;
+            let hit_mask = dist.cmp_lt(EPSILON_MIN) & !gt_nan_mask;
+            hit = hit | hit_mask;
+            let hit_gt_nan_mask = hit | gt_nan_mask;
             t = f32x4::merge(hit_gt_nan_mask, t, t + dist);
-            if hit_gt_nan_mask.move_mask() == 0b1111 {
+            if hit_gt_nan_mask.all() {
                 break;
             }
         }
-        t
+        f32x4::merge(hit, t, f32x4::from(std::f32::MAX))
     }
 
     fn get_shading_info(
@@ -88,7 +99,7 @@ impl<S: SDF<f32x4, Wec3> + Send + Sync> Hitable for TracedSDF<S> {
         let dist = self.sdf.dist(point).abs();
 
         let normal_eps = if primary {
-            camera.half_pixel_size_at(hit.t)
+            camera.half_pixel_size_at(hit.t, hit.ray.time)
         } else {
             f32x4::from(0.001)
         };
@@ -96,9 +107,12 @@ impl<S: SDF<f32x4, Wec3> + Send + Sync> Hitable for TracedSDF<S> {
         let normals = self.sdf.normals_fast(normal_eps);
 
         let normal = normals.normal_at(point);
+        // marching stops anywhere within EPSILON_MIN of the surface, on top of the usual error
+        let offset_by = (f32x4::from(2.0) * EPSILON_MIN.max(dist))
+            .max(intersection_error_bound(&hit, primary, camera));
         (
             self.material,
-            WShadingPoint::new(hit, point, f32x4::from(2.0) * EPSILON_MIN.max(dist), normal),
+            WShadingPoint::new(hit, point, offset_by, normal),
         )
     }
 }
@@ -144,6 +158,63 @@ impl SDF<f32x4, Wec3> for MandelBox {
     }
 }
 
+/// The Mandelbulb fractal of `power`, found with its distance estimate. More `iterations`
+/// bring out finer detail, and with only a couple it's a lumpy sphere. The estimate is
+/// evaluated one lane at a time since it needs trigonometry in spherical coordinates.
+#[derive(Clone, Copy)]
+pub struct Mandelbulb {
+    power: f32,
+    iterations: usize,
+}
+
+impl Mandelbulb {
+    /// The classic bulb has a `power` of 8.
+    #[allow(dead_code)]
+    pub fn new(power: f32, iterations: usize) -> Self {
+        Self { power, iterations }
+    }
+
+    fn lane_dist(&self, point: Vec3) -> f32 {
+        let mut r = point.mag();
+        if r > BULB_RADIUS + BULB_MARGIN {
+            return r - BULB_RADIUS;
+        }
+
+        let mut z = point;
+        let mut dr = 1.0;
+        for _ in 0..self.iterations {
+            if r > BULB_RADIUS {
+                break;
+            }
+            // z^power in spherical coordinates raises the radius and multiplies the angles
+            let theta = if r > 0.0 { (z.z / r).acos() } else { 0.0 };
+            let phi = z.y.atan2(z.x);
+            dr = r.powf(self.power - 1.0) * self.power * dr + 1.0;
+            let (sin_theta, cos_theta) = (theta * self.power).sin_cos();
+            let (sin_phi, cos_phi) = (phi * self.power).sin_cos();
+            z = Vec3::new(sin_theta * cos_phi, sin_theta * sin_phi, cos_theta) * r.powf(self.power)
+                + point;
+            r = z.mag();
+        }
+        // the origin never moves, and ln(0) * 0 is NaN
+        if r == 0.0 {
+            return 0.0;
+        }
+        0.5 * r.ln() * r / dr
+    }
+}
+
+impl SDF<f32x4, Wec3> for Mandelbulb {
+    fn dist(&self, p: Wec3) -> f32x4 {
+        let points: [Vec3; 4] = p.into();
+        let mut dists = [0.0; 4];
+        for (dist, point) in dists.iter_mut().zip(points.iter()) {
+            *dist = self.lane_dist(*point);
+        }
+        f32x4::from(dists)
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct BoxFold {
     l: Wec3,
@@ -215,3 +286,45 @@ impl BrokenSphereFold {
         *dr *= mul;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::Vec2u;
+
+    #[test]
+    fn the_origin_is_on_the_bulb() {
+        assert_eq!(Mandelbulb::new(8.0, 4).lane_dist(Vec3::zero()), 0.0);
+    }
+
+    #[test]
+    fn one_iteration_bulbs_have_a_round_silhouette() {
+        let bulb = TracedSDF::new(Mandelbulb::new(8.0, 1), MaterialHandle(0));
+        let offsets = [0.0, 0.4, 1.5, 2.5];
+        let ray = WRay::new(
+            Wec3::from([
+                Vec3::new(5.0, offsets[0], 0.0),
+                Vec3::new(5.0, offsets[1], 0.0),
+                Vec3::new(5.0, offsets[2], 0.0),
+                Vec3::new(5.0, offsets[3], 0.0),
+            ]),
+            Wec3::splat(-Vec3::unit_x()),
+            f32x4::ZERO,
+            [Vec2u::zero(); 4],
+            [true; 4],
+            [0.0; 4],
+            [0; 4],
+        );
+        let t = bulb.hit(&ray, f32x4::ZERO..f32x4::from(10.0));
+
+        // after one iteration the equator is r^2 + r^16 = 1, nearly a circle of radius 0.901
+        for (offset, t) in offsets.iter().zip(t.as_ref().iter()) {
+            if *offset > 0.901 {
+                assert_eq!(*t, std::f32::MAX);
+                continue;
+            }
+            let circle_t = 5.0 - (0.901f32 * 0.901 - offset * offset).sqrt();
+            assert!((t - circle_t).abs() < 0.01, "{} != {}", t, circle_t);
+        }
+    }
+}
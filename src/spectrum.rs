@@ -51,6 +51,14 @@ macro_rules! srgbs {
             pub fn max_channel(&self) -> $tt {
                 self.0.component_max()
             }
+
+            /// Relative luminance, with the Rec. 709 weights of linear sRGB.
+            #[allow(dead_code)]
+            pub fn luminance(&self) -> $tt {
+                self.0.x * $tt::from(0.2126)
+                    + self.0.y * $tt::from(0.7152)
+                    + self.0.z * $tt::from(0.0722)
+            }
         }
 
         impl Sum for $n {
@@ -67,6 +75,103 @@ impl Srgb {
     pub fn is_nan(&self) -> bool {
         self.x.is_nan() || self.y.is_nan() || self.z.is_nan()
     }
+
+    /// Color of a blackbody radiator at `kelvin` degrees, from Planck's law integrated
+    /// against the CIE 1931 matching functions, scaled to unit luminance so that it can be
+    /// multiplied by the intensity of a light or emitter. Around 6500K is close to white;
+    /// colors of very low temperatures which are outside of the gamut are clipped.
+    #[allow(dead_code)]
+    pub fn from_temperature(kelvin: f32) -> Self {
+        let mut xyz = Vec3::zero();
+        for step in 0..=80 {
+            let lambda = 380.0 + step as f32 * 5.0;
+            xyz += cie_xyz(lambda) * planck(lambda, kelvin);
+        }
+
+        let srgb = Srgb::new(
+            3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+            -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+            0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z,
+        );
+        let srgb = Srgb(srgb.map(|x| x.max(0.0)));
+        let luminance = srgb.luminance();
+        if luminance > 0.0 {
+            srgb / luminance
+        } else {
+            Srgb::zero()
+        }
+    }
+}
+
+/// Relative spectral radiance of a blackbody at `kelvin`, for a wavelength in nanometers.
+fn planck(lambda: f32, kelvin: f32) -> f32 {
+    // in micrometers, so that lambda^5 stays well inside of f32 range
+    let lambda = lambda / 1000.0;
+    let c2 = 14_388.0;
+    1.0 / (lambda.powi(5) * ((c2 / (lambda * kelvin)).exp() - 1.0))
+}
+
+/// CIE 1931 2 degree observer at a wavelength in nanometers, using the multi-lobe gaussian
+/// fit from Wyman et al. 2013.
+fn cie_xyz(lambda: f32) -> Vec3 {
+    let g = |mu: f32, sigma_below: f32, sigma_above: f32| {
+        let sigma = if lambda < mu {
+            sigma_below
+        } else {
+            sigma_above
+        };
+        let t = (lambda - mu) / sigma;
+        (-0.5 * t * t).exp()
+    };
+    Vec3::new(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+/// RGB color spaces that images can be written out in. Rendering always happens in linear
+/// sRGB, and colors are converted from it when saving.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorSpace {
+    Srgb,
+    /// Same primaries and white point as sRGB
+    Rec709,
+    /// ACES AP1 primaries with the ACES (~D60) white point, adapted from D65 with Bradford
+    AcesCg,
+    Rec2020,
+}
+
+impl ColorSpace {
+    /// Row-major matrix taking linear sRGB to this space's linear RGB.
+    pub fn matrix_from_srgb(self) -> [[f32; 3]; 3] {
+        match self {
+            ColorSpace::Srgb | ColorSpace::Rec709 => {
+                [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+            }
+            ColorSpace::AcesCg => [
+                [0.613_097, 0.339_523, 0.047_379],
+                [0.070_194, 0.916_356, 0.013_453],
+                [0.020_616, 0.109_570, 0.869_815],
+            ],
+            ColorSpace::Rec2020 => [
+                [0.627_404, 0.329_282, 0.043_314],
+                [0.069_097, 0.919_540, 0.011_361],
+                [0.016_392, 0.088_013, 0.895_595],
+            ],
+        }
+    }
+
+    /// Converts a linear sRGB color to linear RGB in this space.
+    pub fn convert_srgb(self, color: Srgb) -> Srgb {
+        if let ColorSpace::Srgb | ColorSpace::Rec709 = self {
+            return color;
+        }
+
+        let m = self.matrix_from_srgb();
+        let row = |r: [f32; 3]| r[0] * color.x + r[1] * color.y + r[2] * color.z;
+        Srgb::new(row(m[0]), row(m[1]), row(m[2]))
+    }
 }
 
 impl WSrgb {
@@ -182,3 +287,37 @@ macro_rules! impl_wrapper_ops {
 
 impl_wrapper_ops!(Srgb => f32);
 impl_wrapper_ops!(WSrgb => f32x4);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temperature_colors() {
+        let white = Srgb::from_temperature(6500.0);
+        assert!([white.x, white.y, white.z]
+            .iter()
+            .all(|c| (c - 1.0).abs() < 0.1));
+
+        let warm = Srgb::from_temperature(2000.0);
+        assert!(warm.x > warm.y && warm.y > warm.z);
+        assert!((warm.luminance() - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn color_space_conversions() {
+        let close = |a: Srgb, b: Srgb| (a.0 - b.0).mag() < 1e-4;
+        let color = Srgb::new(0.2, 0.5, 0.9);
+        assert_eq!(ColorSpace::Srgb.convert_srgb(color).0, color.0);
+        assert_eq!(ColorSpace::Rec709.convert_srgb(color).0, color.0);
+
+        // the sRGB primaries are inside the wider gamuts, and white stays white
+        let red = Srgb::new(1.0, 0.0, 0.0);
+        let rec2020_red = Srgb::new(0.627_404, 0.069_097, 0.016_392);
+        assert!(close(ColorSpace::Rec2020.convert_srgb(red), rec2020_red));
+        let white = Srgb::new(1.0, 1.0, 1.0);
+        for space in [ColorSpace::AcesCg, ColorSpace::Rec2020].iter() {
+            assert!(close(space.convert_srgb(white), white), "{:?}", space);
+        }
+    }
+}
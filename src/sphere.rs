@@ -1,13 +1,19 @@
 use crate::animation::WSequenced;
 use crate::camera::Camera;
-use crate::hitable::{Hitable, WHit, WShadingPoint};
+use crate::hitable::{intersection_error_bound, Hitable, WHit, WShadingPoint, SHADOW_T_MIN};
 use crate::material::MaterialHandle;
-use crate::math::{f32x4, Wec3};
+use crate::math::{f32x4, gamma, Aabb, Mask, Vec3, Wec2, Wec3};
 use crate::ray::WRay;
 
+/// Times per interval that the position of a moving sphere is sampled at to bound it.
+const MOTION_BOUND_SAMPLES: usize = 16;
+
 pub struct Sphere<TR> {
     transform_seq: TR,
     radius: f32,
+    y_min: f32,
+    y_max: f32,
+    phi_max: f32,
     material: MaterialHandle,
 }
 
@@ -16,9 +22,54 @@ impl<TR> Sphere<TR> {
         Sphere {
             transform_seq,
             radius,
+            y_min: -radius,
+            y_max: radius,
+            phi_max: std::f32::consts::PI * 2.0,
             material,
         }
     }
+
+    /// Clips the sphere to a zone between the heights `y_min` and `y_max` relative to its
+    /// center and to the wedge of angles `0..phi_max` (in degrees) around the y axis,
+    /// counted from +x towards +z. A hemisphere is `with_extents(0.0, radius, 360.0)`.
+    /// Clipped spheres are open, so rays can hit their inside through the cut.
+    #[allow(dead_code)]
+    pub fn with_extents(mut self, y_min: f32, y_max: f32, phi_max: f32) -> Self {
+        self.y_min = y_min.min(y_max).max(-self.radius);
+        self.y_max = y_min.max(y_max).min(self.radius);
+        self.phi_max = phi_max.max(0.0).min(360.0).to_radians();
+        self
+    }
+
+    fn clipped(&self) -> bool {
+        self.y_min > -self.radius
+            || self.y_max < self.radius
+            || self.phi_max < std::f32::consts::PI * 2.0
+    }
+
+    /// Mask of the lanes whose point (relative to the center) is within the extents.
+    fn in_extents(&self, local: Wec3) -> f32x4 {
+        let above_min = !local.y.cmp_lt(f32x4::from(self.y_min));
+        let in_y = above_min & !local.y.cmp_gt(f32x4::from(self.y_max));
+        if self.phi_max >= std::f32::consts::PI * 2.0 {
+            return in_y;
+        }
+        in_y & phis(local).cmp_le(f32x4::from(self.phi_max))
+    }
+}
+
+/// Angle around the y axis in `0..2pi`, counted from +x towards +z.
+fn phis(local: Wec3) -> f32x4 {
+    let xs = local.x.as_ref();
+    let zs = local.z.as_ref();
+    let mut phis = [0.0; 4];
+    for (phi, (x, z)) in phis.iter_mut().zip(xs.iter().zip(zs.iter())) {
+        *phi = z.atan2(*x);
+        if *phi < 0.0 {
+            *phi += std::f32::consts::PI * 2.0;
+        }
+    }
+    f32x4::from(phis)
 }
 
 impl<TR: WSequenced<Wec3>> Hitable for Sphere<TR> {
@@ -35,14 +86,29 @@ impl<TR: WSequenced<Wec3>> Hitable for Sphere<TR> {
 
         let desc_pos = descrim.cmp_gt(f32x4::ZERO);
 
-        if desc_pos.move_mask() != 0b0000 {
+        if desc_pos.any() {
             let desc_sqrt = descrim.sqrt();
 
+            // roots close to the start are only as precise as the cancellation in -b +- sqrt
+            // allows, so anything closer than that (or than `SHADOW_T_MIN`) is treated as the
+            // surface we started on
+            let t_min = (f32x4::from(2.0 * gamma(7)) * oc.mag().max(f32x4::from(self.radius)))
+                .max(f32x4::from(SHADOW_T_MIN));
+
             let t1 = (-b - desc_sqrt) / f32x4::from(2.0);
-            let t1_valid = t1.cmp_gt(f32x4::from(0.001)) & t1.cmp_le(dist);
+            let t1_valid = t1.cmp_gt(t_min) & t1.cmp_le(dist);
 
             let t2 = (-b + desc_sqrt) / f32x4::from(2.0);
-            let t2_valid = t2.cmp_gt(f32x4::from(0.001)) & t2.cmp_le(dist);
+            let t2_valid = t2.cmp_gt(t_min) & t2.cmp_le(dist);
+
+            let (t1_valid, t2_valid) = if self.clipped() {
+                (
+                    t1_valid & self.in_extents(oc + dir * t1),
+                    t2_valid & self.in_extents(oc + dir * t2),
+                )
+            } else {
+                (t1_valid, t2_valid)
+            };
 
             f32x4::merge((t1_valid | t2_valid) & desc_pos, f32x4::ZERO, f32x4::ONE)
         } else {
@@ -62,7 +128,7 @@ impl<TR: WSequenced<Wec3>> Hitable for Sphere<TR> {
 
         let miss = f32x4::from(std::f32::MAX);
 
-        if desc_pos.move_mask() != 0b0000 {
+        if desc_pos.any() {
             let desc_sqrt = descrim.sqrt();
 
             let t1 = (-b - desc_sqrt) / (f32x4::from(2.0) * a);
@@ -71,6 +137,16 @@ impl<TR: WSequenced<Wec3>> Hitable for Sphere<TR> {
             let t2 = (-b + desc_sqrt) / (f32x4::from(2.0) * a);
             let t2_valid = t2.cmp_gt(t_range.start) & t2.cmp_le(t_range.end) & desc_pos;
 
+            // if the near root is clipped away, the far one may still hit the inside
+            let (t1_valid, t2_valid) = if self.clipped() {
+                (
+                    t1_valid & self.in_extents(oc + ray.dir * t1),
+                    t2_valid & self.in_extents(oc + ray.dir * t2),
+                )
+            } else {
+                (t1_valid, t2_valid)
+            };
+
             let take_t1 = t1.cmp_lt(t2) & t1_valid;
 
             let t = f32x4::merge(take_t1, t1, t2);
@@ -81,18 +157,134 @@ impl<TR: WSequenced<Wec3>> Hitable for Sphere<TR> {
         }
     }
 
+    /// Swept over the whole interval. The position is sampled at `MOTION_BOUND_SAMPLES`
+    /// times, and the boxes around each pair of neighbouring samples are padded by half the
+    /// distance between them, so that curved paths are covered in between the samples too.
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<Aabb> {
+        let step = (t1 - t0) / (MOTION_BOUND_SAMPLES - 1) as f32;
+        let mut bounds: Option<Aabb> = None;
+        let mut previous: Option<Vec3> = None;
+        for i in 0..MOTION_BOUND_SAMPLES {
+            // every lane gets the same time, so sequences which only look at the first
+            // lane are sampled at the right time as well
+            let time = f32x4::from(t0 + step * i as f32);
+            let origins: [Vec3; 4] = WSequenced::sample_at(&self.transform_seq, time).into();
+            let origin = origins[0];
+            let previous_origin = previous.replace(origin).unwrap_or(origin);
+
+            let half_size = Vec3::broadcast(self.radius + 0.5 * (origin - previous_origin).mag());
+            let swept = Aabb::from_center_half_size(origin, half_size)
+                .union(&Aabb::from_center_half_size(previous_origin, half_size));
+            bounds = Some(match bounds {
+                Some(bounds) => bounds.union(&swept),
+                None => swept,
+            });
+        }
+        bounds
+    }
+
     fn get_shading_info(
         &self,
         hit: WHit,
-        _primary: bool,
-        _camera: &dyn Camera,
+        primary: bool,
+        camera: &dyn Camera,
     ) -> (MaterialHandle, WShadingPoint) {
         let point = hit.point();
+        let offset_by = intersection_error_bound(&hit, primary, camera);
         let origin = WSequenced::sample_at(&self.transform_seq, hit.ray.time);
-        let normal = (point - origin).normalized();
+        let local = point - origin;
+        let normal = local.normalized();
+
+        // (u, v) = (phi / phi_max, (theta - theta_min) / (theta_max - theta_min)), with theta
+        // measured from +y, so that uvs span the whole clipped range
+        let theta_min = (self.y_max / self.radius).max(-1.0).min(1.0).acos();
+        let theta_max = (self.y_min / self.radius).max(-1.0).min(1.0).acos();
+        let theta_range = (theta_max - theta_min).max(std::f32::EPSILON);
+        let mut thetas = [0.0; 4];
+        for (theta, y) in thetas.iter_mut().zip(local.y.as_ref().iter()) {
+            *theta = (y / self.radius).max(-1.0).min(1.0).acos();
+        }
+        let uv = Wec2::new(
+            phis(local) / f32x4::from(self.phi_max),
+            (f32x4::from(thetas) - f32x4::from(theta_min)) / f32x4::from(theta_range),
+        );
+
+        let rho = (local.x * local.x + local.z * local.z).sqrt();
+        let inv_rho = f32x4::ONE / rho.max(f32x4::EPSILON);
+        let dpdu = Wec3::new(-local.z, f32x4::ZERO, local.x) * f32x4::from(self.phi_max);
+        let dpdv = Wec3::new(
+            local.y * local.x * inv_rho,
+            -rho,
+            local.y * local.z * inv_rho,
+        ) * f32x4::from(theta_range);
+
+        // the normal is the offset from the center over the radius, so it turns by as much
+        let shading_point = WShadingPoint::new(hit, point, offset_by, normal);
+        let inv_radius = f32x4::from(1.0 / self.radius);
+        let (dndx, dndy) = (
+            shading_point.dpdx * inv_radius,
+            shading_point.dpdy * inv_radius,
+        );
         (
             self.material,
-            WShadingPoint::new(hit, point, f32x4::ZERO, normal),
+            shading_point
+                .with_normal_differentials(dndx, dndy)
+                .with_uv_derivatives(dpdu, dpdv)
+                .with_uv(uv),
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hemispheres_reject_the_lower_half() {
+        let hemisphere =
+            Sphere::new(Vec3::zero(), 1.0, MaterialHandle(0)).with_extents(0.0, 1.0, 360.0);
+        let range = f32x4::ZERO..f32x4::from(std::f32::MAX);
+        let hit_t = |origin, dir| {
+            hemisphere
+                .hit(&WRay::along(origin, dir), range.clone())
+                .as_ref()[0]
+        };
+
+        assert_eq!(
+            hit_t(Vec3::new(-2.0, -0.5, 0.0), Vec3::unit_x()),
+            std::f32::MAX
+        );
+        assert!(hit_t(Vec3::new(-2.0, 0.5, 0.0), Vec3::unit_x()) < 2.0);
+        // the bottom is cut away, so rays from below go through to the inside of the top
+        assert!((hit_t(Vec3::new(0.0, -2.0, 0.0), Vec3::unit_y()) - 3.0).abs() < 1e-4);
+
+        let start = Wec3::splat(Vec3::new(-2.0, -0.5, 0.0));
+        let end = Wec3::splat(Vec3::new(2.0, -0.5, 0.0));
+        assert!(hemisphere
+            .occluded(start, end, f32x4::ZERO)
+            .cmp_eq(f32x4::ONE)
+            .all());
+    }
+
+    #[test]
+    fn fast_spheres_are_bounded_over_the_whole_shutter() {
+        let linear = |t: f32| Vec3::new(100.0 * t, 0.0, 0.0);
+        // once all the way around a circle while the shutter is open
+        let circle = |t: f32| {
+            let angle = t * 2.0 * std::f32::consts::PI;
+            Vec3::new(angle.cos(), angle.sin(), 0.0) * 10.0
+        };
+        let paths: [&(dyn Fn(f32) -> Vec3 + Send + Sync); 2] = [&linear, &circle];
+
+        for path in paths.iter() {
+            let sphere = Sphere::new(*path, 0.5, MaterialHandle(0));
+            let bounds = sphere.bounding_box(0.0, 1.0).unwrap();
+            for i in 0..=1000 {
+                let center = path(i as f32 / 1000.0);
+                let (min, max) = (center - Vec3::broadcast(0.5), center + Vec3::broadcast(0.5));
+                assert!(min.x >= bounds.min.x && min.y >= bounds.min.y && min.z >= bounds.min.z);
+                assert!(max.x <= bounds.max.x && max.y <= bounds.max.y && max.z <= bounds.max.z);
+            }
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use crate::hitable::WShadingPoint;
+use crate::material::WShadingParamGenerator;
+use crate::math::{f32x4, Vec2};
+use crate::spectrum::{Srgb, WSrgb};
+
+/// How the values stored in a texture image are encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextureEncoding {
+    /// Colors with the sRGB transfer function applied, like most albedo maps. They're
+    /// converted to linear when loaded.
+    Srgb,
+    /// Linear values or non-color data (normals, roughness...), used as they are.
+    Linear,
+}
+
+/// Decodes an sRGB-encoded value in [0, 1] to linear.
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// An image looked up with the uv of the shading point, bilinearly filtered and repeating
+/// outside of [0, 1]. v goes up the image, so uv (0, 0) is its bottom-left corner.
+pub struct ImageTexture {
+    texels: Vec<Srgb>,
+    width: usize,
+    height: usize,
+}
+
+impl ImageTexture {
+    /// `texels` holds `width * height` linear values, row by row from the top.
+    #[allow(dead_code)]
+    pub fn new(texels: Vec<Srgb>, width: usize, height: usize) -> Result<Self, String> {
+        if width == 0 || height == 0 || texels.len() != width * height {
+            return Err(format!(
+                "Texture of size {}x{} needs {} texels, got {}",
+                width,
+                height,
+                width * height,
+                texels.len()
+            ));
+        }
+        Ok(Self {
+            texels,
+            width,
+            height,
+        })
+    }
+
+    /// Loads a texture from an image file, decoding it according to `encoding`.
+    #[allow(dead_code)]
+    pub fn open<P: AsRef<std::path::Path>>(
+        path: P,
+        encoding: TextureEncoding,
+    ) -> Result<Self, String> {
+        let image = image::open(path.as_ref())
+            .map_err(|e| format!("Failed to open texture image: {}", e))?
+            .to_rgb();
+        let (width, height) = image.dimensions();
+
+        let decode = |value: u8| {
+            let value = f32::from(value) / 255.0;
+            match encoding {
+                TextureEncoding::Srgb => srgb_to_linear(value),
+                TextureEncoding::Linear => value,
+            }
+        };
+        let texels = image
+            .pixels()
+            .map(|pixel| {
+                let [r, g, b] = pixel.0;
+                Srgb::new(decode(r), decode(g), decode(b))
+            })
+            .collect::<Vec<_>>();
+        Self::new(texels, width as usize, height as usize)
+    }
+
+    fn texel(&self, x: isize, y: isize) -> Srgb {
+        let x = x.rem_euclid(self.width as isize) as usize;
+        let y = y.rem_euclid(self.height as isize) as usize;
+        self.texels[x + y * self.width]
+    }
+
+    pub fn lookup(&self, uv: Vec2) -> Srgb {
+        let x = uv.x * self.width as f32 - 0.5;
+        let y = (1.0 - uv.y) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+
+        let top = self.texel(x0, y0) * (1.0 - fx) + self.texel(x0 + 1, y0) * fx;
+        let bottom = self.texel(x0, y0 + 1) * (1.0 - fx) + self.texel(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+impl WShadingParamGenerator<WSrgb> for ImageTexture {
+    fn gen(&self, intersection: &WShadingPoint) -> WSrgb {
+        let uvs: [Vec2; 4] = intersection.uv.into();
+        let mut colors = [Srgb::zero(); 4];
+        for (color, uv) in colors.iter_mut().zip(uvs.iter()) {
+            *color = self.lookup(*uv);
+        }
+        WSrgb::from(colors)
+    }
+}
+
+/// Single channel parameters (like roughness) are read from the red channel.
+impl WShadingParamGenerator<f32x4> for ImageTexture {
+    fn gen(&self, intersection: &WShadingPoint) -> f32x4 {
+        let uvs: [Vec2; 4] = intersection.uv.into();
+        let mut values = [0.0; 4];
+        for (value, uv) in values.iter_mut().zip(uvs.iter()) {
+            *value = self.lookup(*uv).x;
+        }
+        f32x4::from(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srgb_decoding() {
+        assert!((srgb_to_linear(0.5) - 0.214).abs() < 1e-3);
+        assert_eq!(srgb_to_linear(0.0), 0.0);
+        assert!((srgb_to_linear(1.0) - 1.0).abs() < 1e-6);
+        // the linear segment and the curve meet at the threshold
+        assert!((srgb_to_linear(0.04045) - srgb_to_linear(0.04046)).abs() < 1e-5);
+    }
+}
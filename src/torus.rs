@@ -0,0 +1,263 @@
+use crate::camera::Camera;
+use crate::hitable::{intersection_error_bound, Hitable, WHit, WShadingPoint, SHADOW_T_MIN};
+use crate::material::MaterialHandle;
+use crate::math::{f32x4, gamma, Aabb, Vec3, Wec3};
+use crate::ray::WRay;
+
+/// Iterations of bisection per root, which is enough to get to f64 precision.
+const BISECTION_STEPS: usize = 64;
+
+/// A ring around `axis` through `center`. Points on the surface are `minor_radius` away from
+/// the circle of radius `major_radius` around the axis.
+///
+/// The ray-torus quartic is solved one lane at a time in f64 with a numeric root finder,
+/// since the closed form solution is too unstable in f32 for grazing rays.
+pub struct Torus {
+    center: Vec3,
+    axis: Vec3,
+    // complete an orthonormal basis with `axis`
+    tangent: Vec3,
+    bitangent: Vec3,
+    major_radius: f32,
+    minor_radius: f32,
+    material: MaterialHandle,
+}
+
+impl Torus {
+    #[allow(dead_code)]
+    pub fn new(
+        center: Vec3,
+        axis: Vec3,
+        major_radius: f32,
+        minor_radius: f32,
+        material: MaterialHandle,
+    ) -> Self {
+        let axis = axis.normalized();
+        let helper = if axis.x.abs() > 0.9 {
+            Vec3::unit_y()
+        } else {
+            Vec3::unit_x()
+        };
+        let tangent = helper.cross(axis).normalized();
+        let bitangent = axis.cross(tangent);
+        Torus {
+            center,
+            axis,
+            tangent,
+            bitangent,
+            major_radius,
+            minor_radius,
+            material,
+        }
+    }
+
+    /// `v` in the frame of the torus, where the axis is y.
+    fn to_local(&self, v: Vec3) -> Vec3 {
+        Vec3::new(v.dot(self.tangent), v.dot(self.axis), v.dot(self.bitangent))
+    }
+
+    /// Closest intersection of a single ray with `t` in `(t_start, t_end]`.
+    fn intersect_one(&self, origin: Vec3, dir: Vec3, t_start: f32, t_end: f32) -> Option<f32> {
+        let o = self.to_local(origin - self.center);
+        let d = self.to_local(dir);
+        let (ox, oy, oz) = (f64::from(o.x), f64::from(o.y), f64::from(o.z));
+        let (dx, dy, dz) = (f64::from(d.x), f64::from(d.y), f64::from(d.z));
+        let major2 = f64::from(self.major_radius) * f64::from(self.major_radius);
+        let minor2 = f64::from(self.minor_radius) * f64::from(self.minor_radius);
+
+        // only look for roots inside of the bounding sphere, so the search interval is finite
+        let dd = dx * dx + dy * dy + dz * dz;
+        let od = ox * dx + oy * dy + oz * dz;
+        let oo = ox * ox + oy * oy + oz * oz;
+        let bound = f64::from(self.major_radius + self.minor_radius);
+        let descrim = od * od - dd * (oo - bound * bound);
+        if descrim <= 0.0 || dd == 0.0 {
+            return None;
+        }
+        let desc_sqrt = descrim.sqrt();
+        let lo = ((-od - desc_sqrt) / dd).max(f64::from(t_start));
+        let hi = ((-od + desc_sqrt) / dd).min(f64::from(t_end));
+        if lo >= hi {
+            return None;
+        }
+
+        // (|p|^2 - R^2 - r^2)^2 + 4R^2 (p_y^2 - r^2) = 0 along p = o + t d
+        let e = oo - major2 - minor2;
+        let coeffs = [
+            e * e - 4.0 * major2 * (minor2 - oy * oy),
+            4.0 * od * e + 8.0 * major2 * oy * dy,
+            2.0 * dd * e + 4.0 * od * od + 4.0 * major2 * dy * dy,
+            4.0 * dd * od,
+            dd * dd,
+        ];
+        roots_in(&coeffs, lo, hi)
+            .into_iter()
+            .find(|t| *t > f64::from(t_start))
+            .map(|t| t as f32)
+    }
+
+    fn intersect(&self, origin: Wec3, dir: Wec3, t_start: f32x4, t_end: f32x4) -> f32x4 {
+        let origins: [Vec3; 4] = origin.into();
+        let dirs: [Vec3; 4] = dir.into();
+        let mut ts = [std::f32::MAX; 4];
+        let lanes = origins.iter().zip(&dirs);
+        let ranges = t_start.as_ref().iter().zip(t_end.as_ref());
+        for (t, ((origin, dir), (t_start, t_end))) in ts.iter_mut().zip(lanes.zip(ranges)) {
+            if let Some(hit) = self.intersect_one(*origin, *dir, *t_start, *t_end) {
+                *t = hit;
+            }
+        }
+        f32x4::from(ts)
+    }
+}
+
+fn evaluate(coeffs: &[f64], t: f64) -> f64 {
+    coeffs.iter().rev().fold(0.0, |acc, c| acc * t + c)
+}
+
+/// All roots in `[lo, hi]` of the polynomial with `coeffs` (constant term first), in
+/// increasing order. The roots of the derivative split the interval into pieces where the
+/// polynomial is monotonic, each of which holds at most one root that bisection can find.
+fn roots_in(coeffs: &[f64], lo: f64, hi: f64) -> Vec<f64> {
+    match coeffs.len() {
+        0 | 1 => Vec::new(),
+        2 => {
+            let root = -coeffs[0] / coeffs[1];
+            if coeffs[1] != 0.0 && root >= lo && root <= hi {
+                vec![root]
+            } else {
+                Vec::new()
+            }
+        }
+        _ => {
+            let derivative = coeffs
+                .iter()
+                .enumerate()
+                .skip(1)
+                .map(|(i, c)| c * i as f64)
+                .collect::<Vec<_>>();
+
+            let mut roots = Vec::new();
+            let mut start = lo;
+            let mut ends = roots_in(&derivative, lo, hi);
+            ends.push(hi);
+            for end in ends {
+                roots.extend(bisect(coeffs, start, end));
+                start = end;
+            }
+            roots
+        }
+    }
+}
+
+/// Root of a polynomial which is monotonic on `[lo, hi]`, if it changes sign there.
+fn bisect(coeffs: &[f64], mut lo: f64, mut hi: f64) -> Option<f64> {
+    let f_lo = evaluate(coeffs, lo);
+    let f_hi = evaluate(coeffs, hi);
+    if f_lo == 0.0 {
+        return Some(lo);
+    }
+    if f_lo.signum() == f_hi.signum() {
+        return None;
+    }
+    for _ in 0..BISECTION_STEPS {
+        let mid = 0.5 * (lo + hi);
+        let f_mid = evaluate(coeffs, mid);
+        if f_mid == 0.0 {
+            return Some(mid);
+        }
+        if f_mid.signum() == f_lo.signum() {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    Some(0.5 * (lo + hi))
+}
+
+impl Hitable for Torus {
+    fn occluded(&self, start: Wec3, end: Wec3, _time: f32x4) -> f32x4 {
+        let dir = end - start;
+        let dist = dir.mag();
+        let dir = dir / dist;
+
+        // roots this close to the start can't be told apart from the surface the ray
+        // started on, and neither can any closer than `SHADOW_T_MIN`
+        let extent = self.major_radius + self.minor_radius;
+        let oc = start - Wec3::splat(self.center);
+        let t_min = (f32x4::from(2.0 * gamma(7)) * oc.mag().max(f32x4::from(extent)))
+            .max(f32x4::from(SHADOW_T_MIN));
+
+        let t = self.intersect(start, dir, t_min, dist);
+        f32x4::merge(
+            t.cmp_lt(f32x4::from(std::f32::MAX)),
+            f32x4::ZERO,
+            f32x4::ONE,
+        )
+    }
+
+    fn hit(&self, ray: &WRay, t_range: ::std::ops::Range<f32x4>) -> f32x4 {
+        self.intersect(ray.origin, ray.dir, t_range.start, t_range.end)
+    }
+
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<Aabb> {
+        // the ring spans less than the major radius along the world axes it's tilted away from
+        let ring = |axis: f32| self.major_radius * (1.0 - axis * axis).max(0.0).sqrt();
+        let half_size = Vec3::new(ring(self.axis.x), ring(self.axis.y), ring(self.axis.z))
+            + Vec3::broadcast(self.minor_radius);
+        Some(Aabb::from_center_half_size(self.center, half_size))
+    }
+
+    fn get_shading_info(
+        &self,
+        hit: WHit,
+        primary: bool,
+        camera: &dyn Camera,
+    ) -> (MaterialHandle, WShadingPoint) {
+        let point = hit.point();
+        let offset_by = intersection_error_bound(&hit, primary, camera);
+
+        // the normal points away from the closest point on the ring through the tube
+        let local = point - Wec3::splat(self.center);
+        let axis = Wec3::splat(self.axis);
+        let in_plane = local - axis * local.dot(axis);
+        let ring_point = in_plane.normalized() * f32x4::from(self.major_radius);
+        let normal = (local - ring_point).normalized();
+
+        (
+            self.material,
+            WShadingPoint::new(hit, point, offset_by, normal),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rays_through_the_hole_miss() {
+        let torus = Torus::new(Vec3::zero(), Vec3::unit_y(), 1.0, 0.25, MaterialHandle(0));
+        let down = -Vec3::unit_y();
+
+        assert_eq!(
+            torus.intersect_one(Vec3::new(0.0, 2.0, 0.0), down, 0.0, 10.0),
+            None
+        );
+        let t = torus.intersect_one(Vec3::new(1.0, 2.0, 0.0), down, 0.0, 10.0);
+        assert!(t.map_or(false, |t| (t - 1.75).abs() < 1e-4));
+        // along the axis of a tilted torus, too
+        let tilted = Torus::new(
+            Vec3::zero(),
+            Vec3::broadcast(1.0),
+            1.0,
+            0.25,
+            MaterialHandle(0),
+        );
+        let dir = -Vec3::broadcast(1.0).normalized();
+        assert_eq!(
+            tilted.intersect_one(Vec3::broadcast(2.0), dir, 0.0, 10.0),
+            None
+        );
+    }
+}
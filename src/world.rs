@@ -1,11 +1,13 @@
 use crate::camera::CameraStore;
 use crate::hitable::HitableStore;
-use crate::light::Light;
+use crate::light::{Light, LightSampler};
 use crate::material::MaterialStore;
 
 pub struct World {
     pub hitables: HitableStore,
     pub lights: Vec<Box<dyn Light>>,
+    /// Must be built from `lights`
+    pub light_sampler: LightSampler,
     pub materials: MaterialStore,
     pub cameras: CameraStore,
 }